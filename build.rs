@@ -0,0 +1,13 @@
+fn main() {
+    // The gRPC code is generated from the .proto file.  We use protox to
+    // parse it, so that building doesn't need protoc to be installed.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/tailsrv.proto");
+        let fds = protox::compile(["tailsrv.proto"], ["proto"]).unwrap();
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(fds)
+            .unwrap();
+    }
+}
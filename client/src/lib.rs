@@ -0,0 +1,277 @@
+//! A client for [tailsrv](https://github.com/asayers/tailsrv).
+//!
+//! [`TailReader`] connects to a tailsrv, sends the header, and hands you the
+//! stream as an [`io::Read`].  It keeps track of how far through the file
+//! it's got, so if the connection is lost it can reconnect and carry on from
+//! where it left off.
+//!
+//! ```no_run
+//! use tailsrv_client::TailReader;
+//!
+//! let mut rdr = TailReader::builder("127.0.0.1:4321")?
+//!     .offset(0)
+//!     .reconnect(true)
+//!     .connect()?;
+//! std::io::copy(&mut rdr, &mut std::io::stdout())?;
+//! # std::io::Result::Ok(())
+//! ```
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::time::Duration;
+
+/// How long we wait before the first attempt to reconnect.  This doubles
+/// after each failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+/// The longest we wait between attempts to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where to start in the file
+#[derive(Debug, Clone, Copy)]
+enum Start {
+    Byte(i64),
+    LastLines(u64),
+}
+
+/// Settings for a [`TailReader`]
+#[derive(Debug, Clone)]
+pub struct Builder {
+    addrs: Vec<SocketAddr>,
+    start: Start,
+    auth: Option<String>,
+    keepalive: Option<Duration>,
+    reconnect: bool,
+}
+
+impl Builder {
+    /// Where to start in the file.  Negative values count back from the end.
+    /// The default is 0.
+    pub fn offset(mut self, offset: i64) -> Builder {
+        self.start = Start::Byte(offset);
+        self
+    }
+
+    /// Start from the last `n` lines of the file, like `tail -n`.  This needs
+    /// a server which understands `line <N>`.  Since we don't know the
+    /// offset, we can't reconnect.
+    pub fn last_lines(mut self, n: u64) -> Builder {
+        self.start = Start::LastLines(n);
+        self
+    }
+
+    /// The token to send, if the server was started with
+    /// `--auth-token-file`.  If the token is wrong, the server sends
+    /// `ERR unauthorized` and hangs up.
+    pub fn auth(mut self, token: impl Into<String>) -> Builder {
+        self.auth = Some(token.into());
+        self
+    }
+
+    /// Use TCP keepalive to detect dead connections, probing after this
+    /// much silence.  The default is 5 seconds.  `None` turns it off.
+    pub fn keepalive(mut self, interval: Option<Duration>) -> Builder {
+        self.keepalive = interval;
+        self
+    }
+
+    /// Whether to reconnect if the connection is lost.  We back off
+    /// exponentially between attempts, and keep trying forever.
+    ///
+    /// This only works if we know how far through the file we are, so it has
+    /// no effect when starting from a negative offset or from the last few
+    /// lines.
+    pub fn reconnect(mut self, reconnect: bool) -> Builder {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Ask the server for the CRC-32 of a range of the file, for checking
+    /// that a copy is intact.  Compare it with [`crc32`].  This needs a server
+    /// which understands `hash <FROM> <TO>`.
+    pub fn hash(&self, range: Range<u64>) -> io::Result<u32> {
+        let command = format!("hash {} {}", range.start, range.end);
+        let conn = send_header(self, &command)?;
+        let mut reply = String::new();
+        BufReader::new(conn).read_line(&mut reply)?;
+        let reply = reply.trim();
+        if let Some(err) = reply.strip_prefix("ERR ") {
+            return Err(io::Error::other(err.to_owned()));
+        }
+        u32::from_str_radix(reply, 16).map_err(|e| io::Error::other(format!("{reply}: {e}")))
+    }
+
+    /// Ask the server how long the file is.  This needs a server which
+    /// understands `size`.
+    pub fn size(&self) -> io::Result<u64> {
+        let conn = send_header(self, "size")?;
+        let mut reply = String::new();
+        BufReader::new(conn).read_line(&mut reply)?;
+        let reply = reply.trim();
+        if let Some(err) = reply.strip_prefix("ERR ") {
+            return Err(io::Error::other(err.to_owned()));
+        }
+        reply
+            .parse()
+            .map_err(|e| io::Error::other(format!("{reply}: {e}")))
+    }
+
+    /// Connect to the server and send the header.  If this fails we don't
+    /// retry, even if `reconnect` is set.
+    pub fn connect(self) -> io::Result<TailReader> {
+        let offset = match self.start {
+            Start::Byte(offset) => u64::try_from(offset).ok(),
+            Start::LastLines(_) => None,
+        };
+        let conn = open(&self, self.start)?;
+        Ok(TailReader {
+            builder: self,
+            conn: Some(conn),
+            offset,
+            bytes_read: 0,
+        })
+    }
+}
+
+/// The stream from a tailsrv
+#[derive(Debug)]
+pub struct TailReader {
+    builder: Builder,
+    /// `None` while we're reconnecting
+    conn: Option<TcpStream>,
+    offset: Option<u64>,
+    bytes_read: u64,
+}
+
+impl TailReader {
+    /// Start setting up a connection to the tailsrv at `addr`
+    pub fn builder(addr: impl ToSocketAddrs) -> io::Result<Builder> {
+        Ok(Builder {
+            addrs: addr.to_socket_addrs()?.collect(),
+            start: Start::Byte(0),
+            auth: None,
+            keepalive: Some(Duration::from_secs(5)),
+            reconnect: false,
+        })
+    }
+
+    /// Connect to the tailsrv at `addr`, starting from `offset`, with the
+    /// default settings
+    pub fn connect(addr: impl ToSocketAddrs, offset: i64) -> io::Result<TailReader> {
+        TailReader::builder(addr)?.offset(offset).connect()
+    }
+
+    /// The position in the file of the next byte to be read.  We don't know
+    /// this if we started from a negative offset.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// The number of bytes which have been read so far, across all
+    /// connections
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    fn can_reconnect(&self) -> bool {
+        self.builder.reconnect && self.offset.is_some()
+    }
+
+    /// Keep trying to connect until we succeed
+    fn reconnect(&mut self, offset: u64) -> TcpStream {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            std::thread::sleep(backoff);
+            if let Ok(conn) = open(&self.builder, Start::Byte(offset as i64)) {
+                return conn;
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+impl Read for TailReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let conn = match &mut self.conn {
+                Some(conn) => conn,
+                None => {
+                    // can_reconnect() checked that we know the offset
+                    let conn = self.reconnect(self.offset.unwrap());
+                    self.conn.insert(conn)
+                }
+            };
+            let result = conn.read(buf);
+            match result {
+                // The server hung up
+                Ok(0) if !buf.is_empty() => (),
+                Ok(n) => {
+                    if let Some(offset) = &mut self.offset {
+                        *offset += n as u64;
+                    }
+                    self.bytes_read += n as u64;
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return result,
+                Err(_) => (),
+            }
+            if !self.can_reconnect() {
+                return result;
+            }
+            self.conn = None;
+        }
+    }
+}
+
+/// Connect to the server and send the header
+fn open(builder: &Builder, start: Start) -> io::Result<TcpStream> {
+    let start = match start {
+        Start::Byte(offset) => offset.to_string(),
+        Start::LastLines(n) => format!("line -{n}"),
+    };
+    send_header(builder, &start)
+}
+
+/// Connect to the server and send `request`, along with the token if there
+/// is one
+fn send_header(builder: &Builder, request: &str) -> io::Result<TcpStream> {
+    let mut conn = TcpStream::connect(&builder.addrs[..])?;
+    if let Some(interval) = builder.keepalive {
+        rustix::net::sockopt::set_socket_keepalive(&conn, true)?;
+        rustix::net::sockopt::set_tcp_keepidle(&conn, interval)?;
+    }
+    match &builder.auth {
+        Some(token) => writeln!(conn, "auth {token} {request}")?,
+        None => writeln!(conn, "{request}")?,
+    }
+    Ok(conn)
+}
+
+/// The lookup table for CRC-32 (the IEEE polynomial, as used by zlib)
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 0 {
+                c >> 1
+            } else {
+                0xedb8_8320 ^ (c >> 1)
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// Extend a CRC-32 with more data.  Start with 0.  This is the same checksum
+/// which the server computes for [`Builder::hash`].
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |c, &b| {
+        CRC_TABLE[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8)
+    })
+}
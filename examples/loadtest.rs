@@ -1,12 +1,14 @@
 use bpaf::{Bpaf, Parser};
-use net2::TcpStreamExt;
+use std::fs::File;
 use std::io::{prelude::*, BufReader};
+use std::path::PathBuf;
 use std::thread::JoinHandle;
 use std::{
     net::{SocketAddr, TcpStream},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tailsrv_client::TailReader;
 
 #[derive(Bpaf)]
 struct Opts {
@@ -15,38 +17,94 @@ struct Opts {
     /// How often to ping the server to check for a dead connection
     #[bpaf(fallback(5))]
     heartbeat_secs: u64,
+    /// Append timestamped lines to this file, which should be the one the
+    /// server is serving.  The clients use the timestamps to measure how
+    /// long each line took to reach them
+    #[bpaf(argument("PATH"))]
+    write: Option<PathBuf>,
+    /// How often to append a line, when using --write
+    #[bpaf(fallback(10))]
+    write_interval_ms: u64,
+    /// Stop after this many seconds and print a summary
+    #[bpaf(argument("SECS"))]
+    duration: Option<u64>,
+    /// Chaos: have each client hang up and reconnect at random, on average
+    /// this often
+    #[bpaf(argument("SECS"))]
+    chaos_disconnect: Option<f64>,
+    /// Chaos: have each client stop reading for a few seconds at random, on
+    /// average this often.  This leaves the server with a full socket
+    #[bpaf(argument("SECS"))]
+    chaos_stall: Option<f64>,
+    /// Chaos: run this many extra clients which keep connecting and sending
+    /// malformed headers
+    #[bpaf(argument("N"), fallback(0))]
+    chaos_bad_headers: usize,
     /// The remote tailsrv to connect to
     #[bpaf(positional("ADDR"))]
     addr: SocketAddr,
 }
 
+/// What a client has seen so far
+#[derive(Default)]
+struct Tail {
+    last_line: String,
+    bytes: u64,
+    /// How long each of the writer's lines took to arrive
+    latencies: Vec<Duration>,
+}
+
 fn main() -> std::io::Result<()> {
     let opts = opts().run();
-    let mut tails: Vec<Arc<Mutex<String>>> = vec![];
+    let start = SystemTime::now();
+    if let Some(path) = opts.write.clone() {
+        let file = File::options().append(true).create(true).open(path)?;
+        let interval = Duration::from_millis(opts.write_interval_ms);
+        std::thread::spawn(move || {
+            if let Err(e) = write_lines(file, interval) {
+                eprintln!("Couldn't write to the file: {e}");
+            }
+        });
+    }
+    let mut tails: Vec<Arc<Mutex<Tail>>> = vec![];
     let mut ts: Vec<JoinHandle<_>> = vec![];
-    for _ in 0..opts.jobs {
-        tails.push(Arc::new(Mutex::new(String::new())));
+    for i in 0..opts.jobs {
+        tails.push(Arc::new(Mutex::new(Tail::default())));
         let tail = tails.last().unwrap().clone();
+        let mut chaos = Chaos {
+            rng: Rng::new(i),
+            disconnect: opts.chaos_disconnect,
+            stall: opts.chaos_stall,
+        };
         ts.push(std::thread::spawn(move || {
-            let mut conn = TcpStream::connect(opts.addr)?;
-            // Use TCP keepalive to detect dead connections
-            let keepalive = Duration::from_secs(opts.heartbeat_secs);
-            conn.set_keepalive(Some(keepalive))?;
-            // Start from the beginning
-            writeln!(conn, "0")?;
-            let mut buf = String::new();
-            let mut conn = BufReader::new(conn);
+            // Use TCP keepalive to detect dead connections.  Start from the
+            // beginning.
+            let builder = TailReader::builder(opts.addr)?
+                .keepalive(Some(Duration::from_secs(opts.heartbeat_secs)));
+            let mut offset = 0;
             loop {
-                buf.clear();
-                let n = conn.read_line(&mut buf)?;
-                if n == 0 {
+                let conn = builder.clone().offset(offset as i64).connect()?;
+                let mut conn = BufReader::new(conn);
+                if !read_lines(&mut conn, &tail, start, &mut chaos)? {
                     return std::io::Result::Ok(());
                 }
-                std::mem::swap(&mut *tail.lock().unwrap(), &mut buf);
+                // Carry on from the end of the last line we read
+                offset = conn.get_ref().offset().unwrap() - conn.buffer().len() as u64;
             }
         }));
     }
+    for i in 0..opts.chaos_bad_headers {
+        let mut rng = Rng::new(opts.jobs + i);
+        std::thread::spawn(move || loop {
+            send_bad_header(opts.addr, &mut rng);
+            std::thread::sleep(Duration::from_millis(100));
+        });
+    }
 
+    let started = Instant::now();
+    let deadline = opts
+        .duration
+        .map(|secs| started + Duration::from_secs(secs));
     let mut term = liveterm::TermPrinter::new(std::io::stdout().lock());
     loop {
         use std::fmt::Write;
@@ -54,24 +112,181 @@ fn main() -> std::io::Result<()> {
         term.buf.clear();
         let reference = tails
             .first()
-            .map(|x| x.lock().unwrap().clone())
+            .map(|x| x.lock().unwrap().last_line.clone())
             .unwrap_or_default();
         let mut n = 0;
         for (i, tail) in tails.iter().enumerate() {
             let tail = tail.lock().unwrap();
-            if *tail == reference {
+            if tail.last_line == reference {
                 n += 1;
             } else {
-                writeln!(&mut term.buf, "#{i}: {}", tail.trim()).unwrap();
+                writeln!(&mut term.buf, "#{i}: {}", tail.last_line.trim()).unwrap();
             }
         }
         writeln!(&mut term.buf, "{n} others: {}", reference.trim()).unwrap();
         let any_alive = ts.iter().any(|t| !t.is_finished());
-        if any_alive {
+        let out_of_time = deadline.is_some_and(|t| Instant::now() >= t);
+        if any_alive && !out_of_time {
             term.print()?;
         } else {
-            return term.print_all();
+            term.print_all()?;
+            break;
         }
         std::thread::sleep(Duration::from_secs(1));
     }
+    print_summary(&tails, started.elapsed());
+    Ok(())
+}
+
+/// Read lines until the server hangs up, in which case this returns false, or
+/// until chaos mode decides to hang up, in which case it returns true
+fn read_lines(
+    conn: &mut impl BufRead,
+    tail: &Mutex<Tail>,
+    start: SystemTime,
+    chaos: &mut Chaos,
+) -> std::io::Result<bool> {
+    let mut next_disconnect = chaos.disconnect.map(|x| chaos.rng.deadline(x));
+    let mut next_stall = chaos.stall.map(|x| chaos.rng.deadline(x));
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        let n = conn.read_line(&mut buf)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        {
+            let mut tail = tail.lock().unwrap();
+            tail.bytes += n as u64;
+            // Lines from before we started would skew the results
+            if let Some(sent) = timestamp(&buf).filter(|&t| t >= start) {
+                let latency = SystemTime::now().duration_since(sent).unwrap_or_default();
+                tail.latencies.push(latency);
+            }
+            std::mem::swap(&mut tail.last_line, &mut buf);
+        }
+        let now = Instant::now();
+        if next_disconnect.is_some_and(|t| now >= t) {
+            return Ok(true);
+        }
+        if next_stall.is_some_and(|t| now >= t) {
+            let stall = Duration::from_secs_f64(1.0 + 4.0 * chaos.rng.next_f64());
+            std::thread::sleep(stall);
+            next_stall = chaos.stall.map(|x| chaos.rng.deadline(x));
+            // Don't let the stall count towards the next disconnect
+            next_disconnect = next_disconnect.map(|t| t + stall);
+        }
+    }
+}
+
+/// Connect, send a header which the server should reject, and hang up
+fn send_bad_header(addr: SocketAddr, rng: &mut Rng) {
+    const BAD_HEADERS: &[&[u8]] = &[
+        b"banana\n",
+        b"byte\n",
+        b"since yesterday\n",
+        b"from /unterminated\n",
+        b"from /(/\n",
+        b"compress gzip 0\n",
+        b"hash 10 0\n",
+        b"\xff\xfe\n",
+        b"0",
+        b"",
+    ];
+    let Ok(mut conn) = TcpStream::connect(addr) else {
+        return;
+    };
+    let header = match rng.next_u64() as usize % (BAD_HEADERS.len() + 1) {
+        // One which is too long
+        0 => vec![b'1'; 1 << 16],
+        i => BAD_HEADERS[i - 1].to_vec(),
+    };
+    if conn.write_all(&header).is_err() {
+        return;
+    }
+    // Wait for the server's response, but not forever
+    let _ = conn.set_read_timeout(Some(Duration::from_secs(1)));
+    let _ = conn.read_to_end(&mut vec![]);
+}
+
+/// How a client should misbehave
+struct Chaos {
+    rng: Rng,
+    /// The mean time between disconnects
+    disconnect: Option<f64>,
+    /// The mean time between stalls
+    stall: Option<f64>,
+}
+
+/// A xorshift generator; good enough for picking when to misbehave
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: usize) -> Rng {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Rng((now.as_nanos() as u64 ^ (seed as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A random point in the future.  The wait is exponentially distributed,
+    /// with the given mean.
+    fn deadline(&mut self, mean_secs: f64) -> Instant {
+        let wait = -mean_secs * (1.0 - self.next_f64()).ln();
+        Instant::now() + Duration::from_secs_f64(wait)
+    }
+}
+
+/// Append a line to the file every `interval`.  Each line is the time at
+/// which it was written, in microseconds since the epoch.
+fn write_lines(mut file: File, interval: Duration) -> std::io::Result<()> {
+    loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        writeln!(file, "{}", now.as_micros())?;
+        std::thread::sleep(interval);
+    }
+}
+
+/// The time at which a line was written by `write_lines`
+fn timestamp(line: &str) -> Option<SystemTime> {
+    let micros: u64 = line.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_micros(micros))
+}
+
+fn print_summary(tails: &[Arc<Mutex<Tail>>], elapsed: Duration) {
+    let mut latencies = vec![];
+    let mut bytes = 0;
+    for tail in tails {
+        let tail = tail.lock().unwrap();
+        latencies.extend_from_slice(&tail.latencies);
+        bytes += tail.bytes;
+    }
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "Received {bytes} bytes in {secs:.1}s ({:.1} MiB/s across all clients)",
+        bytes as f64 / secs / (1 << 20) as f64,
+    );
+    if latencies.is_empty() {
+        println!("No timestamped lines were received (see --write)");
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "Latency over {} lines: p50 {:?}, p99 {:?}, max {:?}",
+        latencies.len(),
+        percentile(50),
+        percentile(99),
+        latencies.last().unwrap(),
+    );
 }
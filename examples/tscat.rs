@@ -1,14 +1,21 @@
 use bpaf::{Bpaf, Parser};
-use net2::TcpStreamExt;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpStream};
+use std::net::SocketAddr;
 use std::time::Duration;
+use tailsrv_client::TailReader;
 
 #[derive(Bpaf)]
 struct Opts {
     /// How often to ping the server to check for a dead connection
     #[bpaf(fallback(5))]
     heartbeat_secs: u64,
+    /// Exit if the connection is lost, rather than reconnecting
+    no_reconnect: bool,
+    /// Start from the last N lines, like `tail -n`
+    #[bpaf(short('n'), long, argument("N"))]
+    lines: Option<u64>,
+    /// Start from the last N bytes, like `tail -c`
+    #[bpaf(short('c'), long, argument("N"))]
+    bytes: Option<u64>,
     /// The remote tailsrv to connect to
     #[bpaf(positional("ADDR"))]
     addr: SocketAddr,
@@ -16,12 +23,18 @@ struct Opts {
 
 fn main() -> std::io::Result<()> {
     let opts = opts().run();
-    let mut conn = TcpStream::connect(opts.addr)?;
-    // Use TCP keepalive to detect dead connections
-    let keepalive = Duration::from_secs(opts.heartbeat_secs);
-    conn.set_keepalive(Some(keepalive))?;
-    // Start from the beginning
-    writeln!(conn, "0")?;
+    // Use TCP keepalive to detect dead connections.  Start from the
+    // beginning, unless asked otherwise.  If the connection is lost, we
+    // reconnect and carry on from where we got to.
+    let builder = TailReader::builder(opts.addr)?
+        .keepalive(Some(Duration::from_secs(opts.heartbeat_secs)))
+        .reconnect(!opts.no_reconnect);
+    let builder = match (opts.lines, opts.bytes) {
+        (Some(n), _) => builder.last_lines(n),
+        (None, Some(n)) => builder.offset(-i64::try_from(n).unwrap_or(i64::MAX)),
+        (None, None) => builder.offset(0),
+    };
+    let mut conn = builder.connect()?;
     // Copy the stream to stdout
     let mut stdout = std::io::stdout().lock();
     std::io::copy(&mut conn, &mut stdout)?;
@@ -1,17 +1,25 @@
 use bpaf::{Bpaf, Parser};
 use fd_lock::RwLock;
-use net2::TcpStreamExt;
 use std::fs::File;
 use std::io::{prelude::*, SeekFrom};
-use std::net::{SocketAddr, TcpStream};
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tailsrv_client::{crc32, TailReader};
 
 #[derive(Bpaf)]
 struct Opts {
     /// How often to ping the server to check for a dead connection
     #[bpaf(fallback(5))]
     heartbeat_secs: u64,
+    /// Exit if the connection is lost, rather than reconnecting
+    no_reconnect: bool,
+    /// Instead of syncing, check that the file matches the server's copy
+    /// and exit.  The exit status is 1 if it doesn't
+    verify: bool,
+    /// Don't download faster than this.  Accepts a suffix of K, M, or G
+    #[bpaf(argument::<String>("BYTES/S"), parse(parse_rate), optional)]
+    max_rate: Option<u64>,
     /// The remote tailsrv to connect to
     #[bpaf(positional("ADDR"))]
     addr: SocketAddr,
@@ -24,7 +32,11 @@ fn main() -> std::io::Result<()> {
     let opts = opts().run();
     // Open the file in append mode, creating it if it doesn't already
     // exist.
-    let file = File::options().append(true).create(true).open(opts.file)?;
+    let file = File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(opts.file)?;
     // Take an exclusive lock on the file, and exit if it's already locked.
     // This prevents two tscats from writing to the same file.
     let mut file = RwLock::new(file);
@@ -32,13 +44,82 @@ fn main() -> std::io::Result<()> {
     // We assume that this point that we're the only process writing to
     // the file, so we can read its length and not worry about TOCTOU.
     let len = file.seek(SeekFrom::End(0))?;
-    let mut conn = TcpStream::connect(opts.addr)?;
-    // Use TCP keepalive to detect dead connections
-    let keepalive = Duration::from_secs(opts.heartbeat_secs);
-    conn.set_keepalive(Some(keepalive))?;
-    // Use the current length as the "start from" offset
-    writeln!(conn, "{len}")?;
+    // Use TCP keepalive to detect dead connections.  Use the current length
+    // as the "start from" offset.  If the connection is lost, we reconnect
+    // and carry on from the end of what we've written.
+    let builder = TailReader::builder(opts.addr)?
+        .keepalive(Some(Duration::from_secs(opts.heartbeat_secs)))
+        .reconnect(!opts.no_reconnect)
+        .offset(len as i64);
+    if opts.verify {
+        // Hash what we've got, and ask the server to hash the same range
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf = vec![0; 64 * 1024];
+        let mut local = 0;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            local = crc32(local, &buf[..n]);
+        }
+        let remote = builder.hash(0..len)?;
+        if local != remote {
+            eprintln!(
+                "Mismatch: the first {len} bytes have CRC-32 {local:08x} here, \
+                 but {remote:08x} on the server"
+            );
+            std::process::exit(1);
+        }
+        eprintln!("{len} bytes verified (CRC-32 {local:08x})");
+        return Ok(());
+    }
+    let mut conn = builder.connect()?;
     // Append the stream to the file
-    std::io::copy(&mut conn, &mut file as &mut File)?;
-    Ok(())
+    match opts.max_rate {
+        None => std::io::copy(&mut conn, &mut file as &mut File).map(|_| ()),
+        Some(rate) => copy_throttled(&mut conn, &mut file as &mut File, rate),
+    }
+}
+
+/// Like `io::copy`, but no faster than `rate` bytes per second.  Reading
+/// slowly makes the socket fill up, so the server slows down too.
+fn copy_throttled(rdr: &mut impl Read, wtr: &mut impl Write, rate: u64) -> std::io::Result<()> {
+    let rate = rate as f64;
+    let mut buf = vec![0; 64 * 1024];
+    // A token bucket which holds up to a second's worth of bytes
+    let mut tokens = rate;
+    let mut last_refill = Instant::now();
+    loop {
+        let now = Instant::now();
+        tokens = (tokens + rate * (now - last_refill).as_secs_f64()).min(rate);
+        last_refill = now;
+        if tokens < 1.0 {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        let len = buf.len().min(tokens as usize);
+        let n = rdr.read(&mut buf[..len])?;
+        if n == 0 {
+            return Ok(());
+        }
+        wtr.write_all(&buf[..n])?;
+        tokens -= n as f64;
+    }
+}
+
+/// Parse a number of bytes per second, with an optional K/M/G suffix
+fn parse_rate(s: String) -> Result<u64, String> {
+    let (digits, multiplier) = match s.trim().as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s.as_str(), 1),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{s}: {e}"))?;
+    match n.checked_mul(multiplier) {
+        Some(0) => Err(format!("{s}: Must be positive")),
+        Some(x) => Ok(x),
+        None => Err(format!("{s}: Too large")),
+    }
 }
@@ -0,0 +1,111 @@
+//! Support for `--access-log`.  When a client's session ends, we append a
+//! line describing it to the log: who it was, what it asked for, and how much
+//! it was sent.  This is handy for auditing, and for capacity planning.
+//!
+//! Each line is a series of space-separated `key=value` pairs, like this:
+//!
+//! ```text
+//! peer=10.0.0.7:51412 requested="byte -1000" start=52311 sent=1000 duration=4.021 reason="hung up"
+//! ```
+//!
+//! Values which might contain spaces are quoted.  `duration` is in seconds.
+
+use crate::Result;
+use rustix::fd::AsFd;
+use rustix::net::SocketAddrAny;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::*;
+
+/// Set if --access-log was given
+static ACCESS_LOG: OnceLock<File> = OnceLock::new();
+
+/// Open the access log for appending to
+pub fn open(path: &Path) -> Result<()> {
+    let file = File::options().append(true).create(true).open(path)?;
+    ACCESS_LOG
+        .set(file)
+        .map_err(|_| "The access log was already opened")?;
+    Ok(())
+}
+
+/// What we know about a client's session, for logging when it ends
+#[derive(Debug)]
+pub struct Session {
+    peer: String,
+    requested: String,
+    start: usize,
+    started: Instant,
+    reason: Option<String>,
+}
+
+impl Session {
+    /// `requested` describes where the client asked to start, and `start` is
+    /// the offset which that resolved to
+    pub fn new(peer: String, requested: String, start: usize) -> Session {
+        Session {
+            peer,
+            requested,
+            start,
+            started: Instant::now(),
+            reason: None,
+        }
+    }
+
+    /// The client's address
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Where the client asked to start
+    pub fn requested(&self) -> &str {
+        &self.requested
+    }
+
+    /// Record why the session is ending.  Only the first reason is kept.
+    pub fn end(&mut self, reason: impl Display) {
+        if self.reason.is_none() {
+            self.reason = Some(reason.to_string());
+        }
+    }
+
+    /// Write the line for this session.  `offset` is how far through the
+    /// file the client got.
+    pub fn record(&self, offset: usize) {
+        let Some(mut file) = ACCESS_LOG.get() else {
+            return;
+        };
+        let line = format!(
+            "peer={} requested={:?} start={} sent={} duration={:.3} reason={:?}\n",
+            self.peer,
+            self.requested,
+            self.start,
+            offset.saturating_sub(self.start),
+            self.started.elapsed().as_secs_f64(),
+            self.reason.as_deref().unwrap_or("closed"),
+        );
+        // The file is opened for appending, so a single write won't be
+        // interleaved with anyone else's
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Couldn't write to the access log: {e}");
+        }
+    }
+}
+
+/// The address of the client at the other end of `conn`, or "-" if it
+/// doesn't have one (eg. it's on an unnamed unix socket)
+pub fn peer(conn: impl AsFd) -> String {
+    match rustix::net::getpeername(conn) {
+        Ok(Some(SocketAddrAny::V4(x))) => x.to_string(),
+        Ok(Some(SocketAddrAny::V6(x))) => x.to_string(),
+        Ok(Some(SocketAddrAny::Unix(x))) => match x.path() {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => "-".into(),
+        },
+        _ => "-".into(),
+    }
+}
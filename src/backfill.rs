@@ -0,0 +1,155 @@
+//! Support for `--backfill`, for logs which get rotated.  The rotated copies
+//! of the file (`PATH.1`, `PATH.2.gz`, and so on) are treated as earlier parts
+//! of it, so a client which asks for `byte 0` gets the whole history followed
+//! by the live file.
+//!
+//! We look for rotated copies when we start up.  Compressed ones (`.gz` or
+//! `.zst`) are decompressed into unlinked temporary files next to PATH.
+//! Offsets count from the start of the oldest copy.
+//!
+//! As far as the runloop is concerned this source isn't a file: it's served
+//! with epoll, and it watches the live file itself.
+
+use crate::source::{Growth, Source};
+use crate::{follow_file, Opts, Result};
+use rustix::fd::BorrowedFd;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::*;
+
+/// The rotated copies, oldest first, followed by the live file.  Clones share
+/// the same files.
+#[derive(Clone)]
+pub struct Backfilled(Arc<Inner>);
+
+struct Inner {
+    history: Vec<Segment>,
+    /// The combined length of the rotated copies
+    history_len: usize,
+    live: File,
+    opts: &'static Opts,
+}
+
+/// One of the rotated copies
+struct Segment {
+    /// Where it starts in the combined stream
+    start: usize,
+    len: usize,
+    file: File,
+}
+
+impl Backfilled {
+    /// `live` is the file at `path`
+    pub fn open(path: &Path, live: File, opts: &'static Opts) -> Result<Backfilled> {
+        let mut history = vec![];
+        let mut history_len = 0;
+        for rotated in find_rotated(path) {
+            let _g = info_span!("", rotated = %rotated.display()).entered();
+            let file = match rotated.extension().and_then(|x| x.to_str()) {
+                Some("gz") => decompress(
+                    path,
+                    flate2::read::MultiGzDecoder::new(File::open(&rotated)?),
+                )?,
+                Some("zst") => decompress(path, zstd::Decoder::new(File::open(&rotated)?)?)?,
+                _ => File::open(&rotated)?,
+            };
+            let len = Source::len(&file)?;
+            if len == 0 {
+                continue;
+            }
+            info!(start = history_len, len, "Backfilling from a rotated copy");
+            history.push(Segment {
+                start: history_len,
+                len,
+                file,
+            });
+            history_len += len;
+        }
+        Ok(Backfilled(Arc::new(Inner {
+            history,
+            history_len,
+            live,
+            opts,
+        })))
+    }
+
+    /// The file containing `offset`, and the offset within that file.  Also
+    /// returns how many bytes are left in it, for the rotated copies.
+    fn locate(&self, offset: usize) -> (&File, usize, Option<usize>) {
+        let inner = &*self.0;
+        if offset >= inner.history_len {
+            return (&inner.live, offset - inner.history_len, None);
+        }
+        let i = inner.history.partition_point(|x| x.start <= offset) - 1;
+        let seg = &inner.history[i];
+        (
+            &seg.file,
+            offset - seg.start,
+            Some(seg.start + seg.len - offset),
+        )
+    }
+}
+
+impl Source for Backfilled {
+    fn len(&self) -> std::io::Result<usize> {
+        Ok(self.0.history_len + Source::len(&self.0.live)?)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize> {
+        // Reads don't cross from one file to the next
+        let (file, offset, left) = self.locate(offset);
+        let len = buf.len().min(left.unwrap_or(usize::MAX));
+        Source::read_at(file, &mut buf[..len], offset)
+    }
+
+    fn copy_to(&self, sock: BorrowedFd, offset: usize, len: usize) -> std::io::Result<usize> {
+        let (file, offset, left) = self.locate(offset);
+        Source::copy_to(file, sock, offset, len.min(left.unwrap_or(usize::MAX)))
+    }
+
+    fn watch(&self, growth: Growth) {
+        let this = self.clone();
+        std::thread::spawn(move || {
+            let inner = &*this.0;
+            if let Err(e) = follow_file(&inner.live, &growth, || this.len(), inner.opts) {
+                error!("Couldn't watch the file: {e}");
+                std::process::exit(1);
+            }
+        });
+    }
+}
+
+/// The rotated copies of `path` which exist, oldest first.  We look for
+/// `PATH.1`, `PATH.2`, etc. (possibly compressed) until one is missing.
+fn find_rotated(path: &Path) -> Vec<PathBuf> {
+    let mut rotated = vec![];
+    for n in 1.. {
+        let candidates = ["", ".gz", ".zst"].map(|ext| {
+            let mut x = path.as_os_str().to_owned();
+            x.push(format!(".{n}{ext}"));
+            PathBuf::from(x)
+        });
+        match candidates.into_iter().find(|x| x.is_file()) {
+            Some(x) => rotated.push(x),
+            None => break,
+        }
+    }
+    rotated.reverse();
+    rotated
+}
+
+/// Decompress a rotated copy into an unlinked temporary file next to `path`
+fn decompress(path: &Path, mut input: impl std::io::Read) -> Result<File> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".backfill-{}", std::process::id()));
+    let mut tmp = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+    std::fs::remove_file(&tmp_path)?;
+    let n = std::io::copy(&mut input, &mut tmp)?;
+    debug!(len = n, "Decompressed");
+    Ok(tmp)
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses, like `10.0.0.0/8` or `fe80::/10`.  A bare address
+/// is treated as a block containing just that address.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6
+        // addresses; we want them to match IPv4 blocks
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net), u32::from(ip), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches<T>(net: T, ip: T, prefix_len: u8) -> bool
+where
+    T: std::ops::BitXor<Output = T> + std::ops::Shr<u32, Output = T> + Eq + Default,
+{
+    let bits = std::mem::size_of::<T>() as u32 * 8;
+    match bits.checked_sub(u32::from(prefix_len)) {
+        Some(host_bits) if host_bits < bits => ((net ^ ip) >> host_bits) == T::default(),
+        // A zero-length prefix matches everything
+        _ => true,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{s}: {e}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_len,
+            Some(len) => match len.parse() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(format!("{s}: Bad prefix length")),
+            },
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(cidr: &str, ip: &str) -> bool {
+        let cidr: Cidr = cidr.parse().unwrap();
+        cidr.contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<Cidr>().unwrap().to_string(),
+            "10.0.0.0/8"
+        );
+        assert_eq!(
+            "10.1.2.3".parse::<Cidr>().unwrap().to_string(),
+            "10.1.2.3/32"
+        );
+        assert_eq!(
+            "fe80::/10".parse::<Cidr>().unwrap().to_string(),
+            "fe80::/10"
+        );
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn ipv4() {
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.7", "192.168.1.7"));
+        assert!(!contains("192.168.1.7", "192.168.1.8"));
+        assert!(contains("192.168.1.0/31", "192.168.1.1"));
+        assert!(!contains("192.168.1.0/31", "192.168.1.2"));
+    }
+
+    #[test]
+    fn ipv6() {
+        assert!(contains("fe80::/10", "fe80::1"));
+        assert!(contains("fe80::/10", "febf::1"));
+        assert!(!contains("fe80::/10", "fec0::1"));
+        assert!(contains("::1", "::1"));
+        assert!(!contains("::1", "::2"));
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+        assert!(contains("127.0.0.1", "::ffff:127.0.0.1"));
+        // Only IPv4 blocks can match IPv4 clients
+        assert!(!contains("::/0", "10.1.2.3"));
+        assert!(!contains("::/0", "::ffff:10.1.2.3"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+    }
+
+    #[test]
+    fn everything() {
+        assert!(contains("0.0.0.0/0", "0.0.0.0"));
+        assert!(contains("0.0.0.0/0", "255.255.255.255"));
+        assert!(contains("10.0.0.0/0", "192.168.1.1"));
+        assert!(contains("::/0", "::"));
+        assert!(contains("::/0", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert!(contains("fe80::/0", "2001:db8::1"));
+    }
+}
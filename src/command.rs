@@ -0,0 +1,166 @@
+//! One-shot commands.  Instead of asking to be sent the file, the client asks
+//! a question about it.  We send the answer (a single line) and hang up.
+//!
+//! Errors are reported the same way as for streaming clients: a line starting
+//! with `ERR`.
+
+use crate::header::Command;
+use crate::index::{self, Kind};
+use crate::source::SOURCE;
+use crate::{Result, FILE_LENGTH, NUM_CLIENTS, OPTS};
+use rustix::fd::OwnedFd;
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::Ordering;
+use std::time::UNIX_EPOCH;
+use tracing::*;
+
+/// How much of the file we hash at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Answer the client's command
+pub fn answer(command: &Command, conn: &OwnedFd) -> Result<()> {
+    let reply = match command {
+        Command::Hash(range) => {
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            match SOURCE.get() {
+                Some(source) if range.end <= file_len => {
+                    let mut buf = vec![0; CHUNK_SIZE.min(range.len())];
+                    let mut crc = 0;
+                    let mut pos = range.start;
+                    while pos < range.end {
+                        let len = buf.len().min(range.end - pos);
+                        let n = source.read_at(&mut buf[..len], pos)?;
+                        if n == 0 {
+                            return Err("The file was truncated".into());
+                        }
+                        crc = crc32(crc, &buf[..n]);
+                        pos += n;
+                    }
+                    debug!(?range, crc, "Hashed range");
+                    format!("{crc:08x}\n")
+                }
+                _ => {
+                    debug!(?range, file_len, "Can't hash a range we don't have");
+                    "ERR range not available\n".to_owned()
+                }
+            }
+        }
+        Command::Stat => stat(),
+        Command::Size => format!("{}\n", FILE_LENGTH.load(Ordering::Acquire)),
+        // The capabilities have been sent already
+        Command::Capabilities => return Ok(()),
+    };
+    rustix::io::write(conn, reply.as_bytes())?;
+    Ok(())
+}
+
+/// A line of JSON, like this:
+///
+/// ```text
+/// {"size":99999,"mtime":1734073200.123,"inode":5678,"clients":3,"index":{"lines":512,"records":null,"persisted":false}}
+/// ```
+///
+/// `mtime` is in seconds since the epoch.  It and `inode` are `null` if the
+/// source isn't a file (or doesn't exist yet).  The index counts are how many
+/// lines (or records) have been indexed so far, or `null` if nobody has asked
+/// for one yet.
+fn stat() -> String {
+    let size = FILE_LENGTH.load(Ordering::Acquire);
+    let clients = NUM_CLIENTS.load(Ordering::Acquire);
+    let meta = SOURCE
+        .get()
+        .and_then(|x| x.as_file())
+        .and_then(|x| x.metadata().ok());
+    let mtime = meta
+        .as_ref()
+        .and_then(|x| x.modified().ok())
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map_or("null".into(), |x| format!("{:.3}", x.as_secs_f64()));
+    let inode = meta.map_or("null".into(), |x| x.ino().to_string());
+    let delim = OPTS.get().and_then(|x| x.delim).unwrap_or(b'\n');
+    let count = |kind| index::count(kind).map_or("null".into(), |x: u64| x.to_string());
+    format!(
+        "{{\"size\":{size},\"mtime\":{mtime},\"inode\":{inode},\"clients\":{clients},\
+         \"index\":{{\"lines\":{},\"records\":{},\"persisted\":{}}}}}\n",
+        count(Kind::Lines(delim)),
+        count(Kind::Records),
+        index::persisted(),
+    )
+}
+
+/// The lookup table for CRC-32 (the IEEE polynomial, as used by zlib)
+const CRC_TABLE: [u32; 256] = crc_table(0xedb8_8320);
+/// The lookup table for CRC-32C (the Castagnoli polynomial, as used by iSCSI
+/// and ext4)
+const CRC32C_TABLE: [u32; 256] = crc_table(0x82f6_3b78);
+
+/// The lookup table for a CRC-32 with this (bit-reversed) polynomial
+const fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 0 { c >> 1 } else { poly ^ (c >> 1) };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+fn extend_crc(table: &[u32; 256], crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |c, &b| {
+        table[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+/// Extend a CRC-32 with more data.  Start with 0.  This has to agree with
+/// `tailsrv_client::crc32`.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    extend_crc(&CRC_TABLE, crc, data)
+}
+
+/// Extend a CRC-32C with more data.  Start with 0.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    extend_crc(&CRC32C_TABLE, crc, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32c(0, b""), 0);
+        assert_eq!(crc32c(0, b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn extends() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let whole = crc32(0, &data);
+        for split in [0, 1, 500, 999, 1000] {
+            let (a, b) = data.split_at(split);
+            assert_eq!(crc32(crc32(0, a), b), whole);
+            assert_eq!(crc32c(crc32c(0, a), b), crc32c(0, &data));
+        }
+    }
+
+    #[test]
+    fn agrees_with_the_client() {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 7 % 256) as u8).collect();
+        for len in [0, 1, 9, 1000] {
+            assert_eq!(
+                crc32(0, &data[..len]),
+                tailsrv_client::crc32(0, &data[..len])
+            );
+        }
+        let crc = tailsrv_client::crc32(0, &data[..400]);
+        assert_eq!(crc32(crc, &data[400..]), tailsrv_client::crc32(0, &data));
+    }
+}
@@ -0,0 +1,297 @@
+//! Support for `--config`.  The config file is a TOML file whose keys are the
+//! same as the command-line flags.  For example:
+//!
+//! ```toml
+//! port = 4321
+//! path = "/var/log/nginx/access.log"
+//! max-rate-per-client = "10M"
+//! allow = ["10.0.0.0/8"]
+//! ```
+//!
+//! Flags given on the command line take precedence over the config file.
+
+use crate::feed::Binding;
+use crate::{
+    parse_delim, parse_marker, parse_regex, parse_size, read_token, Opts, Result,
+    DEFAULT_IDLE_SECS, DEFAULT_SQPOLL_IDLE_MS,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    port: Option<u16>,
+    bind: Option<String>,
+    dual_stack: Option<bool>,
+    unix_socket: Option<PathBuf>,
+    http_port: Option<u16>,
+    #[serde(default)]
+    serve: Vec<String>,
+    #[cfg(feature = "grpc")]
+    grpc_port: Option<u16>,
+    path: Option<PathBuf>,
+    on_delete: Option<String>,
+    on_move: Option<String>,
+    on_truncate: Option<String>,
+    linger_after_file_is_gone: Option<bool>,
+    drain_after_file_is_gone: Option<bool>,
+    exit_on_idle: Option<FlagOrValue<u64>>,
+    inetd: Option<bool>,
+    upgrade_socket: Option<PathBuf>,
+    reopen: Option<FlagOrValue<String>>,
+    glob: Option<String>,
+    backfill: Option<bool>,
+    allow_special: Option<bool>,
+    noatime: Option<bool>,
+    direct: Option<bool>,
+    cursor_file: Option<PathBuf>,
+    state_file: Option<PathBuf>,
+    max_rate_per_client: Option<Size>,
+    max_total_rate: Option<Size>,
+    chunk_size: Option<Size>,
+    drop_behind: Option<bool>,
+    max_clients: Option<usize>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    bulk: Vec<String>,
+    tcp_keepalive: Option<u64>,
+    heartbeat: Option<u64>,
+    nodelay: Option<bool>,
+    tcp_user_timeout: Option<u32>,
+    auth_token_file: Option<PathBuf>,
+    upstream: Option<String>,
+    upstream_auth_token_file: Option<PathBuf>,
+    stdin: Option<bool>,
+    spool: Option<PathBuf>,
+    memory_buffer: Option<Size>,
+    timestamp_regex: Option<String>,
+    timestamp_format: Option<String>,
+    delim: Option<String>,
+    commit_marker: Option<String>,
+    flush_on_close_write: Option<bool>,
+    max_stat_rate: Option<u32>,
+    watch_mode: Option<String>,
+    poll_interval: Option<u64>,
+    persist_index: Option<bool>,
+    allow_compression: Option<bool>,
+    beyond_eof: Option<String>,
+    backend: Option<String>,
+    sqpoll: Option<FlagOrValue<u32>>,
+    uring_entries: Option<u32>,
+    uring_threads: Option<usize>,
+    pipe_size: Option<Size>,
+    pipe_pool_size: Option<usize>,
+    access_log: Option<PathBuf>,
+    stats_interval: Option<u64>,
+    log_format: Option<String>,
+    user: Option<String>,
+    group: Option<String>,
+    #[cfg(feature = "tracing-journald")]
+    journald: Option<bool>,
+}
+
+/// Sizes may be given either as a plain number of bytes, or as a string with
+/// a suffix, like on the command line
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    WithSuffix(String),
+}
+
+impl Size {
+    fn resolve(self) -> Result<u64> {
+        match self {
+            Size::Bytes(x) => Ok(x),
+            Size::WithSuffix(s) => Ok(parse_size(s)?),
+        }
+    }
+}
+
+/// For flags which take an optional value.  For example, `sqpoll = true`
+/// uses the default idle time, and `sqpoll = 100` sets it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FlagOrValue<T> {
+    Flag(bool),
+    Value(T),
+}
+
+impl<T> FlagOrValue<T> {
+    fn resolve(x: Option<Self>, default: T) -> Option<T> {
+        match x {
+            Some(FlagOrValue::Flag(true)) => Some(default),
+            Some(FlagOrValue::Value(x)) => Some(x),
+            Some(FlagOrValue::Flag(false)) | None => None,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)?;
+    let config = toml::from_str(&contents).map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(config)
+}
+
+/// Read just the `serve` list, for reloading it on SIGHUP
+pub fn read_serve(path: &Path) -> Result<Vec<Binding>> {
+    let config = read(path)?;
+    let bindings = config
+        .serve
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<_, _>>()?;
+    Ok(bindings)
+}
+
+/// Fill in any options which weren't given on the command line with values
+/// from the config file
+pub fn load_into(path: &Path, opts: &mut Opts) -> Result<()> {
+    let config = read(path)?;
+
+    opts.port = opts.port.or(config.port);
+    opts.bind = opts.bind.take().or(config.bind);
+    opts.dual_stack |= config.dual_stack.unwrap_or(false);
+    opts.unix_socket = opts.unix_socket.take().or(config.unix_socket);
+    opts.http_port = opts.http_port.or(config.http_port);
+    if opts.serve.is_empty() {
+        opts.serve = config
+            .serve
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()?;
+    }
+    #[cfg(feature = "grpc")]
+    {
+        opts.grpc_port = opts.grpc_port.or(config.grpc_port);
+    }
+    opts.path = opts.path.take().or(config.path);
+    if opts.on_delete.is_none() {
+        opts.on_delete = config.on_delete.map(|x| x.parse()).transpose()?;
+    }
+    if opts.on_move.is_none() {
+        opts.on_move = config.on_move.map(|x| x.parse()).transpose()?;
+    }
+    if opts.on_truncate.is_none() {
+        opts.on_truncate = config.on_truncate.map(|x| x.parse()).transpose()?;
+    }
+    opts.linger_after_file_is_gone |= config.linger_after_file_is_gone.unwrap_or(false);
+    opts.drain_after_file_is_gone |= config.drain_after_file_is_gone.unwrap_or(false);
+    if opts.exit_on_idle.is_none() {
+        opts.exit_on_idle = FlagOrValue::resolve(config.exit_on_idle, DEFAULT_IDLE_SECS);
+    }
+    opts.inetd |= config.inetd.unwrap_or(false);
+    opts.upgrade_socket = opts.upgrade_socket.take().or(config.upgrade_socket);
+    if opts.reopen.is_none() {
+        opts.reopen = FlagOrValue::resolve(config.reopen, "restart".to_owned())
+            .map(|x| x.parse())
+            .transpose()?;
+    }
+    opts.glob = opts.glob.take().or(config.glob);
+    opts.backfill |= config.backfill.unwrap_or(false);
+    opts.allow_special |= config.allow_special.unwrap_or(false);
+    opts.noatime |= config.noatime.unwrap_or(false);
+    opts.direct |= config.direct.unwrap_or(false);
+    opts.cursor_file = opts.cursor_file.take().or(config.cursor_file);
+    opts.state_file = opts.state_file.take().or(config.state_file);
+    if opts.max_rate_per_client.is_none() {
+        opts.max_rate_per_client = config.max_rate_per_client.map(Size::resolve).transpose()?;
+    }
+    if opts.max_total_rate.is_none() {
+        opts.max_total_rate = config.max_total_rate.map(Size::resolve).transpose()?;
+    }
+    if opts.chunk_size.is_none() {
+        opts.chunk_size = config.chunk_size.map(Size::resolve).transpose()?;
+    }
+    opts.drop_behind |= config.drop_behind.unwrap_or(false);
+    opts.max_clients = opts.max_clients.or(config.max_clients);
+    if opts.allow.is_empty() {
+        opts.allow = config
+            .allow
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()?;
+    }
+    if opts.deny.is_empty() {
+        opts.deny = config
+            .deny
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()?;
+    }
+    if opts.bulk.is_empty() {
+        opts.bulk = config
+            .bulk
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<_, _>>()?;
+    }
+    opts.tcp_keepalive = opts.tcp_keepalive.or(config.tcp_keepalive);
+    opts.heartbeat = opts.heartbeat.or(config.heartbeat);
+    opts.nodelay |= config.nodelay.unwrap_or(false);
+    opts.tcp_user_timeout = opts.tcp_user_timeout.or(config.tcp_user_timeout);
+    if opts.auth_token.is_none() {
+        opts.auth_token = config.auth_token_file.map(read_token).transpose()?;
+    }
+    opts.upstream = opts.upstream.take().or(config.upstream);
+    if opts.upstream_auth_token.is_none() {
+        opts.upstream_auth_token = config
+            .upstream_auth_token_file
+            .map(read_token)
+            .transpose()?;
+    }
+    opts.stdin |= config.stdin.unwrap_or(false);
+    opts.spool = opts.spool.take().or(config.spool);
+    if opts.memory_buffer.is_none() {
+        opts.memory_buffer = config.memory_buffer.map(Size::resolve).transpose()?;
+    }
+    if opts.timestamp_regex.is_none() {
+        opts.timestamp_regex = config.timestamp_regex.map(parse_regex).transpose()?;
+    }
+    opts.timestamp_format = opts.timestamp_format.take().or(config.timestamp_format);
+    if opts.delim.is_none() {
+        opts.delim = config.delim.map(parse_delim).transpose()?;
+    }
+    if opts.commit_marker.is_none() {
+        opts.commit_marker = config.commit_marker.map(parse_marker).transpose()?;
+    }
+    opts.flush_on_close_write |= config.flush_on_close_write.unwrap_or(false);
+    opts.max_stat_rate = opts.max_stat_rate.or(config.max_stat_rate);
+    if opts.watch_mode.is_none() {
+        opts.watch_mode = config.watch_mode.map(|x| x.parse()).transpose()?;
+    }
+    opts.poll_interval = opts.poll_interval.or(config.poll_interval);
+    opts.persist_index |= config.persist_index.unwrap_or(false);
+    opts.allow_compression |= config.allow_compression.unwrap_or(false);
+    if opts.beyond_eof.is_none() {
+        opts.beyond_eof = config.beyond_eof.map(|x| x.parse()).transpose()?;
+    }
+    if opts.backend.is_none() {
+        opts.backend = config.backend.map(|x| x.parse()).transpose()?;
+    }
+    if opts.sqpoll.is_none() {
+        opts.sqpoll = FlagOrValue::resolve(config.sqpoll, DEFAULT_SQPOLL_IDLE_MS);
+    }
+    opts.uring_entries = opts.uring_entries.or(config.uring_entries);
+    opts.uring_threads = opts.uring_threads.or(config.uring_threads);
+    opts.pipe_pool_size = opts.pipe_pool_size.or(config.pipe_pool_size);
+    if opts.pipe_size.is_none() {
+        opts.pipe_size = config.pipe_size.map(Size::resolve).transpose()?;
+    }
+    opts.access_log = opts.access_log.take().or(config.access_log);
+    opts.stats_interval = opts.stats_interval.or(config.stats_interval);
+    opts.user = opts.user.take().or(config.user);
+    opts.group = opts.group.take().or(config.group);
+    if opts.log_format.is_none() {
+        opts.log_format = config.log_format.map(|x| x.parse()).transpose()?;
+    }
+    #[cfg(feature = "tracing-journald")]
+    {
+        opts.journald |= config.journald.unwrap_or(false);
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! Named cursors, for consumers which want to carry on where they left off
+//! without keeping track of their offset themselves.
+//!
+//! A client which sends `cursor <NAME>` in its header starts from the offset
+//! last committed under NAME (or from the position it asked for, if nothing
+//! has been committed yet).  While it's connected, it can send lines of the
+//! form `commit <OFFSET>` on the same connection.  The committed offsets are
+//! kept in the `--cursor-file`, so they survive restarts.
+//!
+//! The commits are read on a thread of their own, so that the runloops don't
+//! have to know about them.
+
+use crate::Result;
+use rustix::fd::OwnedFd;
+use rustix::io::Errno;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tracing::*;
+
+/// Lines longer than this are rejected
+const MAX_LINE_LEN: usize = 4096;
+
+/// Where the cursors are kept.  Set if --cursor-file was given.
+static CURSOR_FILE: OnceLock<PathBuf> = OnceLock::new();
+static CURSORS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Load the committed offsets.  The file doesn't have to exist yet.
+pub fn load(path: &Path) -> Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+    let mut cursors = CURSORS.lock().unwrap();
+    for line in contents.lines().filter(|x| !x.trim().is_empty()) {
+        let parsed = line
+            .split_once(' ')
+            .and_then(|(name, offset)| Some((name, offset.trim().parse().ok()?)));
+        let Some((name, offset)) = parsed else {
+            return Err(format!("{}: Bad line: {line}", path.display()).into());
+        };
+        cursors.insert(name.to_owned(), offset);
+    }
+    info!(path = %path.display(), n = cursors.len(), "Loaded cursors");
+    CURSOR_FILE
+        .set(path.to_owned())
+        .map_err(|_| "The cursor file was already loaded")?;
+    Ok(())
+}
+
+/// Whether --cursor-file was given
+pub fn enabled() -> bool {
+    CURSOR_FILE.get().is_some()
+}
+
+/// The offset last committed under this name
+pub fn get(name: &str) -> Option<usize> {
+    CURSORS.lock().unwrap().get(name).copied()
+}
+
+/// Start reading `commit` lines from the client on a thread of its own.
+/// `rest` is whatever the client sent after its header, which was read
+/// along with it.
+pub fn spawn_reader(name: String, conn: &OwnedFd, rest: Vec<u8>) -> Result<()> {
+    let conn = conn.try_clone()?;
+    let span = Span::current();
+    std::thread::spawn(move || {
+        let _g = span.entered();
+        if let Err(e) = read_commits(&name, conn, rest) {
+            warn!(cursor = name, "Stopped reading commits: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Read lines until the client hangs up, starting with the ones in `buf`.
+/// The runloop may have made the socket non-blocking, so we poll it.
+fn read_commits(name: &str, conn: OwnedFd, mut buf: Vec<u8>) -> Result<()> {
+    let mut chunk = [0; 1024];
+    loop {
+        while let Some(eol) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=eol).collect();
+            match parse_commit(&line) {
+                Some(offset) => commit(name, offset)?,
+                None => warn!(line = %String::from_utf8_lossy(&line).trim(), "Bad commit"),
+            }
+        }
+        if buf.len() > MAX_LINE_LEN {
+            return Err("Line is too long".into());
+        }
+        match rustix::io::read(&conn, &mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(Errno::AGAIN) => {
+                let mut fds = [rustix::event::PollFd::new(
+                    &conn,
+                    rustix::event::PollFlags::IN,
+                )];
+                rustix::event::poll(&mut fds, -1)?;
+                continue;
+            }
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn parse_commit(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    line.trim().strip_prefix("commit ")?.trim().parse().ok()
+}
+
+/// Record the offset, and write out all the cursors.  If we've handed over
+/// to a new process, it does the writing instead.
+pub fn commit(name: &str, offset: usize) -> Result<()> {
+    let path = CURSOR_FILE.get().ok_or("Cursors aren't enabled")?;
+    let mut cursors = CURSORS.lock().unwrap();
+    cursors.insert(name.to_owned(), offset);
+    debug!(cursor = name, offset, "Committed");
+    #[cfg(target_os = "linux")]
+    if crate::upgrade::forward_commit(name, offset) {
+        return Ok(());
+    }
+    let mut contents = String::new();
+    for (name, offset) in cursors.iter() {
+        contents.push_str(&format!("{name} {offset}\n"));
+    }
+    // Write it somewhere else first, so that a crash can't leave it half
+    // written
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn commits_sent_with_the_header() {
+        let path = std::env::temp_dir().join(format!("tailsrv-cursors-{}", std::process::id()));
+        load(&path).unwrap();
+        let (mut client, conn) = UnixStream::pair().unwrap();
+        // The client pipelined these after its header, so they were read
+        // along with it.  The second one is cut off part-way.
+        let rest = b"commit 10\ncommit 2".to_vec();
+        client.write_all(b"0\n").unwrap();
+        drop(client);
+        read_commits("pipelined", conn.into(), rest).unwrap();
+        assert_eq!(get("pipelined"), Some(20));
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "pipelined 20\n");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_commit(b"commit 42\n"), Some(42));
+        assert_eq!(parse_commit(b"  commit  42 \r\n"), Some(42));
+        assert_eq!(parse_commit(b"commit -1\n"), None);
+        assert_eq!(parse_commit(b"commit\n"), None);
+        assert_eq!(parse_commit(b"comit 42\n"), None);
+    }
+}
@@ -0,0 +1,87 @@
+//! Support for `--direct`, for serving a file without going through the page
+//! cache.  This is for huge archives, where a backfill would otherwise push
+//! everything else out of the cache.
+//!
+//! O_DIRECT reads have to be aligned, and can't be spliced, so the file is
+//! read into an aligned bounce buffer and sent from there.  That costs a copy
+//! (and means the io_uring backend can't be used), so it's only worth it when
+//! the cache matters more than the CPU.
+
+use crate::source::{Growth, Source};
+use crate::{follow_file, Opts, Result};
+use rustix::fs::OFlags;
+use std::cell::RefCell;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use tracing::*;
+
+/// What O_DIRECT reads have to be aligned to.  This is the page size, which
+/// is at least as big as the block size of any filesystem we're likely to
+/// meet.
+const ALIGN: usize = 4096;
+/// The size of each thread's bounce buffer, in blocks
+const BOUNCE_BLOCKS: usize = 16;
+
+#[repr(C, align(4096))]
+struct Block([u8; ALIGN]);
+
+thread_local! {
+    static BOUNCE: RefCell<Vec<Block>> = RefCell::new(
+        (0..BOUNCE_BLOCKS).map(|_| Block([0; ALIGN])).collect(),
+    );
+}
+
+/// A file which is read with O_DIRECT.  Clones share the same file.
+#[derive(Clone)]
+pub struct Direct(Arc<Inner>);
+
+struct Inner {
+    file: File,
+    opts: &'static Opts,
+}
+
+impl Direct {
+    pub fn new(file: File, opts: &'static Opts) -> Result<Direct> {
+        let flags = rustix::fs::fcntl_getfl(&file)?;
+        rustix::fs::fcntl_setfl(&file, flags | OFlags::DIRECT)
+            .map_err(|e| format!("Couldn't use O_DIRECT: {e}"))?;
+        info!("Reading the file with O_DIRECT");
+        Ok(Direct(Arc::new(Inner { file, opts })))
+    }
+}
+
+impl Source for Direct {
+    fn len(&self) -> std::io::Result<usize> {
+        Source::len(&self.0.file)
+    }
+
+    /// Reads at most one bounce buffer's worth
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize> {
+        let start = offset - offset % ALIGN;
+        let skip = offset - start;
+        BOUNCE.with_borrow_mut(|bounce| {
+            // SAFETY: the blocks are plain bytes, laid out one after another
+            let bounce: &mut [u8] = unsafe {
+                std::slice::from_raw_parts_mut(bounce.as_mut_ptr().cast(), bounce.len() * ALIGN)
+            };
+            let len = (skip + buf.len()).next_multiple_of(ALIGN).min(bounce.len());
+            // A short read means we've hit the end of the file
+            let n = self.0.file.read_at(&mut bounce[..len], start as u64)?;
+            let n = n.saturating_sub(skip).min(buf.len());
+            buf[..n].copy_from_slice(&bounce[skip..skip + n]);
+            Ok(n)
+        })
+    }
+
+    fn watch(&self, growth: Growth) {
+        let this = self.clone();
+        std::thread::spawn(move || {
+            let inner = &*this.0;
+            if let Err(e) = follow_file(&inner.file, &growth, || this.len(), inner.opts) {
+                error!("Couldn't watch the file: {e}");
+                std::process::exit(1);
+            }
+        });
+    }
+}
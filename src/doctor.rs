@@ -0,0 +1,203 @@
+//! `tailsrv doctor`, which checks whether the environment has what tailsrv
+//! needs, and says what to do about it if not.  This is for when tailsrv
+//! doesn't work in some container, and it's not obvious why.
+//!
+//! Each check prints one line: `ok`, `warn` (tailsrv will work, but not as
+//! well as it could), or `FAIL` (tailsrv won't work).
+
+#[cfg(target_os = "linux")]
+use crate::probe::Capabilities;
+use crate::Result;
+use std::fs::File;
+use std::path::Path;
+
+/// Below this many fds, we suggest raising the limit.  Each client takes a
+/// socket and (on io_uring) a pipe, which is two more.
+const MIN_FDS: libc::rlim_t = 4096;
+
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn ok(&mut self, what: &str, msg: impl std::fmt::Display) {
+        println!("ok    {what}: {msg}");
+    }
+
+    fn warn(&mut self, what: &str, msg: impl std::fmt::Display) {
+        println!("warn  {what}: {msg}");
+    }
+
+    fn fail(&mut self, what: &str, msg: impl std::fmt::Display) {
+        println!("FAIL  {what}: {msg}");
+        self.failed = true;
+    }
+}
+
+/// Run the checks.  If `path` is given, we also check that it can be served.
+pub fn run(path: Option<&Path>) -> Result<()> {
+    let mut report = Report::default();
+    #[cfg(target_os = "linux")]
+    {
+        let caps = crate::probe::probe();
+        check_uring(&mut report, &caps);
+        check_inotify(&mut report, &caps);
+        check_pipe_size(&mut report);
+    }
+    check_fds(&mut report);
+    if let Some(path) = path {
+        check_file(&mut report, path);
+    }
+    if report.failed {
+        return Err("Some checks failed".into());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn check_uring(report: &mut Report, caps: &Capabilities) {
+    match &caps.uring {
+        Ok(()) => report.ok("io_uring", "available"),
+        Err(e) => report.warn(
+            "io_uring",
+            format_args!(
+                "{e}.  tailsrv will use epoll instead, which is slower with many \
+                 clients.  If you're in a container, check its seccomp profile."
+            ),
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_inotify(report: &mut Report, caps: &Capabilities) {
+    let instances = read_sysctl("fs/inotify/max_user_instances");
+    let watches = read_sysctl("fs/inotify/max_user_watches");
+    let limits = format!(
+        "max_user_instances={}, max_user_watches={}",
+        instances.as_deref().unwrap_or("?"),
+        watches.as_deref().unwrap_or("?"),
+    );
+    match &caps.inotify {
+        Ok(()) => report.ok("inotify", limits),
+        Err(e) => report.warn(
+            "inotify",
+            format_args!(
+                "{e} ({limits}).  tailsrv will poll the file instead, which adds \
+                 latency.  Try raising fs.inotify.max_user_instances."
+            ),
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_pipe_size(report: &mut Report) {
+    match read_sysctl("fs/pipe-max-size") {
+        Some(x) => report.ok(
+            "pipe size",
+            format_args!("--pipe-size may be at most {x} bytes (fs.pipe-max-size)"),
+        ),
+        None => report.warn("pipe size", "couldn't read fs.pipe-max-size"),
+    }
+}
+
+fn check_fds(report: &mut Report) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit() only writes to the struct we give it
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        let e = std::io::Error::last_os_error();
+        report.warn(
+            "file descriptors",
+            format_args!("couldn't get the limit: {e}"),
+        );
+        return;
+    }
+    let (soft, hard) = (limit.rlim_cur, limit.rlim_max);
+    if soft < MIN_FDS {
+        report.warn(
+            "file descriptors",
+            format_args!(
+                "the limit is {soft} (hard limit {hard}), which is only enough for a few \
+                 hundred clients.  Raise it with `ulimit -n`, or LimitNOFILE= in a \
+                 systemd unit."
+            ),
+        );
+    } else {
+        report.ok("file descriptors", format_args!("the limit is {soft}"));
+    }
+}
+
+fn check_file(report: &mut Report, path: &Path) {
+    let what = path.display().to_string();
+    let meta = match std::fs::metadata(path) {
+        Ok(x) => x,
+        Err(e) => {
+            report.fail(&what, format_args!("{e}"));
+            return;
+        }
+    };
+    if !meta.is_file() {
+        report.fail(
+            &what,
+            "not a regular file.  Serve a FIFO with --spool, or a device with \
+             --allow-special.",
+        );
+        return;
+    }
+    let file = match File::open(path) {
+        Ok(x) => x,
+        Err(e) => {
+            report.fail(
+                &what,
+                format_args!("{e}.  Check that tailsrv's user (or --user) can read it."),
+            );
+            return;
+        }
+    };
+    report.ok(&what, format_args!("readable, {} bytes", meta.len()));
+    #[cfg(target_os = "linux")]
+    check_splice(report, &what, &file);
+    #[cfg(not(target_os = "linux"))]
+    drop(file);
+}
+
+/// Whether the file's filesystem supports splice(), which both backends use
+/// (sendfile() is built on it)
+#[cfg(target_os = "linux")]
+fn check_splice(report: &mut Report, what: &str, file: &File) {
+    use rustix::pipe::{pipe, splice, SpliceFlags};
+    let what = format!("{what} (splice)");
+    let (_rdr, wtr) = match pipe() {
+        Ok(x) => x,
+        Err(e) => {
+            report.fail(&what, format_args!("couldn't create a pipe: {e}"));
+            return;
+        }
+    };
+    let mut offset = 0;
+    match splice(
+        file,
+        Some(&mut offset),
+        &wtr,
+        None,
+        1,
+        SpliceFlags::NONBLOCK,
+    ) {
+        Ok(_) => report.ok(&what, "supported"),
+        Err(rustix::io::Errno::INVAL) => report.warn(
+            &what,
+            "this filesystem doesn't support splice(), so tailsrv will copy the \
+             data through userspace, which uses more CPU",
+        ),
+        Err(e) => report.warn(&what, format_args!("couldn't splice from the file: {e}")),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_sysctl(name: &str) -> Option<String> {
+    let x = std::fs::read_to_string(Path::new("/proc/sys").join(name)).ok()?;
+    Some(x.trim().to_owned())
+}
@@ -0,0 +1,483 @@
+//! Clients whose data has to be transformed on its way to them: either
+//! compressed (`compress zstd`), split into server-sent events (the HTTP
+//! `/events` endpoint), or filtered (`grep /<PATTERN>/` and `sample <N>`).
+//!
+//! These clients can't take the zero-copy path.  Instead we read a chunk of
+//! the file into userspace, encode it, and write out the result.  Each chunk
+//! is sent as soon as it's encoded, so the client can decode everything it's
+//! been sent so far without waiting for more.
+
+use crate::command::crc32c;
+use crate::source::Source;
+use regex::bytes::Regex;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The most data we read from the file at a time.  This is the same as the
+/// maximum zstd block size, so flushing after each chunk costs us very little.
+const CHUNK_SIZE: usize = 128 * 1024;
+
+pub struct Encoder {
+    format: Format,
+    /// Encoded data, some of which may have been sent
+    out: Vec<u8>,
+    /// How much of `out` has been sent
+    sent: usize,
+    /// Scratch space for reading the file
+    input: Vec<u8>,
+    /// Lines which don't get through all of these aren't sent
+    filters: Vec<Filter>,
+    /// The byte which ends each line, for the filters
+    delim: u8,
+    /// When the client was last sent anything, for --heartbeat
+    last_sent: Instant,
+}
+
+enum Format {
+    /// The data is sent as it is (after filtering)
+    Raw,
+    Zstd(Box<zstd::stream::write::Encoder<'static, Vec<u8>>>),
+    /// One event per line.  Each event's ID is its line number, or a `Token`.
+    Sse {
+        /// The number of the next line in the file
+        next_line: u64,
+        /// Lines before this one are read but not sent
+        first_line: u64,
+        /// Whether the events have a `crc` field
+        crc: bool,
+        /// Whether the events' IDs are `Token`s
+        tokens: bool,
+    },
+}
+
+/// Where a client got to, as sent in the IDs of server-sent events with
+/// `/events?token`.  A client which comes back with one carries on from
+/// just after that event, unless the file has been replaced since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    /// The file's device and inode numbers (see `Source::identity`), or
+    /// zeroes if it isn't a file
+    pub file: (u64, u64),
+    /// Where the next line starts
+    pub offset: usize,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.file.0, self.file.1, self.offset)
+    }
+}
+
+impl FromStr for Token {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Token, String> {
+        let bad = || format!("{s}: Not a token");
+        let mut parts = s.split('-');
+        let mut next = || parts.next().ok_or_else(bad);
+        let token = Token {
+            file: (
+                next()?.parse().map_err(|_| bad())?,
+                next()?.parse().map_err(|_| bad())?,
+            ),
+            offset: next()?.parse().map_err(|_| bad())?,
+        };
+        match parts.next() {
+            None => Ok(token),
+            Some(_) => Err(bad()),
+        }
+    }
+}
+
+/// An event's ID
+enum EventId {
+    Line(u64),
+    Token(Token),
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventId::Line(n) => write!(f, "{n}"),
+            EventId::Token(token) => write!(f, "{token}"),
+        }
+    }
+}
+
+/// Decides which lines a client is sent.  Clients with a filter are only sent
+/// complete lines.
+pub enum Filter {
+    /// Lines which match the pattern
+    Grep(Regex),
+    /// Every `every`th line, starting with the first
+    Sample { every: u64, seen: u64 },
+}
+
+impl Filter {
+    fn keep(&mut self, line: &[u8]) -> bool {
+        match self {
+            Filter::Grep(regex) => regex.is_match(line),
+            Filter::Sample { every, seen } => {
+                let keep = *seen % *every == 0;
+                *seen += 1;
+                keep
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Encoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self.format {
+            Format::Raw => "raw",
+            Format::Zstd(_) => "zstd",
+            Format::Sse { .. } => "sse",
+        };
+        f.debug_struct("Encoder")
+            .field("format", &format)
+            .field("filters", &self.filters.len())
+            .field("pending", &self.pending().len())
+            .finish()
+    }
+}
+
+impl Encoder {
+    /// Send the data as it is.  Useful with a filter, or when the file can't
+    /// be spliced from.
+    pub fn raw() -> Encoder {
+        Encoder::new(Format::Raw)
+    }
+
+    pub fn zstd() -> std::io::Result<Encoder> {
+        let encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+        Ok(Encoder::new(Format::Zstd(Box::new(encoder))))
+    }
+
+    /// Encode lines as server-sent events.  The client's offset must be the
+    /// start of line `next_line`.  Lines before `first_line` are skipped.
+    pub fn sse(next_line: u64, first_line: u64) -> Encoder {
+        Encoder::new(Format::Sse {
+            next_line,
+            first_line,
+            crc: false,
+            tokens: false,
+        })
+    }
+
+    /// Make the IDs of the server-sent events `Token`s, rather than line
+    /// numbers
+    pub fn tokens(mut self) -> Encoder {
+        if let Format::Sse { tokens, .. } = &mut self.format {
+            *tokens = true;
+        }
+        self
+    }
+
+    /// Give each server-sent event which carries part of the file a `crc`
+    /// field, so that the client can check that it arrived intact
+    pub fn crc(mut self) -> Encoder {
+        if let Format::Sse { crc, .. } = &mut self.format {
+            *crc = true;
+        }
+        self
+    }
+
+    fn new(format: Format) -> Encoder {
+        Encoder {
+            format,
+            out: Vec::new(),
+            sent: 0,
+            input: vec![0; CHUNK_SIZE],
+            filters: vec![],
+            delim: b'\n',
+            last_sent: Instant::now(),
+        }
+    }
+
+    /// Lines end with `delim`, rather than a newline.  This only matters for
+    /// the filters; events are always split on newlines.
+    pub fn delim(mut self, delim: u8) -> Encoder {
+        self.delim = delim;
+        self
+    }
+
+    /// Only send the lines which get through `filter`
+    pub fn filter(mut self, filter: Filter) -> Encoder {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Encoded data which hasn't been sent yet
+    pub fn pending(&self) -> &[u8] {
+        &self.out[self.sent..]
+    }
+
+    /// Record that `n` bytes of the pending data have been sent
+    pub fn consume(&mut self, n: usize) {
+        self.sent += n;
+        if n > 0 {
+            self.last_sent = Instant::now();
+        }
+    }
+
+    /// If the client hasn't been sent anything for `interval`, queue an
+    /// empty `heartbeat` event, so that it can tell a quiet file from a dead
+    /// server.  Only server-sent events are framed, so other formats don't
+    /// get one.  Returns whether a heartbeat was queued.
+    pub fn heartbeat(&mut self, interval: Duration) -> bool {
+        if !matches!(self.format, Format::Sse { .. })
+            || !self.pending().is_empty()
+            || self.last_sent.elapsed() < interval
+        {
+            return false;
+        }
+        self.out.clear();
+        self.sent = 0;
+        self.control("heartbeat", "")
+    }
+
+    /// Queue an event of the given type, which says something about the
+    /// stream rather than the file (eg. that the file has been rotated).  It
+    /// goes after anything which is already pending.  Only server-sent events
+    /// are framed, so other formats don't get one.  Returns whether an event
+    /// was queued.
+    ///
+    /// `out` may be moved by this, so it mustn't be called while the pending
+    /// data is being sent (as the io_uring backend does).
+    pub fn control(&mut self, event: &str, data: &str) -> bool {
+        if !matches!(self.format, Format::Sse { .. }) {
+            return false;
+        }
+        writeln!(self.out, "event: {event}").unwrap();
+        write_event(&mut self.out, None, data.as_bytes(), false);
+        true
+    }
+
+    /// Encode some data which isn't part of the file, to be sent before the
+    /// file.  Server-sent events made this way don't have an ID.
+    pub fn prefix(&mut self, data: &[u8]) -> std::io::Result<()> {
+        assert!(self.pending().is_empty());
+        self.out.clear();
+        self.sent = 0;
+        match &mut self.format {
+            Format::Raw | Format::Zstd(_) => emit(&mut self.format, data, &mut self.out)?,
+            Format::Sse { .. } => {
+                write_event(
+                    &mut self.out,
+                    None,
+                    data.strip_suffix(b"\n").unwrap_or(data),
+                    false,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the source, starting at `offset` and stopping at `file_len`, and
+    /// encode it.  Returns the number of bytes of the file which were
+    /// consumed.  We keep going until there's something to send or we run
+    /// out of input.
+    ///
+    /// Only call this when there's nothing pending, since the pending data
+    /// may be in the middle of being sent.
+    pub fn encode(
+        &mut self,
+        source: &dyn Source,
+        offset: usize,
+        file_len: usize,
+    ) -> std::io::Result<usize> {
+        assert!(self.pending().is_empty());
+        self.out.clear();
+        self.sent = 0;
+        let mut consumed = 0;
+        while self.out.is_empty() {
+            let len = CHUNK_SIZE.min(file_len.saturating_sub(offset + consumed));
+            let n = source.read_at(&mut self.input[..len], offset + consumed)?;
+            let input = &self.input[..n];
+            let n_consumed = match &mut self.format {
+                Format::Sse {
+                    next_line,
+                    first_line,
+                    crc,
+                    tokens,
+                } => {
+                    let start = tokens.then(|| Token {
+                        file: source.identity().unwrap_or_default(),
+                        offset: offset + consumed,
+                    });
+                    encode_events(input, &mut self.out, next_line, *first_line, *crc, start)
+                }
+                format if self.filters.is_empty() => {
+                    emit(format, input, &mut self.out)?;
+                    n
+                }
+                format => {
+                    let mut kept = Vec::new();
+                    let n = filter_lines(&mut self.filters, self.delim, input, &mut kept);
+                    emit(format, &kept, &mut self.out)?;
+                    n
+                }
+            };
+            if n_consumed == 0 {
+                break;
+            }
+            consumed += n_consumed;
+        }
+        Ok(consumed)
+    }
+}
+
+/// Append `data` to `out`, encoded in the given format.  `out` must be empty
+/// (or `Raw`).
+fn emit(format: &mut Format, data: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+    match format {
+        Format::Raw => out.extend_from_slice(data),
+        Format::Zstd(encoder) => {
+            encoder.write_all(data)?;
+            encoder.flush()?;
+            std::mem::swap(out, encoder.get_mut());
+        }
+        Format::Sse { .. } => unreachable!("Events are encoded a line at a time"),
+    }
+    Ok(())
+}
+
+/// Copy the complete lines of `input` which get through all the filters to
+/// `out`.  Returns how much of the input was used: a partial line at the end
+/// is left for next time.
+fn filter_lines(filters: &mut [Filter], delim: u8, input: &[u8], out: &mut Vec<u8>) -> usize {
+    let Some(end) = input.iter().rposition(|&b| b == delim) else {
+        if input.len() < CHUNK_SIZE {
+            return 0;
+        }
+        // The line is too long to wait for, so we judge what we have
+        if filters.iter_mut().all(|f| f.keep(input)) {
+            out.extend_from_slice(input);
+        }
+        return input.len();
+    };
+    for line in input[..=end].split_inclusive(|&b| b == delim) {
+        if filters.iter_mut().all(|f| f.keep(&line[..line.len() - 1])) {
+            out.extend_from_slice(line);
+        }
+    }
+    end + 1
+}
+
+/// Turn each complete line of `input` into an event.  Returns how much of
+/// the input was used: a partial line at the end is left for next time.
+/// With tokens for IDs, `start` is the token for the start of the input.
+fn encode_events(
+    input: &[u8],
+    out: &mut Vec<u8>,
+    next_line: &mut u64,
+    first_line: u64,
+    crc: bool,
+    start: Option<Token>,
+) -> usize {
+    let Some(end) = input.iter().rposition(|&b| b == b'\n') else {
+        if input.len() < CHUNK_SIZE {
+            return 0;
+        }
+        // The line is too long to wait for.  We send what we have, but
+        // without an ID, so that a client which resumes from here gets the
+        // whole line again.
+        if *next_line >= first_line {
+            write_event(out, None, input, crc);
+        }
+        return input.len();
+    };
+    let mut token = start;
+    for line in input[..end].split(|&b| b == b'\n') {
+        // A token says where the line after this one starts
+        if let Some(token) = &mut token {
+            token.offset += line.len() + 1;
+        }
+        if *next_line >= first_line {
+            let id = match token {
+                Some(token) => EventId::Token(token),
+                None => EventId::Line(*next_line),
+            };
+            write_event(out, Some(id), line, crc);
+        }
+        *next_line += 1;
+    }
+    end + 1
+}
+
+/// With `crc`, the event gets the CRC-32C of its data, as the client will
+/// see it (so with each CR turned into a newline; see below)
+fn write_event(out: &mut Vec<u8>, id: Option<EventId>, line: &[u8], crc: bool) {
+    if let Some(id) = id {
+        writeln!(out, "id: {id}").unwrap();
+    }
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if crc {
+        let crc = line
+            .split(|&b| b == b'\r')
+            .enumerate()
+            .fold(0, |crc, (i, data)| {
+                let crc = if i == 0 { crc } else { crc32c(crc, b"\n") };
+                crc32c(crc, data)
+            });
+        writeln!(out, "crc: {crc:08x}").unwrap();
+    }
+    // A CR would end the field, so it's sent as a separate data field.
+    // Clients join these with a newline.
+    for data in line.split(|&b| b == b'\r') {
+        out.extend_from_slice(b"data: ");
+        out.extend_from_slice(data);
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tokens() {
+        let token = Token {
+            file: (1, 2),
+            offset: 3,
+        };
+        assert_eq!(token.to_string(), "1-2-3");
+        assert_eq!("1-2-3".parse(), Ok(token));
+        for s in ["", "12", "1-2", "1-2-3-4", "1-2-x", "-1-2-3", "1--3"] {
+            assert!(s.parse::<Token>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn events() {
+        let mut out = Vec::new();
+        let mut next_line = 5;
+        // Line 5 is before the first one the client wants, and the last line
+        // isn't finished
+        let n = encode_events(b"one\ntwo\r\nth", &mut out, &mut next_line, 6, false, None);
+        assert_eq!(n, 9);
+        assert_eq!(next_line, 7);
+        assert_eq!(out, b"id: 6\ndata: two\n\n");
+    }
+
+    #[test]
+    fn token_ids() {
+        let mut out = Vec::new();
+        let start = Token {
+            file: (1, 2),
+            offset: 100,
+        };
+        encode_events(b"a\nbc\n", &mut out, &mut 0, 0, false, Some(start));
+        assert_eq!(out, b"id: 1-2-102\ndata: a\n\nid: 1-2-105\ndata: bc\n\n");
+    }
+
+    #[test]
+    fn checksums() {
+        let mut out = Vec::new();
+        write_event(&mut out, None, b"a\rb\r", true);
+        let expected = format!("crc: {:08x}\ndata: a\ndata: b\n\n", crc32c(0, b"a\nb"));
+        assert_eq!(out, expected.as_bytes());
+    }
+}
@@ -0,0 +1,116 @@
+//! A fallback backend for systems where io_uring is unavailable (old kernels,
+//! containers whose seccomp policy blocks io_uring_setup, gVisor, etc.)
+//!
+//! It uses epoll to wait for events, and classic sendfile() to move the data.
+
+use crate::ratelimit::{TokenBucket, THROTTLE_TICK};
+use crate::sendfile::{send_data, Progress};
+use crate::source::Source;
+use crate::{
+    handle_inotify, handle_messages, read_eventfd, take_inbox, Clients, Opts, Result, Scheduler,
+    EVENTFD,
+};
+use rustix::event::epoll;
+use rustix::fd::OwnedFd;
+use rustix::io::Errno;
+use std::collections::HashSet;
+use tracing::*;
+
+const NEW_CLIENT: u64 = 0;
+const INOTIFY: u64 = 1;
+const CLIENT_FROM: u64 = 100_000;
+
+/// `ino_fd` is the inotify watch on the file, if the source is a file
+pub fn run(source: &dyn Source, ino_fd: Option<&OwnedFd>, opts: &Opts) -> Result<()> {
+    let epoll_fd = epoll::create(epoll::CreateFlags::CLOEXEC)?;
+    info!("Created an epoll instance");
+    epoll::add(
+        &epoll_fd,
+        &*EVENTFD,
+        epoll::EventData::new_u64(NEW_CLIENT),
+        epoll::EventFlags::IN,
+    )?;
+    if let Some(ino_fd) = ino_fd {
+        epoll::add(
+            &epoll_fd,
+            ino_fd,
+            epoll::EventData::new_u64(INOTIFY),
+            epoll::EventFlags::IN,
+        )?;
+    }
+    info!("Polling the eventfd and inotify watch for events");
+
+    info!("Starting runloop");
+    // Only the io_uring backend can have more than one shard
+    let inbox = take_inbox(0);
+    let mut clients = Clients::new();
+    // The clients whose sockets have been registered with epoll
+    let mut registered = HashSet::new();
+    let mut total_rate = opts.max_total_rate.map(TokenBucket::new);
+    let mut sched = Scheduler::new(opts);
+    let mut events = epoll::EventVec::with_capacity(1024);
+    loop {
+        handle_messages(&inbox, &mut clients);
+        let progress = send_data(
+            source,
+            &mut clients,
+            &mut registered,
+            &mut total_rate,
+            &mut sched,
+            |client_id, client| {
+                // Edge-triggered, so we only hear about it when the socket
+                // _becomes_ writable.  Hangups and errors are always
+                // reported, so idle clients which go away are noticed too.
+                // (A client which has only shut down its side of the
+                // connection is still reading, so we don't ask for RDHUP.)
+                epoll::add(
+                    &epoll_fd,
+                    &client.conn,
+                    epoll::EventData::new_u64(client_id + CLIENT_FROM),
+                    epoll::EventFlags::OUT | epoll::EventFlags::ET,
+                )?;
+                Ok(())
+            },
+        )?;
+        let timeout = match progress {
+            Progress::Idle => -1,
+            Progress::Throttled => i32::try_from(THROTTLE_TICK.as_millis()).unwrap(),
+            Progress::MoreToSend => 0,
+        };
+        trace!("Waiting for wake-ups");
+        match epoll::wait(&epoll_fd, &mut events, timeout) {
+            Ok(()) => (),
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        trace!("Woke up!");
+        for ev in &events {
+            match ev.data.u64() {
+                NEW_CLIENT => read_eventfd(),
+                INOTIFY => {
+                    if let (Some(ino_fd), Some(file)) = (ino_fd, source.as_file()) {
+                        handle_inotify(ino_fd, file, opts)?
+                    }
+                }
+                x => {
+                    let client_id = x - CLIENT_FROM;
+                    let hangup = epoll::EventFlags::HUP | epoll::EventFlags::ERR;
+                    let flags = ev.flags;
+                    if flags.intersects(hangup) {
+                        info!(client_id, "Client hung up");
+                        // Closing the socket removes it from the epoll set
+                        if let Some(client) = clients.remove(&client_id) {
+                            client.disconnect("hung up");
+                        }
+                        registered.remove(&client_id);
+                    } else {
+                        trace!(client_id, "Socket is writable");
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            client.in_flight = false;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Exit codes, so that a supervisor can tell a file which was rotated away
+//! from a crash.  The reason is also logged, and (with the `sd-notify`
+//! feature) given to systemd as the service's status.
+//!
+//! Errors which aren't covered here (eg. at startup) exit with 1.
+
+use std::fmt;
+use tracing::*;
+
+/// Why tailsrv is exiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// There's nothing left to do (eg. --exit-on-idle, or we've handed over
+    /// to a new process)
+    Finished,
+    FileDeleted,
+    FileMoved,
+    FileTruncated,
+    /// A socket we accept clients on was closed
+    ListenerClosed,
+    /// The io_uring runloop failed
+    #[cfg(target_os = "linux")]
+    Uring,
+    /// Couldn't save the clients to the --state-file on the way out
+    StateSaveFailed,
+    /// We were sent this signal (SIGTERM or SIGINT).  Once we've cleaned up
+    /// we're killed by it, as we would have been without a handler.
+    Signal(i32),
+}
+
+impl Reason {
+    pub fn code(self) -> i32 {
+        match self {
+            Reason::Finished => 0,
+            Reason::FileDeleted => 10,
+            Reason::FileMoved => 11,
+            Reason::FileTruncated => 12,
+            Reason::ListenerClosed => 20,
+            #[cfg(target_os = "linux")]
+            Reason::Uring => 21,
+            Reason::StateSaveFailed => 22,
+            // The same as a shell reports for a process killed by the signal
+            Reason::Signal(signal) => 128 + signal,
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Finished => f.write_str("finished"),
+            Reason::FileDeleted => f.write_str("the file was deleted"),
+            Reason::FileMoved => f.write_str("the file was moved"),
+            Reason::FileTruncated => f.write_str("the file was truncated"),
+            Reason::ListenerClosed => f.write_str("a listening socket was closed"),
+            #[cfg(target_os = "linux")]
+            Reason::Uring => f.write_str("the io_uring runloop failed"),
+            Reason::StateSaveFailed => f.write_str("couldn't save the clients"),
+            Reason::Signal(signal) => write!(f, "received signal {signal}"),
+        }
+    }
+}
+
+/// Tell systemd why we're stopping, and exit
+pub fn exit(reason: Reason) -> ! {
+    info!(code = reason.code(), "Exiting: {reason}");
+    #[cfg(feature = "sd-notify")]
+    {
+        let status = format!("Exiting: {reason}");
+        let states = [
+            sd_notify::NotifyState::Stopping,
+            sd_notify::NotifyState::Status(&status),
+        ];
+        if let Err(e) = sd_notify::notify(false, &states) {
+            debug!("Couldn't notify systemd: {e}");
+        }
+    }
+    if let Reason::Signal(signal) = reason {
+        // Exiting with 128+N isn't the same as being killed: systemd counts
+        // only the latter as a clean stop
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+    std::process::exit(reason.code())
+}
@@ -0,0 +1,104 @@
+//! Page cache hints, so that a client catching up on a big backlog doesn't
+//! push the end of the file (which everyone else is reading) out of the
+//! cache.
+//!
+//! The file is read sequentially, so we say so when we start, which makes
+//! the kernel read further ahead.  Clients which are far behind also get an
+//! explicit readahead of the part they're about to be sent.  With
+//! `--drop-behind`, we also tell the kernel that it can drop the parts of the
+//! file which every client has already been sent.
+
+use crate::source::{Source, SOURCE};
+use crate::{visit_clients, Client, Opts, FILE_LENGTH};
+use rustix::fs::Advice;
+use std::fs::File;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::*;
+
+/// A client this far from the end of the file is reading backlog
+const FAR_BEHIND: usize = 16 * 1024 * 1024;
+/// How much of the file we ask the kernel to read ahead of such a client
+const READAHEAD: usize = 4 * 1024 * 1024;
+/// The end of the file is never dropped, so that new clients which start
+/// near the end find it in the cache
+const KEEP_TAIL: usize = 64 * 1024 * 1024;
+/// With --drop-behind, how often we look for parts of the file to drop
+const DROP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Give the kernel the hints which apply to the whole file, and start
+/// dropping old parts of it if we've been asked to
+pub fn start(source: &dyn Source, opts: &Opts) {
+    if let Some(file) = source.as_file() {
+        if let Err(e) = rustix::fs::fadvise(file, 0, 0, Advice::Sequential) {
+            debug!("Couldn't advise sequential access: {e}");
+        }
+    }
+    if opts.drop_behind {
+        std::thread::spawn(drop_behind);
+    }
+}
+
+/// If the client is far behind, make sure the kernel is reading ahead of it.
+/// We don't ask again until it's halfway through what we asked for last time.
+pub fn readahead(file: &File, client: &mut Client, file_len: usize) {
+    let behind = file_len.saturating_sub(client.offset);
+    if behind < FAR_BEHIND || client.readahead > client.offset + READAHEAD / 2 {
+        return;
+    }
+    let len = READAHEAD.min(behind);
+    match rustix::fs::fadvise(file, client.offset as u64, len as u64, Advice::WillNeed) {
+        Ok(()) => trace!(offset = client.offset, len, "Asked for readahead"),
+        Err(e) => debug!("Couldn't ask for readahead: {e}"),
+    }
+    client.readahead = client.offset + len;
+}
+
+/// Every so often, let the kernel drop the parts of the file which every
+/// client has been sent
+fn drop_behind() {
+    let mut dropped = 0;
+    loop {
+        std::thread::sleep(DROP_INTERVAL);
+        let Some(file) = SOURCE.get().and_then(|x| x.as_file()) else {
+            return;
+        };
+        // Clients of --serve ports are reading other files
+        let slowest = visit_clients(|clients| {
+            clients
+                .values()
+                .filter(|c| c.feed.is_none())
+                .map(|c| c.offset)
+                .min()
+        })
+        .into_iter()
+        .flatten()
+        .min();
+        // Nobody's connected, so we don't know what'll be wanted next
+        let Some(slowest) = slowest else {
+            continue;
+        };
+        let file_len = FILE_LENGTH.load(Ordering::Acquire);
+        let upto = slowest.min(file_len.saturating_sub(KEEP_TAIL));
+        // The file may have been replaced (eg. with --reopen), in which case
+        // we start again
+        if upto < dropped {
+            dropped = 0;
+        }
+        if upto == dropped {
+            continue;
+        }
+        let len = (upto - dropped) as u64;
+        match rustix::fs::fadvise(file, dropped as u64, len, Advice::DontNeed) {
+            Ok(()) => {
+                debug!(
+                    from = dropped,
+                    to = upto,
+                    "Dropped the old part of the file"
+                );
+                dropped = upto;
+            }
+            Err(e) => warn!("Couldn't drop the old part of the file: {e}"),
+        }
+    }
+}
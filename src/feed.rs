@@ -0,0 +1,233 @@
+//! Support for `--serve PORT=PATH`, for serving more files from the same
+//! process.  Each file is served on a port of its own, by the same ring (and
+//! runloop) as the main file.  It has its own inotify watch, and its own slot
+//! in the ring's registered files.
+//!
+//! With --config, the set of files can be changed without restarting: on
+//! SIGHUP we read the config file's `serve` list again, start serving the new
+//! files, and stop accepting clients for the ones which have gone.  Clients
+//! which are already connected to one of those are sent the rest of it, and
+//! then disconnected.
+//!
+//! Clients of these files can start from a byte offset, a percentage, a time,
+//! or a pattern, and can ask for compression, grep, and so on.  The index,
+//! cursors, and commands are all about the main file, so `line <N>`,
+//! `seqnum <N>`, `cursor`, and commands are only accepted on the main port.
+
+use crate::source::Source;
+use crate::{bind_tcp_socket, open_file, Opts, Result};
+#[cfg(target_os = "linux")]
+use rustix::fd::OwnedFd;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::*;
+
+/// Identifies a file served with --serve.  This is also its slot in each
+/// ring's registered files; slot 0 is the main file, so these start at 1.
+pub type FeedId = u32;
+
+/// The most files which can be served with --serve.  Each ring registers this
+/// many slots up front, so that files can be added later.
+pub const MAX_FEEDS: FeedId = 256;
+
+/// The files being served, not counting ones which have been removed
+static FEEDS: RwLock<BTreeMap<FeedId, Arc<Feed>>> = RwLock::new(BTreeMap::new());
+/// The IDs of every `Feed` which still exists, including removed ones whose
+/// clients haven't finished.  An ID can't be reused until its old `Feed` has
+/// been dropped, because the rings may still have it registered.
+static LIVE: Mutex<BTreeSet<FeedId>> = Mutex::new(BTreeSet::new());
+/// Bumped whenever a file is added or removed, so that the runloops know to
+/// update their registered files
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+/// Set by the io_uring runloop, which is the only one that can serve these
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The argument to --serve
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub port: u16,
+    pub path: PathBuf,
+}
+
+impl FromStr for Binding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Binding, String> {
+        let Some((port, path)) = s.split_once('=') else {
+            return Err(format!("{s}: Expected PORT=PATH"));
+        };
+        let port = port.parse().map_err(|e| format!("{s}: {e}"))?;
+        if path.is_empty() {
+            return Err(format!("{s}: Expected PORT=PATH"));
+        }
+        Ok(Binding {
+            port,
+            path: path.into(),
+        })
+    }
+}
+
+/// A file served with --serve
+#[derive(Debug)]
+pub struct Feed {
+    pub id: FeedId,
+    pub port: u16,
+    pub path: PathBuf,
+    pub file: File,
+    len: AtomicUsize,
+    removed: AtomicBool,
+    pub listener: TcpListener,
+    #[cfg(target_os = "linux")]
+    pub ino_fd: OwnedFd,
+}
+
+impl Feed {
+    fn open(id: FeedId, binding: &Binding, opts: &Opts) -> Result<Feed> {
+        let _g = info_span!("", path = %binding.path.display()).entered();
+        // Unlike the main file, these have to exist already
+        let meta = std::fs::metadata(&binding.path)
+            .map_err(|e| format!("{}: {e}", binding.path.display()))?;
+        if !meta.is_file() {
+            return Err(format!("{}: Not a regular file", binding.path.display()).into());
+        }
+        let file = open_file(&binding.path)?;
+        let len = Source::len(&file)?;
+        let host = opts.bind.as_deref().unwrap_or("0.0.0.0");
+        let listener = bind_tcp_socket(host, binding.port, opts.dual_stack)?;
+        info!(id, listen_addr = %listener.local_addr()?, len, "Serving");
+        let feed = Feed {
+            id,
+            port: binding.port,
+            path: binding.path.clone(),
+            #[cfg(target_os = "linux")]
+            ino_fd: crate::watch_file(&file)?,
+            file,
+            len: AtomicUsize::new(len),
+            removed: AtomicBool::new(false),
+            listener,
+        };
+        LIVE.lock().unwrap().insert(id);
+        Ok(feed)
+    }
+
+    fn binding(&self) -> Binding {
+        Binding {
+            port: self.port,
+            path: self.path.clone(),
+        }
+    }
+
+    /// Whether the file has been taken out of the config.  Its clients are
+    /// disconnected once they've been sent the rest of it.
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Acquire)
+    }
+
+    /// The length of the file, as of the last inotify event
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Read the pending inotify events, and check how long the file is now
+    #[cfg(target_os = "linux")]
+    pub fn handle_inotify(&self) -> Result<()> {
+        use rustix::fs::inotify;
+        use rustix::io::Errno;
+        let mut buf = [const { std::mem::MaybeUninit::uninit() }; 1024];
+        let mut evs = inotify::Reader::new(&self.ino_fd, &mut buf);
+        loop {
+            match evs.next() {
+                Ok(ev) => trace!(feed = self.id, "inotify event: {:?}", ev),
+                Err(Errno::AGAIN) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let len = Source::len(&self.file)?;
+        let old_len = self.len.swap(len, Ordering::AcqRel);
+        if len < old_len {
+            warn!(feed = self.id, path = %self.path.display(), "The file shrank");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        debug!(feed = self.id, "Closed the file");
+        LIVE.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Make the files being served match `bindings`: the ones which aren't there
+/// any more are removed, and the new ones are opened and their ports bound.
+/// This is called at startup with the --serve flags, and on SIGHUP with the
+/// config file's `serve` list.
+pub fn sync(bindings: &[Binding], opts: &Opts) -> Result<()> {
+    let mut feeds = FEEDS.write().unwrap();
+    let result = sync_locked(&mut feeds, bindings, opts);
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    result
+}
+
+fn sync_locked(
+    feeds: &mut BTreeMap<FeedId, Arc<Feed>>,
+    bindings: &[Binding],
+    opts: &Opts,
+) -> Result<()> {
+    feeds.retain(|_, feed| {
+        let keep = bindings.contains(&feed.binding());
+        if !keep {
+            info!(feed = feed.id, path = %feed.path.display(), "No longer serving");
+            feed.removed.store(true, Ordering::Release);
+        }
+        keep
+    });
+    for binding in bindings {
+        if feeds.values().any(|x| x.binding() == *binding) {
+            continue;
+        }
+        let live = LIVE.lock().unwrap().clone();
+        let Some(id) = (1..=MAX_FEEDS).find(|id| !live.contains(id)) else {
+            return Err(format!("--serve: At most {MAX_FEEDS} files can be served").into());
+        };
+        feeds.insert(id, Arc::new(Feed::open(id, binding, opts)?));
+    }
+    Ok(())
+}
+
+/// Read the config file's `serve` list again, and start or stop serving files
+/// to match it
+pub fn reload(config: &Path, opts: &Opts) -> Result<()> {
+    let bindings = crate::config::read_serve(config)?;
+    if !bindings.is_empty() && !ENABLED.load(Ordering::Acquire) {
+        return Err("--serve needs the io_uring backend".into());
+    }
+    info!(files = bindings.len(), "Reloading the files to serve");
+    let result = sync(&bindings, opts);
+    // The runloops check for changes when they wake up
+    crate::wake_runloop();
+    result
+}
+
+/// Called by the io_uring runloop, to say that files can be added later
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// This changes whenever a file is added or removed
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+pub fn get(id: FeedId) -> Option<Arc<Feed>> {
+    FEEDS.read().unwrap().get(&id).cloned()
+}
+
+/// All the files which are being served with --serve, in order of ID
+pub fn all() -> Vec<Arc<Feed>> {
+    FEEDS.read().unwrap().values().cloned().collect()
+}
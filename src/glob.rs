@@ -0,0 +1,101 @@
+//! Support for `--glob`, for apps which start a new file every so often
+//! (eg. one per day).  We serve the newest file matching the pattern, and
+//! switch when a newer one appears.  "Newest" means the name which sorts
+//! last, so it's best if the names have the date in them.
+//!
+//! Only the last component of the pattern may contain wildcards.  `*`
+//! matches any number of characters, and `?` matches exactly one.
+
+use crate::{parent_dir, wait_for_dir_event, wait_for_file, watch_dir, Result};
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+pub struct Glob {
+    dir: PathBuf,
+    pattern: Vec<u8>,
+}
+
+impl Glob {
+    pub fn parse(s: &str) -> Result<Glob> {
+        let path = Path::new(s);
+        let Some(pattern) = path.file_name() else {
+            return Err(format!("{s}: The pattern must end with a file name").into());
+        };
+        let dir = parent_dir(path);
+        if dir.as_os_str().as_bytes().iter().any(|b| b"*?".contains(b)) {
+            return Err(format!("{s}: Only the file name may contain wildcards").into());
+        }
+        Ok(Glob {
+            dir: dir.to_owned(),
+            pattern: pattern.as_bytes().to_owned(),
+        })
+    }
+
+    /// The directory which the matching files are in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The matching file whose name sorts last, if there is one
+    pub fn newest(&self) -> std::io::Result<Option<PathBuf>> {
+        let mut newest = None;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !is_match(&self.pattern, name.as_bytes()) || !entry.path().is_file() {
+                continue;
+            }
+            if newest.as_ref().is_none_or(|x| name > *x) {
+                newest = Some(name);
+            }
+        }
+        Ok(newest.map(|x| self.dir.join(x)))
+    }
+
+    /// Wait for a matching file which is newer than `after` (or for any
+    /// matching file, if `after` is `None`) and open it
+    pub fn wait_for_newer(&self, after: Option<&Path>) -> Result<(PathBuf, File)> {
+        let _g = info_span!("", dir = %self.dir.display()).entered();
+        let dir_watch = watch_dir(&self.dir);
+        loop {
+            let newest = self.newest()?;
+            if let Some(path) = newest.filter(|x| after.is_none_or(|after| x.as_path() > after)) {
+                let file = wait_for_file(&path)?;
+                return Ok((path, file));
+            }
+            debug!("Waiting for a newer file");
+            wait_for_dir_event(dir_watch.as_ref());
+        }
+    }
+}
+
+/// Whether `name` matches the pattern
+fn is_match(pattern: &[u8], name: &[u8]) -> bool {
+    // When a `*` fails to work out, we go back and have it match one more
+    // character.  We only ever need to go back to the most recent `*`.
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
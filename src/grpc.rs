@@ -0,0 +1,253 @@
+//! Support for `--grpc-port` (with the `grpc` feature), for service meshes
+//! which only speak gRPC.  See `proto/tailsrv.proto` for the interface.
+//!
+//! gRPC clients don't go through the runloop, since the data has to be
+//! wrapped up in HTTP/2 frames.  Instead each call gets a task on a tokio
+//! runtime, which reads the file and sends it in chunks.  The runloop lets
+//! these tasks know when the file grows.
+
+use crate::access_log::Session;
+use crate::header::{BeyondEof, Header};
+use crate::ratelimit::{TokenBucket, THROTTLE_TICK};
+use crate::seek;
+use crate::source::{Source, SOURCE};
+use crate::{is_allowed, is_authorized, ClientSlot, Opts, Result};
+use crate::{BYTES_SENT, FILE_LENGTH, NEXT_CLIENT_ID};
+use std::net::TcpListener;
+use std::sync::atomic::Ordering;
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tracing::*;
+
+mod proto {
+    tonic::include_proto!("tailsrv");
+}
+use proto::tail_server::{Tail, TailServer};
+use proto::{Chunk, TailRequest};
+
+/// The most data we send in a single message
+const CHUNK_SIZE: usize = 64 * 1024;
+
+static FILE_GREW: Notify = Notify::const_new();
+
+/// Let the streams which are waiting for more data know that there is some
+pub fn file_grew() {
+    FILE_GREW.notify_waiters();
+}
+
+/// Serve gRPC calls on the listener.  Doesn't return unless something goes
+/// wrong.  The source must have been set already.
+pub fn serve(listener: TcpListener, opts: &'static Opts) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let service = Service {
+            source: &**SOURCE.get().ok_or("The source hasn't been set")?,
+            opts,
+        };
+        info!("Serving gRPC");
+        tonic::transport::Server::builder()
+            .add_service(TailServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await?;
+        Ok(())
+    })
+}
+
+struct Service {
+    source: &'static dyn Source,
+    opts: &'static Opts,
+}
+
+#[tonic::async_trait]
+impl Tail for Service {
+    type TailStream = ReceiverStream<Result<Chunk, Status>>;
+
+    async fn tail(&self, req: Request<TailRequest>) -> Result<Response<Self::TailStream>, Status> {
+        let opts = self.opts;
+        if let Some(peer) = req.remote_addr() {
+            if !is_allowed(peer.ip(), opts) {
+                info!(%peer, "Rejecting gRPC call from disallowed address");
+                return Err(Status::permission_denied("Address not allowed"));
+            }
+        }
+        let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let span = info_span!("", client_id);
+        let _g = span.enter();
+        info!(peer = ?req.remote_addr(), "Accepted gRPC call");
+
+        let header: Header = req
+            .get_ref()
+            .header
+            .parse()
+            .map_err(Status::invalid_argument)?;
+        // The token may also be sent as metadata, the usual way for gRPC
+        let bearer = req
+            .metadata()
+            .get("authorization")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        if !is_authorized(header.auth.as_deref().or(bearer), opts) {
+            error!("Client failed to authenticate");
+            return Err(Status::unauthenticated("Unauthorized"));
+        }
+        if header.compress.is_some() {
+            return Err(Status::invalid_argument(
+                "compress isn't supported over gRPC; use gRPC's own compression instead",
+            ));
+        }
+        if header.command.is_some() {
+            return Err(Status::invalid_argument(
+                "Commands aren't supported over gRPC",
+            ));
+        }
+        if header.cursor.is_some() {
+            return Err(Status::invalid_argument(
+                "Cursors aren't supported over gRPC",
+            ));
+        }
+        if header.csv_header || header.grep.is_some() || header.sample.is_some() || header.hello {
+            return Err(Status::invalid_argument(
+                "csv-header, grep, sample, and hello aren't supported over gRPC",
+            ));
+        }
+        let Some(slot) = ClientSlot::acquire(opts.max_clients) else {
+            warn!("Too many clients; rejecting call");
+            return Err(Status::resource_exhausted("Too many clients"));
+        };
+
+        // Finding the starting position may mean reading through the file
+        if header.strict {
+            seek::check_strict(&header.start).map_err(|file_len| {
+                Status::out_of_range(format!("Before the start of the file ({file_len} bytes)"))
+            })?;
+        }
+        let offset = tokio::task::block_in_place(|| seek::resolve(&header.start, opts))
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let policy = header
+            .beyond_eof
+            .or(opts.beyond_eof)
+            .unwrap_or(BeyondEof::Wait);
+        let offset = seek::beyond_eof(offset, policy).map_err(|file_len| {
+            Status::out_of_range(format!("Beyond the end of the file ({file_len} bytes)"))
+        })?;
+        let offset = match header.align {
+            Some(align) => {
+                let file_len = FILE_LENGTH.load(Ordering::Acquire);
+                let delim = seek::delim(&header.start, opts);
+                tokio::task::block_in_place(|| {
+                    seek::align(self.source, offset, file_len, align, delim)
+                })
+                .map_err(|e| Status::internal(e.to_string()))?
+            }
+            None => offset,
+        };
+        info!("Starting from initial offset {offset}");
+        let peer = req.remote_addr().map_or("-".into(), |x| x.to_string());
+        let (tx, rx) = mpsc::channel(4);
+        let stream = Stream {
+            source: self.source,
+            offset,
+            rate_limit: opts.max_rate_per_client.map(TokenBucket::new),
+            tx,
+            session: Session::new(peer, header.start.to_string(), offset),
+            _slot: slot,
+        };
+        drop(_g);
+        tokio::spawn(stream.run().instrument(span));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// A single gRPC client
+struct Stream {
+    source: &'static dyn Source,
+    offset: usize,
+    rate_limit: Option<TokenBucket>,
+    tx: mpsc::Sender<Result<Chunk, Status>>,
+    session: Session,
+    _slot: ClientSlot,
+}
+
+impl Stream {
+    async fn run(mut self) {
+        loop {
+            // Start listening before checking the length, so that we can't
+            // miss a notification
+            let grew = FILE_GREW.notified();
+            tokio::pin!(grew);
+            grew.as_mut().enable();
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            if self.offset >= file_len {
+                tokio::select! {
+                    _ = grew => continue,
+                    _ = self.tx.closed() => break,
+                }
+            }
+            let mut len = CHUNK_SIZE.min(file_len - self.offset);
+            if let Some(bucket) = &mut self.rate_limit {
+                match bucket.available() {
+                    0 => {
+                        trace!("Client is throttled");
+                        tokio::time::sleep(THROTTLE_TICK).await;
+                        continue;
+                    }
+                    n => len = len.min(n),
+                }
+            }
+            let data = match self.read(len).await {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("{e}");
+                    let _ = self.tx.send(Err(Status::internal(e.to_string()))).await;
+                    self.session.end(e);
+                    return;
+                }
+            };
+            if data.is_empty() {
+                // The file must have been truncated
+                grew.await;
+                continue;
+            }
+            trace!("Sending {} bytes to client", data.len());
+            if let Some(bucket) = &mut self.rate_limit {
+                bucket.consume(data.len());
+            }
+            let chunk = Chunk {
+                offset: self.offset as u64,
+                data,
+            };
+            let n_sent = chunk.data.len();
+            self.offset += n_sent;
+            if self.tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+            BYTES_SENT.fetch_add(n_sent as u64, Ordering::Relaxed);
+        }
+        info!("Client hung up");
+        self.session.end("hung up");
+    }
+
+    /// Read up to `len` bytes of the source from the current offset
+    async fn read(&self, len: usize) -> std::io::Result<Vec<u8>> {
+        let source = self.source;
+        let offset = self.offset;
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; len];
+            let n = source.read_at(&mut buf, offset)?;
+            buf.truncate(n);
+            Ok(buf)
+        })
+        .await?
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.session.record(self.offset);
+    }
+}
@@ -0,0 +1,639 @@
+use chrono::{DateTime, Utc};
+use regex::bytes::Regex;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// The header which a client sends when it connects.
+///
+/// In its simplest form the header is just a signed integer (see the README).
+/// It may also be made up of several whitespace-separated items:
+///
+/// * `byte <N>` - the same as a bare integer.  Either may be followed by
+///   `wait`, `clamp`, or `error`, saying what to do if the file isn't that
+///   long yet (see `BeyondEof`), and by `strict`, which makes a negative N
+///   which goes back past the start of the file an error
+/// * `since <TIME>` - start from the first line timestamped at or after TIME
+/// * `from /<PATTERN>/` - start from the first line which matches PATTERN
+/// * `after /<PATTERN>/` - start from the last line which matches PATTERN
+/// * `line <N>` - start from line N.  Negative values count back from the end
+/// * `zero <N>` - the same, but the lines are NUL-terminated
+/// * `seqnum <N>` - start from record N, in a file of length-prefixed records
+/// * `pct <P>` - start P percent of the way through the file
+/// * `auth <TOKEN>` - the authentication token (see `--auth-token-file`)
+/// * `compress <ALGO>` - compress the stream (see `--allow-compression`)
+/// * `cursor <NAME>` - start from the offset committed under NAME (see
+///   `--cursor-file`)
+/// * `align <UNIT>` - move the starting position forward to a boundary (see
+///   `Align`)
+/// * `csv-header` - send the first line of the file before anything else
+/// * `grep /<PATTERN>/` - only send lines which match PATTERN
+/// * `sample <N>` - only send every Nth line
+/// * `hello` - reply with a line of JSON saying where the stream starts
+///
+/// Instead of a starting position, the header may contain a one-shot
+/// command (see `command`):
+///
+/// * `hash <FROM> <TO>` - the CRC-32 of the given range of bytes
+/// * `stat` - a line of JSON describing the file and the server
+/// * `size` - the length of the file
+///
+/// The header may also be written over several lines, one item per line, as
+/// `key: value` (eg. `grep: /foo/`), ending with an empty line.  Items
+/// which don't take an argument have an empty value.  A header is in this
+/// form if its first word ends with a colon.
+///
+/// Clients which want to use newer parts of the protocol can start the
+/// header with `v2`.  The server then replies with the items it understands
+/// before anything else (see `PROTOCOL_VERSION`).
+#[derive(Debug)]
+pub struct Header {
+    pub start: Start,
+    pub auth: Option<String>,
+    pub compress: Option<Compression>,
+    pub command: Option<Command>,
+    pub cursor: Option<String>,
+    /// What to do if the starting position is past the end of the file.
+    /// `None` means the server's default.
+    pub beyond_eof: Option<BeyondEof>,
+    /// Whether a negative byte offset which goes back past the start of the
+    /// file is an error, rather than meaning the start of the file
+    pub strict: bool,
+    pub align: Option<Align>,
+    /// Whether to send the first line of the file (eg. the column names of
+    /// a CSV file) before starting from the requested position
+    pub csv_header: bool,
+    /// Only lines which match this are sent
+    pub grep: Option<Regex>,
+    /// Only every Nth line is sent
+    pub sample: Option<u64>,
+    /// Whether to tell the client where it's starting before sending it
+    /// anything else
+    pub hello: bool,
+    /// How urgently the client wants its data.  `None` means realtime,
+    /// unless the server says otherwise.
+    pub class: Option<Class>,
+    /// The protocol version which the client asked for with `v<N>`.  `None`
+    /// means the original protocol, where the server doesn't reply.
+    pub version: Option<u32>,
+}
+
+/// The protocol version which clients can ask for with `v<N>`
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The items which every server understands, as listed in reply to `v2`.
+/// (`auth`, `compress` and `cursor` are only listed if they're enabled.)
+/// `multiline` means the multi-line form of the header.
+pub const ITEMS: &str = "multiline byte since from after line zero seqnum pct align csv-header \
+                         grep sample hello class hash stat size";
+
+/// A question which the client wants answered, instead of being sent the
+/// file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// The CRC-32 of this range of the file
+    Hash(Range<usize>),
+    /// What we know about the file, and how many clients there are
+    Stat,
+    /// The length of the file
+    Size,
+    /// Nothing: the client sent `v<N>` on its own, to find out which items
+    /// the server understands
+    Capabilities,
+}
+
+/// Where the client wants to start
+#[derive(Debug)]
+pub enum Start {
+    /// A byte offset.  Negative values count back from the end of the file.
+    Byte(isize),
+    /// The first line whose timestamp is at or after this time
+    Since(DateTime<Utc>),
+    /// The first line which matches the pattern
+    From(Regex),
+    /// The last line which matches the pattern
+    After(Regex),
+    /// A line number, counting from zero.  Negative values count back from
+    /// the end of the file, so -1 is the last line.
+    Line(isize),
+    /// Like `Line`, but the lines end with a NUL byte instead of whatever
+    /// --delim says
+    Zero(isize),
+    /// A record number, counting from zero, in a file made of records which
+    /// are each prefixed with their length (as an unsigned LEB128 varint)
+    Seqnum(u64),
+    /// A percentage of the way through the file, from 0 to 100
+    Pct(f64),
+}
+
+impl Start {
+    /// Whether we may have to read through the file to find this position.
+    /// Only the positions which are plain arithmetic on the file's length
+    /// are cheap enough to resolve on the runloop.
+    #[cfg(target_os = "linux")]
+    pub fn needs_scan(&self) -> bool {
+        !matches!(self, Start::Byte(_) | Start::Pct(_))
+    }
+
+    /// Whether this position is looked up in the index, which only covers
+    /// the main file
+    pub fn is_indexed(&self) -> bool {
+        matches!(self, Start::Line(0..) | Start::Zero(0..) | Start::Seqnum(_))
+    }
+}
+
+/// Written the way the client would have sent it
+impl std::fmt::Display for Start {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Start::Byte(n) => write!(f, "byte {n}"),
+            Start::Since(t) => write!(f, "since {}", t.to_rfc3339()),
+            Start::From(re) => write!(f, "from /{re}/"),
+            Start::After(re) => write!(f, "after /{re}/"),
+            Start::Line(n) => write!(f, "line {n}"),
+            Start::Zero(n) => write!(f, "zero {n}"),
+            Start::Seqnum(n) => write!(f, "seqnum {n}"),
+            Start::Pct(p) => write!(f, "pct {p}"),
+        }
+    }
+}
+
+/// What to do with a client which asks to start past the end of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeyondEof {
+    /// Wait for the file to grow that far
+    Wait,
+    /// Start from the end of the file instead
+    Clamp,
+    /// Send an error and disconnect
+    Error,
+}
+
+impl FromStr for BeyondEof {
+    type Err = String;
+    fn from_str(s: &str) -> Result<BeyondEof, String> {
+        match s {
+            "wait" => Ok(BeyondEof::Wait),
+            "clamp" => Ok(BeyondEof::Clamp),
+            "error" => Ok(BeyondEof::Error),
+            _ => Err(format!("{s}: Unknown policy")),
+        }
+    }
+}
+
+/// A boundary which the client wants to start on, whatever offset it asked
+/// for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    /// The start of a line
+    Line,
+    /// The start of a UTF-8 character
+    Utf8,
+}
+
+impl FromStr for Align {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Align, String> {
+        match s {
+            "line" => Ok(Align::Line),
+            "utf8" => Ok(Align::Utf8),
+            _ => Err(format!("{s}: Unknown alignment")),
+        }
+    }
+}
+
+/// Which clients are served first.  Realtime clients (eg. live dashboards)
+/// are served before bulk ones (eg. backfills), which get what's left over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Class {
+    Realtime,
+    Bulk,
+}
+
+impl FromStr for Class {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Class, String> {
+        match s {
+            "realtime" => Ok(Class::Realtime),
+            "bulk" => Ok(Class::Bulk),
+            _ => Err(format!("{s}: Unknown class")),
+        }
+    }
+}
+
+/// The compression algorithms which a client can ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("{s}: Unknown compression algorithm")),
+        }
+    }
+}
+
+/// Whether a header whose first line is `line` is in the multi-line form
+pub fn is_multiline(line: &str) -> bool {
+    let mut rest = line;
+    next_word(&mut rest).is_some_and(|x| x.ends_with(':'))
+}
+
+/// Where the header at the start of `buf` ends (just after its last
+/// newline), or `None` if we haven't got all of it yet
+pub fn find_end(buf: &[u8]) -> Option<usize> {
+    let eol = buf.iter().position(|&b| b == b'\n')?;
+    let first_line = std::str::from_utf8(&buf[..eol]).unwrap_or("");
+    if !is_multiline(first_line) {
+        return Some(eol + 1);
+    }
+    let mut start = eol + 1;
+    loop {
+        let eol = start + buf[start..].iter().position(|&b| b == b'\n')?;
+        if buf[start..eol].trim_ascii().is_empty() {
+            return Some(eol + 1);
+        }
+        start = eol + 1;
+    }
+}
+
+/// Turn a multi-line header into the equivalent single-line one
+fn join_lines(s: &str) -> Result<String, String> {
+    let mut joined = String::new();
+    for line in s.lines().filter(|x| !x.trim().is_empty()) {
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(format!("{line}: Expected \"key: value\""));
+        };
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(format!("{key}: Bad key"));
+        }
+        joined.push_str(key);
+        joined.push(' ');
+        joined.push_str(value.trim());
+        joined.push(' ');
+    }
+    Ok(joined)
+}
+
+impl FromStr for Header {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Header, String> {
+        let joined;
+        let s = match is_multiline(s.lines().next().unwrap_or("")) {
+            true => {
+                joined = join_lines(s)?;
+                &joined
+            }
+            false => s,
+        };
+        let mut start = None;
+        let mut auth = None;
+        let mut compress = None;
+        let mut command = None;
+        let mut cursor = None;
+        let mut beyond_eof = None;
+        let mut strict = false;
+        let mut align = None;
+        let mut csv_header = false;
+        let mut grep = None;
+        let mut sample = None;
+        let mut hello = false;
+        let mut class = None;
+        let mut version = None;
+        let mut rest = s;
+        // `v<N>` may only come first
+        let mut peek = rest;
+        if let Some(n) = next_word(&mut peek).and_then(|x| x.strip_prefix('v')?.parse().ok()) {
+            version = Some(n);
+            rest = peek;
+        }
+        while let Some(word) = next_word(&mut rest) {
+            let mut arg = |name: &str| {
+                next_word(&mut rest).ok_or_else(|| format!("Expected an argument after \"{name}\""))
+            };
+            match word {
+                "auth" => auth = Some(arg(word)?.to_owned()),
+                "compress" => compress = Some(arg(word)?.parse()?),
+                "cursor" => cursor = Some(arg(word)?.to_owned()),
+                "align" => align = Some(arg(word)?.parse()?),
+                "csv-header" => csv_header = true,
+                "hello" => hello = true,
+                "class" => class = Some(arg(word)?.parse()?),
+                "grep" => grep = Some(parse_pattern(&mut rest)?),
+                "sample" => match parse_position(arg(word)?)? {
+                    0 => return Err("sample 0: N must be at least 1".into()),
+                    n => sample = Some(n as u64),
+                },
+                "byte" => {
+                    start = Some(Start::Byte(parse_offset(arg(word)?)?));
+                    parse_byte_modifiers(&mut rest, &mut beyond_eof, &mut strict);
+                }
+                "since" => start = Some(Start::Since(parse_time(arg(word)?)?)),
+                "from" => start = Some(Start::From(parse_pattern(&mut rest)?)),
+                "after" => start = Some(Start::After(parse_pattern(&mut rest)?)),
+                "line" => start = Some(Start::Line(parse_offset(arg(word)?)?)),
+                "zero" => start = Some(Start::Zero(parse_offset(arg(word)?)?)),
+                "seqnum" => start = Some(Start::Seqnum(parse_position(arg(word)?)? as u64)),
+                "pct" => start = Some(Start::Pct(parse_pct(arg(word)?)?)),
+                "hash" => {
+                    let from = parse_position(arg(word)?)?;
+                    let to = parse_position(arg(word)?)?;
+                    if to < from {
+                        return Err(format!("{from}..{to}: Range is backwards"));
+                    }
+                    command = Some(Command::Hash(from..to));
+                }
+                "stat" => command = Some(Command::Stat),
+                "size" => command = Some(Command::Size),
+                _ => {
+                    start = Some(Start::Byte(parse_offset(word)?));
+                    parse_byte_modifiers(&mut rest, &mut beyond_eof, &mut strict);
+                }
+            }
+        }
+        if start.is_none() && command.is_none() && cursor.is_none() && version.is_some() {
+            command = Some(Command::Capabilities);
+        }
+        let start = match start {
+            Some(start) => start,
+            // Commands don't need a starting position, and cursors start
+            // from the beginning until something's committed
+            None if command.is_some() || cursor.is_some() => Start::Byte(0),
+            None => return Err("No starting position given".into()),
+        };
+        Ok(Header {
+            start,
+            auth,
+            compress,
+            command,
+            cursor,
+            beyond_eof,
+            strict,
+            align,
+            csv_header,
+            grep,
+            sample,
+            hello,
+            class,
+            version,
+        })
+    }
+}
+
+/// Take the modifiers which may follow a byte offset from the start of `s`:
+/// a `BeyondEof` policy and/or `strict`, in either order
+fn parse_byte_modifiers(s: &mut &str, beyond_eof: &mut Option<BeyondEof>, strict: &mut bool) {
+    loop {
+        let mut rest = *s;
+        match next_word(&mut rest) {
+            Some("strict") => *strict = true,
+            Some(word) => match word.parse() {
+                Ok(policy) => *beyond_eof = Some(policy),
+                Err(_) => return,
+            },
+            None => return,
+        }
+        *s = rest;
+    }
+}
+
+/// Take the next whitespace-separated word from the start of `s`
+fn next_word<'a>(s: &mut &'a str) -> Option<&'a str> {
+    let t = s.trim_start();
+    if t.is_empty() {
+        return None;
+    }
+    let (word, rest) = t.split_at(t.find(char::is_whitespace).unwrap_or(t.len()));
+    *s = rest;
+    Some(word)
+}
+
+/// Take a pattern delimited by slashes from the start of `s`.  The pattern
+/// may contain whitespace, but slashes have to be escaped.
+fn parse_pattern(s: &mut &str) -> Result<Regex, String> {
+    let body = s
+        .trim_start()
+        .strip_prefix('/')
+        .ok_or("Expected a pattern between slashes, like /foo/")?;
+    let mut escaped = false;
+    let end = body
+        .char_indices()
+        .find(|&(_, c)| {
+            let found = c == '/' && !escaped;
+            escaped = c == '\\' && !escaped;
+            found
+        })
+        .ok_or("Pattern is missing its closing slash")?
+        .0;
+    let pattern = &body[..end];
+    *s = &body[end + 1..];
+    Regex::new(pattern).map_err(|e| format!("/{pattern}/: {e}"))
+}
+
+fn parse_offset(s: &str) -> Result<isize, String> {
+    s.parse().map_err(|e| format!("{s}: {e}"))
+}
+
+fn parse_position(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|e| format!("{s}: {e}"))
+}
+
+/// A percentage, from 0 to 100.  It doesn't have to be a whole number.
+fn parse_pct(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        Ok(_) => Err(format!("pct {s}: Must be between 0 and 100")),
+        Err(e) => Err(format!("{s}: {e}")),
+    }
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    let t = DateTime::parse_from_rfc3339(s).map_err(|e| format!("{s}: {e}"))?;
+    Ok(t.to_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Header {
+        s.parse().unwrap_or_else(|e| panic!("{s:?}: {e}"))
+    }
+
+    #[test]
+    fn bare_offsets() {
+        assert!(matches!(parse("0\n").start, Start::Byte(0)));
+        assert!(matches!(parse("-100\n").start, Start::Byte(-100)));
+        assert!(matches!(parse("byte 42\n").start, Start::Byte(42)));
+        assert!("".parse::<Header>().is_err());
+        assert!("\n".parse::<Header>().is_err());
+        assert!("x\n".parse::<Header>().is_err());
+        assert!("byte\n".parse::<Header>().is_err());
+        assert!("byte 1.5\n".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn byte_modifiers() {
+        let header = parse("byte 10000000 wait\n");
+        assert_eq!(header.beyond_eof, Some(BeyondEof::Wait));
+        assert!(!header.strict);
+        let header = parse("-999999999 strict\n");
+        assert!(matches!(header.start, Start::Byte(-999999999)));
+        assert!(header.strict);
+        let header = parse("byte -5 strict clamp\n");
+        assert_eq!(header.beyond_eof, Some(BeyondEof::Clamp));
+        assert!(header.strict);
+        let header = parse("5 error strict hello\n");
+        assert_eq!(header.beyond_eof, Some(BeyondEof::Error));
+        assert!(header.strict && header.hello);
+        let header = parse("5\n");
+        assert_eq!(header.beyond_eof, None);
+        assert!(!header.strict);
+        assert!("5 never\n".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn positions() {
+        let header = parse("since 2024-12-13T07:00:00+09:00\n");
+        let Start::Since(t) = header.start else {
+            panic!("{:?}", header.start)
+        };
+        assert_eq!(t.to_rfc3339(), "2024-12-12T22:00:00+00:00");
+        assert!("since yesterday\n".parse::<Header>().is_err());
+        let Start::From(re) = parse("from /a b\\/c/\n").start else {
+            panic!()
+        };
+        assert_eq!(re.as_str(), "a b\\/c");
+        assert!(matches!(parse("after /x/\n").start, Start::After(_)));
+        assert!("from /unclosed\n".parse::<Header>().is_err());
+        assert!("from nope\n".parse::<Header>().is_err());
+        assert!("from /(/\n".parse::<Header>().is_err());
+        assert!(matches!(parse("line -3\n").start, Start::Line(-3)));
+        assert!(matches!(parse("zero 7\n").start, Start::Zero(7)));
+        assert!(matches!(parse("seqnum 9\n").start, Start::Seqnum(9)));
+        assert!("seqnum -1\n".parse::<Header>().is_err());
+        assert!(matches!(parse("pct 12.5\n").start, Start::Pct(p) if p == 12.5));
+        assert!(matches!(parse("pct 100\n").start, Start::Pct(p) if p == 100.0));
+        assert!("pct 100.1\n".parse::<Header>().is_err());
+        assert!("pct -1\n".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn options() {
+        let header = parse(
+            "auth s3cret compress zstd cursor me align utf8 csv-header grep /ERR/ \
+             sample 10 hello class bulk line 0\n",
+        );
+        assert_eq!(header.auth.as_deref(), Some("s3cret"));
+        assert_eq!(header.compress, Some(Compression::Zstd));
+        assert_eq!(header.cursor.as_deref(), Some("me"));
+        assert_eq!(header.align, Some(Align::Utf8));
+        assert!(header.csv_header && header.hello);
+        assert_eq!(header.grep.unwrap().as_str(), "ERR");
+        assert_eq!(header.sample, Some(10));
+        assert_eq!(header.class, Some(Class::Bulk));
+        assert!(matches!(header.start, Start::Line(0)));
+        // Cursors start from the beginning until something's committed
+        assert!(matches!(parse("cursor me\n").start, Start::Byte(0)));
+        assert!("sample 0 0\n".parse::<Header>().is_err());
+        assert!("compress gzip 0\n".parse::<Header>().is_err());
+        assert!("align word 0\n".parse::<Header>().is_err());
+        assert!("class urgent 0\n".parse::<Header>().is_err());
+        assert!("auth\n".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("hash 10 20\n").command, Some(Command::Hash(10..20)));
+        assert_eq!(parse("stat\n").command, Some(Command::Stat));
+        assert_eq!(parse("size\n").command, Some(Command::Size));
+        assert!("hash 20 10\n".parse::<Header>().is_err());
+        assert!("hash 10\n".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn versions() {
+        let header = parse("v2 line -1\n");
+        assert_eq!(header.version, Some(2));
+        assert!(matches!(header.start, Start::Line(-1)));
+        assert_eq!(header.command, None);
+        // On its own, it asks what the server understands
+        let header = parse("v2\n");
+        assert_eq!(header.version, Some(2));
+        assert_eq!(header.command, Some(Command::Capabilities));
+        // Unsupported versions are parsed, so that they can be rejected
+        assert_eq!(parse("v99 0\n").version, Some(99));
+        assert_eq!(parse("0\n").version, None);
+        // It may only come first
+        assert!("0 v2\n".parse::<Header>().is_err());
+    }
+
+    #[test]
+    fn multiline() {
+        let text = "v2:\ngrep: /foo bar/\nsample: 3\nhello:\nbyte: -10 strict\n\n";
+        assert!(is_multiline(text.lines().next().unwrap()));
+        let header = parse(text);
+        assert_eq!(header.version, Some(2));
+        assert_eq!(header.grep.unwrap().as_str(), "foo bar");
+        assert_eq!(header.sample, Some(3));
+        assert!(header.hello && header.strict);
+        assert!(matches!(header.start, Start::Byte(-10)));
+        let header = parse("line: 5\r\nauth:  tok \r\n\r\n");
+        assert!(matches!(header.start, Start::Line(5)));
+        assert_eq!(header.auth.as_deref(), Some("tok"));
+        assert!("line: 5\nno colon\n\n".parse::<Header>().is_err());
+        assert!("line: 5\nbad key: 1\n\n".parse::<Header>().is_err());
+        assert!("line: 5\n: 1\n\n".parse::<Header>().is_err());
+        assert!(!is_multiline("line 5"));
+        assert!(!is_multiline(""));
+    }
+
+    #[test]
+    fn header_end() {
+        assert_eq!(find_end(b"0\nleftovers"), Some(2));
+        assert_eq!(find_end(b"0"), None);
+        assert_eq!(find_end(b"line: 5\nhello:\n"), None);
+        assert_eq!(find_end(b"line: 5\nhello:\n\ncommit 1\n"), Some(16));
+        assert_eq!(find_end(b"line: 5\r\n\r\n"), Some(11));
+    }
+
+    #[test]
+    fn runloop_positions() {
+        assert!(parse("line 0\n").start.is_indexed());
+        assert!(parse("seqnum 0\n").start.is_indexed());
+        assert!(!parse("line -1\n").start.is_indexed());
+        assert!(!parse("zero -1\n").start.is_indexed());
+        #[cfg(target_os = "linux")]
+        {
+            assert!(!parse("-5\n").start.needs_scan());
+            assert!(!parse("pct 50\n").start.needs_scan());
+            for s in [
+                "line 5",
+                "line -5",
+                "zero -5",
+                "seqnum 5",
+                "from /x/",
+                "since 2024-12-13T07:00:00Z",
+            ] {
+                assert!(parse(s).start.needs_scan(), "{s}");
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in [
+            "byte -5",
+            "line -1",
+            "zero 3",
+            "seqnum 9",
+            "pct 12.5",
+            "from /a b/",
+        ] {
+            assert_eq!(parse(s).start.to_string(), s);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Support for `--http-port`, which serves the file over HTTP/1.1.
+//!
+//! A `GET /` returns the file and keeps streaming as it grows, just like the
+//! plain protocol.  A `Range: bytes=N-` header does the job of the plain
+//! protocol's header: it's mapped to a byte offset (and `bytes=-N` to a
+//! negative one).
+//!
+//! We don't use chunked encoding, since that would rule out sending the data
+//! straight from the pagecache.  Instead the response has no length, which
+//! means the body ends when the connection is closed.
+//!
+//! For text files, `GET /events` streams the file as server-sent events, one
+//! per line.  Each event's ID is its line number (counting from zero), and
+//! `/events?from_line=N` starts from line N.  When a browser reconnects it
+//! sends `Last-Event-ID`, and we carry on from the line after that.
+//!
+//! Requests are read on a thread per client, whatever the backend.  Once the
+//! response head has been sent, the client is handed over to the runloop.
+
+use crate::encode::{Encoder, Token};
+use crate::exit;
+use crate::index::{self, Kind};
+use crate::seek::resolve_offset;
+use crate::source::{Source, SOURCE};
+use crate::{
+    add_client, is_authorized, tcp_client_id, Client, ClientId, ClientSlot, HeaderReader, Opts,
+};
+use crate::{Result, FILE_LENGTH};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use tracing::*;
+
+/// Requests with longer heads than this are rejected
+const MAX_REQUEST_LEN: usize = 8192;
+
+pub fn listen(listener: TcpListener, opts: &'static Opts) {
+    for conn in listener.incoming() {
+        match conn {
+            Ok(conn) => {
+                #[cfg(target_os = "linux")]
+                if crate::upgrade::forward(&conn, crate::upgrade::Conn::Http) {
+                    continue;
+                }
+                if let Some(client_id) = tcp_client_id(&conn, opts) {
+                    spawn_client(conn, client_id, opts);
+                }
+            }
+            Err(e) => error!("Bad connection: {e}"),
+        }
+    }
+    error!("HTTP listening socket was closed!");
+    exit::exit(exit::Reason::ListenerClosed);
+}
+
+pub fn spawn_client(mut conn: TcpStream, client_id: ClientId, opts: &'static Opts) {
+    let Some(slot) = ClientSlot::acquire(opts.max_clients) else {
+        warn!(client_id, "Too many clients; rejecting connection");
+        if let Err(e) = respond_error(&mut conn, "503 Service Unavailable", "Too many clients") {
+            debug!(client_id, "Couldn't send error: {e}");
+        }
+        return;
+    };
+    std::thread::spawn(move || {
+        let _g = info_span!("", client_id).entered();
+        match handshake(conn, slot, opts) {
+            Ok(client) => {
+                trace!("Prepared client: {client:?}");
+                add_client(client_id, client);
+            }
+            Err(e) => error!("{e}"),
+        }
+    });
+}
+
+/// Read the request, send the response head, and set up the client
+fn handshake(mut conn: TcpStream, slot: ClientSlot, opts: &Opts) -> Result<Client> {
+    info!("Connected over HTTP");
+    let req = match Request::read(&mut conn) {
+        Ok(x) => x,
+        Err(e) => {
+            respond_error(&mut conn, "400 Bad Request", &e)?;
+            return Err(e.into());
+        }
+    };
+    debug!(method = req.method, path = req.path, "Received request");
+    if req.method != "GET" {
+        respond_error(&mut conn, "405 Method Not Allowed", "Only GET is supported")?;
+        return Err(format!("{}: Unsupported method", req.method).into());
+    }
+    if !is_authorized(req.bearer_token(), opts) {
+        respond_error(&mut conn, "401 Unauthorized", "Unauthorized")?;
+        return Err("Client failed to authenticate".into());
+    }
+    let (path, query) = req.path.split_once('?').unwrap_or((&req.path, ""));
+    match path {
+        "/" => (),
+        "/events" => return events(conn, &req, query, slot, opts),
+        _ => {
+            respond_error(&mut conn, "404 Not Found", "Not found")?;
+            return Err(format!("{}: Not found", req.path).into());
+        }
+    }
+
+    let offset = req.range_start();
+    // A range which we can't serve is ignored, and the whole file is sent
+    let status = if offset.is_some() {
+        "206 Partial Content"
+    } else {
+        "200 OK"
+    };
+    // There's no Content-Range, since the file doesn't have a length yet
+    write!(
+        conn,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: application/octet-stream\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\
+         \r\n",
+    )?;
+    let requested = format!("range {}", offset.unwrap_or(0));
+    let offset = resolve_offset(offset.unwrap_or(0));
+    Client::start(conn.into(), requested, offset, None, slot, opts)
+}
+
+/// Stream the file as server-sent events
+fn events(
+    mut conn: TcpStream,
+    req: &Request,
+    query: &str,
+    slot: ClientSlot,
+    opts: &Opts,
+) -> Result<Client> {
+    // A browser which reconnects sends the ID of the last event it saw
+    let token = req
+        .last_event_id
+        .as_deref()
+        .and_then(|id| id.parse::<Token>().ok());
+    let first_line = match req.last_event_id.as_deref() {
+        _ if token.is_some() => Some(0),
+        Some(id) => id.parse::<u64>().map(|x| x + 1).ok(),
+        None => query.split('&').find_map(|param| {
+            let value = param.strip_prefix("from_line=")?;
+            value.parse::<u64>().ok()
+        }),
+    };
+    let first_line = first_line.unwrap_or(0);
+    // Lines are looked up in the index, like `line <N>`.  The first lookup
+    // may have to read through the file, and we'd rather do that here than on
+    // the runloop.  Events are always split on newlines, whatever --delim
+    // says, so that's what we count.  A token says where to go already, but
+    // not which line that is; we don't need to know, since the IDs will be
+    // tokens too.
+    let (offset, next_line) = match (SOURCE.get(), token) {
+        (Some(source), Some(token)) => {
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            (resume(&**source, token, file_len), 0)
+        }
+        (Some(source), None) => {
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            index::find_numbered(Kind::Lines(b'\n'), &**source, first_line, file_len)?
+        }
+        (None, _) => (0, 0),
+    };
+    info!(first_line, next_line, "Streaming server-sent events");
+    write!(
+        conn,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\
+         \r\n",
+    )?;
+    let mut encoder = Encoder::sse(next_line, first_line);
+    if query.split('&').any(|param| param == "crc") {
+        encoder = encoder.crc();
+    }
+    if token.is_some() || query.split('&').any(|param| param == "token") {
+        encoder = encoder.tokens();
+    }
+    let requested = match token {
+        Some(token) => format!("events {token}"),
+        None => format!("events {first_line}"),
+    };
+    Client::start(conn.into(), requested, offset, Some(encoder), slot, opts)
+}
+
+/// Where a client which was sent `token` carries on from.  If the file has
+/// been replaced (or truncated) since, it's all new to the client, so that's
+/// the start of the file.
+fn resume(source: &dyn Source, token: Token, file_len: usize) -> usize {
+    let file = source.identity().unwrap_or_default();
+    if token.file == file && token.offset <= file_len {
+        token.offset
+    } else {
+        info!(%token, "The file has changed since; starting from the beginning");
+        0
+    }
+}
+
+fn respond_error(conn: &mut TcpStream, status: &str, msg: &str) -> std::io::Result<()> {
+    write!(
+        conn,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {msg}\n",
+        msg.len() + 1,
+    )
+}
+
+/// The parts of an HTTP request which we care about
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    authorization: Option<String>,
+    last_event_id: Option<String>,
+}
+
+impl Request {
+    /// Read the request line and headers.  Anything after that is ignored.
+    fn read(conn: &mut TcpStream) -> Result<Request, String> {
+        let mut rdr = BufReader::new(HeaderReader::new(conn)).take(MAX_REQUEST_LEN as u64);
+        let mut line = String::new();
+        let mut read_line = |line: &mut String| -> Result<(), String> {
+            line.clear();
+            match rdr.read_line(line) {
+                Ok(_) if line.ends_with('\n') => Ok(()),
+                Ok(_) => Err("Request is incomplete or too long".into()),
+                Err(e) => Err(e.to_string()),
+            }
+        };
+
+        read_line(&mut line)?;
+        let mut words = line.split_whitespace();
+        let (Some(method), Some(path), Some(_version)) = (words.next(), words.next(), words.next())
+        else {
+            return Err("Malformed request line".into());
+        };
+        let mut req = Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            range: None,
+            authorization: None,
+            last_event_id: None,
+        };
+
+        loop {
+            read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                return Ok(req);
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(format!("{line}: Malformed header"));
+            };
+            let value = Some(value.trim().to_owned());
+            if name.eq_ignore_ascii_case("range") {
+                req.range = value;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                req.authorization = value;
+            } else if name.eq_ignore_ascii_case("last-event-id") {
+                req.last_event_id = value;
+            }
+        }
+    }
+
+    /// The token from an `Authorization: Bearer <TOKEN>` header
+    fn bearer_token(&self) -> Option<&str> {
+        let (scheme, token) = self.authorization.as_deref()?.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    }
+
+    /// The starting position requested by a `Range` header.  Only open-ended
+    /// ranges (`bytes=N-`) and suffixes (`bytes=-N`) make sense for a file
+    /// which is still growing.
+    fn range_start(&self) -> Option<isize> {
+        let range = self.range.as_deref()?.strip_prefix("bytes=")?.trim();
+        if let Some(suffix) = range.strip_prefix('-') {
+            suffix.parse::<isize>().ok().map(|n| -n)
+        } else {
+            range.strip_suffix('-')?.parse().ok()
+        }
+    }
+}
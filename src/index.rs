@@ -0,0 +1,729 @@
+//! An index of where the lines (and `seqnum` records) start, so that `line
+//! <N>` and `seqnum <N>` don't have to read through the whole file every
+//! time.
+//!
+//! We note where every Nth entry starts, where N (the stride) starts off at
+//! 1024.  To find an entry we jump to the nearest noted one before it and
+//! read forward from there.  The number of noted entries is capped, so that
+//! the index doesn't grow without bound along with the file: when it's full,
+//! we double the stride and forget every other entry.
+//!
+//! The index is built as it's needed: each lookup extends it as far as the
+//! entry it was looking for.  Once it exists, it's also extended in the
+//! background as the file grows, so that lookups near the end of the file
+//! don't have to wait for the new data to be read.
+//!
+//! With `--persist-index` the index is also kept in `PATH.tsidx`, so that it
+//! survives restarts.  The sidecar remembers which file it belongs to (by
+//! device and inode number) and a checksum of the start of the file; if
+//! either has changed, or the file is now shorter than what was indexed, the
+//! file must have been replaced or truncated and we start again.
+
+use crate::command::crc32;
+use crate::source::{Reader, Source, SOURCE};
+use crate::{Result, FILE_LENGTH};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// The stride we start off with
+const MIN_STRIDE: u64 = 1024;
+/// The most entries we note in each index.  That's 8 MiB of offsets.
+const MAX_CHECKPOINTS: usize = 1 << 20;
+/// With --persist-index, how often we save the index if it's changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How much of the start of the file we checksum, to notice if it's been
+/// replaced
+const PREFIX_LEN: usize = 4096;
+/// A u64 takes at most ten bytes as a varint
+const MAX_VARINT_LEN: usize = 10;
+/// The first bytes of a sidecar file
+const MAGIC: &[u8; 8] = b"TSIDX\0\0\0";
+/// Bump this whenever the format of the sidecar changes
+const VERSION: u32 = 2;
+
+/// What the entries are
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Lines, each ending with this byte (normally a newline)
+    Lines(u8),
+    /// Records, each prefixed with its length as an unsigned LEB128 varint
+    Records,
+}
+
+impl Kind {
+    /// Lines are numbered by their delimiter, so there's room for all of them
+    fn to_u32(self) -> u32 {
+        match self {
+            Kind::Lines(delim) => u32::from(delim),
+            Kind::Records => 256,
+        }
+    }
+
+    fn from_u32(x: u32) -> Option<Kind> {
+        match x {
+            0..=255 => Some(Kind::Lines(x as u8)),
+            256 => Some(Kind::Records),
+            _ => None,
+        }
+    }
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    indexes: Vec::new(),
+    sidecar: None,
+    dirty: false,
+});
+/// Set when the file grows, and cleared by the updater
+static GREW: Mutex<bool> = Mutex::new(false);
+static GREW_CV: Condvar = Condvar::new();
+
+struct State {
+    /// At most one of each kind
+    indexes: Vec<Index>,
+    /// Where to save the indexes.  Set if --persist-index was given.
+    sidecar: Option<PathBuf>,
+    /// Whether the indexes have changed since they were last saved
+    dirty: bool,
+}
+
+impl State {
+    fn get_mut(&mut self, kind: Kind) -> &mut Index {
+        match self.indexes.iter().position(|x| x.kind == kind) {
+            Some(i) => &mut self.indexes[i],
+            None => {
+                self.indexes.push(Index::new(kind));
+                self.indexes.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+struct Index {
+    kind: Kind,
+    /// We note the start of every `stride`th entry.  Always a power of two.
+    stride: u64,
+    /// `checkpoints[i]` is where entry `i * stride` starts
+    checkpoints: Vec<u64>,
+    /// How many complete entries we've seen
+    count: u64,
+    /// Where the entry after the last complete one starts.  This is as far
+    /// as we've indexed.
+    next_start: u64,
+    /// The length and CRC-32 of the start of the file, as it was when we
+    /// started indexing it
+    prefix_len: u64,
+    prefix_crc: u32,
+}
+
+impl Index {
+    fn new(kind: Kind) -> Index {
+        Index {
+            kind,
+            stride: MIN_STRIDE,
+            checkpoints: vec![0],
+            count: 0,
+            next_start: 0,
+            prefix_len: 0,
+            prefix_crc: 0,
+        }
+    }
+
+    /// Whether the start of the file is the same as when we indexed it, and
+    /// it's at least as long as it was
+    fn still_valid(&self, source: &dyn Source, file_len: usize) -> Result<bool> {
+        if (file_len as u64) < self.next_start.max(self.prefix_len) {
+            return Ok(false);
+        }
+        Ok(prefix_crc(source, self.prefix_len as usize)? == self.prefix_crc)
+    }
+
+    /// Extend the index until it covers entry `n`, or the end of the file.
+    /// Returns whether anything changed.
+    fn update(&mut self, source: &dyn Source, n: u64, file_len: usize) -> Result<bool> {
+        if self.prefix_len > 0 && !self.still_valid(source, file_len)? {
+            info!(kind = ?self.kind, "The file has been replaced or truncated; re-indexing");
+            *self = Index::new(self.kind);
+        }
+        if self.prefix_len < PREFIX_LEN as u64 && (file_len as u64) > self.prefix_len {
+            self.prefix_len = file_len.min(PREFIX_LEN) as u64;
+            self.prefix_crc = prefix_crc(source, self.prefix_len as usize)?;
+        }
+        let before = self.count;
+        let mut count = self.count;
+        let mut stride = self.stride;
+        let checkpoints = &mut self.checkpoints;
+        let next_start = step(
+            source,
+            self.kind,
+            self.next_start as usize,
+            n.saturating_sub(self.count),
+            file_len,
+            |start| {
+                count += 1;
+                if count % stride == 0 {
+                    checkpoints.push(start as u64);
+                    if checkpoints.len() > MAX_CHECKPOINTS {
+                        // Keep the ones which are multiples of the new stride
+                        let mut i = 0;
+                        checkpoints.retain(|_| {
+                            i += 1;
+                            i % 2 == 1
+                        });
+                        stride *= 2;
+                    }
+                }
+            },
+        )?;
+        if stride != self.stride {
+            debug!(kind = ?self.kind, stride, "The index is full; doubled the stride");
+        }
+        self.count = count;
+        self.stride = stride;
+        self.next_start = next_start as u64;
+        if count != before {
+            debug!(kind = ?self.kind, count, len = next_start, "Extended the index");
+        }
+        Ok(count != before)
+    }
+
+    /// Where entry `n` starts.  If the file doesn't have that many complete
+    /// entries yet, the start of the first incomplete one.
+    fn find(&self, source: &dyn Source, n: u64, file_len: usize) -> Result<usize> {
+        if n >= self.count {
+            return Ok(self.next_start as usize);
+        }
+        let i = n / self.stride;
+        let from = self.checkpoints[i as usize] as usize;
+        step(
+            source,
+            self.kind,
+            from,
+            n - i * self.stride,
+            file_len,
+            |_| (),
+        )
+    }
+}
+
+/// Where entry `n` starts.  If the file doesn't have that many complete
+/// entries yet, this is the start of the first incomplete one (or the end of
+/// the file).
+pub fn find(kind: Kind, source: &dyn Source, n: u64, file_len: usize) -> Result<usize> {
+    let mut state = STATE.lock().unwrap();
+    let index = state.get_mut(kind);
+    let changed = index.update(source, n, file_len)?;
+    let offset = index.find(source, n, file_len)?;
+    state.dirty |= changed;
+    Ok(offset)
+}
+
+/// Like `find`, but also returns the number of the entry which starts there.
+/// This is less than `n` if the file doesn't have that many entries yet.
+pub fn find_numbered(
+    kind: Kind,
+    source: &dyn Source,
+    n: u64,
+    file_len: usize,
+) -> Result<(usize, u64)> {
+    let mut state = STATE.lock().unwrap();
+    let index = state.get_mut(kind);
+    let changed = index.update(source, n, file_len)?;
+    let offset = index.find(source, n, file_len)?;
+    let number = n.min(index.count);
+    state.dirty |= changed;
+    Ok((offset, number))
+}
+
+/// How many entries have been indexed so far, if anyone has asked for this
+/// kind of index
+pub fn count(kind: Kind) -> Option<u64> {
+    let state = STATE.lock().unwrap();
+    state
+        .indexes
+        .iter()
+        .find(|x| x.kind == kind)
+        .map(|x| x.count)
+}
+
+/// Whether the indexes are being saved (ie. --persist-index was given)
+pub fn persisted() -> bool {
+    STATE.lock().unwrap().sidecar.is_some()
+}
+
+/// Let the updater know that the file has grown
+pub fn file_grew() {
+    *GREW.lock().unwrap() = true;
+    GREW_CV.notify_one();
+}
+
+/// Start extending the indexes in the background as the file grows, and
+/// saving them if --persist-index was given.  Only the indexes which have
+/// been used are kept up to date.  The source must have been set already.
+pub fn spawn_updater() {
+    std::thread::spawn(|| {
+        let mut last_saved = Instant::now();
+        loop {
+            let guard = GREW.lock().unwrap();
+            let (mut grew, _) = GREW_CV
+                .wait_timeout_while(guard, SAVE_INTERVAL, |grew| !*grew)
+                .unwrap();
+            *grew = false;
+            drop(grew);
+            let source = &**SOURCE.get().unwrap();
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            let mut state = STATE.lock().unwrap();
+            let State { indexes, dirty, .. } = &mut *state;
+            for index in indexes {
+                match index.update(source, u64::MAX, file_len) {
+                    Ok(changed) => *dirty |= changed,
+                    Err(e) => warn!(kind = ?index.kind, "Couldn't update the index: {e}"),
+                }
+            }
+            if state.dirty && last_saved.elapsed() >= SAVE_INTERVAL {
+                if let Some(path) = &state.sidecar {
+                    if let Err(e) = save(path, &state, source) {
+                        warn!(path = %path.display(), "Couldn't save the index: {e}");
+                    }
+                }
+                state.dirty = false;
+                last_saved = Instant::now();
+            }
+        }
+    });
+}
+
+/// Bring the sidecar for the file at `path` up to date, for `tailsrv index`.
+/// Returns how many complete entries the file has.
+pub fn build(path: &Path, kind: Kind) -> Result<u64> {
+    let file = File::open(path)?;
+    let sidecar = sidecar_path(path);
+    load(&sidecar, &file)?;
+    let mut state = STATE.lock().unwrap();
+    let index = state.get_mut(kind);
+    index.update(&file, u64::MAX, Source::len(&file)?)?;
+    let count = index.count;
+    save(&sidecar, &state, &file)?;
+    Ok(count)
+}
+
+/// The name of the sidecar file for `path`
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut x = path.as_os_str().to_owned();
+    x.push(".tsidx");
+    PathBuf::from(x)
+}
+
+/// Load the indexes saved in the sidecar, if they're still good, and keep
+/// them up to date from now on.  The sidecar doesn't have to exist yet.
+pub fn load(path: &Path, source: &dyn Source) -> Result<()> {
+    let mut state = STATE.lock().unwrap();
+    state.sidecar = Some(path.to_owned());
+    let buf = match std::fs::read(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+    let file_len = source.len()?;
+    match decode(&buf, identity(source)) {
+        Ok(indexes) => {
+            for index in indexes {
+                if !index.still_valid(source, file_len)? {
+                    info!(kind = ?index.kind, "The file has changed since it was indexed");
+                    continue;
+                }
+                info!(kind = ?index.kind, count = index.count, "Loaded the index");
+                *state.get_mut(index.kind) = index;
+            }
+        }
+        Err(e) => info!(path = %path.display(), "Not using the saved index: {e}"),
+    }
+    Ok(())
+}
+
+/// Write out all the indexes
+fn save(path: &Path, state: &State, source: &dyn Source) -> Result<()> {
+    let buf = encode(&state.indexes, identity(source));
+    // Write it somewhere else first, so that a crash can't leave it half
+    // written
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, buf)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The device and inode number of the source, if it's a file
+fn identity(source: &dyn Source) -> (u64, u64) {
+    match source.as_file().and_then(|x| x.metadata().ok()) {
+        Some(meta) => (meta.dev(), meta.ino()),
+        None => (0, 0),
+    }
+}
+
+fn prefix_crc(source: &dyn Source, len: usize) -> std::io::Result<u32> {
+    let mut buf = vec![0; len];
+    let mut got = 0;
+    while got < len {
+        match source.read_at(&mut buf[got..], got)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => got += n,
+        }
+    }
+    Ok(crc32(0, &buf))
+}
+
+/// The sidecar is a header followed by each index, with everything stored
+/// little-endian:
+///
+/// ```text
+/// magic (8 bytes)  version (u32)  dev (u64)  ino (u64)  n_indexes (u32)
+/// for each index:
+///     kind (u32)  stride (u64)  count (u64)  next_start (u64)
+///     prefix_len (u64)  prefix_crc (u32)  n_checkpoints (u64)
+///     checkpoints (u64 each)
+/// ```
+///
+/// For lines, the kind is the delimiter; for records it's 256.
+fn encode(indexes: &[Index], (dev, ino): (u64, u64)) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&dev.to_le_bytes());
+    buf.extend_from_slice(&ino.to_le_bytes());
+    buf.extend_from_slice(&(indexes.len() as u32).to_le_bytes());
+    for index in indexes {
+        buf.extend_from_slice(&index.kind.to_u32().to_le_bytes());
+        for x in [
+            index.stride,
+            index.count,
+            index.next_start,
+            index.prefix_len,
+        ] {
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+        buf.extend_from_slice(&index.prefix_crc.to_le_bytes());
+        buf.extend_from_slice(&(index.checkpoints.len() as u64).to_le_bytes());
+        for x in &index.checkpoints {
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+    buf
+}
+
+fn decode(mut buf: &[u8], identity: (u64, u64)) -> Result<Vec<Index>> {
+    let magic: [u8; 8] = take(&mut buf)?;
+    if &magic != MAGIC {
+        return Err("Not an index file".into());
+    }
+    let version = u32::from_le_bytes(take(&mut buf)?);
+    if version != VERSION {
+        return Err(format!("Unsupported version {version}").into());
+    }
+    let dev = u64::from_le_bytes(take(&mut buf)?);
+    let ino = u64::from_le_bytes(take(&mut buf)?);
+    if (dev, ino) != identity {
+        return Err("It belongs to a different file".into());
+    }
+    let n_indexes = u32::from_le_bytes(take(&mut buf)?);
+    let mut indexes = Vec::new();
+    for _ in 0..n_indexes {
+        let kind = u32::from_le_bytes(take(&mut buf)?);
+        let kind = Kind::from_u32(kind).ok_or("Unknown kind of index")?;
+        let stride = u64::from_le_bytes(take(&mut buf)?);
+        if stride < MIN_STRIDE || !stride.is_power_of_two() {
+            return Err(format!("Unsupported stride {stride}").into());
+        }
+        let count = u64::from_le_bytes(take(&mut buf)?);
+        let next_start = u64::from_le_bytes(take(&mut buf)?);
+        let prefix_len = u64::from_le_bytes(take(&mut buf)?);
+        let prefix_crc = u32::from_le_bytes(take(&mut buf)?);
+        let n_checkpoints = u64::from_le_bytes(take(&mut buf)?);
+        if n_checkpoints != count / stride + 1 {
+            return Err("Wrong number of checkpoints".into());
+        }
+        let mut checkpoints = Vec::new();
+        for _ in 0..n_checkpoints {
+            checkpoints.push(u64::from_le_bytes(take(&mut buf)?));
+        }
+        indexes.push(Index {
+            kind,
+            stride,
+            checkpoints,
+            count,
+            next_start,
+            prefix_len,
+            prefix_crc,
+        });
+    }
+    Ok(indexes)
+}
+
+fn take<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N]> {
+    let Some((x, rest)) = buf.split_first_chunk::<N>() else {
+        return Err("Truncated".into());
+    };
+    *buf = rest;
+    Ok(*x)
+}
+
+/// Step over up to `n` complete entries, starting from `pos` (which must be
+/// the start of one).  `f` is called with the start of each entry we reach.
+/// Returns where we stopped: the start of the nth entry, or of the first
+/// incomplete one.
+fn step(
+    source: &dyn Source,
+    kind: Kind,
+    mut pos: usize,
+    n: u64,
+    file_len: usize,
+    mut f: impl FnMut(usize),
+) -> Result<usize> {
+    let mut stepped = 0;
+    match kind {
+        Kind::Lines(delim) => {
+            let file = Reader {
+                source,
+                pos,
+                end: file_len,
+            };
+            let mut rdr = BufReader::with_capacity(1 << 16, file);
+            let mut scanned = pos;
+            while stepped < n {
+                let buf = rdr.fill_buf()?;
+                if buf.is_empty() {
+                    break;
+                }
+                let mut used = buf.len();
+                for (i, _) in buf.iter().enumerate().filter(|(_, &b)| b == delim) {
+                    stepped += 1;
+                    pos = scanned + i + 1;
+                    f(pos);
+                    if stepped == n {
+                        used = i + 1;
+                        break;
+                    }
+                }
+                scanned += used;
+                rdr.consume(used);
+            }
+        }
+        Kind::Records => {
+            while stepped < n {
+                let mut buf = [0; MAX_VARINT_LEN];
+                let avail = buf.len().min(file_len - pos);
+                let got = source.read_at(&mut buf[..avail], pos)?;
+                let Some((len, prefix_len)) = decode_varint(&buf[..got])? else {
+                    break;
+                };
+                let end = usize::try_from(len)
+                    .ok()
+                    .and_then(|len| (pos + prefix_len).checked_add(len));
+                match end {
+                    Some(end) if end <= file_len => pos = end,
+                    _ => break,
+                }
+                stepped += 1;
+                f(pos);
+            }
+        }
+    }
+    Ok(pos)
+}
+
+/// Decode an unsigned LEB128 varint from the start of `buf`.  Returns the
+/// value and how many bytes it took up, or `None` if it's incomplete.
+fn decode_varint(buf: &[u8]) -> Result<Option<(u64, usize)>> {
+    let mut x = 0;
+    for (i, &b) in buf.iter().enumerate() {
+        x |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some((x, i + 1)));
+        }
+    }
+    if buf.len() == MAX_VARINT_LEN {
+        return Err("Bad record length: varint is too long".into());
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINES: Kind = Kind::Lines(b'\n');
+
+    /// `n` lines, each saying which one it is
+    fn lines(n: usize) -> Vec<u8> {
+        (0..n).flat_map(|i| format!("{i}\n").into_bytes()).collect()
+    }
+
+    /// Where line `n` starts, found the slow way
+    fn line_start(file: &[u8], n: u64) -> usize {
+        let lines = file.split_inclusive(|&b| b == b'\n');
+        lines.take(n as usize).map(|x| x.len()).sum()
+    }
+
+    /// Look up line `n` the way `find` does
+    fn find_line(index: &mut Index, file: &dyn Source, n: u64) -> usize {
+        let len = file.len().unwrap();
+        index.update(file, n, len).unwrap();
+        index.find(file, n, len).unwrap()
+    }
+
+    #[test]
+    fn find_lines() {
+        let file = lines(5000);
+        let mut index = Index::new(LINES);
+        // Each lookup extends the index a bit further
+        let ns = [0, 1, 1023, 1024, 1025, 3000, 4999];
+        for n in ns {
+            assert_eq!(
+                find_line(&mut index, &file, n),
+                line_start(&file, n),
+                "line {n}"
+            );
+        }
+        assert_eq!(index.count, 4999);
+        let every_1024th = [0, 1, 2, 3, 4].map(|i| line_start(&file, i * 1024) as u64);
+        assert_eq!(index.checkpoints, every_1024th);
+        // Now they're all in the index
+        for n in ns {
+            assert_eq!(
+                index.find(&file, n, file.len()).unwrap(),
+                line_start(&file, n)
+            );
+        }
+        // Past the end, we get the end of the file
+        assert_eq!(find_line(&mut index, &file, 6000), file.len());
+        assert_eq!(index.count, 5000);
+    }
+
+    #[test]
+    fn incomplete_lines() {
+        let mut file = lines(10);
+        let complete = file.len();
+        file.extend_from_slice(b"10");
+        let mut index = Index::new(LINES);
+        assert_eq!(find_line(&mut index, &file, 10), complete);
+        assert_eq!(find_line(&mut index, &file, 11), complete);
+        assert_eq!(index.count, 10);
+        // The line is finished, and another one started
+        file.extend_from_slice(b"\n11\n1");
+        assert_eq!(find_line(&mut index, &file, 11), complete + 3);
+        assert_eq!(find_line(&mut index, &file, 12), complete + 6);
+        assert_eq!(index.count, 12);
+    }
+
+    #[test]
+    fn growing_file() {
+        let file = lines(3000);
+        let mut index = Index::new(LINES);
+        let half = line_start(&file, 1500) + 2;
+        index.update(&file, 2000, half).unwrap();
+        assert_eq!(index.count, 1500);
+        assert_eq!(
+            index.find(&file, 2000, half).unwrap(),
+            line_start(&file, 1500)
+        );
+        assert_eq!(find_line(&mut index, &file, 2000), line_start(&file, 2000));
+    }
+
+    #[test]
+    fn replaced_file() {
+        let old = lines(2000);
+        let mut index = Index::new(LINES);
+        find_line(&mut index, &old, 2000);
+        // A different file of the same length
+        let new: Vec<u8> = old
+            .iter()
+            .map(|&b| if b == b'1' { b'\n' } else { b })
+            .collect();
+        assert_eq!(find_line(&mut index, &new, 5), line_start(&new, 5));
+        assert_eq!(find_line(&mut index, &new, 1500), line_start(&new, 1500));
+        // A shorter one
+        let short = lines(100);
+        assert_eq!(find_line(&mut index, &short, 50), line_start(&short, 50));
+        assert_eq!(find_line(&mut index, &short, 1500), short.len());
+    }
+
+    #[test]
+    fn find_records() {
+        let mut file = Vec::new();
+        let mut starts = Vec::new();
+        for len in [0, 1, 127, 128, 300, 5] {
+            starts.push(file.len());
+            let mut x = len;
+            while x >= 0x80 {
+                file.push(x as u8 | 0x80);
+                x >>= 7;
+            }
+            file.push(x as u8);
+            file.resize(file.len() + len, b'x');
+        }
+        starts.push(file.len());
+        let mut index = Index::new(Kind::Records);
+        for (n, &start) in starts.iter().enumerate() {
+            index.update(&file, n as u64, file.len()).unwrap();
+            assert_eq!(
+                index.find(&file, n as u64, file.len()).unwrap(),
+                start,
+                "record {n}"
+            );
+        }
+        // An incomplete record isn't counted
+        let cut = file[..file.len() - 1].to_vec();
+        let mut index = Index::new(Kind::Records);
+        index.update(&cut, 10, cut.len()).unwrap();
+        assert_eq!(index.count, 5);
+        assert_eq!(index.find(&cut, 10, cut.len()).unwrap(), starts[5]);
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(decode_varint(&[0x00]).unwrap(), Some((0, 1)));
+        assert_eq!(decode_varint(&[0x7f, 0xff]).unwrap(), Some((127, 1)));
+        assert_eq!(decode_varint(&[0x80, 0x01]).unwrap(), Some((128, 2)));
+        assert_eq!(decode_varint(&[0xac, 0x02]).unwrap(), Some((300, 2)));
+        assert_eq!(decode_varint(&[]).unwrap(), None);
+        assert_eq!(decode_varint(&[0x80]).unwrap(), None);
+        assert!(decode_varint(&[0x80; MAX_VARINT_LEN]).is_err());
+    }
+
+    #[test]
+    fn sidecar() {
+        let file = lines(3000);
+        let mut index = Index::new(LINES);
+        find_line(&mut index, &file, 2500);
+        let buf = encode(std::slice::from_ref(&index), (1, 2));
+        let decoded = decode(&buf, (1, 2)).unwrap();
+        let [copy] = &decoded[..] else {
+            panic!("{} indexes", decoded.len())
+        };
+        assert_eq!(copy.kind, index.kind);
+        assert_eq!(copy.stride, index.stride);
+        assert_eq!(copy.checkpoints, index.checkpoints);
+        assert_eq!(copy.count, index.count);
+        assert_eq!(copy.next_start, index.next_start);
+        assert_eq!(
+            (copy.prefix_len, copy.prefix_crc),
+            (index.prefix_len, index.prefix_crc)
+        );
+        assert_eq!(
+            copy.find(&file, 2100, file.len()).unwrap(),
+            line_start(&file, 2100)
+        );
+        // It belongs to a different file
+        assert!(decode(&buf, (1, 3)).is_err());
+        assert!(decode(&buf[..buf.len() - 1], (1, 2)).is_err());
+        assert!(decode(b"TSIDX\0\0\x01", (1, 2)).is_err());
+        let mut old = buf.clone();
+        old[8..12].copy_from_slice(&1u32.to_le_bytes());
+        assert!(decode(&old, (1, 2)).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Support for `--inetd`, where tailsrv serves a single client over stdin and
+//! stdout, and exits when it's done.  This is for running tailsrv under
+//! inetd, or as a systemd service with `Accept=yes`, or over ssh.
+//!
+//! Under inetd stdin and stdout are the same socket, but over ssh they're two
+//! pipes; so we read the header from one and send the data to the other.
+
+use crate::{spawn_client, spawn_idle_watcher, Opts, NEXT_CLIENT_ID};
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::io::{Read, Write};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::*;
+
+/// Stdin and stdout, treated as a single connection
+struct Stdio {
+    stdout: OwnedFd,
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(rustix::io::write(&self.stdout, buf)?)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The header is read from stdin, so that's what we wait on
+impl AsFd for Stdio {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        std::io::stdin().as_fd()
+    }
+}
+
+impl From<Stdio> for OwnedFd {
+    fn from(x: Stdio) -> OwnedFd {
+        x.stdout
+    }
+}
+
+/// Start serving the client on stdin/stdout.  Once it's gone, we exit.
+pub fn spawn(opts: &'static Opts) -> std::io::Result<()> {
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    info!(client_id, "Serving a single client over stdin/stdout");
+    spawn_client(Stdio { stdout }, client_id, opts);
+    spawn_idle_watcher(Duration::ZERO);
+    Ok(())
+}
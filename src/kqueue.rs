@@ -0,0 +1,153 @@
+//! The kqueue backend, for macOS and FreeBSD.
+//!
+//! We use an EVFILT_VNODE filter on the file in place of inotify, an
+//! EVFILT_USER event in place of the eventfd, and BSD sendfile() to move the
+//! data.
+
+use crate::ratelimit::{TokenBucket, THROTTLE_TICK};
+use crate::sendfile::{send_data, Progress};
+use crate::source::Source;
+use crate::{
+    handle_file_event, handle_messages, take_inbox, ClientId, Clients, FileEvent, Opts, Result,
+    Scheduler,
+};
+use rustix::event::kqueue::{
+    kevent, kqueue, Event, EventFilter, EventFlags, UserDefinedFlags, UserFlags, VnodeEvents,
+};
+use rustix::fd::{AsRawFd, OwnedFd};
+use rustix::io::Errno;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{info, trace};
+
+/// The listener threads trigger an event on this to notify the runloop of
+/// new clients
+static KQUEUE: LazyLock<OwnedFd> = LazyLock::new(|| kqueue().unwrap());
+
+const NEW_CLIENT: isize = 0;
+const FILE: isize = 1;
+const CLIENT_FROM: isize = 100_000;
+
+fn new_client_event(flags: EventFlags, trigger: bool) -> Event {
+    let filter = EventFilter::User {
+        ident: NEW_CLIENT,
+        flags: if trigger {
+            UserFlags::TRIGGER
+        } else {
+            UserFlags::empty()
+        },
+        user_flags: UserDefinedFlags::new(0),
+    };
+    Event::new(filter, flags, NEW_CLIENT)
+}
+
+/// Wake up the runloop
+pub fn wake() -> Result<()> {
+    let ev = new_client_event(EventFlags::empty(), true);
+    unsafe { kevent(&*KQUEUE, &[ev], &mut Vec::new(), None)? };
+    Ok(())
+}
+
+pub fn run(source: &dyn Source, opts: &Opts) -> Result<()> {
+    let new_clients = new_client_event(EventFlags::ADD | EventFlags::CLEAR, false);
+    unsafe { kevent(&*KQUEUE, &[new_clients], &mut Vec::new(), None)? };
+    // Sources other than files tell us themselves when they grow
+    if let Some(file) = source.as_file() {
+        let watch_file = Event::new(
+            EventFilter::Vnode {
+                vnode: file.as_raw_fd(),
+                flags: VnodeEvents::DELETE
+                    | VnodeEvents::WRITE
+                    | VnodeEvents::EXTEND
+                    | VnodeEvents::ATTRIBUTES
+                    | VnodeEvents::RENAME,
+            },
+            EventFlags::ADD | EventFlags::CLEAR,
+            FILE,
+        );
+        unsafe { kevent(&*KQUEUE, &[watch_file], &mut Vec::new(), None)? };
+        info!("Watching the file for changes");
+    }
+
+    info!("Starting runloop");
+    // Only the io_uring backend can have more than one shard
+    let inbox = take_inbox(0);
+    let mut clients = Clients::new();
+    // The clients whose sockets have been registered with kqueue
+    let mut registered = HashSet::new();
+    let mut total_rate = opts.max_total_rate.map(TokenBucket::new);
+    let mut sched = Scheduler::new(opts);
+    let mut events = Vec::with_capacity(1024);
+    loop {
+        handle_messages(&inbox, &mut clients);
+        let progress = send_data(
+            source,
+            &mut clients,
+            &mut registered,
+            &mut total_rate,
+            &mut sched,
+            |client_id, client| {
+                // EV_CLEAR makes it edge-triggered, so we only hear about it
+                // when the socket _becomes_ writable
+                let ev = Event::new(
+                    EventFilter::Write(client.conn.as_raw_fd()),
+                    EventFlags::ADD | EventFlags::CLEAR,
+                    CLIENT_FROM + isize::try_from(client_id)?,
+                );
+                unsafe { kevent(&*KQUEUE, &[ev], &mut Vec::new(), None)? };
+                Ok(())
+            },
+        )?;
+        let timeout = match progress {
+            Progress::Idle => None,
+            Progress::Throttled => Some(THROTTLE_TICK),
+            Progress::MoreToSend => Some(Duration::ZERO),
+        };
+        trace!("Waiting for wake-ups");
+        match unsafe { kevent(&*KQUEUE, &[], &mut events, timeout) } {
+            Ok(_) => (),
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        trace!("Woke up!");
+        for ev in &events {
+            match ev.udata() {
+                NEW_CLIENT => trace!("Received notification of new clients"),
+                FILE => {
+                    let (EventFilter::Vnode { flags, .. }, Some(file)) =
+                        (ev.filter(), source.as_file())
+                    else {
+                        continue;
+                    };
+                    trace!("vnode event: {:?}", flags);
+                    let ev = FileEvent {
+                        moved: flags.contains(VnodeEvents::RENAME),
+                        // We check the link count, so DELETE is treated like
+                        // any other attribute change
+                        attrib: flags.intersects(VnodeEvents::ATTRIBUTES | VnodeEvents::DELETE),
+                        modified: flags.intersects(VnodeEvents::WRITE | VnodeEvents::EXTEND),
+                    };
+                    handle_file_event(ev, file, opts)?;
+                }
+                x => {
+                    let client_id = ClientId::try_from(x - CLIENT_FROM)?;
+                    if ev.flags().contains(EventFlags::EOF) {
+                        // The peer has gone away, even if it's idle
+                        info!(client_id, "Client hung up");
+                        // Closing the socket removes it from the kqueue
+                        if let Some(client) = clients.remove(&client_id) {
+                            client.disconnect("hung up");
+                        }
+                        registered.remove(&client_id);
+                    } else {
+                        trace!(client_id, "Socket is writable");
+                        if let Some(client) = clients.get_mut(&client_id) {
+                            client.in_flight = false;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,3131 @@
+//! tailsrv as a library, for programs which want to serve a file of their
+//! own (eg. a journal) without running a separate process.  See
+//! [`TailServer`].  The `tailsrv` binary is a thin wrapper around this.
+
+mod access_log;
+#[cfg(target_os = "linux")]
+mod backfill;
+mod cidr;
+mod command;
+mod config;
+mod cursor;
+#[cfg(target_os = "linux")]
+mod direct;
+mod doctor;
+mod encode;
+#[cfg(target_os = "linux")]
+mod epoll;
+mod exit;
+#[cfg(target_os = "linux")]
+mod fadvise;
+mod feed;
+#[cfg(target_os = "linux")]
+mod glob;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod header;
+mod http;
+mod index;
+mod inetd;
+#[cfg(not(target_os = "linux"))]
+mod kqueue;
+mod marker;
+#[cfg(target_os = "linux")]
+mod nursery;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(target_os = "linux")]
+mod pipe_pool;
+mod privs;
+#[cfg(target_os = "linux")]
+mod probe;
+mod ratelimit;
+mod relay;
+#[cfg(target_os = "linux")]
+mod reopen;
+mod ring;
+mod seek;
+mod sendfile;
+mod source;
+mod spool;
+mod state;
+#[cfg(target_os = "linux")]
+mod upgrade;
+#[cfg(target_os = "linux")]
+mod uring;
+
+use crate::access_log::Session;
+use crate::cidr::Cidr;
+use crate::encode::{Encoder, Filter};
+use crate::feed::Feed;
+use crate::header::{BeyondEof, Class, Compression, Header};
+use crate::ratelimit::TokenBucket;
+use crate::ring::RingBuffer;
+use crate::source::SOURCE;
+use bpaf::{Bpaf, Parser};
+#[cfg(target_os = "linux")]
+use rustix::event::EventfdFlags;
+use rustix::fd::AsFd;
+#[cfg(target_os = "linux")]
+use rustix::fd::AsRawFd;
+use rustix::fd::OwnedFd;
+#[cfg(target_os = "linux")]
+use rustix::fs::inotify;
+#[cfg(target_os = "linux")]
+use rustix::io::Errno;
+use rustix::net::{SendFlags, SocketAddrAny};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+#[cfg(target_os = "linux")]
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::*;
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+pub use crate::source::{Growth, Source};
+
+/// tailsrv's settings.  These are normally read from the command line (see
+/// [`Opts::from_args`]); embedders can use [`TailServer::builder`] instead.
+#[derive(Bpaf, Default)]
+pub struct Opts {
+    /// Read options from this TOML file.  Options given on the command line
+    /// take precedence
+    #[bpaf(argument("PATH"))]
+    config: Option<PathBuf>,
+    /// The port number on which to listen for new connections
+    #[bpaf(long, short, argument("PORT"))]
+    port: Option<u16>,
+    /// The address on which to listen for TCP connections.  May be an IPv4
+    /// address, an IPv6 address, or a hostname.  [default: 0.0.0.0]
+    #[bpaf(argument("ADDR"))]
+    bind: Option<String>,
+    /// When binding to an IPv6 address, also accept IPv4 connections
+    dual_stack: bool,
+    /// Listen for new connections on a unix domain socket at this path
+    /// (instead of, or in addition to, the TCP port)
+    #[bpaf(argument("SOCKET"))]
+    unix_socket: Option<PathBuf>,
+    /// Also serve the file over HTTP on this port.  Clients can choose where
+    /// to start with a `Range: bytes=N-` header
+    #[bpaf(argument("PORT"))]
+    http_port: Option<u16>,
+    /// Also serve the file at PATH, to clients which connect to PORT.  The
+    /// file has to exist already.  Its clients can't use `line <N>`,
+    /// `seqnum <N>`, cursors, or commands.  May be given multiple times
+    /// (io_uring backend only)
+    #[bpaf(argument("PORT=PATH"), many)]
+    serve: Vec<feed::Binding>,
+    /// Also offer the stream over gRPC on this port
+    #[cfg(feature = "grpc")]
+    #[bpaf(argument("PORT"))]
+    grpc_port: Option<u16>,
+    /// What to do when the file is deleted: "exit" (the default), "linger"
+    /// (carry on serving it), "drain" (exit once every client has been sent
+    /// the whole file, and it has stopped growing), "reopen" (wait for a new
+    /// file at the same path, and serve that; see --reopen), or
+    /// "disconnect-clients" (carry on serving it, but disconnect everyone who
+    /// is connected).  With --reopen or --glob, the default is "reopen"
+    #[bpaf(argument("POLICY"))]
+    on_delete: Option<FilePolicy>,
+    /// What to do when the file is moved.  The policies are the same as for
+    /// --on-delete
+    #[bpaf(argument("POLICY"))]
+    on_move: Option<FilePolicy>,
+    /// What to do when the file shrinks to less than a client has been sent:
+    /// "disconnect-clients" (the default; disconnect those clients), "exit",
+    /// "linger" (send them whatever is written past where they got to), or
+    /// "reopen" (send them the file again from the start)
+    #[bpaf(argument("POLICY"))]
+    on_truncate: Option<FilePolicy>,
+    /// Deprecated; the same as --on-delete linger --on-move linger
+    #[bpaf(hide)]
+    linger_after_file_is_gone: bool,
+    /// Deprecated; the same as --on-delete drain --on-move drain
+    #[bpaf(hide)]
+    drain_after_file_is_gone: bool,
+    #[bpaf(external(exit_on_idle))]
+    exit_on_idle: Option<u64>,
+    #[bpaf(external(reopen))]
+    reopen: Option<ReopenPolicy>,
+    /// Serve the newest file matching this pattern, instead of PATH, and
+    /// switch to newer files as they appear (Linux only).  The newest file is
+    /// the one whose name sorts last.  Only the file name may contain
+    /// wildcards (`*` and `?`)
+    #[bpaf(argument("PATTERN"))]
+    glob: Option<String>,
+    /// Keep clients' named cursors in this file (see `cursor` in the README)
+    #[bpaf(argument("PATH"))]
+    cursor_file: Option<PathBuf>,
+    /// When asked to quit (with SIGTERM or SIGINT), write the connected
+    /// clients to this file.  On the next start, clients with a named cursor
+    /// which hasn't been committed carry on from where they'd got to
+    #[bpaf(argument("PATH"))]
+    state_file: Option<PathBuf>,
+    /// Treat rotated copies of PATH (`PATH.1`, `PATH.2.gz`, and so on) as
+    /// earlier parts of it, so that clients can be sent the whole history
+    /// (Linux only)
+    backfill: bool,
+    /// Serve PATH even if it isn't a regular file, as long as it can be read
+    /// at any offset: block devices (eg. a disk image as it's being
+    /// written), and other seekable devices
+    allow_special: bool,
+    /// Open the file with O_NOATIME, so that reading it doesn't update its
+    /// access time.  Only works on files which tailsrv's user owns (Linux
+    /// only)
+    noatime: bool,
+    /// Read the file with O_DIRECT, bypassing the page cache.  For huge
+    /// archives which would otherwise push everything else out of the cache.
+    /// The data has to be copied through userspace, so this uses more CPU,
+    /// and implies --backend epoll (Linux only)
+    direct: bool,
+    /// Serve a single client over stdin and stdout, and exit when it goes
+    /// away.  For running under inetd, or over ssh
+    inetd: bool,
+    /// Take over the listening sockets of the tailsrv which is listening on
+    /// this unix socket, and listen on it ourselves for the next upgrade.  The
+    /// old process carries on serving its clients, and exits once they've all
+    /// gone (Linux only)
+    #[bpaf(argument("SOCKET"))]
+    upgrade_socket: Option<PathBuf>,
+    /// Limit the rate at which data is sent to each client.  Accepts a
+    /// suffix of K, M, or G
+    #[bpaf(argument::<String>("BYTES/S"), parse(parse_size), optional)]
+    max_rate_per_client: Option<u64>,
+    /// Limit the rate at which data is sent to all clients combined.  Accepts
+    /// a suffix of K, M, or G
+    #[bpaf(argument::<String>("BYTES/S"), parse(parse_size), optional)]
+    max_total_rate: Option<u64>,
+    /// The most data a client may be sent in one pass of the runloop.  The
+    /// clients take turns, so a smaller value stops a client with a big
+    /// backlog from holding up the ones which are keeping up.  Accepts a
+    /// suffix of K or M.  [default: 1M]
+    #[bpaf(argument::<String>("BYTES"), parse(parse_size), optional)]
+    chunk_size: Option<u64>,
+    /// Let the kernel drop the parts of the file which every client has
+    /// already been sent from the page cache, so that they don't push out
+    /// anything more useful.  The last 64M is always kept (Linux only)
+    drop_behind: bool,
+    /// The maximum number of clients which may be connected at once.  Any
+    /// more will be sent an error and disconnected
+    #[bpaf(argument("N"))]
+    max_clients: Option<usize>,
+    /// Only accept TCP connections from this block of addresses (eg.
+    /// `10.0.0.0/8`).  May be given multiple times
+    #[bpaf(argument("CIDR"), many)]
+    allow: Vec<Cidr>,
+    /// Reject TCP connections from this block of addresses.  Takes
+    /// precedence over --allow.  May be given multiple times
+    #[bpaf(argument("CIDR"), many)]
+    deny: Vec<Cidr>,
+    /// Put clients from this block of addresses in the bulk class, so that
+    /// they only get what the realtime clients leave over, whatever their
+    /// header says.  May be given multiple times
+    #[bpaf(argument("CIDR"), many)]
+    bulk: Vec<Cidr>,
+    /// Send TCP keepalive probes to clients after this many seconds of
+    /// silence, so that clients which vanish without closing the connection
+    /// are eventually noticed
+    #[bpaf(argument("SECS"))]
+    tcp_keepalive: Option<u64>,
+    /// Send server-sent event clients an empty `heartbeat` event after this
+    /// many seconds without any data, so that they can tell a quiet file
+    /// from a dead server
+    #[bpaf(argument("SECS"))]
+    heartbeat: Option<u64>,
+    /// Set TCP_NODELAY on client connections, so that small appends are sent
+    /// straight away rather than being batched up
+    nodelay: bool,
+    /// Drop TCP connections when data sent to them goes unacknowledged for
+    /// this many seconds (Linux only)
+    #[bpaf(argument("SECS"))]
+    tcp_user_timeout: Option<u32>,
+    /// Require clients to authenticate with the token contained in this file
+    #[bpaf(long("auth-token-file"), argument::<PathBuf>("PATH"), parse(read_token), optional)]
+    auth_token: Option<String>,
+    /// Relay another tailsrv: follow it as a client, append what it sends to
+    /// PATH, and serve PATH as usual.  If PATH already exists, we carry on
+    /// from the end of it
+    #[bpaf(argument("HOST:PORT"))]
+    upstream: Option<String>,
+    /// Authenticate with the upstream using the token contained in this file
+    #[bpaf(
+        long("upstream-auth-token-file"),
+        argument::<PathBuf>("PATH"),
+        parse(read_token),
+        optional
+    )]
+    upstream_auth_token: Option<String>,
+    /// Serve data piped to stdin.  It's appended to the --spool file, which
+    /// is served like any other
+    stdin: bool,
+    /// The file to append stdin to, when using --stdin.  This takes the place
+    /// of PATH
+    #[bpaf(argument("PATH"))]
+    spool: Option<PathBuf>,
+    /// Instead of a --spool file, keep the data from --stdin (or a FIFO) in
+    /// memory.  Only the last SIZE bytes are kept.  Accepts a suffix of K, M,
+    /// or G
+    #[bpaf(argument::<String>("SIZE"), parse(parse_size), optional)]
+    memory_buffer: Option<u64>,
+    /// A regex for finding the timestamp in each line, for clients which ask
+    /// for `since <TIME>`.  If it has a capture group, the timestamp is the
+    /// first group; otherwise it's the whole match.  [default: the timestamp
+    /// is at the start of the line]
+    #[bpaf(argument::<String>("REGEX"), parse(parse_regex), optional)]
+    timestamp_regex: Option<regex::Regex>,
+    /// The format of the timestamps, in strftime syntax (eg. `%Y-%m-%d
+    /// %H:%M:%S`).  Timestamps without a timezone are taken to be UTC.
+    /// [default: RFC 3339]
+    #[bpaf(argument("FORMAT"))]
+    timestamp_format: Option<String>,
+    /// The byte which ends each line, for everything which works with lines
+    /// (`line <N>`, `grep`, and so on).  May be a character, an escape
+    /// (`\n`, `\t`, `\r` or `\0`), or a byte in hex (`0x1e`).  [default: \n]
+    #[bpaf(argument::<String>("BYTE"), parse(parse_delim), optional)]
+    delim: Option<u8>,
+    /// Only send the file up to the end of the last occurrence of BYTES, so
+    /// that clients never see a record which the writer is still in the
+    /// middle of appending.  Escapes (`\n`, `\t`, `\r`, `\0`, `\\` and
+    /// `\xHH`) may be used.
+    #[bpaf(argument::<String>("BYTES"), parse(parse_marker), optional)]
+    commit_marker: Option<Vec<u8>>,
+    /// Only let clients see what's been written to the file when the writer
+    /// closes it (IN_CLOSE_WRITE), rather than after every write.  For
+    /// writers which open the file, append a record, and close it again.
+    /// (Linux only)
+    flush_on_close_write: bool,
+    /// Look at the file's length at most N times a second, however often
+    /// it's written to.  After a burst of writes the length is always
+    /// checked again, so nothing is missed; clients just hear about new data
+    /// up to 1/N seconds later.
+    #[bpaf(argument("N"))]
+    max_stat_rate: Option<u32>,
+    /// How to notice the file growing: "inotify", "poll" (look at its length
+    /// every --poll-interval), or "auto" (the default).  "auto" uses inotify,
+    /// unless the file is on a network or FUSE filesystem, where writes from
+    /// other machines don't produce inotify events (Linux only)
+    #[bpaf(argument("MODE"))]
+    watch_mode: Option<WatchMode>,
+    /// When polling the file, how often to look at its length, in
+    /// milliseconds.  [default: 100]
+    #[bpaf(argument("MS"))]
+    poll_interval: Option<u64>,
+    /// Keep the index which `line <N>` and `seqnum <N>` are looked up in
+    /// next to the file, in `PATH.tsidx`, so that it doesn't have to be
+    /// rebuilt after a restart
+    persist_index: bool,
+    /// What to do when a client asks to start past the end of the file:
+    /// "wait" for the file to grow that far (the default), "clamp" the offset
+    /// to the end of the file, or send an "error".  Clients can choose for
+    /// themselves in the header
+    #[bpaf(argument("POLICY"))]
+    beyond_eof: Option<BeyondEof>,
+    /// Let clients ask for the stream to be compressed.  Compressed clients
+    /// can't be served using zero-copy I/O, so they use more CPU
+    allow_compression: bool,
+    /// Which I/O backend to use: "uring", "epoll", "kqueue", or "auto" (the
+    /// default).  On Linux, "auto" uses io_uring if it's available, and falls
+    /// back to epoll if not.  Elsewhere, it means kqueue
+    #[bpaf(argument("BACKEND"))]
+    backend: Option<Backend>,
+    #[bpaf(external(sqpoll))]
+    sqpoll: Option<u32>,
+    /// The size of the io_uring's submission queue.  The completion queue is
+    /// sized to match.  [default: 256]
+    #[bpaf(argument("N"))]
+    uring_entries: Option<u32>,
+    /// Run this many io_uring runloops, each on a thread of its own with its
+    /// own share of the clients.  For when one core can't keep up.  The
+    /// --max-total-rate is split evenly between them.  [default: 1]
+    #[bpaf(argument("N"))]
+    uring_threads: Option<usize>,
+    /// The size of the pipe each client's data passes through on the
+    /// io_uring backend.  Bigger pipes mean fewer, larger splices.  Accepts
+    /// a suffix of K or M.  [default: the system default, usually 64K]
+    #[bpaf(argument::<String>("BYTES"), parse(parse_size), optional)]
+    pipe_size: Option<u64>,
+    /// The maximum number of pipes to keep around for reuse after their
+    /// clients disconnect.  [default: 64]
+    #[bpaf(argument("N"))]
+    pipe_pool_size: Option<usize>,
+    /// Append a line to this file whenever a client disconnects, saying who
+    /// it was, where it started, and how much it was sent
+    #[bpaf(argument("PATH"))]
+    access_log: Option<PathBuf>,
+    /// Log a summary line every SECS seconds, with the number of clients,
+    /// how much has been sent to them, and how far behind the slowest one is
+    #[bpaf(argument("SECS"))]
+    stats_interval: Option<u64>,
+    /// How to format the logs written to stderr: "text" (the default) or
+    /// "json", with one object per line
+    #[bpaf(argument("FORMAT"))]
+    log_format: Option<LogFormat>,
+    /// Once everything's been opened, switch to this user (a name or a
+    /// number).  For binding privileged ports, or serving files only root
+    /// can read, without serving clients as root
+    #[bpaf(argument("USER"))]
+    user: Option<String>,
+    /// Once everything's been opened, switch to this group (a name or a
+    /// number).  [default: the --user's primary group]
+    #[bpaf(argument("GROUP"))]
+    group: Option<String>,
+    /// Send traces to journald instead of the terminal.
+    #[cfg(feature = "tracing-journald")]
+    journald: bool,
+    /// The file which will be broadcast to all clients
+    #[bpaf(positional("PATH"))]
+    path: Option<PathBuf>,
+}
+
+/// How long the SQPOLL thread spins before going to sleep, if the user
+/// doesn't say
+const DEFAULT_SQPOLL_IDLE_MS: u32 = 1000;
+/// How long --exit-on-idle waits for a new client, if the user doesn't say
+const DEFAULT_IDLE_SECS: u64 = 10;
+/// How often we check for the file, when we're waiting for it to be created
+/// and can't watch its directory
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(3);
+/// When polling the file, how often we check whether it has grown, if the
+/// user doesn't say
+const DEFAULT_POLL_INTERVAL_MS: u64 = 100;
+/// With --exit-on-idle, how often we check whether anyone's connected
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// With --drain-after-file-is-gone, how often we check whether the clients
+/// have caught up
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// With --heartbeat, how often we look for clients which are due one
+const HEARTBEAT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The most runloops which --uring-threads may ask for
+const MAX_SHARDS: usize = 64;
+/// How much a client may be sent in one pass, if --chunk-size isn't given.
+/// This is as big as an unprivileged process's pipes can be, so on io_uring
+/// it doesn't get in the way of --pipe-size.
+const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+/// How long a client has to send its whole header (or HTTP request).  Until
+/// then it's taking up a slot without being served.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Headers longer than this are rejected
+const MAX_HEADER_LEN: usize = 4096;
+/// How long we wait, before exiting, for the sends which were in flight to
+/// finish so that those clients can be sent their EOF frames
+const EOF_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `--sqpoll` takes an optional value, which must be given as `--sqpoll=N`
+fn sqpoll() -> impl Parser<Option<u32>> {
+    let with_idle = bpaf::long("sqpoll")
+        .help(
+            "Have a kernel thread poll the io_uring for new requests, saving a syscall \
+             per submission.  The thread sleeps after IDLE_MS milliseconds without any \
+             work [default: 1000]",
+        )
+        .argument::<u32>("IDLE_MS")
+        .adjacent();
+    let without_idle = bpaf::long("sqpoll").req_flag(DEFAULT_SQPOLL_IDLE_MS).hide();
+    bpaf::construct!([with_idle, without_idle]).optional()
+}
+
+/// `--exit-on-idle` takes an optional value, which must be given as
+/// `--exit-on-idle=SECS`
+fn exit_on_idle() -> impl Parser<Option<u64>> {
+    let with_secs = bpaf::long("exit-on-idle")
+        .help(
+            "Quit once there have been no clients for SECS seconds.  The grace period \
+             lets clients reconnect without the server going away [default: 10]",
+        )
+        .argument::<u64>("SECS")
+        .adjacent();
+    let without_secs = bpaf::long("exit-on-idle")
+        .req_flag(DEFAULT_IDLE_SECS)
+        .hide();
+    bpaf::construct!([with_secs, without_secs]).optional()
+}
+
+/// `--reopen` takes an optional value, which must be given as
+/// `--reopen=POLICY`
+fn reopen() -> impl Parser<Option<ReopenPolicy>> {
+    let with_policy = bpaf::long("reopen")
+        .help(
+            "When the file is moved or deleted, wait for a new one to appear at the same \
+             path and switch to it (Linux only).  POLICY says what happens to the clients: \
+             \"restart\" sends them the new file from the beginning, and \"disconnect\" \
+             hangs up on them [default: restart]",
+        )
+        .argument::<ReopenPolicy>("POLICY")
+        .adjacent();
+    let without_policy = bpaf::long("reopen").req_flag(ReopenPolicy::Restart).hide();
+    bpaf::construct!([with_policy, without_policy]).optional()
+}
+
+/// Parse a number of bytes, with an optional K/M/G suffix (powers of 1024)
+fn parse_size(s: String) -> Result<u64, String> {
+    let (digits, multiplier) = match s.trim().as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 1 << 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 1 << 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s.as_str(), 1),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{s}: {e}"))?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| format!("{s}: Too large"))
+}
+
+fn parse_regex(s: String) -> Result<regex::Regex, String> {
+    regex::Regex::new(&s).map_err(|e| e.to_string())
+}
+
+/// Parse a single byte, written as a character, an escape (`\n`, `\t`, `\r`
+/// or `\0`), or in hex (`0x1e`)
+fn parse_delim(s: String) -> Result<u8, String> {
+    match s.as_str() {
+        "\\n" => Ok(b'\n'),
+        "\\t" => Ok(b'\t'),
+        "\\r" => Ok(b'\r'),
+        "\\0" => Ok(0),
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => match s.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).map_err(|e| format!("{s}: {e}")),
+            None => Err(format!("{s}: Expected a single byte, eg. '\\0' or 0x1e")),
+        },
+    }
+}
+
+/// Parse a string of bytes, which may contain the escapes `\n`, `\t`, `\r`,
+/// `\0`, `\\` and `\xHH`
+fn parse_marker(s: String) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b'0') => out.push(0),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).unwrap_or("");
+                let b = u8::from_str_radix(hex, 16)
+                    .map_err(|_| format!("{s}: Expected two hex digits after \\x"))?;
+                out.push(b);
+            }
+            _ => return Err(format!("{s}: Unknown escape")),
+        }
+    }
+    if out.is_empty() {
+        return Err("The marker can't be empty".into());
+    }
+    Ok(out)
+}
+
+fn read_token(path: PathBuf) -> Result<String, String> {
+    let token = std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Err(format!("{}: Token must be a single word", path.display()));
+    }
+    Ok(token.to_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Auto,
+    Uring,
+    Epoll,
+    Kqueue,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "auto" => Ok(Backend::Auto),
+            "uring" | "io_uring" => Ok(Backend::Uring),
+            "epoll" => Ok(Backend::Epoll),
+            "kqueue" => Ok(Backend::Kqueue),
+            _ => Err(format!("{s}: Unknown backend")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchMode {
+    Auto,
+    Inotify,
+    Poll,
+}
+
+impl std::str::FromStr for WatchMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<WatchMode, String> {
+        match s {
+            "auto" => Ok(WatchMode::Auto),
+            "inotify" => Ok(WatchMode::Inotify),
+            "poll" => Ok(WatchMode::Poll),
+            _ => Err(format!("{s}: Unknown watch mode")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{s}: Unknown log format")),
+        }
+    }
+}
+
+/// What happens to the clients when we switch to a new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReopenPolicy {
+    /// Carry on from the start of the new file
+    Restart,
+    /// Disconnect them, so they can reconnect and choose where to start
+    Disconnect,
+}
+
+impl std::str::FromStr for ReopenPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ReopenPolicy, String> {
+        match s {
+            "restart" => Ok(ReopenPolicy::Restart),
+            "disconnect" => Ok(ReopenPolicy::Disconnect),
+            _ => Err(format!("{s}: Unknown policy")),
+        }
+    }
+}
+
+/// What to do when the file is deleted, moved, or truncated (see
+/// [`Builder::on_delete`], [`Builder::on_move`], and [`Builder::on_truncate`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilePolicy {
+    /// Tell the clients why their streams are ending, and quit
+    Exit,
+    /// Carry on serving the file we have open
+    Linger,
+    /// Quit once every client has been sent the whole file
+    Drain,
+    /// Serve a new file from the same path, or (when truncated) the same file
+    /// from the start
+    Reopen,
+    /// Disconnect the clients, and carry on serving the file to new ones
+    DisconnectClients,
+}
+
+impl std::str::FromStr for FilePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<FilePolicy, String> {
+        match s {
+            "exit" => Ok(FilePolicy::Exit),
+            "linger" => Ok(FilePolicy::Linger),
+            "drain" => Ok(FilePolicy::Drain),
+            "reopen" => Ok(FilePolicy::Reopen),
+            "disconnect-clients" => Ok(FilePolicy::DisconnectClients),
+            _ => Err(format!("{s}: Unknown policy")),
+        }
+    }
+}
+
+pub type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
+
+/// Identifies a client for as long as tailsrv is running.  IDs are handed out
+/// sequentially and never reused.
+type ClientId = u64;
+/// The clients which a runloop is serving.  Only the runloop touches it.
+type Clients = BTreeMap<ClientId, Client>;
+
+static OPTS: OnceLock<Opts> = OnceLock::new();
+static FILE_LENGTH: AtomicUsize = AtomicUsize::new(0);
+static NUM_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
+/// Clients with lower IDs than this are to be disconnected (see
+/// `disconnect_clients`)
+static DISCONNECT_BELOW: AtomicU64 = AtomicU64::new(0);
+/// The number of bytes sent to clients since tailsrv started
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+/// How many runloops the clients are shared out between.  Each owns its own
+/// shard of the clients.  This is set before the first client connects, and
+/// doesn't change after that.
+static NUM_SHARDS: AtomicUsize = AtomicUsize::new(1);
+/// The runloops' inboxes, indexed by shard.  Each runloop takes the receiving
+/// end of its own when it starts.
+static INBOXES: LazyLock<Vec<Inbox>> =
+    LazyLock::new(|| (0..MAX_SHARDS).map(|_| Inbox::new()).collect());
+/// Set once the runloops have started.  Before then there are no clients to
+/// look at, since they're all still waiting in the inboxes.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Set once we've found that the file can't be spliced from (as on some FUSE
+/// and network filesystems).  From then on, clients are sent the data via
+/// userspace instead.
+static CANT_SPLICE: AtomicBool = AtomicBool::new(false);
+/// The number of failed sends (splices, sendfile()s, and writes) of each
+/// `ErrorClass` since tailsrv started
+static SEND_ERRORS: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+/// With --max-stat-rate, when we last looked at the file's length
+static LAST_STAT: Mutex<Option<Instant>> = Mutex::new(None);
+/// Set when `stat_allowed` says no, so that the stat pacer looks instead
+static STAT_DEFERRED: AtomicBool = AtomicBool::new(false);
+/// A client whose sends fail with transient errors this many times in a row
+/// is disconnected
+const MAX_RETRIES: u32 = 5;
+/// How long a client waits after its first transient error.  This doubles
+/// with each one after that.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+#[cfg(target_os = "linux")]
+static EVENTFD: LazyLock<OwnedFd> =
+    LazyLock::new(|| rustix::event::eventfd(0, EventfdFlags::NONBLOCK).unwrap());
+
+impl Opts {
+    /// Parse the command line.  If `--config` was given, the config file
+    /// fills in anything which wasn't.
+    pub fn from_args() -> Result<Opts> {
+        opts().run().with_config()
+    }
+
+    fn with_config(mut self) -> Result<Opts> {
+        if let Some(config) = self.config.clone() {
+            config::load_into(&config, &mut self)?;
+        }
+        Ok(self)
+    }
+
+    /// What to do when the file is moved or (if not `moved`) deleted
+    fn on_gone(&self, moved: bool) -> FilePolicy {
+        let policy = if moved { self.on_move } else { self.on_delete };
+        policy.unwrap_or(if self.drain_after_file_is_gone {
+            FilePolicy::Drain
+        } else if self.linger_after_file_is_gone {
+            FilePolicy::Linger
+        } else if self.reopen.is_some() || self.glob.is_some() {
+            FilePolicy::Reopen
+        } else {
+            FilePolicy::Exit
+        })
+    }
+
+    /// Whether we may switch to a new file, so the source has to be able to
+    /// change underneath the runloop
+    fn reopens(&self) -> bool {
+        self.on_gone(true) == FilePolicy::Reopen || self.on_gone(false) == FilePolicy::Reopen
+    }
+}
+
+/// Options for `tailsrv index`
+#[derive(Bpaf)]
+pub struct IndexOpts {
+    /// Index the records of a length-prefixed file (for `seqnum <N>`) instead
+    /// of its lines
+    records: bool,
+    /// The byte which ends each line, as for the server.  [default: \n]
+    #[bpaf(argument::<String>("BYTE"), parse(parse_delim), optional)]
+    delim: Option<u8>,
+    /// The file to index.  The index is written to PATH.tsidx
+    #[bpaf(positional("PATH"))]
+    path: PathBuf,
+}
+
+impl IndexOpts {
+    /// Build the index for the file, or bring it up to date
+    pub fn run(self) -> Result<()> {
+        let kind = match (self.records, self.delim) {
+            (true, Some(_)) => return Err("Records don't have a delimiter".into()),
+            (true, None) => index::Kind::Records,
+            (false, delim) => index::Kind::Lines(delim.unwrap_or(b'\n')),
+        };
+        let count = index::build(&self.path, kind)?;
+        info!(path = %self.path.display(), ?kind, count, "Indexed the file");
+        Ok(())
+    }
+}
+
+/// Options for `tailsrv doctor`
+#[derive(Bpaf)]
+pub struct DoctorOpts {
+    /// Also check that this file can be served
+    #[bpaf(positional("PATH"), optional)]
+    path: Option<PathBuf>,
+}
+
+impl DoctorOpts {
+    /// Check the environment, and print what we found
+    pub fn run(self) -> Result<()> {
+        doctor::run(self.path.as_deref())
+    }
+}
+
+/// What the command line asks for
+pub enum Command {
+    /// Serve the file
+    Serve(Opts),
+    /// Build the index for a file ahead of time, so that the server doesn't
+    /// have to
+    Index(IndexOpts),
+    /// Check whether the environment has what the server needs
+    Doctor(DoctorOpts),
+}
+
+impl Command {
+    /// Parse the command line.  See [`Opts::from_args`].
+    pub fn from_args() -> Result<Command> {
+        let index = index_opts()
+            .to_options()
+            .descr("Build (or repair) the index which `line <N>` and `seqnum <N>` use")
+            .command("index")
+            .map(Command::Index);
+        let doctor = doctor_opts()
+            .to_options()
+            .descr("Check whether tailsrv will work here, and what to do if not")
+            .command("doctor")
+            .map(Command::Doctor);
+        let serve = opts().map(Command::Serve);
+        match bpaf::construct!([index, doctor, serve]).run() {
+            Command::Serve(opts) => Ok(Command::Serve(opts.with_config()?)),
+            cmd => Ok(cmd),
+        }
+    }
+}
+
+/// A file server, ready to run.  There can only be one of these running per
+/// process.
+///
+/// ```no_run
+/// # fn main() -> tailsrv::Result<()> {
+/// let listener = std::net::TcpListener::bind("0.0.0.0:4321")?;
+/// tailsrv::TailServer::builder()
+///     .path("/var/log/journal.bin")
+///     .listener(listener)
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TailServer {
+    opts: Opts,
+    listeners: Listeners,
+    source: Option<Box<dyn Source>>,
+}
+
+impl TailServer {
+    pub fn builder() -> Builder {
+        Builder(TailServer::from_opts(Opts::default()))
+    }
+
+    pub fn from_opts(opts: Opts) -> TailServer {
+        // These can only have been set on the command line (or in the config
+        // file); the builder starts from the defaults
+        if opts.linger_after_file_is_gone {
+            warn!("--linger-after-file-is-gone is deprecated; use --on-delete linger --on-move linger");
+        }
+        if opts.drain_after_file_is_gone {
+            warn!(
+                "--drain-after-file-is-gone is deprecated; use --on-delete drain --on-move drain"
+            );
+        }
+        TailServer {
+            opts,
+            listeners: Listeners::default(),
+            source: None,
+        }
+    }
+
+    /// Serve the file.  This doesn't return unless something goes wrong.
+    ///
+    /// Note that, as with the binary, the process exits when the file is
+    /// moved or deleted, unless [`Builder::on_move`] or [`Builder::on_delete`]
+    /// says otherwise.
+    pub fn run(self) -> Result<()> {
+        if OPTS.set(self.opts).is_err() {
+            return Err("Only one TailServer can run per process".into());
+        }
+        serve(OPTS.get().unwrap(), self.listeners, self.source)
+    }
+}
+
+/// Settings for a [`TailServer`].  Anything not set here takes the same
+/// default as the corresponding command-line flag.
+pub struct Builder(TailServer);
+
+impl Builder {
+    /// The file to serve
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.0.opts.path = Some(path.into());
+        self
+    }
+
+    /// Serve this instead of a file.  The path is ignored.
+    pub fn source(mut self, source: impl Source + 'static) -> Builder {
+        self.0.source = Some(Box::new(source));
+        self
+    }
+
+    /// Accept clients on this TCP socket
+    pub fn listener(mut self, listener: TcpListener) -> Builder {
+        self.0.listeners.tcp = Some(listener);
+        self
+    }
+
+    /// Accept clients on this unix socket
+    pub fn unix_listener(mut self, listener: UnixListener) -> Builder {
+        self.0.listeners.unix = Some(listener);
+        self
+    }
+
+    /// See `--max-clients`
+    pub fn max_clients(mut self, n: usize) -> Builder {
+        self.0.opts.max_clients = Some(n);
+        self
+    }
+
+    /// See `--max-rate-per-client`
+    pub fn max_rate_per_client(mut self, bytes_per_sec: u64) -> Builder {
+        self.0.opts.max_rate_per_client = Some(bytes_per_sec);
+        self
+    }
+
+    /// See `--max-total-rate`
+    pub fn max_total_rate(mut self, bytes_per_sec: u64) -> Builder {
+        self.0.opts.max_total_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// See `--chunk-size`
+    pub fn chunk_size(mut self, bytes: u64) -> Builder {
+        self.0.opts.chunk_size = Some(bytes);
+        self
+    }
+
+    /// See `--auth-token-file`
+    pub fn auth_token(mut self, token: impl Into<String>) -> Builder {
+        self.0.opts.auth_token = Some(token.into());
+        self
+    }
+
+    /// Carry on serving the file after it's moved or deleted, rather than
+    /// exiting
+    #[deprecated(note = "use `on_delete(FilePolicy::Linger)` and `on_move(FilePolicy::Linger)`")]
+    pub fn linger_after_file_is_gone(mut self, linger: bool) -> Builder {
+        self.0.opts.linger_after_file_is_gone = linger;
+        self
+    }
+
+    /// See `--exit-on-idle`
+    pub fn exit_on_idle(mut self, grace: Option<Duration>) -> Builder {
+        self.0.opts.exit_on_idle = grace.map(|x| x.as_secs());
+        self
+    }
+
+    /// Once the file is moved or deleted, exit when every client has been
+    /// sent the whole file
+    #[deprecated(note = "use `on_delete(FilePolicy::Drain)` and `on_move(FilePolicy::Drain)`")]
+    pub fn drain_after_file_is_gone(mut self, drain: bool) -> Builder {
+        self.0.opts.drain_after_file_is_gone = drain;
+        self
+    }
+
+    /// What to do when the file is deleted.  See `--on-delete`.
+    pub fn on_delete(mut self, policy: FilePolicy) -> Builder {
+        self.0.opts.on_delete = Some(policy);
+        self
+    }
+
+    /// What to do when the file is moved.  See `--on-move`.
+    pub fn on_move(mut self, policy: FilePolicy) -> Builder {
+        self.0.opts.on_move = Some(policy);
+        self
+    }
+
+    /// What to do when the file shrinks to less than a client has been sent.
+    /// See `--on-truncate`.
+    pub fn on_truncate(mut self, policy: FilePolicy) -> Builder {
+        self.0.opts.on_truncate = Some(policy);
+        self
+    }
+
+    /// Only serve the file up to the end of the last occurrence of
+    /// `marker`, so that clients never see a partly-written record
+    pub fn commit_marker(mut self, marker: impl Into<Vec<u8>>) -> Builder {
+        self.0.opts.commit_marker = Some(marker.into());
+        self
+    }
+
+    pub fn build(self) -> TailServer {
+        self.0
+    }
+
+    /// Shorthand for `build().run()`
+    pub fn run(self) -> Result<()> {
+        self.build().run()
+    }
+}
+
+/// Bind the sockets given in `opts`, wait for the file, and serve it.  If
+/// the embedder gave us a source then we serve that instead.
+fn serve(
+    opts: &'static Opts,
+    mut listeners: Listeners,
+    source: Option<Box<dyn Source>>,
+) -> Result<()> {
+    #[cfg(feature = "grpc")]
+    let grpc_port = opts.grpc_port;
+    #[cfg(not(feature = "grpc"))]
+    let grpc_port: Option<u16> = None;
+    let listening = listeners.tcp.is_some()
+        || listeners.unix.is_some()
+        || opts.port.is_some()
+        || opts.unix_socket.is_some()
+        || opts.http_port.is_some()
+        || grpc_port.is_some()
+        || !opts.serve.is_empty();
+    if opts.inetd && listening {
+        return Err("--inetd serves stdin/stdout, so it can't listen on sockets too".into());
+    }
+    if !listening && !opts.inetd {
+        return Err("Please specify --port, --unix-socket, and/or --http-port".into());
+    }
+    if opts.inetd && opts.stdin {
+        return Err("--inetd uses stdin for the client, so it can't be used with --stdin".into());
+    }
+    // Data from stdin or a FIFO gets appended to the spool, which is what we
+    // actually serve.  The spool is either a file or a ring buffer.
+    let fifo = opts.path.as_deref().filter(|x| spool::is_fifo(x));
+    let spooling = opts.stdin || fifo.is_some();
+    if opts.stdin && opts.path.is_some() {
+        return Err("Please specify either a PATH or --stdin, not both".into());
+    }
+    if spooling && source.is_some() {
+        return Err("--stdin and FIFOs can't be used with a custom source".into());
+    }
+    match (spooling, &opts.spool, opts.memory_buffer) {
+        (true, None, None) => {
+            return Err("Please specify a --spool file or a --memory-buffer".into());
+        }
+        (true, Some(_), Some(_)) => {
+            return Err("Please specify either --spool or --memory-buffer, not both".into());
+        }
+        (false, Some(_), _) => {
+            return Err("--spool is only used with --stdin, or when PATH is a FIFO".into());
+        }
+        (false, _, Some(_)) => {
+            return Err("--memory-buffer is only used with --stdin, or when PATH is a FIFO".into());
+        }
+        _ => (),
+    }
+    if opts.chunk_size == Some(0) {
+        return Err("--chunk-size can't be zero".into());
+    }
+    let ring = match opts.memory_buffer {
+        Some(0) => return Err("--memory-buffer can't be zero".into()),
+        Some(size) => Some(RingBuffer::new(usize::try_from(size)?)),
+        None => None,
+    };
+    let source = match &ring {
+        Some(ring) => Some(Box::new(ring.clone()) as Box<dyn Source>),
+        None => source,
+    };
+    let path = opts.spool.as_deref().or(opts.path.as_deref());
+    if opts.glob.is_some() && (path.is_some() || source.is_some() || spooling) {
+        return Err("Please specify either a PATH or --glob, not both".into());
+    }
+    if path.is_none() && source.is_none() && opts.glob.is_none() {
+        return Err("Please specify the PATH to serve".into());
+    }
+    if opts.on_truncate == Some(FilePolicy::Drain) {
+        return Err("--on-truncate can't be \"drain\"".into());
+    }
+    if opts.reopens() {
+        if cfg!(not(target_os = "linux")) {
+            return Err("--reopen and --glob are only supported on Linux".into());
+        }
+        if source.is_some() || spooling {
+            return Err("--reopen can only be used when serving a PATH".into());
+        }
+    }
+    if opts.backfill {
+        if cfg!(not(target_os = "linux")) {
+            return Err("--backfill is only supported on Linux".into());
+        }
+        if source.is_some() || spooling || opts.reopens() {
+            return Err("--backfill can only be used when serving a PATH".into());
+        }
+    }
+    if opts.direct {
+        if cfg!(not(target_os = "linux")) {
+            return Err("--direct is only supported on Linux".into());
+        }
+        if source.is_some() || spooling || opts.reopens() {
+            return Err("--direct can only be used when serving a PATH".into());
+        }
+        if opts.backfill {
+            return Err("--direct can't be used with --backfill".into());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if opts.noatime {
+        warn!("--noatime is only supported on Linux");
+    }
+    if opts.persist_index && path.is_none() {
+        return Err("--persist-index can only be used when serving a PATH".into());
+    }
+    if opts.upgrade_socket.is_some() {
+        if cfg!(not(target_os = "linux")) {
+            return Err("--upgrade-socket is only supported on Linux".into());
+        }
+        // The old process would carry on accepting gRPC clients, and a new
+        // process can't take over stdin or an upstream connection
+        if spooling || opts.upstream.is_some() || grpc_port.is_some() || !opts.serve.is_empty() {
+            return Err(
+                "--upgrade-socket can't be used with --stdin, FIFOs, --upstream, --grpc-port, \
+                 or --serve"
+                    .into(),
+            );
+        }
+    }
+    #[cfg(target_os = "linux")]
+    let glob = opts.glob.as_deref().map(glob::Glob::parse).transpose()?;
+    #[cfg(not(target_os = "linux"))]
+    if opts.tcp_user_timeout.is_some() {
+        warn!("--tcp-user-timeout is only supported on Linux");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if opts.drop_behind {
+        warn!("--drop-behind is only supported on Linux");
+    }
+
+    // Set up the backend first, so that we fail fast if io_uring was
+    // explicitly requested but isn't available
+    let mut backend = opts.backend.unwrap_or(Backend::Auto);
+    // With --reopen the file can change, with --backfill there's more than
+    // one, and with --direct it has to be read into userspace, so we can't
+    // splice from it
+    if opts.reopens()
+        || opts.backfill
+        || opts.direct
+        || source.as_ref().is_some_and(|x| x.as_file().is_none())
+    {
+        backend = match backend {
+            Backend::Uring => return Err("Only files can be served by the io_uring backend".into()),
+            #[cfg(target_os = "linux")]
+            Backend::Auto => Backend::Epoll,
+            x => x,
+        };
+    }
+    let runloop = Runloop::new(backend, opts)?;
+
+    // If we're replacing a running tailsrv, we take over its sockets instead
+    // of binding our own
+    #[cfg(target_os = "linux")]
+    let (mut inherited, mut inherited_http) = match &opts.upgrade_socket {
+        Some(path) => upgrade::take_over(path)?,
+        None => (Listeners::default(), None),
+    };
+    #[cfg(not(target_os = "linux"))]
+    let (mut inherited, mut inherited_http) = (Listeners::default(), None);
+
+    // Bind the listener sockets.  We do this ASAP, so clients can start
+    // connecting immediately. It's fine for them to connect even before the
+    // file exists.  Of course, they won't recieve any data until it _does_
+    // exist.
+    if let Some(port) = opts.port {
+        let host = opts.bind.as_deref().unwrap_or("0.0.0.0");
+        let listener = match inherited.tcp.take() {
+            Some(listener) => listener,
+            None => bind_tcp_socket(host, port, opts.dual_stack)?,
+        };
+        let listen_addr = listener.local_addr()?;
+        info!(%listen_addr, "Bound socket");
+        listeners.tcp = Some(listener);
+    }
+    if let Some(path) = &opts.unix_socket {
+        let listener = match inherited.unix.take() {
+            Some(listener) => listener,
+            None => bind_unix_socket(path)?,
+        };
+        info!(path = %path.display(), "Bound unix socket");
+        listeners.unix = Some(listener);
+    }
+    let mut http_listener = match opts.http_port {
+        Some(port) => {
+            let host = opts.bind.as_deref().unwrap_or("0.0.0.0");
+            let listener = match inherited_http.take() {
+                Some(listener) => listener,
+                None => bind_tcp_socket(host, port, opts.dual_stack)?,
+            };
+            let listen_addr = listener.local_addr()?;
+            info!(%listen_addr, "Bound HTTP socket");
+            Some(listener)
+        }
+        None => None,
+    };
+    #[cfg(feature = "grpc")]
+    let grpc_listener = match opts.grpc_port {
+        Some(port) => {
+            let host = opts.bind.as_deref().unwrap_or("0.0.0.0");
+            let listener = bind_tcp_socket(host, port, opts.dual_stack)?;
+            let listen_addr = listener.local_addr()?;
+            info!(%listen_addr, "Bound gRPC socket");
+            Some(listener)
+        }
+        None => None,
+    };
+
+    feed::sync(&opts.serve, opts)?;
+
+    if let Some(upstream) = &opts.upstream {
+        let Some(path) = path.filter(|_| source.is_none()) else {
+            return Err("--upstream needs a PATH to write to".into());
+        };
+        relay::spawn(upstream, path, opts)?;
+    }
+    if spooling {
+        let spool: Box<dyn Write + Send> = match (&ring, path) {
+            (Some(ring), _) => Box::new(ring.clone()),
+            (None, Some(path)) => Box::new(spool::open(path)?),
+            (None, None) => unreachable!(),
+        };
+        match fifo {
+            Some(fifo) => spool::spawn_fifo(fifo, spool),
+            None => spool::spawn(std::io::stdin(), spool),
+        }
+    }
+
+    if let Some(path) = &opts.cursor_file {
+        cursor::load(path)?;
+    }
+    if let Some(path) = &opts.state_file {
+        state::load(path)?;
+    }
+    if let Some(path) = &opts.access_log {
+        access_log::open(path)?;
+    }
+    spawn_signal_handler(opts)?;
+    if let Some(secs) = opts.stats_interval {
+        spawn_stats_reporter(Duration::from_secs(secs));
+    }
+    if let Some(secs) = opts.heartbeat {
+        spawn_heartbeats(Duration::from_secs(secs));
+    }
+    match opts.max_stat_rate {
+        Some(0) => return Err("--max-stat-rate must be at least 1".into()),
+        Some(rate) => spawn_stat_pacer(rate, opts),
+        None => (),
+    }
+
+    // Normally we start accepting clients before the file exists.  But if
+    // we're going to drop privileges, we have to open the file first (only
+    // root may be able to read it), and we don't want to accept anyone
+    // until we've dropped them.
+    let dropping = opts.user.is_some() || opts.group.is_some();
+    if !dropping {
+        start_accepting(&runloop, &mut listeners, http_listener.take(), opts)?;
+    }
+
+    // Now we wait until the file exists
+    #[cfg(target_os = "linux")]
+    let source = match glob {
+        Some(glob) => {
+            let (path, file) = glob.wait_for_newer(None)?;
+            let policy = opts.reopen.unwrap_or(ReopenPolicy::Restart);
+            let target = reopen::Target::Glob(glob);
+            Some(Box::new(reopen::Reopening::new(target, path, file, policy)) as Box<dyn Source>)
+        }
+        None => source,
+    };
+    let source: Box<dyn Source> = match (source, path) {
+        (Some(source), _) => source,
+        #[cfg(target_os = "linux")]
+        (None, Some(path)) if opts.reopens() => {
+            let file = wait_for_file(path)?;
+            let policy = opts.reopen.unwrap_or(ReopenPolicy::Restart);
+            let target = reopen::Target::Path(path.to_owned());
+            Box::new(reopen::Reopening::new(
+                target,
+                path.to_owned(),
+                file,
+                policy,
+            ))
+        }
+        #[cfg(target_os = "linux")]
+        (None, Some(path)) if opts.backfill => {
+            let file = wait_for_file(path)?;
+            Box::new(backfill::Backfilled::open(path, file, opts)?)
+        }
+        #[cfg(target_os = "linux")]
+        (None, Some(path)) if opts.direct => {
+            let file = wait_for_file(path)?;
+            Box::new(direct::Direct::new(file, opts)?)
+        }
+        (None, Some(path)) => Box::new(wait_for_file(path)?),
+        (None, None) => unreachable!(),
+    };
+
+    if dropping {
+        privs::switch(opts.user.as_deref(), opts.group.as_deref())?;
+        start_accepting(&runloop, &mut listeners, http_listener.take(), opts)?;
+    }
+
+    let file_len = marker::committed(&*source, source.len()?)?;
+    FILE_LENGTH.store(file_len, Ordering::Release);
+    info!("Initial file size: {} kiB", file_len / 1024);
+    if SOURCE.set(source).is_err() {
+        return Err("The source was already set".into());
+    }
+    let source = &**SOURCE.get().unwrap();
+    if let (true, Some(path)) = (opts.persist_index, path) {
+        index::load(&index::sidecar_path(path), source)?;
+    }
+    index::spawn_updater();
+    source.watch(Growth(()));
+    #[cfg(target_os = "linux")]
+    fadvise::start(source, opts);
+
+    #[cfg(feature = "grpc")]
+    if let Some(listener) = grpc_listener {
+        std::thread::spawn(move || {
+            if let Err(e) = grpc::serve(listener, opts) {
+                error!("gRPC server failed: {e}");
+                std::process::exit(1);
+            }
+        });
+    }
+
+    runloop.run(source, listeners, opts)
+}
+
+/// Start serving the listening sockets, and let systemd know that we're
+/// ready.  (The io_uring backend accepts connections itself, once it's
+/// running.)
+fn start_accepting(
+    runloop: &Runloop,
+    listeners: &mut Listeners,
+    http_listener: Option<TcpListener>,
+    opts: &'static Opts,
+) -> Result<()> {
+    if opts.inetd {
+        inetd::spawn(opts)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(path) = &opts.upgrade_socket {
+        upgrade::start(path, listeners, http_listener.as_ref(), opts)?;
+    }
+    // The io_uring backend accepts connections on the ring.  The others need
+    // a thread per listener.
+    if !runloop.accepts_connections() {
+        listeners.spawn_threads(opts);
+    }
+    if let Some(listener) = http_listener {
+        std::thread::spawn(move || http::listen(listener, opts));
+    }
+
+    // We're ready to accept clients now; let systemd know it can start them
+    #[cfg(feature = "sd-notify")]
+    sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?;
+    if let Some(secs) = opts.exit_on_idle {
+        spawn_idle_watcher(Duration::from_secs(secs));
+    }
+    Ok(())
+}
+
+/// The backend, once it's been chosen
+enum Runloop {
+    #[cfg(target_os = "linux")]
+    Uring(Box<rustix_uring::IoUring>),
+    #[cfg(target_os = "linux")]
+    Epoll,
+    #[cfg(not(target_os = "linux"))]
+    Kqueue,
+}
+
+impl Runloop {
+    #[cfg(target_os = "linux")]
+    fn new(backend: Backend, opts: &Opts) -> Result<Runloop> {
+        let caps = probe::probe();
+        let runloop = match backend {
+            Backend::Uring => {
+                if let Err(e) = &caps.uring {
+                    return Err(format!("io_uring can't be used: {e}").into());
+                }
+                match &caps.inotify {
+                    Err(_) if opts.watch_mode == Some(WatchMode::Poll) => (),
+                    Err(e) => return Err(format!("The io_uring backend needs inotify: {e}").into()),
+                    Ok(()) => (),
+                }
+                Runloop::Uring(Box::new(uring::setup(opts)?))
+            }
+            Backend::Epoll => Runloop::Epoll,
+            Backend::Kqueue => return Err("kqueue isn't available on Linux".into()),
+            Backend::Auto => match (&caps.uring, &caps.inotify) {
+                (Err(e), _) => {
+                    warn!("io_uring can't be used ({e}); falling back to epoll");
+                    Runloop::Epoll
+                }
+                (_, Err(e)) if opts.watch_mode != Some(WatchMode::Poll) => {
+                    warn!("inotify can't be used ({e}); falling back to epoll");
+                    Runloop::Epoll
+                }
+                // Our options (eg. --sqpoll) may still be refused
+                (Ok(()), _) => match uring::setup(opts) {
+                    Ok(uring) => Runloop::Uring(Box::new(uring)),
+                    Err(e) => {
+                        warn!("Couldn't set up the io_uring ({e}); falling back to epoll");
+                        Runloop::Epoll
+                    }
+                },
+            },
+        };
+        info!(
+            backend = match runloop {
+                Runloop::Uring(_) => "io_uring",
+                Runloop::Epoll => "epoll",
+            },
+            uring = caps.uring.is_ok(),
+            inotify = caps.inotify.is_ok(),
+            "Chose a backend"
+        );
+        if opts.sqpoll.is_some() && !matches!(runloop, Runloop::Uring(_)) {
+            warn!("--sqpoll only has an effect on the io_uring backend");
+        }
+        if !opts.serve.is_empty() && !matches!(runloop, Runloop::Uring(_)) {
+            return Err("--serve needs the io_uring backend".into());
+        }
+        match (opts.uring_threads, &runloop) {
+            (Some(n), Runloop::Uring(_)) if n == 0 || n > MAX_SHARDS => {
+                return Err(format!("--uring-threads must be between 1 and {MAX_SHARDS}").into());
+            }
+            // Nobody's connected yet, so the clients can't end up in the
+            // wrong shard
+            (Some(n), Runloop::Uring(_)) => NUM_SHARDS.store(n, Ordering::Release),
+            (Some(_), _) => warn!("--uring-threads only has an effect on the io_uring backend"),
+            (None, _) => (),
+        }
+        if opts.pipe_size.is_some() {
+            if let Runloop::Uring(_) = runloop {
+                probe::check_pipe_size(opts)?;
+            } else {
+                warn!("--pipe-size only has an effect on the io_uring backend");
+            }
+        }
+        Ok(runloop)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(backend: Backend, opts: &Opts) -> Result<Runloop> {
+        if !opts.serve.is_empty() {
+            return Err("--serve is only supported on Linux".into());
+        }
+        if opts.flush_on_close_write {
+            return Err("--flush-on-close-write is only supported on Linux".into());
+        }
+        if opts.watch_mode.is_some() || opts.poll_interval.is_some() {
+            return Err("--watch-mode is only supported on Linux".into());
+        }
+        if opts.sqpoll.is_some() {
+            warn!("--sqpoll only has an effect on the io_uring backend");
+        }
+        if opts.uring_threads.is_some() {
+            warn!("--uring-threads only has an effect on the io_uring backend");
+        }
+        if opts.pipe_size.is_some() {
+            warn!("--pipe-size only has an effect on the io_uring backend");
+        }
+        match backend {
+            Backend::Kqueue | Backend::Auto => Ok(Runloop::Kqueue),
+            Backend::Uring | Backend::Epoll => {
+                Err(format!("{backend:?} is only available on Linux").into())
+            }
+        }
+    }
+
+    /// Whether the backend accepts new connections itself, rather than
+    /// relying on listener threads
+    fn accepts_connections(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Runloop::Uring(_) = self {
+            return true;
+        }
+        false
+    }
+
+    #[cfg(target_os = "linux")]
+    fn run(self, source: &dyn Source, listeners: Listeners, opts: &'static Opts) -> Result<()> {
+        // Files are watched with inotify, or polled.  Other sources tell us
+        // themselves when they grow.
+        let ino_fd = match source.as_file() {
+            Some(file) => watch_or_poll(file, opts)?,
+            None => None,
+        };
+        match (self, source.as_file(), &ino_fd) {
+            (Runloop::Uring(uring), Some(file), ino_fd) => {
+                let result = uring::run(*uring, file, ino_fd.as_ref(), listeners, opts);
+                if let Err(e) = &result {
+                    error!("Runloop failed: {e}");
+                    exit::exit(exit::Reason::Uring);
+                }
+                result
+            }
+            (Runloop::Uring(_), ..) => {
+                Err("Only files can be served by the io_uring backend".into())
+            }
+            (Runloop::Epoll, ..) => epoll::run(source, ino_fd.as_ref(), opts),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn run(self, source: &dyn Source, _listeners: Listeners, opts: &Opts) -> Result<()> {
+        match self {
+            Runloop::Kqueue => kqueue::run(source, opts),
+        }
+    }
+}
+
+/// Decide how to notice the file growing (see --watch-mode).  If it's with
+/// inotify, this returns the watch; otherwise it starts polling the file.
+#[cfg(target_os = "linux")]
+fn watch_or_poll(file: &File, opts: &'static Opts) -> Result<Option<OwnedFd>> {
+    if opts.poll_interval == Some(0) {
+        return Err("--poll-interval must be at least 1".into());
+    }
+    let mut mode = opts.watch_mode.unwrap_or(WatchMode::Auto);
+    if mode == WatchMode::Auto {
+        if let Some(fs) = remote_fs(file) {
+            info!(fs, "The file is on a remote filesystem; polling it");
+            mode = WatchMode::Poll;
+        }
+    }
+    // Polling would see every write
+    if mode == WatchMode::Poll && opts.flush_on_close_write {
+        return Err("--flush-on-close-write needs --watch-mode inotify".into());
+    }
+    if mode == WatchMode::Poll {
+        spawn_file_poller(opts);
+        return Ok(None);
+    }
+    match watch_file(file) {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if opts.flush_on_close_write => {
+            Err(format!("--flush-on-close-write needs inotify: {e}").into())
+        }
+        Err(e) if mode == WatchMode::Auto => {
+            warn!("Can't watch the file ({e}); checking its length every so often instead");
+            spawn_file_poller(opts);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// If the file is on a filesystem where inotify only sees writes made on this
+/// machine, its name
+#[cfg(target_os = "linux")]
+fn remote_fs(file: &File) -> Option<&'static str> {
+    let stat = match rustix::fs::fstatfs(file) {
+        Ok(x) => x,
+        Err(e) => {
+            debug!("Couldn't find out the file's filesystem: {e}");
+            return None;
+        }
+    };
+    // f_type is signed on some platforms, and these are compared as u32 by
+    // the kernel
+    match stat.f_type as u32 {
+        0x6969 => Some("nfs"),
+        0x517b => Some("smb"),
+        0xff53_4d42 => Some("cifs"),
+        0xfe53_4d42 => Some("smb2"),
+        0x6573_5546 => Some("fuse"),
+        0x00c3_6400 => Some("ceph"),
+        0x0102_1997 => Some("9p"),
+        _ => None,
+    }
+}
+
+/// Set up an inotify watch on the file.  We watch it via /proc/self/fd, since
+/// an embedder may have given us a file without a path.
+#[cfg(target_os = "linux")]
+fn watch_file(file: &File) -> Result<OwnedFd> {
+    let ino_fd = inotify::init(inotify::CreateFlags::NONBLOCK)?;
+    inotify::add_watch(
+        &ino_fd,
+        format!("/proc/self/fd/{}", file.as_raw_fd()),
+        growth_events() | inotify::WatchFlags::MOVE_SELF | inotify::WatchFlags::ATTRIB,
+    )?;
+    info!(fd = ino_fd.as_raw_fd(), "Created an inotify watch");
+    Ok(ino_fd)
+}
+
+/// The inotify events which mean that the file has grown.  With
+/// --flush-on-close-write, that's only when a writer closes it, so that a
+/// record written in many small appends wakes us up once.
+#[cfg(target_os = "linux")]
+fn growth_events() -> inotify::WatchFlags {
+    if OPTS.get().is_some_and(|x| x.flush_on_close_write) {
+        inotify::WatchFlags::CLOSE_WRITE
+    } else {
+        inotify::WatchFlags::MODIFY
+    }
+}
+
+/// Whether some of what's in the file may have to be held back from clients
+/// until we've been told about it (see --commit-marker and
+/// --flush-on-close-write)
+#[cfg(target_os = "linux")]
+fn held_back() -> bool {
+    marker::enabled() || OPTS.get().is_some_and(|x| x.flush_on_close_write)
+}
+
+/// Whether an event's flags include one of `growth_events()`
+#[cfg(target_os = "linux")]
+fn is_growth(events: inotify::ReadFlags) -> bool {
+    events.intersects(inotify::ReadFlags::MODIFY | inotify::ReadFlags::CLOSE_WRITE)
+}
+
+/// Without inotify, we notice the file growing (or being deleted) by looking
+/// at it every --poll-interval.  Renames go unnoticed.
+#[cfg(target_os = "linux")]
+fn spawn_file_poller(opts: &'static Opts) {
+    let interval = Duration::from_millis(opts.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL_MS));
+    info!(?interval, "Polling the file for growth");
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Some(file) = SOURCE.get().and_then(|x| x.as_file()) else {
+            continue;
+        };
+        let before = FILE_LENGTH.load(Ordering::Acquire);
+        let ev = FileEvent {
+            moved: false,
+            attrib: true,
+            modified: true,
+        };
+        if let Err(e) = handle_file_event(ev, file, opts) {
+            error!("Couldn't check the file: {e}");
+        }
+        if FILE_LENGTH.load(Ordering::Acquire) != before {
+            wake_runloop();
+        }
+    });
+}
+
+/// Read the pending events from the inotify fd and act on them
+#[cfg(target_os = "linux")]
+fn handle_inotify(ino_fd: &OwnedFd, file: &File, opts: &Opts) -> Result<()> {
+    let mut buf = [const { MaybeUninit::uninit() }; 1024];
+    let mut evs = inotify::Reader::new(ino_fd, &mut buf);
+    // A busy writer produces an event per write.  We only need to look at
+    // the file once for all the events which have piled up.
+    let mut batch = FileEvent {
+        moved: false,
+        attrib: false,
+        modified: false,
+    };
+    let mut n = 0;
+    loop {
+        match evs.next() {
+            Ok(ev) => {
+                trace!("inotify event: {:?}", ev);
+                batch.moved |= ev.events().contains(inotify::ReadFlags::MOVE_SELF);
+                batch.attrib |= ev.events().contains(inotify::ReadFlags::ATTRIB);
+                batch.modified |= is_growth(ev.events());
+                n += 1;
+            }
+            Err(Errno::AGAIN) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    trace!(n, "Handling a batch of inotify events");
+    handle_file_event(batch, file, opts)
+}
+
+/// Report the growth of a file which the runloop isn't watching itself
+/// (because the source isn't a plain file), and notice if it goes away.
+/// `len` says how long the source is, whenever the file grows.
+#[cfg(target_os = "linux")]
+fn follow_file(
+    file: &File,
+    growth: &Growth,
+    len: impl Fn() -> std::io::Result<usize>,
+    opts: &Opts,
+) -> Result<()> {
+    let ino_fd = inotify::init(inotify::CreateFlags::CLOEXEC)?;
+    inotify::add_watch(
+        &ino_fd,
+        format!("/proc/self/fd/{}", file.as_raw_fd()),
+        growth_events() | inotify::WatchFlags::MOVE_SELF | inotify::WatchFlags::ATTRIB,
+    )?;
+    let mut buf = [const { MaybeUninit::uninit() }; 1024];
+    let mut evs = inotify::Reader::new(&ino_fd, &mut buf);
+    loop {
+        let ev = evs.next()?;
+        trace!("inotify event: {:?}", ev);
+        if is_growth(ev.events()) {
+            growth.notify(len()?);
+        }
+        if ev.events().contains(inotify::ReadFlags::MOVE_SELF) {
+            info!("File was moved");
+            file_is_gone(true, opts);
+        }
+        // See `handle_file_event` for why we look at the link count
+        if ev.events().contains(inotify::ReadFlags::ATTRIB) && file.metadata()?.nlink() == 0 {
+            info!("File was deleted");
+            file_is_gone(false, opts);
+        }
+    }
+}
+
+/// Something happened to the file.  This is a platform-independent version of
+/// the events reported by inotify/kqueue.
+struct FileEvent {
+    moved: bool,
+    attrib: bool,
+    modified: bool,
+}
+
+fn handle_file_event(ev: FileEvent, file: &File, opts: &Opts) -> Result<()> {
+    // Without inotify we look every so often, but we only want to act once
+    // per file.  This is the inode of the one we last saw deleted; after
+    // --reopen, the new file has a different one.  (We still have the old
+    // one open, so it can't have been reused.)
+    static DELETED: AtomicU64 = AtomicU64::new(0);
+    if ev.moved {
+        info!("File was moved");
+        file_is_gone(true, opts);
+    }
+    if ev.attrib {
+        // The DELETE_SELF event only occurs when the file is unlinked and all FDs are
+        // closed.  Since tailsrv itself keeps an FD open, this means we never recieve
+        // DELETE_SELF events.  Instead we have to rely on the ATTRIB event which occurs
+        // when the user unlinks the file (and at other times too).
+        let meta = file.metadata()?;
+        if meta.nlink() == 0 && DELETED.swap(meta.ino(), Ordering::AcqRel) != meta.ino() {
+            info!("File was deleted");
+            file_is_gone(false, opts);
+        }
+    }
+    if ev.modified && stat_allowed(opts) {
+        let file_len = Source::len(file)?;
+        trace!("New file size: {}", file_len);
+        source::grew(file_len);
+    }
+    Ok(())
+}
+
+/// With --max-stat-rate, whether we may look at the file's length now.  If
+/// not, the stat pacer looks later, so the end of a burst of writes isn't
+/// missed.
+fn stat_allowed(opts: &Opts) -> bool {
+    let Some(rate) = opts.max_stat_rate else {
+        return true;
+    };
+    let mut last = LAST_STAT.lock().unwrap();
+    if last.is_some_and(|x| x.elapsed() < Duration::from_secs(1) / rate) {
+        STAT_DEFERRED.store(true, Ordering::Release);
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
+
+/// Look at the file's length whenever `stat_allowed` has put it off
+fn spawn_stat_pacer(rate: u32, opts: &'static Opts) {
+    let interval = Duration::from_secs(1) / rate;
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        if !STAT_DEFERRED.swap(false, Ordering::AcqRel) {
+            continue;
+        }
+        let Some(file) = SOURCE.get().and_then(|x| x.as_file()) else {
+            continue;
+        };
+        let before = FILE_LENGTH.load(Ordering::Acquire);
+        let ev = FileEvent {
+            moved: false,
+            attrib: false,
+            modified: true,
+        };
+        if let Err(e) = handle_file_event(ev, file, opts) {
+            error!("Couldn't check the file: {e}");
+        }
+        if FILE_LENGTH.load(Ordering::Acquire) != before {
+            wake_runloop();
+        }
+    });
+}
+
+/// The file was moved or (if not `moved`) deleted.  We still have it open,
+/// so we can carry on serving it if we've been asked to.
+fn file_is_gone(moved: bool, opts: &Opts) {
+    let reason = if moved {
+        exit::Reason::FileMoved
+    } else {
+        exit::Reason::FileDeleted
+    };
+    match opts.on_gone(moved) {
+        FilePolicy::Exit => spawn_exit("rotated", reason),
+        FilePolicy::Drain => spawn_drain_watcher(reason),
+        FilePolicy::DisconnectClients => disconnect_clients(),
+        // The source switches to the new file itself (see `reopen`)
+        FilePolicy::Reopen | FilePolicy::Linger => (),
+    }
+}
+
+/// Tell the clients why their streams are ending, and quit
+fn spawn_exit(event: &'static str, reason: exit::Reason) {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    // We may be on the runloop, which has to be free to visit its clients
+    std::thread::spawn(move || {
+        send_eof_frames(event);
+        exit::exit(reason);
+    });
+}
+
+/// Disconnect everyone who's connected now.  New clients are served as
+/// usual.  The runloops do the disconnecting, the next time they look at
+/// each client.
+fn disconnect_clients() {
+    let below = NEXT_CLIENT_ID.load(Ordering::Acquire);
+    info!(clients_before = below, "Disconnecting the clients");
+    DISCONNECT_BELOW.fetch_max(below, Ordering::AcqRel);
+    wake_runloop();
+}
+
+/// Whether the client was connected when `disconnect_clients` was called
+fn should_disconnect(client_id: ClientId) -> bool {
+    client_id < DISCONNECT_BELOW.load(Ordering::Acquire)
+}
+
+/// What to do when the file shrinks to less than a client has been sent
+fn truncate_policy() -> FilePolicy {
+    OPTS.get()
+        .and_then(|x| x.on_truncate)
+        .unwrap_or(FilePolicy::DisconnectClients)
+}
+
+/// Quit once every client has been sent the whole file.  A file which has
+/// been moved may still be written to for a while, so we also wait for it to
+/// stop growing.
+fn spawn_drain_watcher(reason: exit::Reason) {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    info!("Waiting for the clients to catch up before exiting");
+    std::thread::spawn(move || {
+        let mut last_len = None;
+        loop {
+            std::thread::sleep(DRAIN_CHECK_INTERVAL);
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            let caught_up =
+                visit_clients(|clients| clients.values().all(|c| c.caught_up(c.file_len())));
+            if last_len == Some(file_len) && caught_up.into_iter().all(|x| x) {
+                info!("Every client has been sent the whole file; exiting");
+                send_eof_frames("rotated");
+                exit::exit(reason);
+            }
+            last_len = Some(file_len);
+        }
+    });
+}
+
+/// Quit once there have been no clients for `grace`.  This includes the time
+/// before the first client connects.
+fn spawn_idle_watcher(grace: Duration) {
+    std::thread::spawn(move || {
+        let mut idle_since = Instant::now();
+        loop {
+            std::thread::sleep(IDLE_CHECK_INTERVAL);
+            if NUM_CLIENTS.load(Ordering::Acquire) > 0 {
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= grace {
+                info!("No clients for {grace:?}; exiting");
+                exit::exit(exit::Reason::Finished);
+            }
+        }
+    });
+}
+
+/// Log a snapshot of the clients whenever we receive SIGUSR1.  This is for
+/// finding out why a client is behind, without attaching a debugger.  When
+/// we're asked to quit, we save the clients (with --state-file) and tell the
+/// framed ones that we're going (with --http-port).  With --config, SIGHUP
+/// reloads the list of files to --serve.
+fn spawn_signal_handler(opts: &'static Opts) -> Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+    let mut wanted = vec![SIGUSR1];
+    if opts.config.is_some() {
+        wanted.push(SIGHUP);
+    }
+    // Framed clients are told when we're shutting down, and systemd is told
+    // why
+    wanted.extend([SIGTERM, SIGINT]);
+    let mut signals = signal_hook::iterator::Signals::new(wanted)?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match (signal, &opts.state_file) {
+                (SIGUSR1, _) => dump_clients(),
+                (SIGHUP, _) => {
+                    let config = opts.config.as_deref().unwrap();
+                    if let Err(e) = feed::reload(config, opts) {
+                        error!("Couldn't reload the files to serve: {e}");
+                    }
+                }
+                (_, state_file) => {
+                    info!(signal, "Shutting down");
+                    if let Some(path) = state_file {
+                        if let Err(e) = state::save(path) {
+                            error!("Couldn't save the clients: {e}");
+                            exit::exit(exit::Reason::StateSaveFailed);
+                        }
+                    }
+                    send_eof_frames("shutdown");
+                    exit::exit(exit::Reason::Signal(signal));
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Tell the framed clients that their streams are ending, and why (see
+/// `Client::end_stream`).  The clients with a send in flight are told when
+/// it finishes, so we give the runloops a moment to do that.
+fn send_eof_frames(event: &'static str) {
+    visit_clients(move |clients| {
+        for client in clients.values_mut() {
+            client.end_stream(event);
+        }
+    });
+    let deadline = Instant::now() + EOF_FRAME_TIMEOUT;
+    loop {
+        let waiting: usize =
+            visit_clients(|clients| clients.values().filter(|c| c.eof_frame.is_some()).count())
+                .into_iter()
+                .sum();
+        if waiting == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            debug!(waiting, "Gave up waiting to send the EOF frames");
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Log the state of every connected client
+fn dump_clients() {
+    let file_len = FILE_LENGTH.load(Ordering::Acquire);
+    // The runloops take a snapshot, and we do the logging
+    let snapshots = visit_clients(|clients| {
+        let snapshot = |(&client_id, client): (&ClientId, &Client)| {
+            // Only the io_uring backend uses the pipe
+            #[cfg(target_os = "linux")]
+            let bytes_in_pipe = client.bytes_in_pipe;
+            #[cfg(not(target_os = "linux"))]
+            let bytes_in_pipe = 0;
+            let peer = client.session.peer().to_owned();
+            (
+                client_id,
+                peer,
+                client.offset,
+                client.file_len().saturating_sub(client.offset),
+                bytes_in_pipe,
+                client.in_flight,
+            )
+        };
+        clients.iter().map(snapshot).collect::<Vec<_>>()
+    });
+    let n: usize = snapshots.iter().map(|x| x.len()).sum();
+    info!(file_len, "{n} clients connected");
+    for (client_id, peer, offset, lag, bytes_in_pipe, in_flight) in snapshots.into_iter().flatten()
+    {
+        info!(
+            client_id,
+            peer = peer.as_str(),
+            offset,
+            lag,
+            bytes_in_pipe,
+            in_flight,
+            "Client"
+        );
+    }
+}
+
+/// Queue a heartbeat for each client which is due one (see
+/// `Encoder::heartbeat`).  The runloops send them on their next pass, which
+/// is straight after visiting their clients.
+fn spawn_heartbeats(interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(HEARTBEAT_CHECK_INTERVAL.min(interval));
+        let n = visit_clients(move |clients| {
+            clients
+                .values_mut()
+                .filter_map(|c| c.encoder.as_mut())
+                .filter(|x| x.heartbeat(interval))
+                .count()
+        });
+        trace!(n = n.iter().sum::<usize>(), "Queued heartbeats");
+    });
+}
+
+/// Log a summary of what we've been doing every `interval`
+fn spawn_stats_reporter(interval: Duration) {
+    std::thread::spawn(move || {
+        let mut last_sent = BYTES_SENT.load(Ordering::Relaxed);
+        let mut last_errors = ErrorClass::ALL.map(ErrorClass::count);
+        loop {
+            std::thread::sleep(interval);
+            let sent = BYTES_SENT.load(Ordering::Relaxed);
+            let errors = ErrorClass::ALL.map(ErrorClass::count);
+            let file_len = FILE_LENGTH.load(Ordering::Acquire);
+            let max_lag = visit_clients(|clients| {
+                clients
+                    .values()
+                    .map(|c| c.file_len().saturating_sub(c.offset))
+                    .max()
+            })
+            .into_iter()
+            .flatten()
+            .max()
+            .unwrap_or(0);
+            info!(
+                clients = NUM_CLIENTS.load(Ordering::Acquire),
+                bytes_sent = sent - last_sent,
+                max_lag,
+                file_len,
+                transient_errors = errors[0] - last_errors[0],
+                closed_errors = errors[1] - last_errors[1],
+                fatal_errors = errors[2] - last_errors[2],
+                "Stats"
+            );
+            last_sent = sent;
+            last_errors = errors;
+        }
+    });
+}
+
+/// Reset the eventfd which the listener threads use to notify the runloop of
+/// new clients
+#[cfg(target_os = "linux")]
+fn read_eventfd() {
+    let mut buf = [0; 8];
+    match rustix::io::read(&*EVENTFD, &mut buf) {
+        Ok(8) | Err(Errno::AGAIN) => {
+            let x = u64::from_ne_bytes(buf);
+            trace!("Received notification of {x} new clients");
+        }
+        Ok(x) => error!("Incomplete read: {x}"),
+        Err(e) => error!("{e}"),
+    }
+}
+
+/// Wait until the file exists and open it.  If it already exists then this
+/// returns immediately.  If not, we watch the directory it'll be created in
+/// (on Linux), so that we can open it straight away.  If the directory can't
+/// be watched (eg. it doesn't exist yet) we poll every few seconds instead.
+fn wait_for_file(path: &Path) -> Result<File> {
+    let _g = info_span!("", path = %path.display()).entered();
+    #[cfg(target_os = "linux")]
+    let mut dir_watch = None;
+    // Check what it is before opening it, since opening a FIFO would block
+    let meta = loop {
+        match std::fs::metadata(path) {
+            Ok(x) => break x,
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    #[cfg(target_os = "linux")]
+                    {
+                        if dir_watch.is_none() {
+                            info!("Waiting for file to be created");
+                            // It may have been created before the watch was
+                            // set up, so check again before waiting
+                            dir_watch = Some(watch_dir(parent_dir(path)));
+                            continue;
+                        }
+                        wait_for_dir_event(dir_watch.as_ref().unwrap().as_ref());
+                    }
+                    #[cfg(not(target_os = "linux"))]
+                    {
+                        info!("Waiting for file to be created");
+                        std::thread::sleep(FILE_POLL_INTERVAL);
+                    }
+                }
+                _ => return Err(e.into()),
+            },
+        }
+    };
+    let special = meta.file_type().is_block_device() || meta.file_type().is_char_device();
+    let allow_special = OPTS.get().is_some_and(|x| x.allow_special);
+    if !meta.is_file() && !(special && allow_special) {
+        return Err(format!("{}: Not a file", path.display()).into());
+    }
+    let file = open_file(path)?;
+    if special {
+        // We read the file at arbitrary offsets, which not every device
+        // supports
+        rustix::fs::seek(&file, rustix::fs::SeekFrom::Current(0))
+            .map_err(|e| format!("{}: Can't be served: {e}", path.display()))?;
+        info!("Serving a device");
+    }
+    info!("Opened file");
+    Ok(file)
+}
+
+/// Open the file for reading, with O_NOATIME if we've been asked to
+fn open_file(path: &Path) -> std::io::Result<File> {
+    #[cfg(target_os = "linux")]
+    if OPTS.get().is_some_and(|x| x.noatime) {
+        use std::os::unix::fs::OpenOptionsExt;
+        match File::options()
+            .read(true)
+            .custom_flags(libc::O_NOATIME)
+            .open(path)
+        {
+            // Only the file's owner (or root) may use O_NOATIME
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                warn!("Not allowed to use O_NOATIME; opening the file without it");
+            }
+            x => return x,
+        }
+    }
+    File::open(path)
+}
+
+/// The directory which the file is in, or is going to be created in
+#[cfg(target_os = "linux")]
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    }
+}
+
+/// Watch a directory for new files being created in it
+#[cfg(target_os = "linux")]
+fn watch_dir(dir: &Path) -> Option<OwnedFd> {
+    let watch = || -> rustix::io::Result<OwnedFd> {
+        let ino_fd = inotify::init(inotify::CreateFlags::NONBLOCK)?;
+        inotify::add_watch(
+            &ino_fd,
+            dir,
+            inotify::WatchFlags::CREATE | inotify::WatchFlags::MOVED_TO,
+        )?;
+        Ok(ino_fd)
+    };
+    match watch() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            info!(dir = %dir.display(), "Can't watch the directory ({e}); polling instead");
+            None
+        }
+    }
+}
+
+/// Wait until something is created in the watched directory.  It might not
+/// be the file we're waiting for, so the caller has to check.  We give up
+/// after `FILE_POLL_INTERVAL`, in case the watch misses something.
+#[cfg(target_os = "linux")]
+fn wait_for_dir_event(dir_watch: Option<&OwnedFd>) {
+    let Some(ino_fd) = dir_watch else {
+        std::thread::sleep(FILE_POLL_INTERVAL);
+        return;
+    };
+    let timeout = i32::try_from(FILE_POLL_INTERVAL.as_millis()).unwrap();
+    let mut fds = [rustix::event::PollFd::new(
+        ino_fd,
+        rustix::event::PollFlags::IN,
+    )];
+    if let Err(e) = rustix::event::poll(&mut fds, timeout) {
+        debug!("Couldn't poll the directory watch: {e}");
+        std::thread::sleep(FILE_POLL_INTERVAL);
+        return;
+    }
+    // Throw away the events; we only wanted to be woken up
+    let mut buf = [const { MaybeUninit::uninit() }; 1024];
+    let mut evs = inotify::Reader::new(ino_fd, &mut buf);
+    while let Ok(ev) = evs.next() {
+        trace!("inotify event: {:?}", ev);
+    }
+}
+
+/// Bind a TCP socket.  If the host resolves to multiple addresses, we use the
+/// first one which works.
+fn bind_tcp_socket(host: &str, port: u16, dual_stack: bool) -> Result<TcpListener> {
+    let mut last_err = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match bind_tcp_addr(addr, dual_stack) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => {
+                debug!(%addr, "Couldn't bind: {e}");
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.map_or_else(|| format!("{host}: No addresses found").into(), Into::into))
+}
+
+fn bind_tcp_addr(addr: SocketAddr, dual_stack: bool) -> std::io::Result<TcpListener> {
+    use rustix::net::*;
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::INET,
+        SocketAddr::V6(_) => AddressFamily::INET6,
+    };
+    let sock = socket(family, SocketType::STREAM, None)?;
+    // std sets SO_REUSEADDR on its listeners, so we do the same
+    sockopt::set_socket_reuseaddr(&sock, true)?;
+    if addr.is_ipv6() {
+        // Don't rely on the system default (net.ipv6.bindv6only)
+        sockopt::set_ipv6_v6only(&sock, !dual_stack)?;
+    } else if dual_stack {
+        warn!(%addr, "--dual-stack only makes sense for IPv6 addresses");
+    }
+    bind(&sock, &addr)?;
+    listen(&sock, 128)?;
+    Ok(TcpListener::from(sock))
+}
+
+/// The sockets on which we listen for new connections
+#[derive(Default)]
+struct Listeners {
+    tcp: Option<TcpListener>,
+    unix: Option<UnixListener>,
+}
+
+impl Listeners {
+    /// Handle incoming client connections in separate threads
+    fn spawn_threads(&mut self, opts: &'static Opts) {
+        if let Some(listener) = self.tcp.take() {
+            std::thread::spawn(move || listen_for_tcp_clients(listener, opts));
+        }
+        if let Some(listener) = self.unix.take() {
+            std::thread::spawn(move || listen_for_unix_clients(listener, opts));
+        }
+    }
+}
+
+fn listen_for_tcp_clients(listener: TcpListener, opts: &'static Opts) {
+    for conn in listener.incoming() {
+        match conn {
+            Ok(conn) => {
+                #[cfg(target_os = "linux")]
+                if upgrade::forward(&conn, upgrade::Conn::Tcp) {
+                    continue;
+                }
+                if let Some(client_id) = tcp_client_id(&conn, opts) {
+                    spawn_client(conn, client_id, opts);
+                }
+            }
+            Err(e) => error!("Bad connection: {e}"),
+        }
+    }
+    error!("Listening socket was closed!");
+    exit::exit(exit::Reason::ListenerClosed);
+}
+
+/// Vet a newly-accepted TCP connection.  If it's allowed, returns the ID by
+/// which we'll know the client.
+fn tcp_client_id(conn: &TcpStream, opts: &Opts) -> Option<ClientId> {
+    let peer = match conn.peer_addr() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Bad connection: {e}");
+            return None;
+        }
+    };
+    if !is_allowed(peer.ip(), opts) {
+        info!(%peer, "Rejecting connection from disallowed address");
+        return None;
+    }
+    let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    info!(client_id, %peer, "Accepted connection");
+    if let Err(e) = set_tcp_options(conn, opts) {
+        warn!(client_id, "Couldn't set socket options: {e}");
+    }
+    Some(client_id)
+}
+
+fn set_tcp_options(conn: &TcpStream, opts: &Opts) -> std::io::Result<()> {
+    use rustix::net::sockopt;
+    if let Some(secs) = opts.tcp_keepalive {
+        let interval = Duration::from_secs(secs);
+        sockopt::set_socket_keepalive(conn, true)?;
+        sockopt::set_tcp_keepidle(conn, interval)?;
+        sockopt::set_tcp_keepintvl(conn, interval)?;
+    }
+    if opts.nodelay {
+        sockopt::set_tcp_nodelay(conn, true)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(secs) = opts.tcp_user_timeout {
+        sockopt::set_tcp_user_timeout(conn, secs.saturating_mul(1000))?;
+    }
+    Ok(())
+}
+
+fn is_allowed(ip: IpAddr, opts: &Opts) -> bool {
+    if opts.deny.iter().any(|cidr| cidr.contains(ip)) {
+        return false;
+    }
+    opts.allow.is_empty() || opts.allow.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// The class of a client which doesn't ask for one: bulk if it's connecting
+/// from one of the --bulk addresses, and realtime otherwise
+fn default_class(conn: &OwnedFd, opts: &Opts) -> Class {
+    let ip = match rustix::net::getpeername(conn) {
+        Ok(Some(SocketAddrAny::V4(x))) => IpAddr::V4(*x.ip()),
+        Ok(Some(SocketAddrAny::V6(x))) => IpAddr::V6(*x.ip()),
+        _ => return Class::Realtime,
+    };
+    if opts.bulk.iter().any(|cidr| cidr.contains(ip)) {
+        Class::Bulk
+    } else {
+        Class::Realtime
+    }
+}
+
+/// Bind a unix socket at the given path.  If there's a stale socket left
+/// over from a previous run then we remove it first; if there's anything
+/// else at that path we leave it alone and let bind() fail.
+fn bind_unix_socket(path: &Path) -> Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            info!(path = %path.display(), "Removing stale socket");
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+fn listen_for_unix_clients(listener: UnixListener, opts: &'static Opts) {
+    for conn in listener.incoming() {
+        match conn {
+            Ok(conn) => {
+                #[cfg(target_os = "linux")]
+                if upgrade::forward(&conn, upgrade::Conn::Unix) {
+                    continue;
+                }
+                spawn_client(conn, unix_client_id(), opts);
+            }
+            Err(e) => error!("Bad connection: {e}"),
+        }
+    }
+    error!("Listening socket was closed!");
+    exit::exit(exit::Reason::ListenerClosed);
+}
+
+fn unix_client_id() -> ClientId {
+    let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    info!(client_id, "Accepted connection on unix socket");
+    client_id
+}
+
+/// Read the client's header (on a dedicated thread, since it may take a
+/// while) and then hand the client over to the runloop.
+fn spawn_client(
+    mut conn: impl Read + Write + AsFd + Into<OwnedFd> + Send + 'static,
+    client_id: ClientId,
+    opts: &'static Opts,
+) {
+    let Some(slot) = admit_client(&mut conn, client_id, opts) else {
+        return;
+    };
+    std::thread::spawn(move || {
+        let _g = info_span!("", client_id).entered();
+        match Client::new(conn, slot, opts) {
+            Ok(Some(client)) => {
+                trace!("Prepared client: {client:?}");
+                add_client(client_id, client);
+            }
+            Ok(None) => info!("Answered command"),
+            Err(e) => error!("{e}"),
+        }
+    });
+}
+
+/// Reads from `conn`, but gives up once it's been `HEADER_TIMEOUT` since the
+/// reader was made.  This stops clients which never finish their header from
+/// holding on to their slots.
+struct HeaderReader<T> {
+    conn: T,
+    deadline: Instant,
+}
+
+impl<T> HeaderReader<T> {
+    fn new(conn: T) -> HeaderReader<T> {
+        HeaderReader {
+            conn,
+            deadline: Instant::now() + HEADER_TIMEOUT,
+        }
+    }
+}
+
+impl<T: Read + AsFd> Read for HeaderReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        let timeout = i32::try_from(left.as_millis()).unwrap_or(i32::MAX);
+        let mut fds = [rustix::event::PollFd::new(
+            &self.conn,
+            rustix::event::PollFlags::IN,
+        )];
+        if rustix::event::poll(&mut fds, timeout)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out waiting for the header",
+            ));
+        }
+        self.conn.read(buf)
+    }
+}
+
+/// Take a slot for the new client, or turn it away if we're at capacity
+fn admit_client(conn: &mut impl Write, client_id: ClientId, opts: &Opts) -> Option<ClientSlot> {
+    let slot = ClientSlot::acquire(opts.max_clients);
+    if slot.is_none() {
+        warn!(client_id, "Too many clients; rejecting connection");
+        if let Err(e) = conn.write_all(b"ERR too many clients\n") {
+            debug!(client_id, "Couldn't send error: {e}");
+        }
+    }
+    slot
+}
+
+/// How many runloops there are, each with its own shard of the clients
+fn num_shards() -> usize {
+    NUM_SHARDS.load(Ordering::Acquire)
+}
+
+/// Something for a runloop to do.  These are handled when it's woken.
+enum Message {
+    /// A client which is ready to be served
+    NewClient(ClientId, Client),
+    /// Something which needs to look at the clients (eg. to log them).  It's
+    /// run on the runloop, in between passes.
+    Visit(Box<dyn FnOnce(&mut Clients) + Send>),
+}
+
+struct Inbox {
+    tx: Sender<Message>,
+    rx: Mutex<Option<Receiver<Message>>>,
+}
+
+impl Inbox {
+    fn new() -> Inbox {
+        let (tx, rx) = std::sync::mpsc::channel();
+        Inbox {
+            tx,
+            rx: Mutex::new(Some(rx)),
+        }
+    }
+}
+
+/// Take the receiving end of a runloop's inbox.  Each runloop does this once,
+/// when it starts.
+fn take_inbox(shard: usize) -> Receiver<Message> {
+    RUNNING.store(true, Ordering::Release);
+    let rx = INBOXES[shard].rx.lock().unwrap().take();
+    rx.expect("The inbox was already taken")
+}
+
+/// Handle everything which has arrived in the runloop's inbox
+fn handle_messages(inbox: &Receiver<Message>, clients: &mut Clients) {
+    for msg in inbox.try_iter() {
+        match msg {
+            Message::NewClient(client_id, client) => {
+                clients.insert(client_id, client);
+            }
+            Message::Visit(f) => f(clients),
+        }
+    }
+}
+
+/// Hand a client over to the runloop which owns its shard
+fn add_client(client_id: ClientId, client: Client) {
+    let shard = (client_id % num_shards() as u64) as usize;
+    // The receiving end lives as long as the process
+    let _ = INBOXES[shard]
+        .tx
+        .send(Message::NewClient(client_id, client));
+    wake_runloop();
+}
+
+/// Run `f` on each runloop's clients, and collect the results.  This blocks
+/// until every runloop has got round to it, so it mustn't be called from a
+/// runloop.  If the runloops haven't started yet then there aren't any
+/// clients, and `f` is run once on an empty table instead.
+fn visit_clients<R: Send + 'static>(
+    f: impl Fn(&mut Clients) -> R + Send + Sync + 'static,
+) -> Vec<R> {
+    if !RUNNING.load(Ordering::Acquire) {
+        return vec![f(&mut Clients::new())];
+    }
+    let f = Arc::new(f);
+    let (tx, rx) = std::sync::mpsc::channel();
+    for inbox in &INBOXES[..num_shards()] {
+        let (f, tx) = (f.clone(), tx.clone());
+        let visit = move |clients: &mut Clients| {
+            let _ = tx.send(f(clients));
+        };
+        let _ = inbox.tx.send(Message::Visit(Box::new(visit)));
+    }
+    drop(tx);
+    wake_runloop();
+    rx.iter().collect()
+}
+
+/// Let the runloop know that there's a new client, or that the source has
+/// grown.  With more than one io_uring runloop, they're all woken.
+fn wake_runloop() {
+    #[cfg(target_os = "linux")]
+    rustix::io::write(&*EVENTFD, &1u64.to_ne_bytes()).unwrap();
+    #[cfg(not(target_os = "linux"))]
+    kqueue::wake().unwrap();
+    trace!("Woke up the runloop");
+}
+
+/// Decides the order in which a runloop serves its clients, and how much
+/// each of them may be sent, on each pass.  Realtime clients go before bulk
+/// ones.  Within each class, each pass starts one client further along than
+/// the last, so that when there isn't enough to go round (eg. with
+/// --max-total-rate) it isn't always the same clients who miss out.
+struct Scheduler {
+    /// The most a client may be sent in one pass
+    chunk_size: usize,
+    /// The client to start with next time
+    next: ClientId,
+}
+
+impl Scheduler {
+    fn new(opts: &Opts) -> Scheduler {
+        let chunk_size = opts.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        Scheduler {
+            chunk_size: usize::try_from(chunk_size).unwrap_or(usize::MAX),
+            next: 0,
+        }
+    }
+
+    /// The clients to visit on this pass, in order
+    fn order(&mut self, clients: &Clients) -> Vec<ClientId> {
+        let mut order: Vec<ClientId> = clients
+            .range(self.next..)
+            .chain(clients.range(..self.next))
+            .map(|(&client_id, _)| client_id)
+            .collect();
+        if let Some(&x) = order.get(1) {
+            self.next = x;
+        }
+        // The sort is stable, so each class still takes turns
+        order.sort_by_key(|x| clients[x].class);
+        order
+    }
+}
+
+/// Counts towards the number of connected clients for as long as it's alive.
+/// Clients hold one of these from the moment they're accepted.
+#[derive(Debug)]
+struct ClientSlot(());
+
+impl ClientSlot {
+    fn acquire(max_clients: Option<usize>) -> Option<ClientSlot> {
+        NUM_CLIENTS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max_clients.unwrap_or(usize::MAX)).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ClientSlot(()))
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        NUM_CLIENTS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug)]
+struct Client {
+    conn: OwnedFd,
+    offset: usize,
+    #[cfg(target_os = "linux")]
+    bytes_in_pipe: usize,
+    in_flight: bool,
+    rate_limit: Option<TokenBucket>,
+    /// The number of sends in a row which have failed with transient errors
+    retries: u32,
+    /// When the client may be sent data again, if its last send failed with
+    /// a transient error
+    retry_at: Option<Instant>,
+    /// Tokens taken from the total rate limit for the in-flight splice
+    #[cfg(target_os = "linux")]
+    reserved: usize,
+    /// Whether the io_uring backend is polling the socket for hangups
+    #[cfg(target_os = "linux")]
+    watching_hangup: bool,
+    /// The number of requests on the ring which refer to this client and
+    /// haven't completed.  The io_uring backend doesn't drop a client until
+    /// this is zero, since the kernel may still be using its socket, pipe, or
+    /// buffers.
+    #[cfg(target_os = "linux")]
+    ops: usize,
+    /// Whether TCP_CORK is set on the socket.  `None` if the socket doesn't
+    /// support it (ie. it's a unix socket).
+    #[cfg(target_os = "linux")]
+    corked: Option<bool>,
+    /// How far into the file we've asked the kernel to read ahead of the
+    /// client (see `fadvise`)
+    #[cfg(target_os = "linux")]
+    readahead: usize,
+    // The pipe is only used by the io_uring backend.  It's returned to the
+    // pool when the client is dropped.
+    #[cfg(target_os = "linux")]
+    pipe: ManuallyDrop<pipe_pool::Pipe>,
+    /// Set if the client's data needs transforming on the way (eg. it asked
+    /// for compression).  `offset` then counts the bytes which have been
+    /// encoded, some of which may not have been sent yet.
+    encoder: Option<Encoder>,
+    /// An EOF frame which is waiting for the send in flight to finish (see
+    /// `end_stream`)
+    eof_frame: Option<&'static str>,
+    class: Class,
+    /// The file which the client is reading, if it connected to a --serve
+    /// port.  `None` means the main file.
+    feed: Option<Arc<Feed>>,
+    session: Session,
+    /// Lasts as long as the client is connected.  The sends are recorded as
+    /// events within it.
+    span: Span,
+    _slot: ClientSlot,
+}
+
+impl Client {
+    fn new(
+        mut conn: impl Read + Write + AsFd + Into<OwnedFd>,
+        slot: ClientSlot,
+        opts: &Opts,
+    ) -> Result<Option<Client>> {
+        info!("Connected");
+        // The first thing the client will do is send a header
+        let mut buf = String::new();
+        let header = HeaderReader::new(&mut conn).take(MAX_HEADER_LEN as u64);
+        let mut rdr = std::io::BufReader::new(header);
+        rdr.read_line(&mut buf)?;
+        // A multi-line header ends with an empty line
+        if header::is_multiline(&buf) {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if rdr.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    break;
+                }
+                buf.push_str(&line);
+            }
+        }
+        if rdr.get_ref().limit() == 0 {
+            return Err("Header is too long".into());
+        }
+        // The client may have sent more (eg. cursor commits) straight after
+        let rest = rdr.buffer().to_vec();
+        Client::from_header(conn.into(), buf.parse()?, rest, slot, None, opts)
+    }
+
+    /// Set up a client whose header has been read.  If the client sent a
+    /// one-shot command instead, we answer it and return `None`.  `rest` is
+    /// whatever was read past the end of the header.  `feed` is the file it
+    /// wants, if it connected to a --serve port.
+    fn from_header(
+        conn: OwnedFd,
+        header: Header,
+        rest: Vec<u8>,
+        slot: ClientSlot,
+        feed: Option<Arc<Feed>>,
+        opts: &Opts,
+    ) -> Result<Option<Client>> {
+        if let Some(version) = header.version.filter(|&x| x != header::PROTOCOL_VERSION) {
+            rustix::io::write(&conn, b"ERR unsupported version\n")?;
+            return Err(format!("Client asked for protocol version {version}").into());
+        }
+        if !is_authorized(header.auth.as_deref(), opts) {
+            rustix::io::write(&conn, b"ERR unauthorized\n")?;
+            return Err("Client failed to authenticate".into());
+        }
+        // Only once we know who it is, since this says how we're configured
+        if header.version.is_some() {
+            write_all(&conn, capabilities(opts).as_bytes())?;
+        }
+        // These are all about the main file
+        if feed.is_some()
+            && (header.command.is_some() || header.cursor.is_some() || header.start.is_indexed())
+        {
+            rustix::io::write(&conn, b"ERR not supported on this port\n")?;
+            return Err("Client asked for something which only the main port supports".into());
+        }
+        if feed.as_ref().is_some_and(|x| x.is_removed()) {
+            rustix::io::write(&conn, b"ERR file removed\n")?;
+            return Err("Client connected to a file which has since been removed".into());
+        }
+        if let Some(command) = &header.command {
+            debug!(?command, "Received command");
+            command::answer(command, &conn)?;
+            return Ok(None);
+        }
+        if header.compress.is_some() && !opts.allow_compression {
+            rustix::io::write(&conn, b"ERR compression not allowed\n")?;
+            return Err("Client asked for compression, but it isn't allowed".into());
+        }
+        let mut encoder = match header.compress {
+            None => None,
+            Some(Compression::Zstd) => {
+                info!("Compressing the stream with zstd");
+                Some(Encoder::zstd()?)
+            }
+        };
+        let committed = match &header.cursor {
+            Some(_) if !cursor::enabled() => {
+                rustix::io::write(&conn, b"ERR cursors not enabled\n")?;
+                return Err("Client asked for a cursor, but there's no --cursor-file".into());
+            }
+            Some(name) => cursor::get(name).or_else(|| state::take(name)),
+            None => None,
+        };
+        // The main file may not exist yet
+        let source: Option<&dyn Source> = match &feed {
+            Some(feed) => Some(&feed.file),
+            None => SOURCE.get().map(|x| &**x),
+        };
+        let file_len = || match &feed {
+            Some(feed) => feed.len(),
+            None => FILE_LENGTH.load(Ordering::Acquire),
+        };
+        if header.strict && committed.is_none() {
+            if let Err(file_len) = seek::check_strict_in(&header.start, file_len()) {
+                rustix::io::write(
+                    &conn,
+                    format!("ERR before start of file {file_len}\n").as_bytes(),
+                )?;
+                return Err(
+                    format!("{} goes back past the start of the file", header.start).into(),
+                );
+            }
+        }
+        let offset = match committed {
+            Some(x) => x,
+            None => seek::resolve_in(&header.start, source, file_len(), opts)?,
+        };
+        let policy = header
+            .beyond_eof
+            .or(opts.beyond_eof)
+            .unwrap_or(BeyondEof::Wait);
+        let offset = match seek::beyond_eof_in(offset, policy, source.map(|_| file_len())) {
+            Ok(x) => x,
+            Err(file_len) => {
+                rustix::io::write(
+                    &conn,
+                    format!("ERR beyond end of file {file_len}\n").as_bytes(),
+                )?;
+                return Err(format!("Offset {offset} is beyond the end of the file").into());
+            }
+        };
+        let delim = seek::delim(&header.start, opts);
+        let offset = match (header.align, source) {
+            (Some(align), Some(source)) => seek::align(source, offset, file_len(), align, delim)?,
+            _ => offset,
+        };
+        // With --memory-buffer, old data may have gone already
+        let first = source.map_or(0, |x| x.first());
+        if offset < first {
+            rustix::io::write(&conn, format!("ERR out of window {first}\n").as_bytes())?;
+            return Err(format!("Offset {offset} is out of the window").into());
+        }
+        let requested = match &header.cursor {
+            Some(name) => format!("cursor {name}"),
+            None => header.start.to_string(),
+        };
+        if header.hello {
+            write_all(&conn, hello(offset, source, file_len()).as_bytes())?;
+        }
+        if let Some(pattern) = header.grep {
+            info!(%pattern, "Only sending matching lines");
+            let filter = Filter::Grep(pattern);
+            encoder = Some(
+                encoder
+                    .unwrap_or_else(Encoder::raw)
+                    .delim(delim)
+                    .filter(filter),
+            );
+        }
+        // This comes after grep, so it samples the matching lines
+        if let Some(every) = header.sample {
+            info!(every, "Only sending a sample of the lines");
+            let filter = Filter::Sample { every, seen: 0 };
+            encoder = Some(
+                encoder
+                    .unwrap_or_else(Encoder::raw)
+                    .delim(delim)
+                    .filter(filter),
+            );
+        }
+        // Clients which start at (or in) the first line get it anyway
+        if let (true, Some(source)) = (header.csv_header, source) {
+            match seek::first_line(source, file_len(), delim)? {
+                Some(line) if offset >= line.len() => {
+                    debug!(len = line.len(), "Sending the CSV header");
+                    match &mut encoder {
+                        Some(encoder) => encoder.prefix(&line)?,
+                        None => write_all(&conn, &line)?,
+                    }
+                }
+                _ => (),
+            }
+        }
+        if let Some(name) = header.cursor {
+            info!(cursor = name, "Using a cursor");
+            cursor::spawn_reader(name, &conn, rest)?;
+        }
+        let mut client = Client::start(conn, requested, offset, encoder, slot, opts)?;
+        // The header can demote a client, but not promote one which the
+        // server has put in the bulk class
+        if let Some(class) = header.class {
+            client.class = client.class.max(class);
+        }
+        client.feed = feed;
+        debug!(class = ?client.class, "Assigned a class");
+        Ok(Some(client))
+    }
+
+    /// Set up a client whose request has been vetted.  This is shared by all
+    /// the protocols.  `requested` describes where the client asked to start,
+    /// for the access log.
+    fn start(
+        conn: OwnedFd,
+        requested: String,
+        offset: usize,
+        encoder: Option<Encoder>,
+        slot: ClientSlot,
+        opts: &Opts,
+    ) -> Result<Client> {
+        info!("Starting from initial offset {offset}");
+        let encoder = encoder.or_else(|| CANT_SPLICE.load(Ordering::Relaxed).then(Encoder::raw));
+
+        #[cfg(target_os = "linux")]
+        let pipe = pipe_pool::take(opts)?;
+        let class = default_class(&conn, opts);
+        let session = Session::new(access_log::peer(&conn), requested, offset);
+        let span = info_span!("session", peer = session.peer(), start = offset);
+        Ok(Client {
+            conn,
+            offset,
+            #[cfg(target_os = "linux")]
+            bytes_in_pipe: 0,
+            in_flight: false,
+            rate_limit: opts.max_rate_per_client.map(TokenBucket::new),
+            retries: 0,
+            retry_at: None,
+            #[cfg(target_os = "linux")]
+            reserved: 0,
+            #[cfg(target_os = "linux")]
+            watching_hangup: false,
+            #[cfg(target_os = "linux")]
+            ops: 0,
+            #[cfg(target_os = "linux")]
+            corked: Some(false),
+            #[cfg(target_os = "linux")]
+            readahead: 0,
+            #[cfg(target_os = "linux")]
+            pipe: ManuallyDrop::new(pipe),
+            encoder,
+            eof_frame: None,
+            class,
+            feed: None,
+            session,
+            span,
+            _slot: slot,
+        })
+    }
+
+    /// Drop the client, recording why in the access log
+    fn disconnect(mut self, reason: impl std::fmt::Display) {
+        self.session.end(reason);
+    }
+
+    /// Queue a control event for a framed client, saying what's happened to
+    /// the stream and how far into the file it had got (see
+    /// `Encoder::control`).  Returns whether an event was queued.
+    fn queue_control(&mut self, event: &str) -> bool {
+        let offset = self.offset.to_string();
+        self.encoder
+            .as_mut()
+            .is_some_and(|x| x.control(event, &offset))
+    }
+
+    /// Like `send_eof_frame`, but if a send is in flight the frame is put off
+    /// until it finishes.  The runloop then sends it with
+    /// `send_queued_eof_frame`.
+    fn end_stream(&mut self, event: &'static str) {
+        if self.in_flight && self.encoder.is_some() {
+            self.eof_frame = Some(event);
+        } else {
+            self.send_eof_frame(event);
+        }
+    }
+
+    /// Send the EOF frame which `end_stream` put off, if there is one.  Only
+    /// call this when no send is in flight.
+    fn send_queued_eof_frame(&mut self) {
+        if let Some(event) = self.eof_frame.take() {
+            self.send_eof_frame(event);
+        }
+    }
+
+    /// Tell a framed client why its stream is ending, so that it doesn't have
+    /// to guess from the connection closing.  This is best-effort: we don't
+    /// wait for there to be room in the socket.  It mustn't be called while
+    /// the io_uring backend is sending the client's encoded data.
+    fn send_eof_frame(&mut self, event: &str) {
+        if !self.queue_control(event) {
+            return;
+        }
+        let encoder = self.encoder.as_mut().unwrap();
+        while !encoder.pending().is_empty() {
+            match rustix::net::send(&self.conn, encoder.pending(), SendFlags::DONTWAIT) {
+                Ok(n) => encoder.consume(n),
+                Err(e) => {
+                    debug!(event, "Couldn't send the control event: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The file has shrunk to less than the client has been sent.  Deal with
+    /// it according to --on-truncate, and say whether the client should be
+    /// disconnected.
+    fn truncated(&mut self) -> bool {
+        match truncate_policy() {
+            FilePolicy::Exit => {
+                spawn_exit("truncated", exit::Reason::FileTruncated);
+                false
+            }
+            FilePolicy::Reopen => {
+                self.queue_control("truncated");
+                self.offset = 0;
+                #[cfg(target_os = "linux")]
+                {
+                    self.readahead = 0;
+                }
+                false
+            }
+            FilePolicy::DisconnectClients | FilePolicy::Linger | FilePolicy::Drain => {
+                self.send_eof_frame("truncated");
+                true
+            }
+        }
+    }
+
+    /// Send the client its data with read() and write() from now on, since
+    /// the file can't be spliced (or sendfile()d) from
+    fn stop_splicing(&mut self) {
+        if !CANT_SPLICE.swap(true, Ordering::Relaxed) {
+            warn!("The file doesn't support splice(); copying the data through userspace instead");
+        }
+        self.encoder = Some(Encoder::raw());
+    }
+
+    /// A send failed with a transient error, so hold off on the next one for
+    /// a while.  Returns false if there have been too many in a row, in which
+    /// case the client should be disconnected.
+    fn back_off(&mut self, e: Errno) -> bool {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            warn!(retries = MAX_RETRIES, "{e}; giving up");
+            return false;
+        }
+        let delay = RETRY_BACKOFF * 2_u32.pow(self.retries - 1);
+        debug!(retries = self.retries, ?delay, "{e}; retrying");
+        self.retry_at = Some(Instant::now() + delay);
+        true
+    }
+
+    /// Whether the client is waiting to retry after a transient error
+    fn backing_off(&mut self) -> bool {
+        match self.retry_at {
+            Some(t) if t > Instant::now() => true,
+            _ => {
+                self.retry_at = None;
+                false
+            }
+        }
+    }
+
+    /// The length of the file which the client is reading
+    fn file_len(&self) -> usize {
+        match &self.feed {
+            Some(feed) => feed.len(),
+            None => FILE_LENGTH.load(Ordering::Acquire),
+        }
+    }
+
+    /// Whether the client has been sent everything up to `file_len`
+    fn caught_up(&self, file_len: usize) -> bool {
+        self.offset >= file_len && self.encoder.as_ref().is_none_or(|c| c.pending().is_empty())
+    }
+
+    /// The number of bytes this client may be sent right now, taking the rate
+    /// limits into account.  Zero means the client is being throttled.
+    fn allowance(&mut self, total_rate: &mut Option<TokenBucket>) -> usize {
+        let mut n = usize::MAX;
+        if let Some(bucket) = &mut self.rate_limit {
+            n = n.min(bucket.available());
+        }
+        if let Some(bucket) = total_rate {
+            n = n.min(bucket.available());
+        }
+        n
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.session.record(self.offset);
+        // SAFETY: the pipe isn't touched again after this
+        #[cfg(target_os = "linux")]
+        let pipe = unsafe { ManuallyDrop::take(&mut self.pipe) };
+        // The io_uring backend doesn't drop a client until its requests
+        // have completed, and the others don't use the pipe, so nothing is
+        // going to write to it now
+        #[cfg(target_os = "linux")]
+        pipe_pool::put(pipe);
+    }
+}
+
+/// How a failed send is dealt with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    /// Worth trying again after a pause (eg. EINTR, or the kernel being
+    /// short of memory)
+    Transient = 0,
+    /// The client went away.  It's disconnected.
+    Closed = 1,
+    /// Anything else.  The client is disconnected, and we log an error.
+    Fatal = 2,
+}
+
+impl ErrorClass {
+    const ALL: [ErrorClass; 3] = [ErrorClass::Transient, ErrorClass::Closed, ErrorClass::Fatal];
+
+    /// Classify the error, and count it
+    fn of(e: Errno) -> ErrorClass {
+        let class = match e {
+            Errno::AGAIN | Errno::INTR | Errno::NOBUFS | Errno::NOMEM => ErrorClass::Transient,
+            Errno::PIPE | Errno::CONNRESET => ErrorClass::Closed,
+            _ => ErrorClass::Fatal,
+        };
+        SEND_ERRORS[class as usize].fetch_add(1, Ordering::Relaxed);
+        class
+    }
+
+    /// The number of errors of this class since tailsrv started
+    fn count(self) -> u64 {
+        SEND_ERRORS[self as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// Write the whole buffer to a socket which hasn't been handed to the runloop
+/// yet (so it's still blocking)
+fn write_all(conn: &OwnedFd, mut buf: &[u8]) -> rustix::io::Result<()> {
+    while !buf.is_empty() {
+        let n = rustix::io::write(conn, buf)?;
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+/// The reply to `v2`: the protocol version, followed by the header items
+/// which this server understands.  Items which depend on the server's
+/// options are only included if they've been enabled.
+fn capabilities(opts: &Opts) -> String {
+    let mut caps = format!("v{} {}", header::PROTOCOL_VERSION, header::ITEMS);
+    if opts.auth_token.is_some() {
+        caps.push_str(" auth");
+    }
+    if opts.allow_compression {
+        caps.push_str(" compress");
+    }
+    if cursor::enabled() {
+        caps.push_str(" cursor");
+    }
+    caps.push('\n');
+    caps
+}
+
+/// The reply to `hello`: a line of JSON saying where the client is starting,
+/// how long the file is, and which file it is (if it's a file)
+fn hello(offset: usize, source: Option<&dyn Source>, file_len: usize) -> String {
+    let inode = source
+        .and_then(|x| x.as_file())
+        .and_then(|x| x.metadata().ok())
+        .map_or("null".into(), |x| x.ino().to_string());
+    format!("{{\"offset\":{offset},\"file_len\":{file_len},\"inode\":{inode}}}\n")
+}
+
+/// Check the client's token against --auth-token-file, if it was given
+fn is_authorized(token: Option<&str>, opts: &Opts) -> bool {
+    match &opts.auth_token {
+        None => true,
+        Some(expected) => {
+            token.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+        }
+    }
+}
+
+/// Compare two byte strings without leaking (via timing) how much of a prefix
+/// they share
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Send traces to stderr (or journald, if `--journald` was given)
+pub fn log_init(opts: &Opts) {
+    let subscriber = tracing_subscriber::registry();
+
+    // Respect RUST_LOG, falling back to INFO
+    let filter = EnvFilter::builder()
+        .with_default_directive(Level::INFO.into())
+        .from_env_lossy();
+    let subscriber = subscriber.with(filter);
+
+    // Spans are exported as well as logged
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(otel::layer());
+
+    #[cfg(feature = "tracing-journald")]
+    if opts.journald {
+        let subscriber = subscriber.with(tracing_journald::layer().unwrap());
+        return subscriber.init();
+    }
+
+    let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    if opts.log_format == Some(LogFormat::Json) {
+        return subscriber.with(layer.json()).init();
+    }
+    let subscriber = subscriber.with(layer);
+    subscriber.init();
+}
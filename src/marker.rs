@@ -0,0 +1,79 @@
+//! Support for `--commit-marker`, for writers which append each record in
+//! more than one write.  Clients are only sent the file up to the end of the
+//! last marker, so they never see a record which is only partly written.
+//! The marker is whatever the writer puts at the end of each record: a
+//! newline for a log, or a trailer of its own.
+//!
+//! Each time the file grows we only search the new part, so the cost is one
+//! extra read of everything that's appended (which is usually still in the
+//! page cache).
+
+use crate::source::Source;
+use crate::OPTS;
+use std::sync::Mutex;
+use tracing::*;
+
+/// The most of the file we read at a time, when looking for markers
+const SCAN_CHUNK: usize = 64 * 1024;
+
+struct Scan {
+    /// Every marker which starts before this has been found
+    next: usize,
+    /// The end of the last marker
+    committed: usize,
+}
+
+static SCAN: Mutex<Scan> = Mutex::new(Scan {
+    next: 0,
+    committed: 0,
+});
+
+fn marker() -> Option<&'static [u8]> {
+    OPTS.get()?.commit_marker.as_deref()
+}
+
+pub fn enabled() -> bool {
+    marker().is_some()
+}
+
+/// Given that the source is now `len` bytes long, how much of it may be
+/// sent.  Without --commit-marker, that's all of it.
+pub fn committed(source: &dyn Source, len: usize) -> std::io::Result<usize> {
+    let Some(marker) = marker() else {
+        return Ok(len);
+    };
+    let mut scan = SCAN.lock().unwrap();
+    if len < scan.next.max(scan.committed) {
+        debug!("The file has shrunk; looking for commit markers from the start");
+        reset_locked(&mut scan);
+    }
+    if len - scan.next < marker.len() {
+        return Ok(scan.committed);
+    }
+    let mut buf = vec![0; (len - scan.next).min(SCAN_CHUNK.max(marker.len()))];
+    while len - scan.next >= marker.len() {
+        let want = (len - scan.next).min(buf.len());
+        let n = source.read_at(&mut buf[..want], scan.next)?;
+        if n < marker.len() {
+            break;
+        }
+        if let Some(i) = buf[..n].windows(marker.len()).rposition(|x| x == marker) {
+            scan.committed = scan.next + i + marker.len();
+        }
+        // A marker may straddle the end of what we read
+        scan.next += n - (marker.len() - 1);
+    }
+    trace!(len, committed = scan.committed, "Looked for commit markers");
+    Ok(scan.committed)
+}
+
+/// The file has been replaced, so the markers we've found were in the old
+/// one
+pub fn reset() {
+    reset_locked(&mut SCAN.lock().unwrap());
+}
+
+fn reset_locked(scan: &mut Scan) {
+    scan.next = 0;
+    scan.committed = 0;
+}
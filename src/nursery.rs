@@ -0,0 +1,188 @@
+//! Clients which have connected but haven't finished sending their header.
+//!
+//! The io_uring backend reads headers using `Recv` requests on the ring, so
+//! that clients which are slow to send their header don't each tie up a
+//! thread.  Once we have a whole line, the client is handed over to the
+//! runloop which owns its shard.  Each `Recv` is linked to a timeout, so
+//! that a client which doesn't finish its header within `HEADER_TIMEOUT`
+//! doesn't keep its slot.
+//! If finding the client's starting position means reading through the file,
+//! that's done on a thread of its own, so as not to hold up the runloop.  The
+//! same goes for answering one-shot commands.
+
+use crate::feed::Feed;
+use crate::header::{self, Header};
+use crate::uring::UserData;
+use crate::{add_client, admit_client, Client, ClientId, ClientSlot, Opts};
+use crate::{HEADER_TIMEOUT, MAX_HEADER_LEN};
+use rustix::fd::{AsRawFd, OwnedFd};
+use rustix::io::Errno;
+use rustix_uring::squeue::Entry;
+use rustix_uring::types::Timespec;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::*;
+
+#[derive(Default)]
+pub struct Nursery {
+    clients: HashMap<ClientId, Nursling>,
+}
+
+struct Nursling {
+    conn: OwnedFd,
+    slot: ClientSlot,
+    /// The file it wants, if it connected to a --serve port
+    feed: Option<Arc<Feed>>,
+    // The kernel writes into this while a `Recv` is in flight, so it's boxed
+    // to keep it in place when the map is resized
+    buf: Box<[u8; MAX_HEADER_LEN]>,
+    len: usize,
+    /// When we give up on the header
+    deadline: Instant,
+    // How long the next `Recv` may take.  Likewise boxed, since the kernel
+    // reads it when the request is submitted.
+    timeout: Box<Timespec>,
+}
+
+impl Nursery {
+    /// Take in a newly-accepted connection, and start reading its header
+    pub fn admit(
+        &mut self,
+        mut conn: impl Write + Into<OwnedFd>,
+        client_id: ClientId,
+        feed: Option<Arc<Feed>>,
+        reqs: &mut VecDeque<Entry>,
+        opts: &Opts,
+    ) {
+        let Some(slot) = admit_client(&mut conn, client_id, opts) else {
+            return;
+        };
+        info!(client_id, feed = feed.as_ref().map(|x| x.id), "Connected");
+        let mut nursling = Nursling {
+            conn: conn.into(),
+            slot,
+            feed,
+            buf: Box::new([0; MAX_HEADER_LEN]),
+            len: 0,
+            deadline: Instant::now() + HEADER_TIMEOUT,
+            timeout: Box::new(Timespec::new()),
+        };
+        reqs.extend(nursling.recv(client_id));
+        self.clients.insert(client_id, nursling);
+    }
+
+    /// A `Recv` has completed.  If the header isn't complete yet, we ask for
+    /// more of it.
+    pub fn handle_recv(
+        &mut self,
+        client_id: ClientId,
+        result: Result<usize, Errno>,
+        reqs: &mut VecDeque<Entry>,
+        opts: &'static Opts,
+    ) {
+        let _g = info_span!("", client_id).entered();
+        let Some(mut nursling) = self.clients.remove(&client_id) else {
+            return;
+        };
+        match result {
+            Ok(0) => {
+                info!("Socket closed before sending a header");
+                return;
+            }
+            Ok(n) => nursling.len += n,
+            // The timeout fired
+            Err(Errno::CANCELED) => {
+                info!("Timed out waiting for the header");
+                return;
+            }
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        }
+        let Some(end) = header::find_end(&nursling.buf[..nursling.len]) else {
+            if nursling.len == MAX_HEADER_LEN {
+                error!("Header is too long");
+                return;
+            }
+            reqs.extend(nursling.recv(client_id));
+            self.clients.insert(client_id, nursling);
+            return;
+        };
+        let Nursling {
+            conn,
+            slot,
+            feed,
+            buf,
+            len,
+            ..
+        } = nursling;
+        let header = std::str::from_utf8(&buf[..end])
+            .map_err(|e| e.to_string())
+            .and_then(str::parse::<Header>);
+        let header = match header {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        };
+        // Anything which means reading the file is done off the runloop
+        let reads_file = header.start.needs_scan() || header.align.is_some() || header.csv_header;
+        // The client may have sent more (eg. cursor commits) straight after
+        let rest = buf[end..len].to_vec();
+        if reads_file || header.command.is_some() {
+            let span = Span::current();
+            std::thread::spawn(move || {
+                let _g = span.entered();
+                promote(client_id, conn, header, rest, slot, feed, opts);
+            });
+        } else {
+            promote(client_id, conn, header, rest, slot, feed, opts);
+        }
+    }
+}
+
+/// Set up the client and hand it over to the runloop which owns its shard
+fn promote(
+    client_id: ClientId,
+    conn: OwnedFd,
+    header: Header,
+    rest: Vec<u8>,
+    slot: ClientSlot,
+    feed: Option<Arc<Feed>>,
+    opts: &Opts,
+) {
+    match Client::from_header(conn, header, rest, slot, feed, opts) {
+        Ok(Some(client)) => {
+            trace!("Prepared client: {client:?}");
+            add_client(client_id, client);
+        }
+        Ok(None) => info!("Answered command"),
+        Err(e) => error!("{e}"),
+    }
+}
+
+impl Nursling {
+    /// A `Recv` for more of the header, and a timeout for however long the
+    /// client has left
+    fn recv(&mut self, client_id: ClientId) -> [Entry; 2] {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        *self.timeout = Timespec::from(left);
+        let unfilled = &mut self.buf[self.len..];
+        let recv = rustix_uring::opcode::Recv::new(
+            rustix_uring::types::Fd(self.conn.as_raw_fd()),
+            unfilled.as_mut_ptr(),
+            unfilled.len() as u32,
+        )
+        .build()
+        .flags(rustix_uring::squeue::Flags::IO_LINK)
+        .user_data(UserData::ReadHeader(client_id).into());
+        let timeout = rustix_uring::opcode::LinkTimeout::new(&*self.timeout)
+            .build()
+            .user_data(UserData::HeaderTimeout.into());
+        [recv, timeout]
+    }
+}
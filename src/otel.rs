@@ -0,0 +1,93 @@
+//! OpenTelemetry export, with the `otel` feature.  Traces and metrics are
+//! sent over OTLP (HTTP).  The exporters are configured by the usual `OTEL_*`
+//! environment variables (eg. `OTEL_EXPORTER_OTLP_ENDPOINT`), and the whole
+//! thing can be turned off with `OTEL_SDK_DISABLED=true`.
+//!
+//! Each client gets a `session` span which lasts as long as it's connected,
+//! and the sends are events within it.  The metrics are read from the same
+//! counters as `--stats-interval`.
+
+use crate::{ErrorClass, Result, BYTES_SENT, FILE_LENGTH, NUM_CLIENTS};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use std::sync::atomic::Ordering;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Set up the exporters, and return a layer which sends our spans to them.
+/// `None` if OpenTelemetry has been disabled, or couldn't be set up.
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if std::env::var("OTEL_SDK_DISABLED").is_ok_and(|x| x.eq_ignore_ascii_case("true")) {
+        return None;
+    }
+    // Logging isn't set up yet, so we can only complain to stderr
+    match init() {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(e) => {
+            eprintln!("Couldn't set up OpenTelemetry: {e}");
+            None
+        }
+    }
+}
+
+fn init() -> Result<Tracer> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .build();
+    let tracer = tracer_provider.tracer("tailsrv");
+    opentelemetry::global::set_tracer_provider(tracer_provider);
+
+    let exporter = opentelemetry_otlp::MetricExporter::builder()
+        .with_http()
+        .build()?;
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter).build())
+        .build();
+    opentelemetry::global::set_meter_provider(meter_provider);
+    register_metrics();
+    Ok(tracer)
+}
+
+/// The metrics are read from our counters whenever they're exported
+fn register_metrics() {
+    let meter = opentelemetry::global::meter("tailsrv");
+    meter
+        .u64_observable_counter("tailsrv.bytes_sent")
+        .with_description("Bytes sent to clients")
+        .with_unit("By")
+        .with_callback(|x| x.observe(BYTES_SENT.load(Ordering::Relaxed), &[]))
+        .build();
+    meter
+        .u64_observable_counter("tailsrv.send_errors")
+        .with_description("Failed sends, by whether they were retried (transient) or not")
+        .with_callback(|x| {
+            for (class, name) in [
+                (ErrorClass::Transient, "transient"),
+                (ErrorClass::Closed, "closed"),
+                (ErrorClass::Fatal, "fatal"),
+            ] {
+                x.observe(class.count(), &[KeyValue::new("class", name)]);
+            }
+        })
+        .build();
+    meter
+        .u64_observable_gauge("tailsrv.clients")
+        .with_description("Clients connected")
+        .with_callback(|x| x.observe(NUM_CLIENTS.load(Ordering::Acquire) as u64, &[]))
+        .build();
+    meter
+        .u64_observable_gauge("tailsrv.file_length")
+        .with_description("The length of the file")
+        .with_unit("By")
+        .with_callback(|x| x.observe(FILE_LENGTH.load(Ordering::Acquire) as u64, &[]))
+        .build();
+}
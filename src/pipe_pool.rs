@@ -0,0 +1,101 @@
+//! A pool of pipes for the io_uring backend to splice through.
+//!
+//! When a client disconnects its pipe is emptied and put back in the pool,
+//! and the next client to connect takes it.  This saves clients
+//! which reconnect frequently from creating and resizing a new pipe each
+//! time.  Pipes which sit in the pool for too long are closed.
+
+use crate::{Opts, Result, OPTS};
+use rustix::fd::OwnedFd;
+use rustix::pipe::{splice, SpliceFlags};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Pipes which have been in the pool for longer than this are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum number of idle pipes to keep around, if the user doesn't say
+const DEFAULT_CAPACITY: usize = 64;
+
+/// Idle pipes, along with when they were returned.  The most recently
+/// returned pipes are at the back.
+static POOL: Mutex<VecDeque<(Pipe, Instant)>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug)]
+pub struct Pipe {
+    pub rdr: OwnedFd,
+    pub wtr: OwnedFd,
+    /// The most data a single splice can move through the pipe
+    pub capacity: usize,
+}
+
+/// Take a pipe from the pool, or create a new one if the pool is empty
+pub fn take(opts: &Opts) -> Result<Pipe> {
+    let mut pool = POOL.lock().unwrap();
+    reclaim(&mut pool);
+    match pool.pop_back() {
+        Some((pipe, _)) => {
+            trace!("Reusing a pipe from the pool");
+            Ok(pipe)
+        }
+        None => create(opts),
+    }
+}
+
+/// Put a pipe back in the pool.  Any data which is still in the pipe is
+/// thrown away.  If the pool is full, the pipe is closed instead.
+pub fn put(pipe: Pipe) {
+    let Some(opts) = OPTS.get() else { return };
+    let capacity = opts.pipe_pool_size.unwrap_or(DEFAULT_CAPACITY);
+    if let Err(e) = empty(&pipe) {
+        debug!("Couldn't empty pipe; closing it: {e}");
+        return;
+    }
+    let mut pool = POOL.lock().unwrap();
+    reclaim(&mut pool);
+    if pool.len() < capacity {
+        pool.push_back((pipe, Instant::now()));
+    }
+}
+
+/// Close any pipes which have been idle for too long
+fn reclaim(pool: &mut VecDeque<(Pipe, Instant)>) {
+    while let Some((_, returned)) = pool.front() {
+        if returned.elapsed() < IDLE_TIMEOUT {
+            break;
+        }
+        trace!("Closing an idle pipe");
+        pool.pop_front();
+    }
+}
+
+/// Discard the contents of the pipe
+fn empty(pipe: &Pipe) -> Result<()> {
+    static DEV_NULL: LazyLock<std::io::Result<File>> =
+        LazyLock::new(|| OpenOptions::new().write(true).open("/dev/null"));
+    let dev_null = DEV_NULL.as_ref().map_err(|e| e.to_string())?;
+    loop {
+        let n = rustix::io::ioctl_fionread(&pipe.rdr)?;
+        if n == 0 {
+            return Ok(());
+        }
+        trace!("Discarding {n} bytes left in the pipe");
+        let len = usize::try_from(n)?;
+        splice(&pipe.rdr, None, dev_null, None, len, SpliceFlags::NONBLOCK)?;
+    }
+}
+
+/// Create a new pipe, with the size given by --pipe-size
+pub fn create(opts: &Opts) -> Result<Pipe> {
+    let (rdr, wtr) = rustix::pipe::pipe()?;
+    if let Some(size) = opts.pipe_size {
+        // The kernel rounds this up to a power-of-two number of pages.
+        // Unprivileged users can't go above /proc/sys/fs/pipe-max-size.
+        rustix::pipe::fcntl_setpipe_size(&wtr, usize::try_from(size)?)?;
+    }
+    let capacity = rustix::pipe::fcntl_getpipe_size(&wtr)?;
+    Ok(Pipe { rdr, wtr, capacity })
+}
@@ -0,0 +1,95 @@
+//! Support for `--user` and `--group`.  tailsrv opens everything it needs
+//! (the listening sockets, the file, the logs) and then switches to an
+//! unprivileged account before it serves anyone.
+
+use crate::Result;
+use std::ffi::CString;
+use tracing::*;
+
+/// How much room getpwnam_r() and getgrnam_r() get for the strings
+const BUF_LEN: usize = 16 * 1024;
+
+/// Switch to the given user and/or group.  If only the user is given, we use
+/// its primary group.  Supplementary groups are dropped either way.
+pub fn switch(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, gid))) => Some(gid),
+        (None, None) => None,
+    };
+    // The group has to be changed first, while we're still allowed to
+    if let Some(gid) = gid {
+        // SAFETY: an empty list doesn't need a valid pointer
+        if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+            return Err(format!("setgroups: {}", std::io::Error::last_os_error()).into());
+        }
+        // glibc makes sure every thread changes, not just this one
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(format!("setgid({gid}): {}", std::io::Error::last_os_error()).into());
+        }
+    }
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(format!("setuid({uid}): {}", std::io::Error::last_os_error()).into());
+        }
+    }
+    info!(uid = ?user.map(|x| x.0), ?gid, "Dropped privileges");
+    Ok(())
+}
+
+/// The user's ID and primary group.  The user may be given by name or by
+/// number, but either way it has to exist.
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; BUF_LEN];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: the pointers are all valid for the duration of the call
+    let ret = match name.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let cname = CString::new(name)?;
+            unsafe {
+                libc::getpwnam_r(
+                    cname.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if ret == 0 && !result.is_null() {
+        Ok((pwd.pw_uid, pwd.pw_gid))
+    } else {
+        Err(format!("{name}: No such user").into())
+    }
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; BUF_LEN];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: the pointers are all valid for the duration of the call
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret == 0 && !result.is_null() {
+        Ok(grp.gr_gid)
+    } else {
+        Err(format!("{name}: No such group").into())
+    }
+}
@@ -0,0 +1,99 @@
+//! Finding out what the kernel will let us do, so that we can pick the best
+//! combination which works (and say why), rather than failing with an opaque
+//! error.  The io_uring backend needs:
+//!
+//! - io_uring itself, which may be missing (before Linux 5.1), or disabled by
+//!   the `kernel.io_uring_disabled` sysctl or a seccomp filter (as in many
+//!   container runtimes);
+//! - the opcodes we use, of which splice is the newest (Linux 5.7);
+//! - multishot polls (Linux 5.13);
+//! - inotify, for noticing when the file grows.
+//!
+//! Without io_uring we use epoll.  Without inotify we use epoll too, and
+//! check the file's length every so often instead.
+
+use crate::Opts;
+use rustix_uring::{opcode, IoUring, Probe};
+use tracing::*;
+
+/// The opcodes which the io_uring backend uses
+const OPCODES: &[(u8, &str)] = &[
+    (opcode::Splice::CODE, "splice"),
+    (opcode::PollAdd::CODE, "poll"),
+    (opcode::PollRemove::CODE, "poll removal"),
+    (opcode::AsyncCancel::CODE, "cancellation"),
+    (opcode::Recv::CODE, "recv"),
+    (opcode::Send::CODE, "send"),
+    (opcode::Timeout::CODE, "timeouts"),
+    (opcode::AcceptMulti::CODE, "accept"),
+];
+
+/// What the kernel lets us do
+#[derive(Debug)]
+pub struct Capabilities {
+    /// Why the io_uring backend can't be used, if it can't
+    pub uring: Result<(), String>,
+    /// Why we can't watch the file with inotify, if we can't
+    pub inotify: Result<(), String>,
+}
+
+pub fn probe() -> Capabilities {
+    let caps = Capabilities {
+        uring: probe_uring(),
+        inotify: probe_inotify(),
+    };
+    debug!(?caps, "Probed the kernel");
+    caps
+}
+
+fn probe_uring() -> Result<(), String> {
+    let uring = IoUring::new(8).map_err(|e| match e.raw_os_error() {
+        Some(libc::ENOSYS) => "this kernel doesn't have io_uring".to_owned(),
+        Some(libc::EPERM) => "io_uring has been disabled (by the kernel.io_uring_disabled \
+                              sysctl, or a seccomp filter)"
+            .to_owned(),
+        _ => format!("couldn't set up a ring: {e}"),
+    })?;
+    let mut probe = Probe::new();
+    uring
+        .submitter()
+        .register_probe(&mut probe)
+        .map_err(|e| format!("couldn't ask which opcodes are supported: {e}"))?;
+    let missing: Vec<&str> = OPCODES
+        .iter()
+        .filter(|(code, _)| !probe.is_supported(*code))
+        .map(|(_, name)| *name)
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "this kernel doesn't support {}",
+            missing.join(", ")
+        ));
+    }
+    // There's no way to ask about multishot polls directly, but they came in
+    // the same release as resource tagging, which the kernel does tell us
+    // about
+    if !uring.params().is_feature_resource_tagging() {
+        return Err("this kernel doesn't support multishot polls".to_owned());
+    }
+    Ok(())
+}
+
+/// Whether we can create an inotify instance.  There's a per-user limit on
+/// these (fs.inotify.max_user_instances), which is easy to run into.
+fn probe_inotify() -> Result<(), String> {
+    match rustix::fs::inotify::init(rustix::fs::inotify::CreateFlags::CLOEXEC) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Check that we're allowed pipes as big as --pipe-size asks for, now rather
+/// than when the first client connects
+pub fn check_pipe_size(opts: &Opts) -> Result<(), String> {
+    crate::pipe_pool::create(opts).map(drop).map_err(|e| {
+        format!(
+            "--pipe-size: {e} (unprivileged processes can't go above /proc/sys/fs/pipe-max-size)"
+        )
+    })
+}
@@ -0,0 +1,48 @@
+use std::time::{Duration, Instant};
+
+/// How long to wait before re-checking a throttled client.  This also
+/// determines the granularity of the splices we issue for throttled clients:
+/// if the limit is 1 MB/s then we'll send ~10 kB every 10 ms.
+pub const THROTTLE_TICK: Duration = Duration::from_millis(10);
+
+/// A classic token bucket.  Tokens are bytes.  The bucket refills at a
+/// constant rate, up to a maximum of one second's worth of tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new bucket which refills at `rate` bytes per second.  The
+    /// bucket starts off full.
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// The number of bytes which may be sent right now
+    pub fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens.max(0.0) as usize
+    }
+
+    /// Record that `n` bytes were sent
+    pub fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+
+    /// Return tokens which were consumed but not actually used
+    #[cfg(target_os = "linux")]
+    pub fn refund(&mut self, n: usize) {
+        self.tokens += n as f64;
+    }
+}
@@ -0,0 +1,124 @@
+//! Support for `--upstream`, which makes tailsrv a relay.  We follow another
+//! tailsrv as a client, append whatever it sends us to the file, and serve
+//! the file as usual.  This way a single connection over a slow link can feed
+//! any number of local clients.  Relays can follow other relays, so they can
+//! be arranged in chains or trees.
+//!
+//! The file is only ever appended to, so its length tells us how far through
+//! the upstream's file we've got.  When the connection is lost we carry on
+//! from there.
+//!
+//! If we crash, though, the end of the file may not have made it to disk
+//! intact.  So every so often we sync the file and record its length in
+//! `<PATH>.offset`.  When we start up, anything past the recorded length is
+//! thrown away and fetched again.
+
+use crate::{Opts, Result};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tailsrv_client::{Builder, TailReader};
+use tracing::*;
+
+/// How long to wait before trying again if we can't reach the upstream when
+/// we start.  Once we're connected, the client library handles reconnecting.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+/// While we're catching up, this is how often we sync the file.  Once we've
+/// caught up, we sync after every read.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// The most data we read from the upstream at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Start following the upstream on a thread of its own
+pub fn spawn(upstream: &str, path: &Path, opts: &Opts) -> Result<()> {
+    let file = File::options().append(true).create(true).open(path)?;
+    let offset_path = offset_path(path);
+    recover(&file, &offset_path)?;
+    let mut builder = TailReader::builder(upstream)
+        .map_err(|e| format!("{upstream}: {e}"))?
+        .reconnect(true);
+    if let Some(token) = &opts.upstream_auth_token {
+        builder = builder.auth(token);
+    }
+    let upstream = upstream.to_owned();
+    std::thread::spawn(move || {
+        let _g = info_span!("", %upstream).entered();
+        if let Err(e) = follow(builder, file, &offset_path) {
+            error!("Relay failed: {e}");
+            std::process::exit(1);
+        }
+    });
+    Ok(())
+}
+
+/// Where we record how much of the file is safely on disk
+fn offset_path(path: &Path) -> PathBuf {
+    let mut x = path.as_os_str().to_owned();
+    x.push(".offset");
+    x.into()
+}
+
+/// Throw away anything past the recorded offset, since it may not have made
+/// it to disk intact
+fn recover(file: &File, offset_path: &Path) -> Result<()> {
+    let recorded = match std::fs::read_to_string(offset_path) {
+        Ok(x) => x
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| format!("{}: {e}", offset_path.display()))?,
+        // The file predates the relay, so we trust all of it
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {e}", offset_path.display()).into()),
+    };
+    let len = file.metadata()?.len();
+    if recorded < len {
+        warn!(recorded, len, "Discarding data which may not be intact");
+        file.set_len(recorded)?;
+    }
+    Ok(())
+}
+
+/// Append the upstream's stream to the file.  This doesn't return unless
+/// something goes wrong.
+fn follow(builder: Builder, mut file: File, offset_path: &Path) -> Result<()> {
+    let mut offset = file.metadata()?.len();
+    let mut conn = loop {
+        match builder.clone().offset(i64::try_from(offset)?).connect() {
+            Ok(conn) => break conn,
+            Err(e) => {
+                warn!("Couldn't connect to upstream: {e}");
+                std::thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    };
+    info!(offset, "Following upstream");
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut last_sync = Instant::now();
+    loop {
+        let n = conn.read(&mut buf)?;
+        if n == 0 {
+            return Err("Upstream closed the connection".into());
+        }
+        file.write_all(&buf[..n])?;
+        offset += n as u64;
+        // A short read means we've caught up, and it may be a while before
+        // the next one
+        if n < buf.len() || last_sync.elapsed() >= SYNC_INTERVAL {
+            record_offset(&file, offset, offset_path)?;
+            last_sync = Instant::now();
+        }
+    }
+}
+
+/// Make sure the first `offset` bytes of the file are on disk, and then
+/// record that they are
+fn record_offset(file: &File, offset: u64, offset_path: &Path) -> Result<()> {
+    file.sync_data()?;
+    let mut tmp = offset_path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, format!("{offset}\n"))?;
+    std::fs::rename(&tmp, offset_path)?;
+    trace!(offset, "Recorded synced offset");
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Support for `--reopen`, for files which get rotated.  Normally tailsrv
+//! serves the same file for its whole life, and exits when the file is moved
+//! or deleted.  With `--reopen` (or `--on-move reopen`, or `--on-delete
+//! reopen`) it waits for a new file to appear at the same path, and switches
+//! to that.  `--glob` works the same way, except that it
+//! also switches whenever a newer matching file appears.
+//!
+//! The file can change underneath the runloop, so as far as the runloop is
+//! concerned this source isn't a file: it's served with epoll, and it watches
+//! the file itself.
+
+use crate::glob::Glob;
+use crate::marker;
+use crate::source::{Growth, Source};
+use crate::{
+    file_is_gone, growth_events, is_growth, visit_clients, wait_for_file, FilePolicy, ReopenPolicy,
+    Result, OPTS,
+};
+use rustix::fd::{AsRawFd, BorrowedFd};
+use rustix::fs::inotify;
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::*;
+
+/// Which file we should be serving
+pub enum Target {
+    /// Whatever's at this path
+    Path(PathBuf),
+    /// The newest file matching this pattern
+    Glob(Glob),
+}
+
+/// The file we're currently serving.  Clones share the same file.
+#[derive(Clone)]
+pub struct Reopening(Arc<Inner>);
+
+struct Inner {
+    target: Target,
+    current: RwLock<Current>,
+    policy: ReopenPolicy,
+}
+
+struct Current {
+    path: PathBuf,
+    file: File,
+}
+
+impl Reopening {
+    /// `path` and `file` are the file to start with
+    pub fn new(target: Target, path: PathBuf, file: File, policy: ReopenPolicy) -> Reopening {
+        Reopening(Arc::new(Inner {
+            target,
+            current: RwLock::new(Current { path, file }),
+            policy,
+        }))
+    }
+}
+
+impl Source for Reopening {
+    fn len(&self) -> std::io::Result<usize> {
+        Source::len(&self.0.current.read().unwrap().file)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize> {
+        Source::read_at(&self.0.current.read().unwrap().file, buf, offset)
+    }
+
+    fn copy_to(&self, sock: BorrowedFd, offset: usize, len: usize) -> std::io::Result<usize> {
+        Source::copy_to(&self.0.current.read().unwrap().file, sock, offset, len)
+    }
+
+    fn identity(&self) -> Option<(u64, u64)> {
+        let meta = self.0.current.read().unwrap().file.metadata().ok()?;
+        Some((meta.dev(), meta.ino()))
+    }
+
+    fn watch(&self, growth: Growth) {
+        let inner = self.0.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = follow(&inner, &growth) {
+                error!("Couldn't follow the file: {e}");
+                std::process::exit(1);
+            }
+        });
+    }
+}
+
+/// Report the file's growth until there's a new file to serve, then switch
+/// to it.  This doesn't return unless something goes wrong.
+fn follow(inner: &Arc<Inner>, growth: &Growth) -> Result<()> {
+    loop {
+        let (path, file) = wait_for_replacement(inner, growth)?;
+        let len = Source::len(&file)?;
+        info!(path = %path.display(), policy = ?inner.policy, "Switching to a new file");
+        // The switch is done on the runloop, in between passes, so that
+        // nobody is sent part of the new file from their offset in the old
+        // one.  (The io_uring backend can't serve a file which is reopened,
+        // so there's only one runloop.)
+        let next = Mutex::new(Some(Current { path, file }));
+        let inner2 = inner.clone();
+        visit_clients(move |clients| {
+            if let Some(next) = next.lock().unwrap().take() {
+                *inner2.current.write().unwrap() = next;
+            }
+            match inner2.policy {
+                // Framed clients are told where they got to in the old file.
+                // This is the epoll backend, which doesn't send from the
+                // encoder's buffer in the background.
+                ReopenPolicy::Restart => {
+                    for client in clients.values_mut() {
+                        client.queue_control("rotated");
+                        client.offset = 0;
+                    }
+                }
+                ReopenPolicy::Disconnect => {
+                    for (_, mut client) in std::mem::take(clients) {
+                        client.send_eof_frame("rotated");
+                        client.disconnect("file replaced");
+                    }
+                }
+            }
+        });
+        marker::reset();
+        growth.notify(len);
+    }
+}
+
+/// Watch the current file, reporting its growth, until there's a new file to
+/// serve instead.  Returns the new file.
+fn wait_for_replacement(inner: &Inner, growth: &Growth) -> Result<(PathBuf, File)> {
+    let ino_fd = inotify::init(inotify::CreateFlags::CLOEXEC)?;
+    let (path, file_wd) = {
+        let current = inner.current.read().unwrap();
+        let wd = inotify::add_watch(
+            &ino_fd,
+            format!("/proc/self/fd/{}", current.file.as_raw_fd()),
+            growth_events() | inotify::WatchFlags::MOVE_SELF | inotify::WatchFlags::ATTRIB,
+        )?;
+        (current.path.clone(), wd)
+    };
+    // With a glob, a newer file may turn up at any time
+    if let Target::Glob(glob) = &inner.target {
+        inotify::add_watch(
+            &ino_fd,
+            glob.dir(),
+            inotify::WatchFlags::CREATE | inotify::WatchFlags::MOVED_TO,
+        )?;
+    }
+    let mut buf = [const { MaybeUninit::uninit() }; 1024];
+    let mut evs = inotify::Reader::new(&ino_fd, &mut buf);
+    loop {
+        let ev = evs.next()?;
+        trace!("inotify event: {:?}", ev);
+        if ev.wd() != file_wd {
+            // Something was created in the glob's directory
+            if let Target::Glob(glob) = &inner.target {
+                if glob.newest()?.is_some_and(|x| x > path) {
+                    return glob.wait_for_newer(Some(&path));
+                }
+            }
+            continue;
+        }
+        let current = inner.current.read().unwrap();
+        if is_growth(ev.events()) {
+            growth.notify(Source::len(&current.file)?);
+        }
+        // See `handle_file_event` for why we look at the link count
+        let moved = ev.events().contains(inotify::ReadFlags::MOVE_SELF);
+        let deleted = ev.events().contains(inotify::ReadFlags::ATTRIB)
+            && current.file.metadata()?.nlink() == 0;
+        if !moved && !deleted {
+            continue;
+        }
+        // With --on-move and --on-delete, only one of them may reopen
+        let opts = OPTS.get().unwrap();
+        if opts.on_gone(moved) != FilePolicy::Reopen {
+            info!(path = %path.display(), moved, "File is gone");
+            file_is_gone(moved, opts);
+            continue;
+        }
+        info!(path = %path.display(), "File is gone; waiting for a new one");
+        drop(current);
+        return match &inner.target {
+            Target::Path(path) => Ok((path.clone(), wait_for_file(path)?)),
+            Target::Glob(glob) => glob.wait_for_newer(Some(&path)),
+        };
+    }
+}
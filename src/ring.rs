@@ -0,0 +1,99 @@
+//! Support for `--memory-buffer`.  Instead of a file, the data goes into a
+//! fixed-size buffer in memory, and only the most recent part of it is kept.
+//! Offsets still count from the very first byte, so the available data is a
+//! window which slides along as more arrives.
+
+use crate::source::{Growth, Source};
+use std::io::Write;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// A source which keeps the last `capacity` bytes written to it.  Clones
+/// share the same buffer.
+#[derive(Clone)]
+pub struct RingBuffer(Arc<Inner>);
+
+struct Inner {
+    data: Mutex<Data>,
+    growth: OnceLock<Growth>,
+}
+
+struct Data {
+    buf: Box<[u8]>,
+    /// How many bytes have been written in total
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer(Arc::new(Inner {
+            data: Mutex::new(Data {
+                buf: vec![0; capacity].into_boxed_slice(),
+                len: 0,
+            }),
+            growth: OnceLock::new(),
+        }))
+    }
+
+    /// Add data to the end, pushing the oldest data out of the window
+    pub fn append(&self, bytes: &[u8]) {
+        let len = {
+            let mut data = self.0.data.lock().unwrap();
+            let cap = data.buf.len();
+            let new_len = data.len + bytes.len();
+            // Anything which wouldn't survive the write doesn't need copying
+            let mut pos = new_len - bytes.len().min(cap);
+            let mut bytes = &bytes[bytes.len() - bytes.len().min(cap)..];
+            while !bytes.is_empty() {
+                let i = pos % cap;
+                let n = bytes.len().min(cap - i);
+                data.buf[i..i + n].copy_from_slice(&bytes[..n]);
+                bytes = &bytes[n..];
+                pos += n;
+            }
+            data.len = new_len;
+            new_len
+        };
+        if let Some(growth) = self.0.growth.get() {
+            growth.notify(len);
+        }
+    }
+}
+
+impl Source for RingBuffer {
+    fn len(&self) -> std::io::Result<usize> {
+        Ok(self.0.data.lock().unwrap().len)
+    }
+
+    fn first(&self) -> usize {
+        let data = self.0.data.lock().unwrap();
+        data.len.saturating_sub(data.buf.len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize> {
+        let data = self.0.data.lock().unwrap();
+        let cap = data.buf.len();
+        if offset < data.len.saturating_sub(cap) {
+            return Err(std::io::Error::other("out of window"));
+        }
+        // Don't go past the end of the data, or wrap around the buffer
+        let i = offset % cap.max(1);
+        let n = buf.len().min(data.len.saturating_sub(offset)).min(cap - i);
+        buf[..n].copy_from_slice(&data.buf[i..i + n]);
+        Ok(n)
+    }
+
+    fn watch(&self, growth: Growth) {
+        let _ = self.0.growth.set(growth);
+    }
+}
+
+impl Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.append(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,588 @@
+//! Turning the client's requested starting position into a byte offset.
+//!
+//! Byte offsets are easy.  For `since <TIME>` we binary-search the file,
+//! assuming that each line starts with a timestamp (or that the timestamp
+//! can be found with --timestamp-regex) and that the timestamps only go up.
+//! For `from /<PATTERN>/` and `after /<PATTERN>/` there's nothing to bisect
+//! on, so we read through the whole file.  `line <N>` and `seqnum <N>` are
+//! looked up in the index (see the `index` module), unless N is a negative
+//! line number, in which case we read backwards from the end.  This can take
+//! a while, so callers should avoid doing it on the runloop (see
+//! `Start::needs_scan`).
+
+use crate::header::{Align, BeyondEof, Start};
+use crate::index::{self, Kind};
+use crate::source::{Reader, Source, SOURCE};
+use crate::{Opts, Result, FILE_LENGTH};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use regex::Regex;
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::Ordering;
+use tracing::*;
+
+/// How much of each line we look at when searching for its timestamp
+const PREFIX_LEN: usize = 4096;
+/// The longest first line which we'll replay for `csv-header`
+const MAX_FIRST_LINE_LEN: usize = 64 * 1024;
+
+/// Resolve the client's requested starting position to a byte offset
+pub fn resolve(start: &Start, opts: &Opts) -> Result<usize> {
+    let file_len = FILE_LENGTH.load(Ordering::Acquire);
+    resolve_in(start, SOURCE.get().map(|x| &**x), file_len, opts)
+}
+
+/// Like `resolve`, but in the given source rather than the main one.  `None`
+/// means that the file doesn't exist yet.  The indexes only cover the main
+/// source, so `line <N>` and `seqnum <N>` (see `Start::is_indexed`) mustn't
+/// be resolved in any other.
+pub fn resolve_in(
+    start: &Start,
+    source: Option<&dyn Source>,
+    file_len: usize,
+    opts: &Opts,
+) -> Result<usize> {
+    let delim = delim(start, opts);
+    match start {
+        Start::Byte(offset) => Ok(offset_in(*offset, file_len)),
+        Start::Since(time) => {
+            // If the file doesn't exist yet, the client will start from the
+            // beginning once it does
+            let Some(source) = source else {
+                return Ok(0);
+            };
+            let timestamps = Timestamps {
+                regex: opts.timestamp_regex.as_ref(),
+                format: opts.timestamp_format.as_deref(),
+            };
+            Ok(since(source, file_len, *time, &timestamps, delim)?)
+        }
+        Start::From(regex) | Start::After(regex) => {
+            // If the file doesn't exist yet, the client will start from the
+            // beginning once it does
+            let Some(source) = source else {
+                return Ok(0);
+            };
+            let last = matches!(start, Start::After(_));
+            Ok(find_match(source, file_len, regex, last, delim)?)
+        }
+        Start::Line(n) | Start::Zero(n) => {
+            let Some(source) = source else {
+                return Ok(0);
+            };
+            let offset = match u64::try_from(*n) {
+                Ok(n) => index::find(Kind::Lines(delim), source, n, file_len)?,
+                Err(_) => last_lines(source, n.unsigned_abs(), file_len, delim)?,
+            };
+            debug!(line = *n, offset, "Found the line");
+            Ok(offset)
+        }
+        Start::Seqnum(n) => {
+            let Some(source) = source else {
+                return Ok(0);
+            };
+            let offset = index::find(Kind::Records, source, *n, file_len)?;
+            debug!(seqnum = *n, offset, "Found the record");
+            Ok(offset)
+        }
+        Start::Pct(p) => Ok((file_len as f64 * p / 100.0) as usize),
+    }
+}
+
+/// The byte which ends each line, for this client.  `zero <N>` means the
+/// lines are NUL-terminated; otherwise it's whatever --delim says.
+pub fn delim(start: &Start, opts: &Opts) -> u8 {
+    match start {
+        Start::Zero(_) => 0,
+        _ => opts.delim.unwrap_or(b'\n'),
+    }
+}
+
+/// Resolve a requested starting position to a byte offset.  Negative values
+/// count back from the end of the file.
+pub fn resolve_offset(offset: isize) -> usize {
+    offset_in(offset, FILE_LENGTH.load(Ordering::Acquire))
+}
+
+fn offset_in(offset: isize, file_len: usize) -> usize {
+    match usize::try_from(offset) {
+        Ok(x) => x,
+        Err(_) => file_len.saturating_add_signed(offset),
+    }
+}
+
+/// With `strict`, a negative byte offset which goes back past the start of
+/// the file is an error.  Returns `Err` with the length of the file if so.
+pub fn check_strict(start: &Start) -> Result<(), usize> {
+    check_strict_in(start, FILE_LENGTH.load(Ordering::Acquire))
+}
+
+/// Like `check_strict`, but for a file of the given length
+pub fn check_strict_in(start: &Start, file_len: usize) -> Result<(), usize> {
+    match start {
+        Start::Byte(n) if *n < 0 && n.unsigned_abs() > file_len => Err(file_len),
+        _ => Ok(()),
+    }
+}
+
+/// Apply the `BeyondEof` policy to an offset which the client asked for.
+/// Returns the offset to start from, or `Err` with the length of the file if
+/// the client should be sent an error instead.
+pub fn beyond_eof(offset: usize, policy: BeyondEof) -> Result<usize, usize> {
+    let file_len = SOURCE.get().map(|_| FILE_LENGTH.load(Ordering::Acquire));
+    beyond_eof_in(offset, policy, file_len)
+}
+
+/// Like `beyond_eof`, but for a file of the given length.  `None` means that
+/// the file doesn't exist yet.
+pub fn beyond_eof_in(
+    offset: usize,
+    policy: BeyondEof,
+    file_len: Option<usize>,
+) -> Result<usize, usize> {
+    // If the file doesn't exist yet, there's nothing to compare with
+    let Some(file_len) = file_len else {
+        return Ok(offset);
+    };
+    match policy {
+        _ if offset <= file_len => Ok(offset),
+        BeyondEof::Wait => Ok(offset),
+        BeyondEof::Clamp => {
+            debug!(
+                offset,
+                file_len, "Clamping the offset to the end of the file"
+            );
+            Ok(file_len)
+        }
+        BeyondEof::Error => Err(file_len),
+    }
+}
+
+/// Move `offset` forward to the next boundary, unless it's on one already.
+/// If there isn't a boundary between `offset` and the end of the file yet,
+/// we're in a line (or character) which is still being written, so we go
+/// back to its start instead.
+pub fn align(
+    source: &dyn Source,
+    offset: usize,
+    file_len: usize,
+    align: Align,
+    delim: u8,
+) -> Result<usize> {
+    // Data which doesn't exist yet can't be looked at
+    if offset == 0 || offset >= file_len {
+        return Ok(offset);
+    }
+    // A line starts after a delimiter, so we look at the byte before `offset`
+    // too.  A character starts on any byte which isn't a continuation byte.
+    let (mut pos, skip) = match align {
+        Align::Line => (offset - 1, 1),
+        Align::Utf8 => (offset, 0),
+    };
+    let file = Reader {
+        source,
+        pos,
+        end: file_len,
+    };
+    let mut rdr = BufReader::with_capacity(1 << 16, file);
+    loop {
+        let buf = rdr.fill_buf()?;
+        if buf.is_empty() {
+            let aligned = match align {
+                Align::Line => last_lines(source, 1, offset, delim)?,
+                Align::Utf8 => char_start(source, offset)?,
+            };
+            debug!(offset, aligned, "Aligned the offset to an incomplete line");
+            return Ok(aligned);
+        }
+        let found = match align {
+            Align::Line => buf.iter().position(|&b| b == delim),
+            Align::Utf8 => buf.iter().position(|&b| b & 0xc0 != 0x80),
+        };
+        if let Some(i) = found {
+            let aligned = pos + i + skip;
+            debug!(offset, aligned, "Aligned the offset");
+            return Ok(aligned);
+        }
+        let n = buf.len();
+        pos += n;
+        rdr.consume(n);
+    }
+}
+
+/// The start of the character which `offset` is in the middle of.  If it
+/// isn't UTF-8 after all, `offset` is as good as anywhere.
+fn char_start(source: &dyn Source, offset: usize) -> std::io::Result<usize> {
+    let from = offset.saturating_sub(3);
+    let mut buf = [0; 3];
+    let n = source.read_at(&mut buf[..offset - from], from)?;
+    Ok(buf[..n]
+        .iter()
+        .rposition(|&b| b & 0xc0 != 0x80)
+        .map_or(offset, |i| from + i))
+}
+
+/// The first line of the file, including its delimiter.  `None` if there
+/// isn't a complete first line yet, or if it's unreasonably long.
+pub fn first_line(
+    source: &dyn Source,
+    file_len: usize,
+    delim: u8,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let file = Reader {
+        source,
+        pos: 0,
+        end: file_len.min(MAX_FIRST_LINE_LEN),
+    };
+    let mut line = Vec::new();
+    BufReader::new(file).read_until(delim, &mut line)?;
+    Ok(line.ends_with(&[delim]).then_some(line))
+}
+
+/// How to find the timestamp in a line
+struct Timestamps<'a> {
+    regex: Option<&'a Regex>,
+    format: Option<&'a str>,
+}
+
+impl Timestamps<'_> {
+    /// Without a regex, the timestamp is at the start of the line.  Without
+    /// a format, it's RFC 3339.  Formats without a timezone are taken to be
+    /// in UTC.
+    fn parse(&self, line: &[u8]) -> Option<DateTime<Utc>> {
+        // The prefix may have cut a character in half
+        let line = match std::str::from_utf8(line) {
+            Ok(x) => x,
+            Err(e) => std::str::from_utf8(&line[..e.valid_up_to()]).unwrap(),
+        };
+        let text = match self.regex {
+            Some(regex) => {
+                let caps = regex.captures(line)?;
+                caps.get(1).or(caps.get(0))?.as_str()
+            }
+            None => line,
+        };
+        match self.format {
+            None => {
+                let word = text.split_whitespace().next()?;
+                DateTime::parse_from_rfc3339(word).ok().map(|t| t.to_utc())
+            }
+            Some(format) => match DateTime::parse_and_remainder(text, format) {
+                Ok((t, _)) => Some(t.to_utc()),
+                Err(_) => NaiveDateTime::parse_and_remainder(text, format)
+                    .ok()
+                    .map(|(t, _)| t.and_utc()),
+            },
+        }
+    }
+}
+
+/// Find the start of the first line whose timestamp is at or after `time`.
+/// Lines without a timestamp are skipped over.  If there's no such line
+/// yet, we return the start of the last (incomplete) line, or the end of the
+/// file.
+fn since(
+    source: &dyn Source,
+    file_len: usize,
+    time: DateTime<Utc>,
+    timestamps: &Timestamps,
+    delim: u8,
+) -> std::io::Result<usize> {
+    // Find the first position for which the next timestamped line is at or
+    // after `time`.  This is monotonic in the position, so we can bisect.
+    let (mut lo, mut hi) = (0, file_len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match next_timestamp(source, mid, file_len, timestamps, delim)? {
+            Some((_, t)) if t < time => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+    let offset = match next_timestamp(source, lo, file_len, timestamps, delim)? {
+        Some((start, _)) => start,
+        None => line_start(source, lo, file_len, delim)?,
+    };
+    debug!(%time, offset, "Found the first line since");
+    Ok(offset)
+}
+
+/// Find the start of the first (or last) complete line which matches
+/// `regex`.  If no line matches, we return the start of the last (incomplete)
+/// line, or the end of the file.
+fn find_match(
+    source: &dyn Source,
+    file_len: usize,
+    regex: &regex::bytes::Regex,
+    last: bool,
+    delim: u8,
+) -> std::io::Result<usize> {
+    let file = Reader {
+        source,
+        pos: 0,
+        end: file_len,
+    };
+    let mut rdr = BufReader::with_capacity(1 << 16, file);
+    let mut line = Vec::new();
+    let mut pos = 0;
+    let mut found = None;
+    loop {
+        line.clear();
+        let n = rdr.read_until(delim, &mut line)?;
+        let Some(line) = line.strip_suffix(&[delim]) else {
+            // End of file, or an incomplete line
+            break;
+        };
+        if regex.is_match(line) {
+            found = Some(pos);
+            if !last {
+                break;
+            }
+        }
+        pos += n;
+    }
+    let offset = found.unwrap_or(pos);
+    debug!(pattern = regex.as_str(), offset, "Found the matching line");
+    Ok(offset)
+}
+
+/// Find the start of the last `n` lines.  The last line doesn't have to be
+/// complete.  If there are fewer than `n` lines, that's the start of the file.
+fn last_lines(source: &dyn Source, n: usize, file_len: usize, delim: u8) -> std::io::Result<usize> {
+    if n == 0 {
+        return Ok(file_len);
+    }
+    let mut buf = [0; 4096];
+    // A delimiter at the very end finishes the last line; it doesn't start a
+    // new one
+    let mut pos = file_len;
+    if pos > 0 {
+        source.read_at(&mut buf[..1], pos - 1)?;
+        if buf[0] == delim {
+            pos -= 1;
+        }
+    }
+    let mut seen = 0;
+    while pos > 0 {
+        let len = 4096.min(pos);
+        let chunk = &mut buf[..len];
+        let n_read = source.read_at(chunk, pos - len)?;
+        if n_read < len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        for (i, _) in chunk.iter().enumerate().rev().filter(|(_, &b)| b == delim) {
+            seen += 1;
+            if seen == n {
+                return Ok(pos - len + i + 1);
+            }
+        }
+        pos -= len;
+    }
+    Ok(0)
+}
+
+/// Find the first complete line starting at or after `pos` which has a
+/// timestamp.  Returns where the line starts, and its timestamp.
+fn next_timestamp(
+    source: &dyn Source,
+    pos: usize,
+    file_len: usize,
+    timestamps: &Timestamps,
+    delim: u8,
+) -> std::io::Result<Option<(usize, DateTime<Utc>)>> {
+    let mut start = line_start(source, pos, file_len, delim)?;
+    let mut prefix = vec![0; PREFIX_LEN];
+    while start < file_len {
+        let n = source.read_at(&mut prefix[..PREFIX_LEN.min(file_len - start)], start)?;
+        let Some(end) = find_delim(source, start, file_len, delim)? else {
+            // Incomplete line
+            return Ok(None);
+        };
+        let line = &prefix[..n.min(end - start)];
+        if let Some(t) = timestamps.parse(line) {
+            return Ok(Some((start, t)));
+        }
+        start = end + 1;
+    }
+    Ok(None)
+}
+
+/// The start of the first line which starts at or after `pos`
+fn line_start(
+    source: &dyn Source,
+    pos: usize,
+    file_len: usize,
+    delim: u8,
+) -> std::io::Result<usize> {
+    if pos == 0 {
+        return Ok(0);
+    }
+    // If the previous byte is a delimiter then `pos` is the start of a line
+    Ok(find_delim(source, pos - 1, file_len, delim)?.map_or(file_len, |i| i + 1))
+}
+
+/// The position of the first delimiter at or after `pos`
+fn find_delim(
+    source: &dyn Source,
+    mut pos: usize,
+    file_len: usize,
+    delim: u8,
+) -> std::io::Result<Option<usize>> {
+    let mut buf = [0; 4096];
+    while pos < file_len {
+        let n = source.read_at(&mut buf[..4096.min(file_len - pos)], pos)?;
+        if n == 0 {
+            break;
+        }
+        if let Some(i) = buf[..n].iter().position(|&b| b == delim) {
+            return Ok(Some(pos + i));
+        }
+        pos += n;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn align_line(file: &[u8], offset: usize) -> usize {
+        align(&file.to_vec(), offset, file.len(), Align::Line, b'\n').unwrap()
+    }
+
+    #[test]
+    fn align_to_lines() {
+        let file = b"one\ntwo\nthr";
+        assert_eq!(align_line(file, 0), 0);
+        assert_eq!(align_line(file, 1), 4);
+        assert_eq!(align_line(file, 4), 4);
+        assert_eq!(align_line(file, 5), 8);
+        // The last line is still being written, so we wait for all of it
+        assert_eq!(align_line(file, 9), 8);
+        assert_eq!(align_line(b"one", 1), 0);
+        assert_eq!(align_line(file, file.len()), file.len());
+    }
+
+    #[test]
+    fn align_to_chars() {
+        let file = "a\u{e9}b".as_bytes().to_vec();
+        let utf8 = |offset| align(&file, offset, file.len(), Align::Utf8, b'\n').unwrap();
+        assert_eq!(utf8(1), 1);
+        assert_eq!(utf8(2), 3);
+        // The rest of the character hasn't been written yet
+        let partial = file[..2].to_vec();
+        assert_eq!(align(&partial, 1, 2, Align::Utf8, b'\n').unwrap(), 1);
+        let partial = "a\u{20ac}".as_bytes()[..3].to_vec();
+        assert_eq!(align(&partial, 2, 3, Align::Utf8, b'\n').unwrap(), 1);
+    }
+
+    fn resolve(start: &str, file: &[u8]) -> usize {
+        let header: crate::header::Header = start.parse().unwrap();
+        let source = file.to_vec();
+        resolve_in(&header.start, Some(&source), file.len(), &Opts::default()).unwrap()
+    }
+
+    #[test]
+    fn offsets() {
+        let file = b"0123456789";
+        assert_eq!(resolve("3", file), 3);
+        assert_eq!(resolve("-3", file), 7);
+        assert_eq!(resolve("-30", file), 0);
+        // The client waits for the file to grow that far
+        assert_eq!(resolve("30", file), 30);
+        assert_eq!(resolve("pct 0", file), 0);
+        assert_eq!(resolve("pct 50", file), 5);
+        assert_eq!(resolve("pct 100", file), 10);
+        assert_eq!(check_strict_in(&Start::Byte(-30), 10), Err(10));
+        assert_eq!(check_strict_in(&Start::Byte(-10), 10), Ok(()));
+    }
+
+    #[test]
+    fn lines_from_the_end() {
+        let file = b"one\ntwo\nthree\n";
+        assert_eq!(resolve("line -1", file), 8);
+        assert_eq!(resolve("line -2", file), 4);
+        assert_eq!(resolve("line -3", file), 0);
+        assert_eq!(resolve("line -4", file), 0);
+        // The last line is still being written, but it counts
+        let file = b"one\ntwo\nthr";
+        assert_eq!(resolve("line -1", file), 8);
+        assert_eq!(resolve("line -2", file), 4);
+        assert_eq!(resolve("zero -1", b"one\0two\0"), 4);
+        assert_eq!(resolve("line -1", b""), 0);
+        assert_eq!(
+            last_lines(&file.to_vec(), 0, file.len(), b'\n').unwrap(),
+            file.len()
+        );
+    }
+
+    #[test]
+    fn lines_from_the_start() {
+        let file = b"one\ntwo\nthr";
+        assert_eq!(resolve("line 0", file), 0);
+        assert_eq!(resolve("line 1", file), 4);
+        // There's no third line yet
+        assert_eq!(resolve("line 2", file), 8);
+        assert_eq!(resolve("line 5", file), 8);
+    }
+
+    #[test]
+    fn patterns() {
+        let file = b"a 1\nb 2\na 3\nb 4\na 5";
+        assert_eq!(resolve("from /^b/", file), 4);
+        assert_eq!(resolve("after /^b/", file), 12);
+        assert_eq!(resolve("from /^a/", file), 0);
+        assert_eq!(resolve("after /^a/", file), 8);
+        // No complete line matches yet
+        assert_eq!(resolve("from /5/", file), 16);
+        assert_eq!(resolve("from /c/", b"a\nb\n"), 4);
+    }
+
+    #[test]
+    fn timestamps() {
+        let file = b"2024-12-13T06:00:00Z a\n\
+                     no timestamp\n\
+                     2024-12-13T07:00:00Z b\n\
+                     2024-12-13T07:00:00Z c\n\
+                     2024-12-13T08:00:00Z d\n\
+                     2024-12-13T09:00:00Z e";
+        // Where the line with this letter starts
+        let line = |c: u8| file.iter().rposition(|&b| b == c).unwrap() - 21;
+        assert_eq!(resolve("since 2024-12-13T00:00:00Z", file), 0);
+        assert_eq!(resolve("since 2024-12-13T06:00:00Z", file), 0);
+        assert_eq!(resolve("since 2024-12-13T06:30:00Z", file), line(b'b'));
+        assert_eq!(resolve("since 2024-12-13T07:00:00Z", file), line(b'b'));
+        assert_eq!(resolve("since 2024-12-13T07:00:01Z", file), line(b'd'));
+        assert_eq!(resolve("since 2024-12-13T08:00:00+01:00", file), line(b'b'));
+        // The last line is incomplete, so we wait there
+        assert_eq!(resolve("since 2024-12-13T09:00:00Z", file), line(b'e'));
+        assert_eq!(resolve("since 2024-12-13T10:00:00Z", file), line(b'e'));
+    }
+
+    #[test]
+    fn timestamp_formats() {
+        let regex = Regex::new(r"\[(.*?)\]").unwrap();
+        let timestamps = Timestamps {
+            regex: Some(&regex),
+            format: Some("%d/%b/%Y:%H:%M:%S"),
+        };
+        let file = b"x [13/Dec/2024:06:00:00] a\n\
+                     x [13/Dec/2024:07:00:00] b\n\
+                     x [13/Dec/2024:08:00:00] c\n"
+            .to_vec();
+        let at = |s| DateTime::parse_from_rfc3339(s).unwrap().to_utc();
+        let since = |t| since(&file, file.len(), at(t), &timestamps, b'\n').unwrap();
+        assert_eq!(since("2024-12-13T06:30:00Z"), 27);
+        assert_eq!(since("2024-12-13T07:00:00Z"), 27);
+        assert_eq!(since("2024-12-13T08:00:00Z"), 54);
+        assert_eq!(since("2024-12-13T09:00:00Z"), 81);
+        let with_zone = Timestamps {
+            regex: None,
+            format: Some("%Y-%m-%d %H:%M:%S %z"),
+        };
+        let file = b"2024-12-13 07:00:00 +0100 a\n2024-12-13 07:00:00 +0000 b\n".to_vec();
+        let t = at("2024-12-13T06:30:00Z");
+        assert_eq!(
+            super::since(&file, file.len(), t, &with_zone, b'\n').unwrap(),
+            28
+        );
+    }
+}
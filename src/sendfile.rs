@@ -0,0 +1,271 @@
+//! The data path shared by the readiness-based backends (epoll and kqueue).
+//!
+//! Client sockets are made non-blocking and registered with the backend's
+//! poller, which tells us when they become writable.  When sendfile() returns
+//! EAGAIN we mark the client as in-flight and wait for the socket to become
+//! writable again.
+//!
+//! Clients whose data needs encoding (see `encode`) are sent it with write()
+//! instead of sendfile(), but are otherwise treated the same.
+
+use crate::encode::Encoder;
+use crate::ratelimit::TokenBucket;
+use crate::source::Source;
+use crate::{
+    should_disconnect, truncate_policy, Client, ClientId, Clients, ErrorClass, FilePolicy, Result,
+    Scheduler, BYTES_SENT, FILE_LENGTH,
+};
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::io::Errno;
+use std::collections::HashSet;
+use std::fs::File;
+use std::sync::atomic::Ordering;
+use tracing::*;
+
+pub enum Progress {
+    /// Every client is either caught up or waiting for its socket to drain
+    Idle,
+    /// Some clients are waiting for the rate limiter, or to retry after a
+    /// transient error
+    Throttled,
+    /// Some clients could be sent more data right away
+    MoreToSend,
+}
+
+/// Send each client a chunk of data.  We only do one sendfile() per client
+/// per pass, of at most `--chunk-size`, so that a client with a big backlog
+/// doesn't hog the runloop.
+///
+/// `register` is called the first time we see each client.  It should
+/// arrange for the client to be marked as no longer in-flight when its socket
+/// becomes writable.
+pub fn send_data(
+    source: &dyn Source,
+    clients: &mut Clients,
+    registered: &mut HashSet<ClientId>,
+    total_rate: &mut Option<TokenBucket>,
+    sched: &mut Scheduler,
+    mut register: impl FnMut(ClientId, &Client) -> Result<()>,
+) -> Result<Progress> {
+    let file_len = FILE_LENGTH.load(Ordering::Acquire);
+    let mut progress = Progress::Idle;
+    let mut dead = vec![];
+    let mut truncated = vec![];
+    let mut kicked = vec![];
+    for client_id in sched.order(clients) {
+        let client = clients.get_mut(&client_id).unwrap();
+        if registered.insert(client_id) {
+            trace!(client_id, "Registering new client");
+            rustix::io::ioctl_fionbio(&client.conn, true)?;
+            register(client_id, client)?;
+        }
+        if should_disconnect(client_id) {
+            kicked.push(client_id);
+            continue;
+        }
+        // The file has shrunk out from under the client.  With --on-truncate
+        // linger, it waits for the file to grow past where it got to.
+        if client.offset > file_len && truncate_policy() != FilePolicy::Linger {
+            truncated.push(client_id);
+            continue;
+        }
+        if client.in_flight {
+            continue;
+        }
+        if client.eof_frame.is_some() {
+            // We're about to exit; the client just needs telling why
+            client.send_queued_eof_frame();
+            continue;
+        }
+        if client.caught_up(file_len) {
+            continue;
+        }
+        if client.backing_off() {
+            if matches!(progress, Progress::Idle) {
+                progress = Progress::Throttled;
+            }
+            continue;
+        }
+        let max_len = match client.allowance(total_rate) {
+            0 => {
+                trace!(client_id, "Client is throttled");
+                if matches!(progress, Progress::Idle) {
+                    progress = Progress::Throttled;
+                }
+                continue;
+            }
+            n => n.min(sched.chunk_size),
+        };
+        #[cfg(target_os = "linux")]
+        if let Some(file) = source.as_file() {
+            crate::fadvise::readahead(file, client, file_len);
+        }
+        let result = match &mut client.encoder {
+            None => {
+                let max_len = max_len.min(file_len - client.offset);
+                source
+                    .copy_to(client.conn.as_fd(), client.offset, max_len)
+                    .map_err(|e| Errno::from_io_error(&e).unwrap_or(Errno::IO))
+                    .inspect(|n_sent| client.offset += n_sent)
+            }
+            Some(encoder) => send_encoded(
+                &client.conn,
+                encoder,
+                source,
+                &mut client.offset,
+                file_len,
+                max_len,
+            ),
+        };
+        match result {
+            Ok(n_sent) => {
+                trace!(parent: &client.span, n_sent, "Sent data to client");
+                client.retries = 0;
+                BYTES_SENT.fetch_add(n_sent as u64, Ordering::Relaxed);
+                if let Some(bucket) = &mut client.rate_limit {
+                    bucket.consume(n_sent);
+                }
+                if let Some(bucket) = total_rate {
+                    bucket.consume(n_sent);
+                }
+                if n_sent > 0 && !client.caught_up(file_len) {
+                    progress = Progress::MoreToSend;
+                }
+            }
+            Err(Errno::AGAIN) => {
+                trace!(client_id, "Socket is full");
+                client.in_flight = true;
+            }
+            // Some filesystems don't support sendfile()
+            Err(Errno::INVAL) if client.encoder.is_none() => {
+                let _g = info_span!("", client_id).entered();
+                client.stop_splicing();
+                progress = Progress::MoreToSend;
+            }
+            Err(e) => {
+                let _g = info_span!("", client_id).entered();
+                match ErrorClass::of(e) {
+                    ErrorClass::Transient if client.back_off(e) => {
+                        if matches!(progress, Progress::Idle) {
+                            progress = Progress::Throttled;
+                        }
+                        continue;
+                    }
+                    ErrorClass::Transient => (),
+                    ErrorClass::Closed => info!("Socket closed by other side"),
+                    ErrorClass::Fatal => error!("{e}"),
+                }
+                dead.push((client_id, e));
+            }
+        }
+    }
+    for client_id in truncated {
+        warn!(client_id, "The file was truncated");
+        if !clients.get_mut(&client_id).unwrap().truncated() {
+            // It may be starting again from the beginning
+            if truncate_policy() == FilePolicy::Reopen {
+                progress = Progress::MoreToSend;
+            }
+            continue;
+        }
+        let client = clients.remove(&client_id).unwrap();
+        client.disconnect("file truncated");
+        registered.remove(&client_id);
+    }
+    for client_id in kicked {
+        let mut client = clients.remove(&client_id).unwrap();
+        client.send_eof_frame("rotated");
+        client.disconnect("file is gone");
+        registered.remove(&client_id);
+    }
+    for (client_id, e) in dead {
+        // Closing the socket removes it from the poller
+        if let Some(client) = clients.remove(&client_id) {
+            client.disconnect(e);
+        }
+        registered.remove(&client_id);
+    }
+    Ok(progress)
+}
+
+/// The encoded equivalent of sendfile().  If everything we've encoded so far
+/// has been sent, we encode the next chunk of the file (advancing `offset`).
+/// Then we write up to `max_len` bytes of encoded data to the socket.
+/// Returns EAGAIN if the socket is full.
+fn send_encoded(
+    sock: &OwnedFd,
+    encoder: &mut Encoder,
+    source: &dyn Source,
+    offset: &mut usize,
+    file_len: usize,
+    max_len: usize,
+) -> Result<usize, Errno> {
+    if encoder.pending().is_empty() {
+        *offset += encoder
+            .encode(source, *offset, file_len)
+            .map_err(|e| Errno::from_io_error(&e).unwrap_or(Errno::IO))?;
+    }
+    let pending = encoder.pending();
+    if pending.is_empty() {
+        // Waiting for the rest of a line
+        return Ok(0);
+    }
+    let n_sent = rustix::io::write(sock, &pending[..pending.len().min(max_len)])?;
+    encoder.consume(n_sent);
+    Ok(n_sent)
+}
+
+/// Send up to `len` bytes of the file, starting at `offset`, to the socket.
+/// Returns EAGAIN if the socket is full.
+#[cfg(target_os = "linux")]
+pub fn sendfile(sock: BorrowedFd, file: &File, offset: usize, len: usize) -> Result<usize, Errno> {
+    let mut offset = offset as u64;
+    rustix::fs::sendfile(sock, file, Some(&mut offset), len)
+}
+
+/// Send up to `len` bytes of the file, starting at `offset`, to the socket.
+/// Returns EAGAIN if the socket is full.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn sendfile(sock: BorrowedFd, file: &File, offset: usize, len: usize) -> Result<usize, Errno> {
+    use std::os::fd::AsRawFd;
+    // Unlike on Linux, the BSD sendfile() reports EAGAIN even when it managed
+    // to send some of the data.  In that case we treat it as a success.
+    #[cfg(target_os = "macos")]
+    let (ret, sent) = {
+        let mut len = len as libc::off_t;
+        let ret = unsafe {
+            libc::sendfile(
+                file.as_raw_fd(),
+                sock.as_raw_fd(),
+                offset as libc::off_t,
+                &mut len,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        (ret, len)
+    };
+    #[cfg(target_os = "freebsd")]
+    let (ret, sent) = {
+        let mut sbytes: libc::off_t = 0;
+        let ret = unsafe {
+            libc::sendfile(
+                file.as_raw_fd(),
+                sock.as_raw_fd(),
+                offset as libc::off_t,
+                len,
+                std::ptr::null_mut(),
+                &mut sbytes,
+                0,
+            )
+        };
+        (ret, sbytes)
+    };
+    if ret == -1 {
+        let e = Errno::from_io_error(&std::io::Error::last_os_error()).unwrap_or(Errno::IO);
+        if !(e == Errno::AGAIN && sent > 0) {
+            return Err(e);
+        }
+    }
+    Ok(sent as usize)
+}
@@ -0,0 +1,180 @@
+//! Where the data comes from.  Normally that's the file given on the command
+//! line, but embedders can serve anything which implements [`Source`]: an
+//! in-memory ring buffer, a shared memory segment, etc.
+//!
+//! Only files can be served by the io_uring backend, since it splices
+//! straight from the file.  Other sources use epoll (or kqueue).
+
+use crate::{wake_runloop, FILE_LENGTH};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::BorrowedFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+
+/// The data being served.  It's set once the file has been opened.
+pub(crate) static SOURCE: OnceLock<Box<dyn Source>> = OnceLock::new();
+
+/// The most data the default `copy_to` reads at a time
+const COPY_CHUNK: usize = 64 * 1024;
+
+/// A growing sequence of bytes which tailsrv can serve.  Data may be added to
+/// the end, but bytes which have been made available mustn't change.
+pub trait Source: Send + Sync {
+    /// How many bytes are available right now
+    fn len(&self) -> std::io::Result<usize>;
+
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The offset of the oldest byte which is still available.  Sources
+    /// which throw old data away (like a ring buffer) should override this.
+    fn first(&self) -> usize {
+        0
+    }
+
+    /// Read the bytes starting at `offset` into `buf`.  Returns how many
+    /// were read, which may be fewer than asked for.
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize>;
+
+    /// Write up to `len` bytes, starting at `offset`, to the socket `sock`.
+    /// The socket is non-blocking: if it's full, return `WouldBlock`.
+    ///
+    /// The default implementation goes via `read_at`.  Override it if the
+    /// data can be sent more directly.
+    fn copy_to(&self, sock: BorrowedFd, offset: usize, len: usize) -> std::io::Result<usize> {
+        let mut buf = vec![0; len.min(COPY_CHUNK)];
+        let n = self.read_at(&mut buf, offset)?;
+        Ok(rustix::io::write(sock, &buf[..n])?)
+    }
+
+    /// This is called once, when the server starts.  The source should call
+    /// `growth.notify()` whenever more data becomes available.
+    fn watch(&self, growth: Growth);
+
+    /// If the source is a file, the file.  This lets it be served with
+    /// io_uring, and lets us notice when it's moved or deleted.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Which file this is, as its device and inode numbers, so that a client
+    /// which comes back later can tell whether it has been replaced.  `None`
+    /// if it isn't a file.
+    fn identity(&self) -> Option<(u64, u64)> {
+        let meta = self.as_file()?.metadata().ok()?;
+        Some((meta.dev(), meta.ino()))
+    }
+}
+
+impl Source for File {
+    fn len(&self) -> std::io::Result<usize> {
+        let meta = self.metadata()?;
+        let len = match meta.file_type() {
+            x if x.is_file() => meta.len(),
+            // These only come with --allow-special
+            #[cfg(target_os = "linux")]
+            x if x.is_block_device() => block_device_len(self)?,
+            // Other seekable files say how long they are when you seek to the
+            // end.  We always read at an explicit offset, so moving the file
+            // position doesn't matter.
+            _ => rustix::fs::seek(self, rustix::fs::SeekFrom::End(0))?,
+        };
+        usize::try_from(len).map_err(std::io::Error::other)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize> {
+        FileExt::read_at(self, buf, offset as u64)
+    }
+
+    fn copy_to(&self, sock: BorrowedFd, offset: usize, len: usize) -> std::io::Result<usize> {
+        Ok(crate::sendfile::sendfile(sock, self, offset, len)?)
+    }
+
+    fn watch(&self, _: Growth) {
+        // The runloop watches files itself, with inotify or kqueue
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// Lets the tests serve a byte string
+#[cfg(test)]
+impl Source for Vec<u8> {
+    fn len(&self) -> std::io::Result<usize> {
+        Ok(Vec::len(self))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> std::io::Result<usize> {
+        let src = self.get(offset..).unwrap_or_default();
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        Ok(n)
+    }
+
+    fn watch(&self, _: Growth) {}
+}
+
+/// The size of a block device, from BLKGETSIZE64
+#[cfg(target_os = "linux")]
+fn block_device_len(file: &File) -> std::io::Result<u64> {
+    use rustix::ioctl::{ioctl, Getter, ReadOpcode};
+    // BLKGETSIZE64 is _IOR(0x12, 114, size_t)
+    type Blkgetsize64 = ReadOpcode<0x12, 114, u64>;
+    // SAFETY: BLKGETSIZE64 writes a u64, which is what the getter expects
+    Ok(unsafe { ioctl(file, Getter::<Blkgetsize64, u64>::new())? })
+}
+
+/// Given to [`Source::watch`], for letting the server know that the source
+/// has grown
+#[derive(Debug, Clone)]
+pub struct Growth(pub(crate) ());
+
+impl Growth {
+    /// The source now has `len` bytes available
+    pub fn notify(&self, len: usize) {
+        grew(len);
+        wake_runloop();
+    }
+}
+
+/// Record the new length, and let anyone waiting for more data know
+pub(crate) fn grew(len: usize) {
+    // With --commit-marker, only the complete records are available
+    let len = match SOURCE.get() {
+        Some(source) => match crate::marker::committed(&**source, len) {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::warn!("Couldn't look for commit markers: {e}");
+                return;
+            }
+        },
+        None => len,
+    };
+    FILE_LENGTH.store(len, Ordering::Release);
+    crate::index::file_grew();
+    #[cfg(feature = "grpc")]
+    crate::grpc::file_grew();
+}
+
+/// Reads a range of the source, for when it's handy to have a `Read`
+pub(crate) struct Reader<'a> {
+    pub source: &'a dyn Source,
+    pub pos: usize,
+    pub end: usize,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.end.saturating_sub(self.pos));
+        let n = self.source.read_at(&mut buf[..len], self.pos)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
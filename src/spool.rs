@@ -0,0 +1,50 @@
+//! Support for producers which can only write to a pipe: either stdin (with
+//! `--stdin`) or a FIFO given as PATH.  We append whatever arrives to the
+//! spool, and serve that.  The spool is usually a file (`--spool`), but may
+//! be a ring buffer in memory (`--memory-buffer`).
+
+use crate::Result;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use tracing::*;
+
+/// Open a spool file for appending to
+pub fn open(spool: &Path) -> Result<File> {
+    Ok(File::options().append(true).create(true).open(spool)?)
+}
+
+/// Start copying `input` to the spool on a thread of its own
+pub fn spawn(mut input: impl Read + Send + 'static, mut spool: impl Write + Send + 'static) {
+    std::thread::spawn(move || match std::io::copy(&mut input, &mut spool) {
+        // The clients still want what we've got, so keep serving it
+        Ok(n) => info!("Input closed after {n} bytes; still serving the spool"),
+        Err(e) => {
+            error!("Couldn't spool the input: {e}");
+            std::process::exit(1);
+        }
+    });
+}
+
+/// Start draining a FIFO into the spool on a thread of its own.  Producers
+/// may come and go: when one closes the FIFO, we wait for the next.
+pub fn spawn_fifo(fifo: &Path, mut spool: impl Write + Send + 'static) {
+    let fifo = fifo.to_owned();
+    std::thread::spawn(move || loop {
+        // This blocks until someone opens the FIFO for writing
+        let result = File::open(&fifo).and_then(|mut input| std::io::copy(&mut input, &mut spool));
+        match result {
+            Ok(n) => info!("The writer closed the FIFO after {n} bytes"),
+            Err(e) => {
+                error!("{}: Couldn't spool the FIFO: {e}", fifo.display());
+                std::process::exit(1);
+            }
+        }
+    });
+}
+
+/// Whether the path refers to a FIFO.  False if it doesn't exist.
+pub fn is_fifo(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|x| x.file_type().is_fifo())
+}
@@ -0,0 +1,93 @@
+//! Support for `--state-file`.  When tailsrv is asked to shut down (with
+//! SIGTERM or SIGINT), it writes out the clients which were connected, and
+//! how far each of them had got.  On the next start it reads the file back,
+//! so that a client with a named cursor which hasn't committed anything
+//! carries on from where it was, rather than from the position in its header.
+//! This suits consumers which never commit, and rely on reconnecting.
+//!
+//! Each line describes one client:
+//!
+//! ```text
+//! peer=10.0.0.7:51412 offset=52311 requested="cursor billing"
+//! ```
+
+use crate::{visit_clients, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::*;
+
+/// The offsets which clients with named cursors had reached when we last
+/// shut down.  Each is used at most once.
+static SAVED: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Read the state written when we last shut down.  The file is removed
+/// afterwards, so that it can't be used twice.  It doesn't have to exist.
+pub fn load(path: &Path) -> Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+    let mut saved = SAVED.lock().unwrap();
+    for line in contents.lines().filter(|x| !x.trim().is_empty()) {
+        let Some((offset, name)) = parse_line(line) else {
+            return Err(format!("{}: Bad line: {line}", path.display()).into());
+        };
+        // If several clients shared a cursor, the one which was furthest
+        // behind wins, so that nobody misses anything
+        if let Some(name) = name {
+            let offset = saved.get(name).map_or(offset, |&x| x.min(offset));
+            saved.insert(name.to_owned(), offset);
+        }
+    }
+    info!(path = %path.display(), n = saved.len(), "Loaded the saved cursors");
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Returns the offset and, if the client used one, the cursor name
+fn parse_line(line: &str) -> Option<(usize, Option<&str>)> {
+    let (_, rest) = line.split_once(" offset=")?;
+    let (offset, requested) = rest.split_once(" requested=")?;
+    let requested = requested.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((offset.parse().ok()?, requested.strip_prefix("cursor ")))
+}
+
+/// Where the client with this cursor had got to when we last shut down.  The
+/// offset is forgotten once it's been taken.
+pub fn take(name: &str) -> Option<usize> {
+    SAVED.lock().unwrap().remove(name)
+}
+
+/// Write out the connected clients.  The runloops carry on while we do it,
+/// so a client may get a little further than we say; if so, it's sent that
+/// part again when it reconnects.
+pub fn save(path: &Path) -> Result<()> {
+    let lines = visit_clients(|clients| {
+        let mut lines = vec![];
+        for client in clients.values() {
+            // Anything still in the pipe hasn't been sent yet
+            #[cfg(target_os = "linux")]
+            let offset = client.offset - client.bytes_in_pipe;
+            #[cfg(not(target_os = "linux"))]
+            let offset = client.offset;
+            lines.push(format!(
+                "peer={} offset={offset} requested={:?}\n",
+                client.session.peer(),
+                client.session.requested(),
+            ));
+        }
+        lines
+    });
+    let lines: Vec<String> = lines.into_iter().flatten().collect();
+    let contents = lines.concat();
+    // Write it somewhere else first, so that a crash can't leave it half
+    // written
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    info!(path = %path.display(), n = lines.len(), "Saved the clients");
+    Ok(())
+}
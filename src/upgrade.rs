@@ -0,0 +1,299 @@
+//! Support for `--upgrade-socket`, for replacing tailsrv with a new version
+//! without disconnecting anyone.  When a new process is started with the
+//! same `--upgrade-socket` as a running one, the old process hands over its
+//! listening sockets and carries on serving the clients it already has.  It
+//! exits once the last of them has gone.
+//!
+//! Until then, both processes are accepting connections on the same sockets.
+//! The old one passes the connections it accepts over to the new one, along
+//! with the cursors which its clients commit, so that only the new process
+//! writes to the `--cursor-file`.
+//!
+//! The messages are sent over a SOCK_SEQPACKET socket, so each one arrives
+//! whole.  The first byte says what it is:
+//!
+//! - `L` hands over the listeners.  The rest of the bytes say what each of the
+//!   attached fds is: `t` (TCP), `u` (unix), or `h` (HTTP).
+//! - `T`, `U`, and `H` pass over a connection which was accepted on one of
+//!   those.  The connection is attached.
+//! - `C` passes over a commit, as `C<NAME> <OFFSET>`.
+
+use crate::{
+    cursor, exit, http, spawn_client, tcp_client_id, unix_client_id, Listeners, Opts, Result,
+    IDLE_CHECK_INTERVAL, NUM_CLIENTS,
+};
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::io::Errno;
+use rustix::net::{
+    AddressFamily, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags, SocketAddrUnix, SocketType,
+};
+use std::io::{IoSlice, IoSliceMut};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use tracing::*;
+
+/// We hand over at most one listener of each kind
+const MAX_FDS: usize = 3;
+/// Longer messages are truncated.  This leaves plenty of room for a commit.
+const MAX_MSG_LEN: usize = 8192;
+
+/// The connection to the process which we took over from, until we start
+/// serving the connections it passes over
+static PREDECESSOR: Mutex<Option<OwnedFd>> = Mutex::new(None);
+/// The connection to the process which took over from us.  Set once we've
+/// handed over.
+static SUCCESSOR: OnceLock<OwnedFd> = OnceLock::new();
+
+/// A connection which was accepted by the old process
+#[derive(Debug, Clone, Copy)]
+pub enum Conn {
+    Tcp,
+    Unix,
+    Http,
+}
+
+/// If there's a tailsrv listening at `path`, take over its listening sockets.
+/// Returns the TCP and unix listeners, and the HTTP one.  These are all empty
+/// if nobody's there.
+pub fn take_over(path: &Path) -> Result<(Listeners, Option<TcpListener>)> {
+    let mut listeners = Listeners::default();
+    let mut http = None;
+    let sock = rustix::net::socket(AddressFamily::UNIX, SocketType::SEQPACKET, None)?;
+    match rustix::net::connect_unix(&sock, &SocketAddrUnix::new(path)?) {
+        Ok(()) => (),
+        Err(Errno::NOENT | Errno::CONNREFUSED) => {
+            info!(path = %path.display(), "No running tailsrv to take over from");
+            return Ok((listeners, http));
+        }
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    }
+    let (msg, fds) = recv(&sock)?.ok_or("The old process hung up without handing over")?;
+    let Some((b'L', kinds)) = msg.split_first() else {
+        return Err("The old process sent a bad handover message".into());
+    };
+    if kinds.len() != fds.len() {
+        return Err("The old process sent the wrong number of listeners".into());
+    }
+    for (kind, fd) in kinds.iter().zip(fds) {
+        match kind {
+            b't' => listeners.tcp = Some(TcpListener::from(fd)),
+            b'u' => listeners.unix = Some(UnixListener::from(fd)),
+            b'h' => http = Some(TcpListener::from(fd)),
+            _ => return Err("The old process sent an unknown kind of listener".into()),
+        }
+    }
+    info!(path = %path.display(), "Took over the listening sockets");
+    *PREDECESSOR.lock().unwrap() = Some(sock);
+    Ok((listeners, http))
+}
+
+/// Serve the connections which the old process passes over to us (if we took
+/// over from one), and wait for a new process to take over from us.  The
+/// listeners are the ones we'll hand over.
+pub fn start(
+    path: &Path,
+    listeners: &Listeners,
+    http: Option<&TcpListener>,
+    opts: &'static Opts,
+) -> Result<()> {
+    let mut fds = vec![];
+    if let Some(x) = &listeners.tcp {
+        fds.push((b't', x.as_fd().try_clone_to_owned()?));
+    }
+    if let Some(x) = &listeners.unix {
+        fds.push((b'u', x.as_fd().try_clone_to_owned()?));
+    }
+    if let Some(x) = http {
+        fds.push((b'h', x.as_fd().try_clone_to_owned()?));
+    }
+    if let Some(predecessor) = PREDECESSOR.lock().unwrap().take() {
+        std::thread::spawn(move || receive(predecessor, opts));
+    }
+
+    // The old process's socket is still there, but it's already handed over
+    // to us, so we replace it
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let sock = rustix::net::socket(AddressFamily::UNIX, SocketType::SEQPACKET, None)?;
+    rustix::net::bind_unix(&sock, &SocketAddrUnix::new(path)?)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    rustix::net::listen(&sock, 1)?;
+    info!(path = %path.display(), "Listening for upgrades");
+    std::thread::spawn(move || {
+        if let Err(e) = hand_over(sock, fds) {
+            error!("Couldn't hand over to the new process: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Wait for a new process to connect, and send it our listeners.  After
+/// that, we only serve the clients we already have.
+fn hand_over(sock: OwnedFd, fds: Vec<(u8, OwnedFd)>) -> Result<()> {
+    let conn = loop {
+        match rustix::net::accept(&sock) {
+            Err(Errno::INTR) => continue,
+            x => break x?,
+        }
+    };
+    let mut msg = vec![b'L'];
+    msg.extend(fds.iter().map(|x| x.0));
+    let fds: Vec<BorrowedFd> = fds.iter().map(|x| x.1.as_fd()).collect();
+    send(&conn, &msg, &fds)?;
+    info!("Handed over to a new process; exiting once the remaining clients have gone");
+    SUCCESSOR.set(conn).map_err(|_| "We already handed over")?;
+    loop {
+        std::thread::sleep(IDLE_CHECK_INTERVAL);
+        if NUM_CLIENTS.load(Ordering::Acquire) == 0 {
+            info!("The last client has gone; exiting");
+            exit::exit(exit::Reason::Finished);
+        }
+    }
+}
+
+/// Pass a newly-accepted connection over to the new process, if we've handed
+/// over to one.  Returns false if we should serve it ourselves.
+pub fn forward(conn: impl AsFd, kind: Conn) -> bool {
+    let Some(successor) = SUCCESSOR.get() else {
+        return false;
+    };
+    let msg = match kind {
+        Conn::Tcp => b"T",
+        Conn::Unix => b"U",
+        Conn::Http => b"H",
+    };
+    match send(successor, msg, &[conn.as_fd()]) {
+        Ok(()) => {
+            debug!(?kind, "Passed a connection to the new process");
+            true
+        }
+        Err(e) => {
+            warn!(?kind, "Couldn't pass a connection to the new process: {e}");
+            false
+        }
+    }
+}
+
+/// Pass a commit over to the new process, if we've handed over to one.
+/// Returns false if we should record it ourselves.
+pub fn forward_commit(name: &str, offset: usize) -> bool {
+    let Some(successor) = SUCCESSOR.get() else {
+        return false;
+    };
+    match send(successor, format!("C{name} {offset}").as_bytes(), &[]) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                cursor = name,
+                "Couldn't pass a commit to the new process: {e}"
+            );
+            false
+        }
+    }
+}
+
+/// Serve the connections which the old process passes over, until it exits
+fn receive(predecessor: OwnedFd, opts: &'static Opts) {
+    loop {
+        let (msg, mut fds) = match recv(&predecessor) {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                info!("The old process has exited");
+                return;
+            }
+            Err(e) => {
+                error!("Couldn't receive from the old process: {e}");
+                return;
+            }
+        };
+        match (msg.split_first(), fds.pop()) {
+            (Some((b'T', _)), Some(fd)) => {
+                let conn = TcpStream::from(fd);
+                if let Some(client_id) = tcp_client_id(&conn, opts) {
+                    spawn_client(conn, client_id, opts);
+                }
+            }
+            (Some((b'U', _)), Some(fd)) => {
+                spawn_client(UnixStream::from(fd), unix_client_id(), opts);
+            }
+            (Some((b'H', _)), Some(fd)) => {
+                let conn = TcpStream::from(fd);
+                if let Some(client_id) = tcp_client_id(&conn, opts) {
+                    http::spawn_client(conn, client_id, opts);
+                }
+            }
+            (Some((b'C', commit)), None) => {
+                let commit = String::from_utf8_lossy(commit);
+                let parsed = commit
+                    .rsplit_once(' ')
+                    .and_then(|(name, offset)| Some((name, offset.parse().ok()?)));
+                match parsed {
+                    Some((name, offset)) => {
+                        if let Err(e) = cursor::commit(name, offset) {
+                            warn!(cursor = name, "Couldn't record commit: {e}");
+                        }
+                    }
+                    None => warn!(%commit, "Bad commit from the old process"),
+                }
+            }
+            _ => warn!("Bad message from the old process"),
+        }
+    }
+}
+
+fn send(sock: &OwnedFd, msg: &[u8], fds: &[BorrowedFd]) -> Result<()> {
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() && !control.push(SendAncillaryMessage::ScmRights(fds)) {
+        return Err("Too many fds to send".into());
+    }
+    loop {
+        match rustix::net::sendmsg(
+            sock,
+            &[IoSlice::new(msg)],
+            &mut control,
+            SendFlags::NOSIGNAL,
+        ) {
+            Err(Errno::INTR) => continue,
+            x => break x?,
+        };
+    }
+    Ok(())
+}
+
+/// Receive a message, along with any fds attached to it.  `None` if the
+/// other end has hung up.
+fn recv(sock: &OwnedFd) -> Result<Option<(Vec<u8>, Vec<OwnedFd>)>> {
+    let mut buf = vec![0; MAX_MSG_LEN];
+    let mut space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let ret = loop {
+        let iov = &mut [IoSliceMut::new(&mut buf)];
+        match rustix::net::recvmsg(sock, iov, &mut control, RecvFlags::CMSG_CLOEXEC) {
+            Err(Errno::INTR) => continue,
+            x => break x?,
+        }
+    };
+    let mut fds = vec![];
+    for msg in control.drain() {
+        if let RecvAncillaryMessage::ScmRights(x) = msg {
+            fds.extend(x);
+        }
+    }
+    if ret.bytes == 0 {
+        return Ok(None);
+    }
+    if ret.flags.contains(RecvFlags::TRUNC) {
+        return Err("Message was too long".into());
+    }
+    buf.truncate(ret.bytes);
+    Ok(Some((buf, fds)))
+}
@@ -0,0 +1,1045 @@
+//! The io_uring backend.  This is the default.
+
+use crate::exit;
+use crate::fadvise;
+use crate::feed::{self, Feed, FeedId, MAX_FEEDS};
+use crate::nursery::Nursery;
+use crate::ratelimit::{TokenBucket, THROTTLE_TICK};
+use crate::source::Source;
+use crate::upgrade;
+use crate::{
+    handle_inotify, handle_messages, held_back, num_shards, read_eventfd, should_disconnect,
+    take_inbox, tcp_client_id, truncate_policy, unix_client_id, wake_runloop, Client, ClientId,
+    Clients, ErrorClass, FilePolicy, Listeners, Opts, Result, Scheduler, BYTES_SENT, EVENTFD,
+    FILE_LENGTH,
+};
+use rustix::fd::{AsRawFd, FromRawFd, OwnedFd};
+use rustix::io::Errno;
+use rustix_uring::IoUring;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs::File;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::*;
+
+pub const FLAG_POLLIN: u32 = 0x1;
+const FLAG_POLLERR: u32 = 0x8;
+const FLAG_POLLHUP: u32 = 0x10;
+
+const DEFAULT_ENTRIES: u32 = 256;
+
+/// Set up a ring with the options we were given.  `probe` has already checked
+/// that io_uring is usable.
+pub fn setup(opts: &Opts) -> std::io::Result<IoUring> {
+    let entries = opts.uring_entries.unwrap_or(DEFAULT_ENTRIES);
+    // Each client has at most two requests in flight, so if we know how many
+    // clients there can be we make sure their completions will all fit.
+    // There are also a handful of long-lived requests (polls, accepts, the
+    // timer).
+    let max_in_flight = opts.max_clients.map_or(0, |n| 2 * n as u32 + 8);
+    let cq_entries = (2 * entries).max(max_in_flight);
+    let mut builder = IoUring::builder();
+    builder.setup_cqsize(cq_entries).setup_clamp();
+    if let Some(idle_ms) = opts.sqpoll {
+        builder.setup_sqpoll(idle_ms);
+    }
+    let uring = builder.build(entries)?;
+    if !uring.params().is_feature_nodrop() {
+        // Dropped completions would leave clients stuck forever
+        warn!("This kernel may drop io_uring completions if the queue overflows");
+    }
+    info!(
+        sq_entries = uring.params().sq_entries(),
+        cq_entries = uring.params().cq_entries(),
+        sqpoll = opts.sqpoll.is_some(),
+        "Set up the io_uring",
+    );
+    Ok(uring)
+}
+
+/// Run the runloops.  This one accepts new clients and watches the file, and
+/// with --uring-threads there are more on threads of their own.  Each serves
+/// its own shard of the clients.
+pub fn run(
+    uring: IoUring,
+    file: &File,
+    ino_fd: Option<&OwnedFd>,
+    listeners: Listeners,
+    opts: &'static Opts,
+) -> Result<()> {
+    feed::enable();
+    for shard in 1..num_shards() {
+        let uring = setup(opts)?;
+        let file = file.try_clone()?;
+        std::thread::spawn(move || {
+            let _g = info_span!("", shard).entered();
+            if let Err(e) = run_shard(uring, &file, None, Listeners::default(), shard, opts) {
+                error!("Runloop failed: {e}");
+                exit::exit(exit::Reason::Uring);
+            }
+        });
+    }
+    run_shard(uring, file, ino_fd, listeners, 0, opts)
+}
+
+fn run_shard(
+    mut uring: IoUring,
+    file: &File,
+    ino_fd: Option<&OwnedFd>,
+    mut listeners: Listeners,
+    shard: usize,
+    opts: &'static Opts,
+) -> Result<()> {
+    info!(fd = EVENTFD.as_raw_fd(), "Created an eventfd");
+    let poll_eventfd = rustix_uring::opcode::PollAdd::new(
+        rustix_uring::types::Fd(EVENTFD.as_raw_fd()),
+        FLAG_POLLIN,
+    )
+    .multi(true)
+    .build()
+    .user_data(UserData::NewClient.into());
+    unsafe { uring.submission().push(&poll_eventfd)? };
+    info!("Polling the eventfd for events");
+
+    // Each file served with --serve goes in the slot matching its ID.  The
+    // slots start out empty, and are filled in by `FeedSlots::sync`.
+    let mut fds = vec![-1; MAX_FEEDS as usize + 1];
+    fds[0] = file.as_raw_fd();
+    uring.submitter().register_files(&fds)?;
+    let file_fd = rustix_uring::types::Fixed(0);
+    info!(?file_fd, "Registered the file with the io_uring");
+
+    if let Some(ino_fd) = ino_fd {
+        let poll_ino = rustix_uring::opcode::PollAdd::new(
+            rustix_uring::types::Fd(ino_fd.as_raw_fd()),
+            FLAG_POLLIN,
+        )
+        .multi(true)
+        .build()
+        .user_data(UserData::Inotify.into());
+        unsafe { uring.submission().push(&poll_ino)? };
+        info!("Polling the inotify watch for events");
+    }
+
+    let mut reqs = VecDeque::new();
+    if let Some(listener) = &listeners.tcp {
+        reqs.push_back(accept(listener, UserData::AcceptTcp));
+    }
+    if let Some(listener) = &listeners.unix {
+        reqs.push_back(accept(listener, UserData::AcceptUnix));
+    }
+    info!("Accepting connections on the ring");
+
+    info!("Starting runloop");
+    let inbox = take_inbox(shard);
+    let mut clients = Clients::new();
+    let mut leaving = Leaving::default();
+    let mut feeds = FeedSlots::default();
+    let mut nursery = Nursery::default();
+    let mut timer = Timer::default();
+    // Each runloop gets an equal share of the total rate
+    let mut total_rate = opts
+        .max_total_rate
+        .map(|x| TokenBucket::new(x / num_shards() as u64));
+    let mut sched = Scheduler::new(opts);
+    loop {
+        handle_messages(&inbox, &mut clients);
+        feeds.sync(&uring, &mut clients, &mut leaving, &mut reqs, shard)?;
+        issue_requests(
+            &mut reqs,
+            &mut uring,
+            file,
+            file_fd,
+            &mut clients,
+            &mut leaving,
+            &mut timer,
+            &mut total_rate,
+            &mut sched,
+        )?;
+        trace!("Waiting for wake-ups");
+        match uring.submit_and_wait(1) {
+            Ok(_) => (),
+            // The kernel refuses new submissions while there are completions
+            // which didn't fit in the completion queue
+            Err(Errno::BUSY) => trace!("Completion queue is full; handling completions first"),
+            Err(e) => return Err(e.into()),
+        }
+        trace!("Woke up!");
+        handle_completions(
+            &mut uring,
+            &mut reqs,
+            file,
+            ino_fd,
+            &mut clients,
+            &mut leaving,
+            &mut listeners,
+            &mut nursery,
+            &mut timer,
+            &mut total_rate,
+            opts,
+        )?;
+    }
+}
+
+/// Clients which have been removed, but which the kernel may still be using:
+/// they have requests on the ring which haven't completed, and which refer to
+/// their sockets, pipes, or buffers.  Each is dropped once the last of its
+/// requests completes.
+#[derive(Default)]
+struct Leaving(HashMap<ClientId, (Client, String)>);
+
+impl Leaving {
+    /// Take the client out of the runloop, and cancel its outstanding
+    /// requests.  It's dropped straight away if it doesn't have any.
+    fn remove(
+        &mut self,
+        clients: &mut Clients,
+        client_id: ClientId,
+        reqs: &mut VecDeque<rustix_uring::squeue::Entry>,
+        reason: impl std::fmt::Display,
+    ) {
+        let Some(client) = clients.remove(&client_id) else {
+            return;
+        };
+        if client.watching_hangup {
+            reqs.push_back(cancel_hangup(client_id));
+        }
+        // The drain may be waiting for data which the fill isn't going to
+        // provide
+        if client.in_flight && client.encoder.is_none() {
+            reqs.push_back(cancel_drain(client_id));
+        }
+        if client.ops == 0 {
+            client.disconnect(reason);
+        } else {
+            trace!(
+                client_id,
+                ops = client.ops,
+                "Waiting for the client's requests to complete"
+            );
+            self.0.insert(client_id, (client, reason.to_string()));
+        }
+    }
+
+    /// One of the client's requests has completed.  If it was the last one
+    /// for a client which has been removed, the client is dropped.
+    fn completed(&mut self, clients: &mut Clients, client_id: ClientId) {
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.ops -= 1;
+        } else if let Some((client, _)) = self.0.get_mut(&client_id) {
+            client.ops -= 1;
+            if client.ops == 0 {
+                let (client, reason) = self.0.remove(&client_id).unwrap();
+                client.disconnect(reason);
+            }
+        } else {
+            warn!(client_id, "Completion for an unknown client");
+        }
+    }
+}
+
+/// The files served with --serve which this ring has registered
+#[derive(Default)]
+struct FeedSlots {
+    /// The `feed::generation()` we last caught up with
+    generation: u64,
+    registered: BTreeMap<FeedId, Arc<Feed>>,
+    /// Files which have been removed, but which our clients are still
+    /// reading
+    retiring: BTreeSet<FeedId>,
+}
+
+impl FeedSlots {
+    /// Register any files which have been added since we last looked.  Once
+    /// a file has been removed, its clients are disconnected as they catch
+    /// up, and when none are left its slot is cleared.
+    fn sync(
+        &mut self,
+        uring: &IoUring,
+        clients: &mut Clients,
+        leaving: &mut Leaving,
+        reqs: &mut VecDeque<rustix_uring::squeue::Entry>,
+        shard: usize,
+    ) -> Result<()> {
+        let generation = feed::generation();
+        if generation != self.generation {
+            self.generation = generation;
+            for feed in feed::all() {
+                if self.registered.contains_key(&feed.id) {
+                    continue;
+                }
+                uring
+                    .submitter()
+                    .register_files_update(feed.id, &[feed.file.as_raw_fd()])?;
+                debug!(feed_id = feed.id, "Registered the file with the io_uring");
+                // Like the main file and listeners, these are looked after by
+                // the first runloop
+                if shard == 0 {
+                    reqs.push_back(watch_feed(feed.id, &feed.ino_fd));
+                    reqs.push_back(accept(&feed.listener, UserData::AcceptFeed(feed.id)));
+                }
+                self.registered.insert(feed.id, feed);
+            }
+            for feed in self.registered.values() {
+                if feed.is_removed() && self.retiring.insert(feed.id) && shard == 0 {
+                    reqs.extend(cancel_feed(feed.id));
+                }
+            }
+        }
+        for feed_id in self.retiring.clone() {
+            let feed = &self.registered[&feed_id];
+            let reads = |c: &Client| c.feed.as_ref().is_some_and(|x| x.id == feed_id);
+            let done: Vec<ClientId> = clients
+                .iter()
+                .filter(|(_, c)| {
+                    reads(c) && !c.in_flight && c.bytes_in_pipe == 0 && c.caught_up(feed.len())
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            for client_id in done {
+                let _g = info_span!("", client_id).entered();
+                info!("Sent the rest of a file which has been removed");
+                leaving.remove(clients, client_id, reqs, "file removed");
+            }
+            if clients
+                .values()
+                .chain(leaving.0.values().map(|x| &x.0))
+                .any(reads)
+            {
+                continue;
+            }
+            uring.submitter().register_files_update(feed_id, &[-1])?;
+            debug!(feed_id, "Unregistered the file from the io_uring");
+            self.retiring.remove(&feed_id);
+            self.registered.remove(&feed_id);
+        }
+        Ok(())
+    }
+}
+
+/// A one-shot timeout on the ring, used to wake up the runloop when
+/// throttled clients are allowed to send again
+#[derive(Default)]
+struct Timer {
+    armed: bool,
+    // The kernel reads this when the SQE is submitted, so it needs to live
+    // somewhere stable
+    timespec: rustix_uring::types::Timespec,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn issue_requests(
+    reqs: &mut VecDeque<rustix_uring::squeue::Entry>,
+    uring: &mut IoUring,
+    file: &File,
+    file_fd: rustix_uring::types::Fixed,
+    clients: &mut Clients,
+    leaving: &mut Leaving,
+    timer: &mut Timer,
+    total_rate: &mut Option<TokenBucket>,
+    sched: &mut Scheduler,
+) -> Result<()> {
+    let file_len = FILE_LENGTH.load(Ordering::Acquire);
+    let mut any_throttled = false;
+    let mut dead = vec![];
+    let mut truncated = vec![];
+    let mut kicked = vec![];
+    for client_id in sched.order(clients) {
+        let client = clients.get_mut(&client_id).unwrap();
+        // Clients of --serve ports read that port's file
+        let feed = client.feed.clone();
+        let (file, file_fd, file_len) = match &feed {
+            Some(feed) => (&feed.file, rustix_uring::types::Fixed(feed.id), feed.len()),
+            None => (file, file_fd, file_len),
+        };
+        if !client.watching_hangup {
+            reqs.push_back(watch_hangup(client_id, client));
+            client.watching_hangup = true;
+            client.ops += 1;
+        }
+        if client.in_flight {
+            // Nothing to do
+        } else if client.eof_frame.is_some() {
+            // We're about to exit; the client just needs telling why
+            client.send_queued_eof_frame();
+        } else if client.backing_off() {
+            // The timer which wakes up throttled clients will do for these
+            // too
+            any_throttled = true;
+        } else if client.bytes_in_pipe > 0 {
+            trace!("Payload only partially delivered. Retrying...");
+            reqs.push_back(drain_pipe(client_id, client));
+            client.in_flight = true;
+            client.ops += 1;
+        } else if should_disconnect(client_id) {
+            kicked.push(client_id);
+        } else if client.offset > file_len
+            && truncate_policy() != FilePolicy::Linger
+            && Source::len(file).is_ok_and(|x| x < client.offset)
+        {
+            // The file has shrunk out from under the client.  (Fills aren't
+            // limited to the length we know about, so being past it isn't
+            // enough.)  With --on-truncate linger, it waits for the file to
+            // grow past where it got to.
+            truncated.push(client_id);
+        } else if !client.caught_up(file_len) {
+            let mut max_len = match client.allowance(total_rate) {
+                0 => {
+                    trace!(client_id, "Client is throttled");
+                    any_throttled = true;
+                    continue;
+                }
+                n => u32::try_from(n.min(sched.chunk_size)).unwrap_or(u32::MAX),
+            };
+            // Fills normally take whatever the file has, even if we haven't
+            // heard about it yet.  But that may be a partial record.  (An
+            // encoded client with output pending can be past the end, if the
+            // file was truncated under --on-truncate linger.)
+            if held_back() {
+                let available = file_len.saturating_sub(client.offset);
+                max_len = max_len.min(u32::try_from(available).unwrap_or(u32::MAX));
+            }
+            fadvise::readahead(file, client, file_len);
+            if let Some(encoder) = &mut client.encoder {
+                // The encoding happens right here on the runloop.  Only the
+                // sending is done on the ring.
+                if encoder.pending().is_empty() {
+                    match encoder.encode(file, client.offset, file_len) {
+                        Ok(n) => client.offset += n,
+                        Err(e) => {
+                            error!(client_id, "{e}");
+                            dead.push((client_id, e));
+                            continue;
+                        }
+                    }
+                }
+                let pending = u32::try_from(encoder.pending().len()).unwrap_or(u32::MAX);
+                if pending == 0 {
+                    trace!(client_id, "Waiting for the rest of a line");
+                    continue;
+                }
+                max_len = max_len.min(pending);
+            }
+            if let Some(bucket) = total_rate {
+                // We don't know how much data the splice will actually
+                // move, so we reserve as much as we're allowing it to, and
+                // refund the difference when it completes.  Otherwise every
+                // client in this pass would think it could spend the whole
+                // bucket.
+                bucket.consume(max_len as usize);
+                client.reserved = max_len as usize;
+            }
+            if client.encoder.is_some() {
+                trace!(client_id, "Sending encoded data");
+                reqs.push_back(send_encoded(client_id, client, max_len));
+                client.in_flight = true;
+                client.ops += 1;
+                continue;
+            }
+            trace!(
+                client_id,
+                file_len,
+                offset = client.offset,
+                "Filling and draining the pipe"
+            );
+            // While there's more data than will fit through the pipe in one go,
+            // we cork the socket so that the kernel only sends full packets.
+            // Once the client has caught up we uncork it, which flushes the
+            // tail straight away.
+            set_cork(
+                client_id,
+                client,
+                file_len - client.offset > client.pipe.capacity,
+            );
+            // Why fill and drain a pipe?
+            //
+            // There's no sendfile() opcode for io_uring (yet).  However,
+            // we can emulate it by splicing once from the file to a pipe,
+            // and then again from the pipe to the socket.  This is exactly
+            // how sendfile() works under the hood, so there should be no
+            // performance impact from this.
+            let fill = fill_pipe(client_id, client, file_fd, max_len);
+            let drain = drain_pipe(client_id, client);
+            // Why IO_HARDLINK, not just IO_LINK?
+            //
+            // We're (usually) asking the kernel to splice --chunk-size bytes
+            // from the file into the pipe.  This is likely going to fail - the
+            // kernel will splice in at most the pipe's capacity (64 KiB unless
+            // --pipe-size says otherwise), possibly less (even if there are
+            // more bytes than this waiting in the file).  It's ok though - the
+            // kernel will splice as much data as it can into the pipe and tell
+            // us how much it managed.  That's what we want.
+            //
+            // However, if we used IO_LINK here then the second splice (pipe ->
+            // socket) would be cancelled.  That's not what we want!
+            // IO_HARDLINK means "sequence these requests, but don't cancel the
+            // second if the first fails".
+            let fill = fill.flags(rustix_uring::squeue::Flags::IO_HARDLINK);
+            reqs.extend([fill, drain]);
+            client.in_flight = true;
+            client.ops += 2;
+        }
+    }
+    for client_id in truncated {
+        let _g = info_span!("", client_id).entered();
+        warn!("The file was truncated");
+        if clients.get_mut(&client_id).unwrap().truncated() {
+            leaving.remove(clients, client_id, reqs, "file truncated");
+        } else if truncate_policy() == FilePolicy::Reopen {
+            // It's starting again from the beginning, which needs another
+            // pass
+            wake_runloop();
+        }
+    }
+    for client_id in kicked {
+        let _g = info_span!("", client_id).entered();
+        clients
+            .get_mut(&client_id)
+            .unwrap()
+            .send_eof_frame("rotated");
+        leaving.remove(clients, client_id, reqs, "file is gone");
+    }
+    for (client_id, e) in dead {
+        leaving.remove(clients, client_id, reqs, e);
+    }
+    if any_throttled && !timer.armed {
+        trace!("Setting a timer to wake up throttled clients");
+        timer.timespec = rustix_uring::types::Timespec::from(THROTTLE_TICK);
+        let timeout = rustix_uring::opcode::Timeout::new(&timer.timespec)
+            .build()
+            .user_data(UserData::Timer.into());
+        reqs.push_back(timeout);
+        timer.armed = true;
+    }
+    trace!("Pushing {} reqs to the ring:", reqs.len());
+    while let Some(req) = reqs.front() {
+        let is_full = unsafe { uring.submission().push(req) }.is_err();
+        if is_full {
+            trace!("Queue is full; submit and retry");
+            match uring.submit() {
+                Ok(_) => (),
+                Err(Errno::BUSY) => {
+                    // The rest will be pushed after the completions have been
+                    // handled
+                    trace!("Completion queue is full; deferring {} reqs", reqs.len());
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            trace!(">> {req:?}");
+            reqs.pop_front();
+        }
+    }
+    Ok(())
+}
+
+/// Poll the inotify watch of a file served with --serve
+fn watch_feed(feed_id: FeedId, ino_fd: &OwnedFd) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::PollAdd::new(rustix_uring::types::Fd(ino_fd.as_raw_fd()), FLAG_POLLIN)
+        .multi(true)
+        .build()
+        .user_data(UserData::FeedInotify(feed_id).into())
+}
+
+/// Stop watching a file which has been removed, and stop accepting clients
+/// for it.  Both requests hold references to the file's fds.
+fn cancel_feed(feed_id: FeedId) -> [rustix_uring::squeue::Entry; 2] {
+    [
+        rustix_uring::opcode::PollRemove::new(UserData::FeedInotify(feed_id).into())
+            .build()
+            .user_data(UserData::CancelFeed.into()),
+        rustix_uring::opcode::AsyncCancel::new(UserData::AcceptFeed(feed_id).into())
+            .build()
+            .user_data(UserData::CancelFeed.into()),
+    ]
+}
+
+/// Accept connections on the listener until further notice.  Each new
+/// connection produces a completion whose result is the new socket.
+fn accept(listener: &impl AsRawFd, user_data: UserData) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::AcceptMulti::new(rustix_uring::types::Fd(listener.as_raw_fd()))
+        .build()
+        .user_data(user_data.into())
+}
+
+fn set_cork(client_id: ClientId, client: &mut Client, cork: bool) {
+    if client.corked != Some(!cork) {
+        return;
+    }
+    match rustix::net::sockopt::set_tcp_cork(&client.conn, cork) {
+        Ok(()) => {
+            trace!(client_id, cork, "Set TCP_CORK");
+            client.corked = Some(cork);
+        }
+        Err(e) => {
+            // Unix sockets don't support it, which is fine
+            if e != Errno::OPNOTSUPP {
+                debug!(client_id, "Couldn't set TCP_CORK: {e}");
+            }
+            client.corked = None;
+        }
+    }
+}
+
+/// Find out when the client goes away, even if we're not sending it
+/// anything.  Otherwise we only notice when a splice fails, which may be
+/// never if the file stops growing.  A client which has only shut down its
+/// side of the connection (eg. `nc` at the end of its input) is still
+/// reading, so that doesn't count.
+fn watch_hangup(client_id: ClientId, client: &Client) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::PollAdd::new(
+        rustix_uring::types::Fd(client.conn.as_raw_fd()),
+        FLAG_POLLHUP | FLAG_POLLERR,
+    )
+    .multi(true)
+    .build()
+    .user_data(UserData::Hangup(client_id).into())
+}
+
+/// The hangup poll holds a reference to the socket, so it needs to be
+/// cancelled when the client is removed.  Otherwise the socket stays open.
+fn cancel_hangup(client_id: ClientId) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::PollRemove::new(UserData::Hangup(client_id).into())
+        .build()
+        .user_data(UserData::CancelHangup.into())
+}
+
+/// Cancel a drain which is waiting for data that isn't coming
+fn cancel_drain(client_id: ClientId) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::AsyncCancel::new(UserData::DrainPipe(client_id).into())
+        .build()
+        .user_data(UserData::CancelDrain.into())
+}
+
+fn fill_pipe(
+    client_id: ClientId,
+    client: &Client,
+    file_fd: rustix_uring::types::Fixed,
+    max_len: u32,
+) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::Splice::new(
+        file_fd,
+        i64::try_from(client.offset).unwrap(),
+        rustix_uring::types::Fd(client.pipe.wtr.as_raw_fd()),
+        -1,
+        max_len,
+    )
+    .build()
+    .user_data(UserData::FillPipe(client_id).into())
+}
+
+fn drain_pipe(client_id: ClientId, client: &Client) -> rustix_uring::squeue::Entry {
+    rustix_uring::opcode::Splice::new(
+        rustix_uring::types::Fd(client.pipe.rdr.as_raw_fd()),
+        -1,
+        rustix_uring::types::Fd(client.conn.as_raw_fd()),
+        -1,
+        u32::MAX,
+    )
+    .build()
+    .user_data(UserData::DrainPipe(client_id).into())
+}
+
+/// Send the client some of its pending encoded data
+fn send_encoded(client_id: ClientId, client: &Client, max_len: u32) -> rustix_uring::squeue::Entry {
+    // The buffer belongs to the client, which isn't removed while the send is
+    // in flight
+    let pending = client.encoder.as_ref().unwrap().pending();
+    rustix_uring::opcode::Send::new(
+        rustix_uring::types::Fd(client.conn.as_raw_fd()),
+        pending.as_ptr(),
+        max_len,
+    )
+    .build()
+    .user_data(UserData::SendEncoded(client_id).into())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_completions(
+    uring: &mut IoUring,
+    reqs: &mut VecDeque<rustix_uring::squeue::Entry>,
+    file: &File,
+    ino_fd: Option<&OwnedFd>,
+    clients: &mut Clients,
+    leaving: &mut Leaving,
+    listeners: &mut Listeners,
+    nursery: &mut Nursery,
+    timer: &mut Timer,
+    total_rate: &mut Option<TokenBucket>,
+    opts: &'static Opts,
+) -> Result<()> {
+    let dropped = uring.completion().overflow();
+    if dropped > 0 {
+        return Err(format!("The io_uring dropped {dropped} completions").into());
+    }
+    for cqe in uring.completion() {
+        let user_data = UserData::try_from(cqe.user_data())?;
+        let more = cqe.flags().contains(rustix_uring::cqueue::Flags::MORE);
+        let result = cqe.result();
+        let result = usize::try_from(result).map_err(|_| Errno::from_raw_os_error(-result));
+        trace!("io_uring completion: {:?}: {:?}", user_data, result);
+        // This is done first, so that a client which is removed below is
+        // dropped straight away if this was its last request
+        match user_data {
+            UserData::FillPipe(client_id)
+            | UserData::DrainPipe(client_id)
+            | UserData::SendEncoded(client_id) => leaving.completed(clients, client_id),
+            UserData::Hangup(client_id) if !more => {
+                if let Some(client) = clients.get_mut(&client_id) {
+                    // It'll be re-armed on the next pass
+                    client.watching_hangup = false;
+                }
+                leaving.completed(clients, client_id);
+            }
+            _ => (),
+        }
+        match (user_data, result) {
+            (UserData::NewClient, Ok(_)) => {
+                trace!("New client");
+                assert!(more);
+                read_eventfd();
+            }
+            (UserData::Inotify, Ok(_)) => {
+                assert!(more);
+                handle_inotify(ino_fd.unwrap(), file, opts)?;
+                // The other runloops don't watch the file, so they need to
+                // be told that it's grown
+                if num_shards() > 1 {
+                    wake_runloop();
+                }
+            }
+            (UserData::AcceptTcp, Ok(fd)) => {
+                let conn = TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+                if !upgrade::forward(&conn, upgrade::Conn::Tcp) {
+                    if let Some(client_id) = tcp_client_id(&conn, opts) {
+                        nursery.admit(conn, client_id, None, reqs, opts);
+                    }
+                }
+            }
+            (UserData::AcceptUnix, Ok(fd)) => {
+                let conn = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+                if !upgrade::forward(&conn, upgrade::Conn::Unix) {
+                    nursery.admit(conn, unix_client_id(), None, reqs, opts);
+                }
+            }
+            (UserData::AcceptFeed(feed_id), Ok(fd)) => {
+                let conn = TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd as i32) });
+                if let (Some(feed), Some(client_id)) =
+                    (feed::get(feed_id), tcp_client_id(&conn, opts))
+                {
+                    nursery.admit(conn, client_id, Some(feed), reqs, opts);
+                }
+            }
+            (UserData::FeedInotify(feed_id), Ok(_)) => {
+                if let Some(feed) = feed::get(feed_id) {
+                    if let Err(e) = feed.handle_inotify() {
+                        error!(feed_id, "Couldn't check the file: {e}");
+                    }
+                }
+                if num_shards() > 1 {
+                    wake_runloop();
+                }
+            }
+            // Cancelled because the file was removed
+            (UserData::AcceptFeed(_) | UserData::FeedInotify(_), Err(Errno::CANCELED)) => (),
+            (UserData::CancelFeed, Ok(_) | Err(Errno::NOENT | Errno::ALREADY)) => (),
+            (UserData::CancelFeed, Err(e)) => error!("{e}"),
+            (UserData::AcceptFeed(feed_id), Err(e)) => error!(feed_id, "Bad connection: {e}"),
+            (UserData::FeedInotify(feed_id), Err(e)) => error!(feed_id, "{e}"),
+            (UserData::ReadHeader(client_id), result) => {
+                nursery.handle_recv(client_id, result, reqs, opts);
+            }
+            // It either fired (and the `Recv` says so), or the `Recv` finished
+            // first
+            (UserData::HeaderTimeout, _) => (),
+            (UserData::AcceptTcp | UserData::AcceptUnix, Err(Errno::INVAL)) => {
+                // Multishot accept needs Linux 5.19
+                if listeners.tcp.is_some() || listeners.unix.is_some() {
+                    warn!("Can't accept connections on the ring; using threads instead");
+                    listeners.spawn_threads(opts);
+                }
+            }
+            (UserData::AcceptTcp | UserData::AcceptUnix, Err(e)) => {
+                error!("Bad connection: {e}");
+            }
+            (UserData::Timer, Ok(_) | Err(Errno::TIME)) => {
+                trace!("Timer fired");
+                timer.armed = false;
+            }
+            (UserData::NewClient | UserData::Inotify | UserData::Timer, Err(e)) => error!("{e}"),
+            // Nothing was spliced, so the drain is waiting for data which
+            // isn't coming
+            (UserData::FillPipe(client_id), Ok(0)) if clients.contains_key(&client_id) => {
+                let _g = info_span!("", client_id).entered();
+                let client = clients.get_mut(&client_id).unwrap();
+                if let Some(bucket) = total_rate.as_mut() {
+                    bucket.refund(std::mem::take(&mut client.reserved));
+                }
+                let len = match &client.feed {
+                    Some(feed) => Source::len(&feed.file),
+                    None => Source::len(file),
+                };
+                let truncated = len.is_ok_and(|len| len <= client.offset)
+                    && truncate_policy() != FilePolicy::Linger;
+                if truncated {
+                    warn!("The file was truncated");
+                }
+                if truncated && client.truncated() {
+                    leaving.remove(clients, client_id, reqs, "file truncated");
+                } else {
+                    trace!("Filled pipe with 0 bytes; retrying");
+                    reqs.push_back(cancel_drain(client_id));
+                }
+            }
+            (UserData::FillPipe(client_id), Ok(n_copied)) => {
+                let _g = info_span!("", client_id).entered();
+                trace!("Filled pipe with {} bytes", n_copied);
+                // The client may have been removed since (eg. it hung up)
+                if let Some(client) = clients.get_mut(&client_id) {
+                    client.bytes_in_pipe += n_copied;
+                    if let Some(bucket) = &mut client.rate_limit {
+                        bucket.consume(n_copied);
+                    }
+                    if let Some(bucket) = total_rate.as_mut() {
+                        bucket.refund(std::mem::take(&mut client.reserved) - n_copied);
+                    }
+                }
+            }
+            (UserData::DrainPipe(client_id), Ok(n_sent)) => {
+                BYTES_SENT.fetch_add(n_sent as u64, Ordering::Relaxed);
+                let Some(client) = clients.get_mut(&client_id) else {
+                    continue;
+                };
+                trace!(parent: &client.span, n_sent, "Sent data to client");
+                client.retries = 0;
+                client.offset += n_sent;
+                client.in_flight = false;
+                if n_sent > client.bytes_in_pipe {
+                    // We've lost track of what's in the pipe, so we can't
+                    // tell where the client has got to
+                    let _g = info_span!("", client_id).entered();
+                    warn!(
+                        n_sent,
+                        bytes_in_pipe = client.bytes_in_pipe,
+                        "Drained more than was put in the pipe; this is a bug"
+                    );
+                    leaving.remove(clients, client_id, reqs, "lost track of the pipe");
+                    continue;
+                }
+                // A short drain leaves the rest in the pipe, and the next
+                // pass drains it before filling again
+                client.bytes_in_pipe -= n_sent;
+            }
+            (UserData::SendEncoded(client_id), Ok(n_sent)) => {
+                BYTES_SENT.fetch_add(n_sent as u64, Ordering::Relaxed);
+                let Some(client) = clients.get_mut(&client_id) else {
+                    continue;
+                };
+                trace!(parent: &client.span, n_sent, "Sent data to client");
+                client.retries = 0;
+                client.encoder.as_mut().unwrap().consume(n_sent);
+                if let Some(bucket) = &mut client.rate_limit {
+                    bucket.consume(n_sent);
+                }
+                if let Some(bucket) = total_rate.as_mut() {
+                    bucket.refund(std::mem::take(&mut client.reserved) - n_sent);
+                }
+                client.in_flight = false;
+            }
+            // Some filesystems don't support splice()
+            (UserData::FillPipe(client_id), Err(Errno::INVAL))
+                if clients.contains_key(&client_id) =>
+            {
+                let _g = info_span!("", client_id).entered();
+                let client = clients.get_mut(&client_id).unwrap();
+                client.stop_splicing();
+                if let Some(bucket) = total_rate.as_mut() {
+                    bucket.refund(std::mem::take(&mut client.reserved));
+                }
+                // The drain is still waiting for the pipe to be filled
+                reqs.push_back(cancel_drain(client_id));
+            }
+            // We cancelled the drain, because the fill didn't put anything in
+            // the pipe (or the client stopped splicing).  The client is tried
+            // again on the next pass.
+            (UserData::DrainPipe(client_id), Err(Errno::CANCELED))
+                if clients.contains_key(&client_id) =>
+            {
+                clients.get_mut(&client_id).unwrap().in_flight = false;
+            }
+            // Encoded clients don't use the pipe, so this is the drain which
+            // we cancelled when the client stopped splicing
+            (UserData::DrainPipe(client_id), Err(_))
+                if clients.get(&client_id).is_some_and(|x| x.encoder.is_some()) =>
+            {
+                clients.get_mut(&client_id).unwrap().in_flight = false;
+            }
+            // The client has been removed already, and this is one of its
+            // requests being cancelled (or failing) on the way out
+            (
+                UserData::FillPipe(client_id)
+                | UserData::DrainPipe(client_id)
+                | UserData::SendEncoded(client_id),
+                Err(_),
+            ) if !clients.contains_key(&client_id) => (),
+            (
+                UserData::FillPipe(client_id)
+                | UserData::DrainPipe(client_id)
+                | UserData::SendEncoded(client_id),
+                Err(e),
+            ) => {
+                let _g = info_span!("", client_id).entered();
+                let client = clients.get_mut(&client_id).unwrap();
+                if let Some(bucket) = total_rate.as_mut() {
+                    bucket.refund(std::mem::take(&mut client.reserved));
+                }
+                let class = ErrorClass::of(e);
+                match user_data {
+                    // The drain is waiting for the pipe to be filled.  When
+                    // it's been cancelled, the client is free to go again.
+                    UserData::FillPipe(_) => {
+                        if class == ErrorClass::Transient && client.back_off(e) {
+                            reqs.push_back(cancel_drain(client_id));
+                            continue;
+                        }
+                    }
+                    // The drain is the last of the pair, so there's nothing
+                    // left to cancel.  Anything still in the pipe is drained
+                    // on the next go.
+                    _ => {
+                        client.in_flight = false;
+                        if class == ErrorClass::Transient && client.back_off(e) {
+                            continue;
+                        }
+                    }
+                }
+                match class {
+                    ErrorClass::Transient => (),
+                    ErrorClass::Closed => info!("Socket closed by other side"),
+                    ErrorClass::Fatal => error!("{e}"),
+                }
+                leaving.remove(clients, client_id, reqs, e);
+            }
+            (UserData::Hangup(client_id), Ok(_)) => {
+                let _g = info_span!("", client_id).entered();
+                if clients.contains_key(&client_id) {
+                    info!("Client hung up");
+                    leaving.remove(clients, client_id, reqs, "hung up");
+                }
+            }
+            // Cancelled because the client was removed
+            (UserData::Hangup(_), Err(Errno::CANCELED)) => (),
+            // It may have completed before we got around to cancelling it
+            (UserData::CancelHangup, Ok(_) | Err(Errno::NOENT | Errno::ALREADY)) => (),
+            // A drain which was already running gets interrupted instead, and
+            // one which has finished can't be cancelled at all
+            (UserData::CancelDrain, Ok(_) | Err(Errno::ALREADY | Errno::NOENT)) => (),
+            (UserData::Hangup(client_id), Err(e)) => error!(client_id, "{e}"),
+            (UserData::CancelHangup | UserData::CancelDrain, Err(e)) => error!("{e}"),
+        }
+        // The kernel may cancel a multishot accept (eg. if we run out of fds),
+        // in which case we need to re-arm it
+        match user_data {
+            UserData::AcceptTcp if !more => {
+                if let Some(listener) = &listeners.tcp {
+                    trace!("Re-arming accept");
+                    reqs.push_back(accept(listener, UserData::AcceptTcp));
+                }
+            }
+            UserData::AcceptUnix if !more => {
+                if let Some(listener) = &listeners.unix {
+                    trace!("Re-arming accept");
+                    reqs.push_back(accept(listener, UserData::AcceptUnix));
+                }
+            }
+            UserData::AcceptFeed(feed_id) if !more && result != Err(Errno::CANCELED) => {
+                if let Some(feed) = feed::get(feed_id) {
+                    trace!(feed_id, "Re-arming accept");
+                    reqs.push_back(accept(&feed.listener, UserData::AcceptFeed(feed_id)));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UserData {
+    NewClient,
+    Inotify,
+    Timer,
+    AcceptTcp,
+    AcceptUnix,
+    CancelHangup,
+    CancelDrain,
+    CancelFeed,
+    HeaderTimeout,
+    FillPipe(ClientId),
+    DrainPipe(ClientId),
+    ReadHeader(ClientId),
+    Hangup(ClientId),
+    SendEncoded(ClientId),
+    AcceptFeed(FeedId),
+    FeedInotify(FeedId),
+}
+// The top byte says what kind of request it was.  For per-client requests the
+// rest is the client ID; it would take years of connections to overflow this.
+const KIND_SHIFT: u32 = 56;
+const ID_MASK: u64 = (1 << KIND_SHIFT) - 1;
+const FILL: u64 = 1;
+const DRAIN: u64 = 2;
+const HEADER: u64 = 3;
+const HANGUP: u64 = 4;
+const SEND: u64 = 5;
+const ACCEPT_FEED: u64 = 6;
+const FEED_INOTIFY: u64 = 7;
+impl From<UserData> for u64 {
+    fn from(value: UserData) -> Self {
+        match value {
+            UserData::NewClient => 0,
+            UserData::Inotify => 1,
+            UserData::Timer => 2,
+            UserData::AcceptTcp => 3,
+            UserData::AcceptUnix => 4,
+            UserData::CancelHangup => 5,
+            UserData::CancelDrain => 6,
+            UserData::CancelFeed => 7,
+            UserData::HeaderTimeout => 8,
+            UserData::FillPipe(id) => FILL << KIND_SHIFT | id,
+            UserData::DrainPipe(id) => DRAIN << KIND_SHIFT | id,
+            UserData::ReadHeader(id) => HEADER << KIND_SHIFT | id,
+            UserData::Hangup(id) => HANGUP << KIND_SHIFT | id,
+            UserData::SendEncoded(id) => SEND << KIND_SHIFT | id,
+            UserData::AcceptFeed(id) => ACCEPT_FEED << KIND_SHIFT | u64::from(id),
+            UserData::FeedInotify(id) => FEED_INOTIFY << KIND_SHIFT | u64::from(id),
+        }
+    }
+}
+impl TryFrom<u64> for UserData {
+    type Error = Box<dyn std::error::Error>;
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match (value >> KIND_SHIFT, value & ID_MASK) {
+            (0, 0) => Ok(UserData::NewClient),
+            (0, 1) => Ok(UserData::Inotify),
+            (0, 2) => Ok(UserData::Timer),
+            (0, 3) => Ok(UserData::AcceptTcp),
+            (0, 4) => Ok(UserData::AcceptUnix),
+            (0, 5) => Ok(UserData::CancelHangup),
+            (0, 6) => Ok(UserData::CancelDrain),
+            (0, 7) => Ok(UserData::CancelFeed),
+            (0, 8) => Ok(UserData::HeaderTimeout),
+            (FILL, id) => Ok(UserData::FillPipe(id)),
+            (DRAIN, id) => Ok(UserData::DrainPipe(id)),
+            (HEADER, id) => Ok(UserData::ReadHeader(id)),
+            (HANGUP, id) => Ok(UserData::Hangup(id)),
+            (SEND, id) => Ok(UserData::SendEncoded(id)),
+            (ACCEPT_FEED, id) => Ok(UserData::AcceptFeed(FeedId::try_from(id)?)),
+            (FEED_INOTIFY, id) => Ok(UserData::FeedInotify(FeedId::try_from(id)?)),
+            _ => Err(format!("Unknown user data: {value}").into()),
+        }
+    }
+}
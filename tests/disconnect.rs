@@ -0,0 +1,143 @@
+//! Clients which go away in the middle of being sent the file.  Each one has
+//! to be torn down properly (including whatever the backend has in flight
+//! for it), or it keeps its slot, and once `MAX_CLIENTS` slots are lost
+//! nobody else can connect.  The file is big enough that no client can be
+//! sent all of it at once, so they're always cut off mid-stream.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{LazyLock, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use tailsrv::{FilePolicy, TailServer};
+
+const FILE_LEN: usize = 32 * 1024 * 1024;
+const MAX_CLIENTS: usize = 4;
+/// How long the server gets to notice that the clients have gone
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+static CONTENTS: LazyLock<Vec<u8>> =
+    LazyLock::new(|| (0..FILE_LEN).map(|i| (i % 251) as u8).collect());
+/// Only one server can run per process, so the tests share it
+static SERVER: OnceLock<SocketAddr> = OnceLock::new();
+/// The tests take turns, since each of them uses every slot
+static TURN: Mutex<()> = Mutex::new(());
+
+/// Start the server if it isn't running, and wait for our turn with it
+fn server() -> (SocketAddr, MutexGuard<'static, ()>) {
+    let addr = *SERVER.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("tailsrv-test-{}", std::process::id()));
+        std::fs::write(&path, &*CONTENTS).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TailServer::builder()
+            .path(&path)
+            .listener(listener)
+            .max_clients(MAX_CLIENTS)
+            .chunk_size(64 * 1024)
+            // So that we can clean up after ourselves
+            .on_delete(FilePolicy::Linger);
+        std::thread::spawn(move || server.run().unwrap());
+        // The server has the file open once it's serving it
+        read_whole_file(addr);
+        std::fs::remove_file(&path).unwrap();
+        addr
+    });
+    let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+    (addr, turn)
+}
+
+/// Read the first `len` bytes of the file, and check them.  Returns `None`
+/// if we were turned away, because every slot is taken.  `len` has to be
+/// long enough to tell the file from the error message.
+fn read_from_start(addr: SocketAddr, len: usize) -> Option<TcpStream> {
+    let mut conn = TcpStream::connect(addr).unwrap();
+    conn.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    // We may already have been turned away
+    conn.write_all(b"0\n").ok()?;
+    let mut buf = vec![0; len];
+    let mut n = 0;
+    while n < len {
+        match conn.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(x) => n += x,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => break,
+            Err(e) => panic!("Couldn't read the file: {e}"),
+        }
+    }
+    if buf[..n].starts_with(b"ERR too many clients") {
+        return None;
+    }
+    assert_eq!(n, len, "The stream ended early");
+    assert!(buf[..] == CONTENTS[..len], "The file was sent wrong");
+    Some(conn)
+}
+
+/// Keep trying until we get a slot.  The clients which went away before
+/// must have given theirs back by then.
+fn admitted(addr: SocketAddr, len: usize) -> TcpStream {
+    let start = Instant::now();
+    loop {
+        if let Some(conn) = read_from_start(addr, len) {
+            return conn;
+        }
+        assert!(
+            start.elapsed() < TEARDOWN_TIMEOUT,
+            "The clients which went away are still taking up slots"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Check that the server is still sending the right data
+fn read_whole_file(addr: SocketAddr) {
+    drop(admitted(addr, FILE_LEN));
+}
+
+#[test]
+fn clients_vanish_mid_stream() {
+    let (addr, _turn) = server();
+    for round in 0..5 {
+        let clients: Vec<_> = (0..MAX_CLIENTS)
+            .map(|i| {
+                // Different amounts, so that they're cut off at different
+                // points in the fill/drain cycle.  Closing the connection
+                // with the rest unread resets it.
+                let len = 1024 * 1024 + (round * MAX_CLIENTS + i) * 37 * 1024;
+                std::thread::spawn(move || drop(admitted(addr, len)))
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        read_whole_file(addr);
+    }
+}
+
+#[test]
+fn clients_vanish_with_data_in_flight() {
+    let (addr, _turn) = server();
+    for _ in 0..3 {
+        // These stop reading, so the server fills their socket buffers, and
+        // is left with a send (or splice) which can't finish
+        let conns: Vec<_> = (0..MAX_CLIENTS)
+            .map(|_| admitted(addr, 64 * 1024))
+            .collect();
+        std::thread::sleep(Duration::from_millis(200));
+        drop(conns);
+        read_whole_file(addr);
+    }
+}
+
+#[test]
+fn half_closed_clients_are_still_served() {
+    let (addr, _turn) = server();
+    let mut conn = admitted(addr, 64 * 1024);
+    // Like `nc -N`: we've nothing more to say, but we're still listening
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut rest = vec![0; FILE_LEN - 64 * 1024];
+    conn.read_exact(&mut rest)
+        .expect("The half-closed client was dropped");
+    assert!(rest[..] == CONTENTS[64 * 1024..], "The file was sent wrong");
+}